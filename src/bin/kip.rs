@@ -10,12 +10,26 @@ use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::*;
 use dialoguer::{theme::ColorfulTheme, Confirm, Password, Select};
-use kip::cli::{Cli, Subcommands};
+use kip::check::parse_percent;
+use kip::cli::{Cli, IndexCommands, Subcommands};
 use kip::compress::KipCompressOpts;
-use kip::conf::KipConf;
+use kip::conf::{KipConf, KipConfMetadata};
+use kip::control::{clear_pause, is_running, request_pause};
 use kip::crypto::{keyring_get_secret, keyring_set_secret};
-use kip::job::{Job, KipFile, KipStatus};
-use kip::providers::{gdrive::KipGdrive, s3::KipS3, usb::KipUsb, KipProviders};
+use kip::job::{resolve_entry, Job, KipFile, KipStatus};
+use kip::providers::{
+    azure::{KipAzure, KipAzureAuth, KipAzureTier},
+    gdrive::KipGdrive,
+    local::KipLocal,
+    s3::{KipObjectLock, KipObjectLockMode, KipS3, KipS3Sse},
+    sftp::{KipSftp, KipSftpAuth},
+    smb::{KipSmb, DEFAULT_SMB_PORT},
+    usb::KipUsb,
+    webdav::{KipWebDav, KipWebDavAuth},
+    KipProviders,
+};
+use kip::retention::KipRetention;
+use kip::run::KipRunOpts;
 use kip::smtp::{send_email, KipEmail};
 use kip::terminate;
 use notify_rust::{Hint, Notification};
@@ -72,6 +86,14 @@ fn main() {
                 eprintln!("{} unable to initialize kip tracing: {e}", "[ERR]".red());
             });
 
+        // Every job's transfers share the global bandwidth limit
+        kip::limiter::set_global_bandwidth(cfg.settings.bandwidth_limit_bytes());
+        kip::retry::set_retry_attempts(cfg.settings.retry_attempts);
+        kip::providers::s3::set_multipart_opts(
+            cfg.settings.s3_part_size,
+            cfg.settings.s3_part_concurrency,
+        );
+
         // Prompt for SMTP password to be stored in keyring
         // if SMTP settings have been modified/configured in cfg
         if cfg.settings.email_notification {
@@ -132,220 +154,28 @@ fn main() {
                         );
                     },
                 );
-                // Confirm if S3 or USB job
-                let provider_selection: usize = Select::with_theme(&ColorfulTheme::default())
-                    .item("S3")
-                    .item("Google Drive")
-                    .item("USB")
-                    .default(0)
-                    .interact()
-                    .expect("[ERR] unable to create provider selection menu.");
-                match provider_selection {
-                    0 => {
-                        // Get S3 access key from user input
-                        print!("Please provide the S3 access key: ");
-                        std::io::stdout()
-                            .flush()
-                            .expect("[ERR] failed to flush stdout.");
-                        let mut s3_acc_key = String::new();
-                        std::io::stdin()
-                            .read_line(&mut s3_acc_key)
-                            .expect("[ERR] failed to read S3 access key from stdin.");
-                        // Store S3 access key onto local OS keyring
-                        keyring_set_secret(
-                            &format!("com.ciehanski.kip.{job}.s3acc"),
-                            &s3_acc_key,
-                        )
-                        .unwrap_or_else(|e| {
-                            terminate!(
-                                5,
-                                "{} failed to push S3 access key onto keyring: {e}.",
-                                "[ERR]".red(),
-                            );
-                        });
-                        // Get S3 secret key from user input
-                        let s3_sec_key = Password::new()
-                            .with_prompt("Please provide the S3 secret key")
-                            .interact()
-                            .expect("[ERR] failed to create S3 secret key prompt.");
-                        // Store S3 secret key onto local OS keyring
-                        keyring_set_secret(
-                            &format!("com.ciehanski.kip.{job}.s3sec"),
-                            &s3_sec_key,
-                        )
-                        .unwrap_or_else(|e| {
-                            terminate!(
-                                5,
-                                "{} failed to push S3 secret key onto keyring: {e}.",
-                                "[ERR]".red(),
-                            );
-                        });
-                        // Get S3 bucket name from user input
-                        print!("Please provide the S3 bucket name: ");
-                        std::io::stdout()
-                            .flush()
-                            .expect("[ERR] failed to flush stdout.");
-                        let mut s3_bucket_name = String::new();
-                        std::io::stdin()
-                            .read_line(&mut s3_bucket_name)
-                            .expect("[ERR] failed to read S3 bucket name from stdin.");
-                        // Get S3 bucket region from user input
-                        print!("Please provide the S3 region: ");
-                        std::io::stdout()
-                            .flush()
-                            .expect("[ERR] failed to flush stdout.");
-                        let mut s3_region = String::new();
-                        std::io::stdin()
-                            .read_line(&mut s3_region)
-                            .expect("[ERR] failed to read from stdin.");
-                        // Create the new job
-                        let provider = KipProviders::S3(KipS3::new(
-                            s3_bucket_name.trim_end(),
-                            Region::new(s3_region.trim_end().to_owned()),
-                        ));
-                        let new_job = Job::new(
-                            &job,
-                            provider,
-                            KipCompressOpts::new(
-                                cfg.settings.compression,
-                                cfg.settings.compression_alg,
-                                cfg.settings.compress_level
-                            ),
-                        );
-                        // Push new job in config
-                        md.jobs.insert(job.clone(), new_job);
-                    }
-                    1 => {
-                        // Google Drive
-                        // Get Google Drive client ID from user input
-                        print!("Please provide the Google Drive OAuth client ID: ");
-                        std::io::stdout()
-                            .flush()
-                            .expect("[ERR] failed to flush stdout.");
-                        let mut gdrive_client_id = String::new();
-                        std::io::stdin().read_line(&mut gdrive_client_id).expect(
-                            "[ERR] failed to read Google Drive OAuth client ID from stdin.",
-                        );
-                        // Store Google Drive client ID onto local OS keyring
-                        keyring_set_secret(
-                            &format!("com.ciehanski.kip.{job}.gdriveid"),
-                            &gdrive_client_id,
-                        )
-                        .unwrap_or_else(|e| {
-                            terminate!(
-                                5,
-                                "{} failed to push Google Drive client ID onto keyring: {e}.",
-                                "[ERR]".red(),
-                            );
-                        });
-                        // Get Google Drive client secret from user input
-                        print!("Please provide the Google Drive OAuth client secret: ");
-                        std::io::stdout()
-                            .flush()
-                            .expect("[ERR] failed to flush stdout.");
-                        let mut gdrive_client_sec = String::new();
-                        std::io::stdin().read_line(&mut gdrive_client_sec).expect(
-                            "[ERR] failed to read Google Drive OAuth client secret from stdin.",
-                        );
-                        // Store Google Drive client ID onto local OS keyring
-                        keyring_set_secret(
-                            &format!("com.ciehanski.kip.{job}.gdrivesec"),
-                            &gdrive_client_sec,
-                        )
-                        .unwrap_or_else(|e| {
-                            terminate!(
-                                5,
-                                "{} failed to push Google Drive client ID onto keyring: {e}.",
-                                "[ERR]".red(),
-                            );
-                        });
-                        // Get GDrive parent folder from user input
-                        print!("Optionally, provide the parent folder ID: ");
-                        std::io::stdout()
-                            .flush()
-                            .expect("[ERR] failed to flush stdout.");
-                        let mut gdrive_folder = String::new();
-                        std::io::stdin().read_line(&mut gdrive_folder).expect(
-                            "[ERR] failed to read Google Drive parent folder ID from stdin.",
-                        );
-                        // Create the new job
-                        let provider =
-                            KipProviders::Gdrive(KipGdrive::new(Some(gdrive_folder.trim_end())));
-                        let new_job = Job::new(
-                            &job,
-                            provider,
-                            KipCompressOpts::new(
-                                cfg.settings.compression,
-                                cfg.settings.compression_alg,
-                                cfg.settings.compress_level
-                            ),
-                        );
-                        // Push new job in config
-                        md.jobs.insert(job.clone(), new_job);
-                    }
-                    2 => {
-                        // USB
-                        let mut sys = System::new();
-                        sys.refresh_disks_list();
-                        let disks = sys.disks();
-                        let disks_str: Vec<String> = disks
-                            .iter()
-                            .filter_map(|d| {
-                                let disk = d
-                                    .name()
-                                    .to_str()
-                                    .expect("[ERR] unable to convert disk's OsStr to String");
-                                match disk {
-                                    "Macintosh HD - Data" => None,
-                                    "VM" => None,
-                                    "Preboot" => None,
-                                    "Update" => None,
-                                    "Recovery" => None,
-                                    "" => None,
-                                    _ => Some(disk.to_owned()),
-                                }
-                            })
-                            .collect();
-                        // Ensure USB devices were found
-                        if disks_str.is_empty() {
-                            terminate!(1, "no USB devices detected.");
-                        };
-                        // Confirm which USB device
-                        let provider_selection: usize =
-                            Select::with_theme(&ColorfulTheme::default())
-                                .items(&disks_str)
-                                .default(0)
-                                .interact()
-                                .unwrap_or_else(|_| { terminate!(1, "[ERR] unable to create USB selection menu") });
-                        // Create the new job
-                        let provider = KipProviders::Usb(KipUsb::new(
-                            disks[provider_selection]
-                                .name()
-                                .to_str()
-                                .unwrap_or_else(|| {
-                                    terminate!(1, "[ERR] unable to convert disk's OsStr to String");
-                                })
-                                .to_owned(),
-                            disks[provider_selection].mount_point(),
-                            disks[provider_selection].total_space(),
-                            disks[provider_selection].available_space(),
-                        ));
-                        let new_job = Job::new(
-                            &job,
-                            provider,
-                            KipCompressOpts::new(
-                                cfg.settings.compression,
-                                cfg.settings.compression_alg,
-                                cfg.settings.compress_level
-                            ),
-                        );
-                        // Push new job in config
-                        md.jobs.insert(job.clone(), new_job);
-                    }
-                    _ => {
-                        terminate!(1, "Invalid selection. Please try again.");
-                    }
+                // Get the job's provider from user input
+                let provider = prompt_provider(&job);
+                // Create the new job
+                let mut new_job = Job::new(
+                    &job,
+                    provider,
+                    KipCompressOpts::new(
+                        cfg.settings.compression,
+                        cfg.settings.compression_alg,
+                        cfg.settings.compress_level,
+                    ),
+                );
+                // Jobs using S3 Object Lock need a bucket with it enabled
+                new_job.sync_object_lock().unwrap_or_else(|e| {
+                    terminate!(2, "{} failed to set Object Lock retention: {e}.", "[ERR]".red());
+                });
+                if let Err(e) = new_job.check_object_lock().await {
+                    let _ = new_job.delete_keyring_entries();
+                    terminate!(2, "{} {e}.", "[ERR]".red());
                 }
+                // Push new job in config
+                md.jobs.insert(job.clone(), new_job);
                 // Store new job in config
                 match md.save() {
                     Ok(_) => println!("{} job '{job}' successfully created.", "[OK]".green()),
@@ -364,17 +194,16 @@ fn main() {
                     terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
                 });
                 for f in &file_path {
-                    // Check if path exists
-                    if !Path::new(f).exists() {
+                    // Check if path exists. Symlinks are added
+                    // even if what they point to doesn't.
+                    if Path::new(f).symlink_metadata().is_err() {
                         terminate!(2, "{} '{f}' doesn't exist.", "[ERR]".red());
                     }
+                    let fpath = resolve_entry(f, cfg.settings.follow_symlinks)
+                        .expect("[ERR] unable to canonicalize path.");
                     // Check if files are excluded by this job
                     for jf in &j.excluded_files {
-                        if jf
-                            == &Path::new(f)
-                                .canonicalize()
-                                .expect("[ERR] unable to canonicalize path.")
-                        {
+                        if jf == &fpath {
                             terminate!(
                                 17,
                                 "{} file(s) are excluded on job '{job}'.",
@@ -385,11 +214,7 @@ fn main() {
                     // Check if files already exist on job
                     // to avoid duplication.
                     for jf in &j.files {
-                        if jf.path
-                            == Path::new(f)
-                                .canonicalize()
-                                .expect("[ERR] unable to canonicalize path.")
-                        {
+                        if jf.path == fpath {
                             terminate!(
                                 17,
                                 "{} file(s) already exist on job '{job}'.",
@@ -403,8 +228,7 @@ fn main() {
                 // Push new files to job
                 for f in file_path {
                     j.files.push(KipFile::new(
-                        PathBuf::from(f)
-                            .canonicalize()
+                        resolve_entry(f, cfg.settings.follow_symlinks)
                             .expect("[ERR] unable to canonicalize path."),
                     ).expect("[ERR] unable to create KipFile."));
                 }
@@ -455,8 +279,7 @@ fn main() {
                     // -f was provided, delete files from job
                     Some(files) => {
                         for f in files {
-                            let fpath = PathBuf::from(&f)
-                                .canonicalize()
+                            let fpath = resolve_entry(&f, cfg.settings.follow_symlinks)
                                 .expect("[ERR] unable to canonicalize path.");
                             let mut found = false;
                             for kf in j.files.iter() {
@@ -470,13 +293,15 @@ fn main() {
                                 // Find all the runs that contain this file's chunks
                                 // and remove them from S3.
                                 if purge {
-                                    j.purge_file(&f).await.unwrap_or_else(|e| {
-                                        terminate!(
-                                            21,
-                                            "{} failed to remove files from S3 for {job}: {e}.",
-                                            "[ERR]".red(),
-                                        );
-                                    });
+                                    j.purge_file(&f, cfg.settings.follow_symlinks)
+                                        .await
+                                        .unwrap_or_else(|e| {
+                                            terminate!(
+                                                21,
+                                                "{} failed to remove files from S3 for {job}: {e}.",
+                                                "[ERR]".red(),
+                                            );
+                                        });
                                 }
                             } else {
                                 terminate!(
@@ -609,7 +434,7 @@ fn main() {
             }
 
             // Start a job's upload
-            Subcommands::Push { job } => {
+            Subcommands::Push { job, force_rehash } => {
                 let _trace = span!(Level::DEBUG, "KIP_PUSH").entered();
                 let mut md = md.write().await;
                 // Get job from argument provided
@@ -637,14 +462,20 @@ fn main() {
                 match j
                     .start_run(
                         &secret,
-                        cfg.settings.follow_symlinks,
+                        KipRunOpts::new(
+                            cfg.settings.follow_symlinks,
+                            force_rehash,
+                            cfg.settings.memory_limit_bytes(),
+                        ),
                     )
                     .await
                 {
+                    // The run was aborted by 'kip abort'
+                    Ok(_) if j.last_status == KipStatus::ABORTED => {}
                     Ok(_) => {
                         // Send success email if setting enabled
                         if cfg.settings.email_notification {
-                            if let Some(run) = j.runs.get(&j.runs.len()) {
+                            if let Some(run) = j.runs.values().next_back() {
                                 // Craft the email
                                 let email = KipEmail {
                                     title: format!(
@@ -673,7 +504,7 @@ fn main() {
                     Err(e) => {
                         // Send error email if setting enabled
                         if cfg.settings.email_notification {
-                            if let Some(run) = j.runs.get(&j.runs.len()) {
+                            if let Some(run) = j.runs.values().next_back() {
                                 // Craft the email
                                 let email = KipEmail {
                                     title: format!(
@@ -780,6 +611,393 @@ fn main() {
                 });
             }
 
+            // Rebuild a job from its provider's manifests
+            Subcommands::Recover { job } => {
+                let _trace = span!(Level::DEBUG, "KIP_RECOVER").entered();
+                let mut md = md.write().await;
+                // Ensure that job does not already exist
+                if md.jobs.contains_key(&job) {
+                    terminate!(17, "{} job '{job}' already exists.", "[ERR]".red());
+                }
+                // Get secret from user input
+                let secret = Password::new()
+                    .with_prompt("Please provide the encryption secret of the job to recover")
+                    .interact()
+                    .expect("[ERR] failed to create encryption secret prompt.");
+                // Store secret onto local OS keyring
+                keyring_set_secret(&format!("com.ciehanski.kip.{job}"), &secret).unwrap_or_else(
+                    |e| {
+                        terminate!(
+                            5,
+                            "{} failed to push secret onto keyring: {e}.",
+                            "[ERR]".red(),
+                        );
+                    },
+                );
+                // Get the provider to recover from
+                let provider = prompt_provider(&job);
+                println!(
+                    "{} searching '{}' for backups...",
+                    "[INFO]".yellow(),
+                    provider.name()
+                );
+                let mut jobs = Job::recover(&job, provider, &secret)
+                    .await
+                    .unwrap_or_else(|e| {
+                        terminate!(2, "{} failed to recover job: {e}.", "[ERR]".red());
+                    });
+                if jobs.is_empty() {
+                    terminate!(
+                        2,
+                        "{} no backups found that can be decrypted with the secret provided.",
+                        "[ERR]".red()
+                    );
+                }
+                // Confirm which job if the secret matched several
+                let job_selection: usize = if jobs.len() > 1 {
+                    let jobs_str: Vec<String> = jobs
+                        .iter()
+                        .map(|j| {
+                            format!(
+                                "{} ({}) - {} runs, last run {}",
+                                j.name,
+                                j.id,
+                                j.runs.len(),
+                                j.last_run.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+                            )
+                        })
+                        .collect();
+                    Select::with_theme(&ColorfulTheme::default())
+                        .items(&jobs_str)
+                        .default(0)
+                        .interact()
+                        .unwrap_or_else(|_| terminate!(1, "[ERR] unable to create job selection menu"))
+                } else {
+                    0
+                };
+                let mut recovered = jobs.swap_remove(job_selection);
+                recovered.name = job.clone();
+                let runs = recovered.runs.len();
+                md.jobs.insert(job.clone(), recovered);
+                // Store recovered job in config
+                match md.save() {
+                    Ok(_) => println!(
+                        "{} job '{job}' successfully recovered with {runs} run(s).",
+                        "[OK]".green()
+                    ),
+                    Err(e) => {
+                        terminate!(7, "{} failed to save kip configuration: {e}", "[ERR]".red())
+                    }
+                }
+            }
+
+            // Verifies a job's chunks against its provider
+            Subcommands::Check {
+                job,
+                read_data,
+                read_data_subset,
+            } => {
+                let _trace = span!(Level::DEBUG, "KIP_CHECK").entered();
+                let md = md.read().await;
+                // Get job from argument provided
+                let j = md.jobs.get(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let secret = confirm_secret(&j.name);
+                let read_percent = match read_data_subset {
+                    Some(subset) => Some(parse_percent(&subset).unwrap_or_else(|e| {
+                        terminate!(2, "{} {e}.", "[ERR]".red());
+                    })),
+                    None if read_data => Some(100.0),
+                    None => None,
+                };
+                println!(
+                    "{} checking job '{job}' against '{}'...",
+                    "[INFO]".yellow(),
+                    j.provider_name()
+                );
+                let report = j.check(&secret, read_percent).await.unwrap_or_else(|e| {
+                    terminate!(2, "{} failed to check job '{job}': {e}.", "[ERR]".red());
+                });
+                for remote_path in report.missing.iter() {
+                    println!("{} missing chunk '{}'.", "[ERR]".red(), remote_path.red());
+                }
+                for remote_path in report.corrupt.iter() {
+                    println!("{} corrupt chunk '{}'.", "[ERR]".red(), remote_path.red());
+                }
+                for remote_path in report.orphaned.iter() {
+                    println!(
+                        "{} orphaned chunk '{}'.",
+                        "[WARN]".yellow(),
+                        remote_path.yellow()
+                    );
+                }
+                // Print results
+                let mut table = Table::new();
+                table
+                    .load_preset(UTF8_FULL)
+                    .apply_modifier(UTF8_ROUND_CORNERS)
+                    .set_content_arrangement(ContentArrangement::Dynamic)
+                    .set_header(vec![
+                        "Referenced",
+                        "Read",
+                        "Missing",
+                        "Corrupt",
+                        "Orphaned",
+                    ])
+                    .add_row(vec![
+                        Cell::new(report.referenced),
+                        Cell::new(report.read),
+                        Cell::new(report.missing.len()),
+                        Cell::new(report.corrupt.len()),
+                        Cell::new(report.orphaned.len()),
+                    ]);
+                println!("{table}");
+                if !report.is_ok() {
+                    terminate!(
+                        23,
+                        "{} job '{job}' has missing or corrupt chunks. Please run 'kip repair {job}'.",
+                        "[ERR]".red()
+                    );
+                }
+                println!("{} job '{job}' passed all checks.", "[OK]".green());
+            }
+
+            // Re-uploads missing or corrupt chunks
+            Subcommands::Repair { job, read_data } => {
+                let _trace = span!(Level::DEBUG, "KIP_REPAIR").entered();
+                let mut md = md.write().await;
+                // Get job from argument provided
+                let j = md.jobs.get_mut(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let secret = confirm_secret(&j.name);
+                let read_percent = if read_data { Some(100.0) } else { None };
+                let report = j.repair(&secret, read_percent).await.unwrap_or_else(|e| {
+                    terminate!(2, "{} failed to repair job '{job}': {e}.", "[ERR]".red());
+                });
+                for remote_path in report.repaired.iter() {
+                    println!("{} repaired chunk '{}'.", "[OK]".green(), remote_path.green());
+                }
+                for path in report.damaged.iter() {
+                    println!(
+                        "{} '{}' is damaged and can't be restored.",
+                        "[ERR]".red(),
+                        path.display().to_string().red()
+                    );
+                }
+                // Save changes to config file
+                md.save().unwrap_or_else(|e| {
+                    terminate!(
+                        7,
+                        "{} failed to save kip configuration: {e}",
+                        "[ERR]".red(),
+                    );
+                });
+                if !report.damaged.is_empty() {
+                    terminate!(
+                        23,
+                        "{} {} file(s) of job '{job}' couldn't be repaired.",
+                        "[ERR]".red(),
+                        report.damaged.len()
+                    );
+                }
+                println!(
+                    "{} job '{job}' repaired {} chunk(s).",
+                    "[OK]".green(),
+                    report.repaired.len()
+                );
+            }
+
+            // Sets a job's retention policy
+            Subcommands::Retention {
+                job,
+                keep_last,
+                keep_hourly,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                keep_yearly,
+                keep_within,
+                keep_forever,
+                clear,
+            } => {
+                let _trace = span!(Level::DEBUG, "KIP_RETENTION").entered();
+                let mut md = md.write().await;
+                // Get job from argument provided
+                let j = md.jobs.get_mut(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let _ = confirm_secret(&j.name);
+                if let Some(within) = &keep_within {
+                    if let Err(e) = humantime::parse_duration(within) {
+                        terminate!(2, "{} invalid keep-within duration '{within}': {e}.", "[ERR]".red());
+                    }
+                }
+                if clear {
+                    j.retention = KipRetention::default();
+                }
+                // Only the rules provided are changed
+                let r = &mut j.retention;
+                r.keep_last = keep_last.or(r.keep_last);
+                r.keep_hourly = keep_hourly.or(r.keep_hourly);
+                r.keep_daily = keep_daily.or(r.keep_daily);
+                r.keep_weekly = keep_weekly.or(r.keep_weekly);
+                r.keep_monthly = keep_monthly.or(r.keep_monthly);
+                r.keep_yearly = keep_yearly.or(r.keep_yearly);
+                r.keep_within = keep_within.or(r.keep_within.take());
+                if let Some(rid) = keep_forever {
+                    let run = j.runs.get_mut(&rid).unwrap_or_else(|| {
+                        terminate!(2, "{} run '{rid}' doesn't exist for job '{job}'.", "[ERR]".red());
+                    });
+                    run.retain_forever = true;
+                }
+                // Objects uploaded from now on are locked for
+                // as long as the new policy keeps runs
+                j.sync_object_lock().unwrap_or_else(|e| {
+                    terminate!(2, "{} failed to set Object Lock retention: {e}.", "[ERR]".red());
+                });
+                println!(
+                    "{} job '{job}' retention policy: {}.",
+                    "[OK]".green(),
+                    j.retention
+                );
+                // Save changes to config file
+                md.save().unwrap_or_else(|e| {
+                    terminate!(
+                        7,
+                        "{} failed to save kip configuration: {e}",
+                        "[ERR]".red(),
+                    );
+                });
+            }
+
+            // Limits a job's upload and restore speed
+            Subcommands::Limit { job, rate } => {
+                let _trace = span!(Level::DEBUG, "KIP_LIMIT").entered();
+                let mut md = md.write().await;
+                // Get job from argument provided
+                let j = md.jobs.get_mut(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let _ = confirm_secret(&j.name);
+                j.bandwidth_limit = rate.unwrap_or(0);
+                match j.bandwidth_limit {
+                    0 => println!("{} job '{job}' bandwidth limit removed.", "[OK]".green()),
+                    kbps => println!(
+                        "{} job '{job}' bandwidth limited to {kbps} KB/s.",
+                        "[OK]".green()
+                    ),
+                }
+                // Save changes to config file
+                md.save().unwrap_or_else(|e| {
+                    terminate!(
+                        7,
+                        "{} failed to save kip configuration: {e}",
+                        "[ERR]".red(),
+                    );
+                });
+            }
+
+            // Removes the runs a job's retention policy doesn't keep
+            Subcommands::Forget {
+                job,
+                prune,
+                dry_run,
+            } => {
+                let _trace = span!(Level::DEBUG, "KIP_FORGET").entered();
+                let mut md = md.write().await;
+                // Get job from argument provided
+                let j = md.jobs.get_mut(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let _ = confirm_secret(&j.name);
+                if dry_run {
+                    let forget = j.runs_to_forget().unwrap_or_else(|e| {
+                        terminate!(2, "{} {e}", "[ERR]".red());
+                    });
+                    for rid in forget.iter() {
+                        println!("{} would forget run '{rid}'.", "[INFO]".yellow());
+                    }
+                    println!(
+                        "{} {} of {} run(s) would be forgotten.",
+                        "[INFO]".yellow(),
+                        forget.len(),
+                        j.runs.len()
+                    );
+                    std::process::exit(0);
+                }
+                let forgotten = j.forget().await.unwrap_or_else(|e| {
+                    terminate!(21, "{} failed to forget runs for {job}: {e}.", "[ERR]".red());
+                });
+                println!(
+                    "{} forgot {} run(s) of job '{job}'.",
+                    "[OK]".green(),
+                    forgotten.len()
+                );
+                // Save before pruning so the forgotten runs
+                // stay forgotten even if pruning fails
+                md.save().unwrap_or_else(|e| {
+                    terminate!(
+                        7,
+                        "{} failed to save kip configuration: {e}",
+                        "[ERR]".red(),
+                    );
+                });
+                if prune {
+                    prune_job(&mut md, &job).await;
+                }
+            }
+
+            // Deletes chunks no remaining run uses
+            Subcommands::Prune { job } => {
+                let _trace = span!(Level::DEBUG, "KIP_PRUNE").entered();
+                let mut md = md.write().await;
+                // Get job from argument provided
+                let j = md.jobs.get(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let _ = confirm_secret(&j.name);
+                prune_job(&mut md, &job).await;
+            }
+
+            // Rebuilds a job's chunk index from its provider
+            Subcommands::Index {
+                command: IndexCommands::Rebuild { job },
+            } => {
+                let _trace = span!(Level::DEBUG, "KIP_INDEX_REBUILD").entered();
+                let md = md.read().await;
+                // Get job from argument provided
+                let j = md.jobs.get(&job).unwrap_or_else(|| {
+                    terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
+                });
+                // Confirm correct secret from user input
+                let _ = confirm_secret(&j.name);
+                println!(
+                    "{} rebuilding chunk index of job '{job}' from '{}'...",
+                    "[INFO]".yellow(),
+                    j.provider_name()
+                );
+                let index = j.rebuild_index().await.unwrap_or_else(|e| {
+                    terminate!(
+                        2,
+                        "{} failed to rebuild chunk index of job '{job}': {e}.",
+                        "[ERR]".red()
+                    );
+                });
+                println!(
+                    "{} job '{job}' index rebuilt with {} chunk(s).",
+                    "[OK]".green(),
+                    index.len()
+                );
+            }
+
             // Pauses a job and future runs
             Subcommands::Pause { job } => {
                 let _trace = span!(Level::DEBUG, "KIP_PAUSE").entered();
@@ -790,8 +1008,21 @@ fn main() {
                 });
                 // Confirm correct secret from user input
                 let _ = confirm_secret(&j.name);
-                // Set job to paused
+                // Set job to paused. The marker reaches a run in
+                // progress in another process, like the daemon, and
+                // keeps it paused if that process is restarted
                 j.paused = true;
+                request_pause(j.id).unwrap_or_else(|e| {
+                    terminate!(2, "{} failed to pause job '{job}': {e}.", "[ERR]".red());
+                });
+                if is_running(j.id).unwrap_or(false) {
+                    println!(
+                        "{} job '{job}' paused. Uploads in progress will finish first.",
+                        "[OK]".green()
+                    );
+                } else {
+                    println!("{} job '{job}' paused.", "[OK]".green());
+                }
                 // Send paused alert email if setting enabled
                 if cfg.settings.email_notification {
                     // Craft the email
@@ -833,6 +1064,9 @@ fn main() {
                 let secret = confirm_secret(&j.name);
                 // Set set to !paused
                 j.paused = false;
+                clear_pause(j.id).unwrap_or_else(|e| {
+                    terminate!(2, "{} failed to resume job '{job}': {e}.", "[ERR]".red());
+                });
                 // Send resumed alert email if setting enabled
                 if cfg.settings.email_notification {
                     // Craft the email
@@ -852,18 +1086,37 @@ fn main() {
                         }
                     }
                 }
-                // Run a manual upload
+                // A paused run in another process continues on its own
+                if is_running(j.id).unwrap_or(false) {
+                    println!("{} job '{job}' resumed.", "[OK]".green());
+                    md.save().unwrap_or_else(|e| {
+                        terminate!(
+                            7,
+                            "{} failed to save kip configuration: {e}",
+                            "[ERR]".red(),
+                        );
+                    });
+                    return;
+                }
+                // Run a manual upload, continuing the paused
+                // run if it was interrupted
                 match j
                     .start_run(
                         &secret,
-                        cfg.settings.follow_symlinks,
+                        KipRunOpts::new(
+                            cfg.settings.follow_symlinks,
+                            false,
+                            cfg.settings.memory_limit_bytes(),
+                        ),
                     )
                     .await
                 {
+                    // The run was aborted by 'kip abort'
+                    Ok(_) if j.last_status == KipStatus::ABORTED => {}
                     Ok(_) => {
                         // Send success email if setting enabled
                         if cfg.settings.email_notification {
-                            if let Some(run) = j.runs.get(&j.runs.len()) {
+                            if let Some(run) = j.runs.values().next_back() {
                                 // Craft the email
                                 let email = KipEmail {
                                     title: format!(
@@ -886,7 +1139,7 @@ fn main() {
                     Err(e) => {
                         // Send error email if setting enabled
                         if cfg.settings.email_notification {
-                            if let Some(run) = j.runs.get(&j.runs.len()) {
+                            if let Some(run) = j.runs.values().next_back() {
                                 // Craft the email
                                 let email = KipEmail {
                                     title: format!(
@@ -926,9 +1179,9 @@ fn main() {
             // Abort a running job
             Subcommands::Abort { job } => {
                 let _trace = span!(Level::DEBUG, "KIP_ABORT").entered();
-                let mut md = md.write().await;
+                let md = md.read().await;
                 // Get job from argument provided
-                let j = md.jobs.get_mut(&job).unwrap_or_else(|| {
+                let j = md.jobs.get(&job).unwrap_or_else(|| {
                     terminate!(2, "{} job '{job}' doesn't exist.", "[ERR]".red());
                 });
                 // Confirm removal
@@ -939,11 +1192,19 @@ fn main() {
                 {
                     std::process::exit(0);
                 }
-                // Abort job
-                // Grab the job's thread id and thread.join() to kill
-                // it. Since we aren't doing multipart, we can't abort
-                // from S3's API :/ IDK how to do this lol
-                j.abort();
+                // Abort job. The process running it, which may be
+                // the daemon, stops its run at the next chunk
+                match j.abort() {
+                    Ok(true) => {
+                        println!("{} job '{job}' abort requested.", "[OK]".green());
+                    }
+                    Ok(false) => {
+                        println!("{} job '{job}' isn't running.", "[INFO]".yellow());
+                    }
+                    Err(e) => {
+                        terminate!(2, "{} failed to abort job '{job}': {e}.", "[ERR]".red());
+                    }
+                }
             }
 
             // List all jobs
@@ -982,6 +1243,11 @@ fn main() {
                             KipProviders::S3(_) => "S3",
                             KipProviders::Usb(_) => "USB",
                             KipProviders::Gdrive(_) => "Google Drive",
+                            KipProviders::Local(_) => "Local",
+                            KipProviders::Sftp(_) => "SFTP",
+                            KipProviders::Smb(_) => "SMB",
+                            KipProviders::Azure(_) => "Azure",
+                            KipProviders::WebDav(_) => "WebDAV",
                         };
                         // Add row with job info
                         table.add_row(vec![
@@ -1037,6 +1303,7 @@ fn main() {
                                 "ID",
                                 "Bucket",
                                 "Region",
+                                "Endpoint",
                                 "Selected Files",
                                 "Total Runs",
                                 "Last Run",
@@ -1049,6 +1316,7 @@ fn main() {
                                 Cell::new(j.id),
                                 Cell::new(&s3.aws_bucket),
                                 Cell::new(&s3.aws_region),
+                                Cell::new(s3.endpoint.as_deref().unwrap_or("AWS")),
                                 Cell::new(correct_files),
                                 Cell::new(j.total_runs),
                                 Cell::new(correct_last_run),
@@ -1111,7 +1379,142 @@ fn main() {
                                 print_status(j.last_status),
                             ]);
                         }
-                    }
+                        KipProviders::Local(local) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "ID",
+                                "Path",
+                                "Selected Files",
+                                "Total Runs",
+                                "Last Run",
+                                "Bytes (in directory)",
+                                "Status",
+                            ]);
+                            // Add row with job info
+                            table.add_row(vec![
+                                Cell::new(&j.name).fg(comfy_table::Color::Green),
+                                Cell::new(j.id),
+                                Cell::new(local.root_path.display().to_string()),
+                                Cell::new(correct_files),
+                                Cell::new(j.total_runs),
+                                Cell::new(correct_last_run),
+                                Cell::new(convert(j.bytes_amt_provider as f64)),
+                                print_status(j.last_status),
+                            ]);
+                        }
+                        KipProviders::Sftp(sftp) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "ID",
+                                "Host",
+                                "User",
+                                "Path",
+                                "Selected Files",
+                                "Total Runs",
+                                "Last Run",
+                                "Bytes (on server)",
+                                "Status",
+                            ]);
+                            // Add row with job info
+                            table.add_row(vec![
+                                Cell::new(&j.name).fg(comfy_table::Color::Green),
+                                Cell::new(j.id),
+                                Cell::new(format!("{}:{}", sftp.host, sftp.port)),
+                                Cell::new(&sftp.username),
+                                Cell::new(&sftp.root_path),
+                                Cell::new(correct_files),
+                                Cell::new(j.total_runs),
+                                Cell::new(correct_last_run),
+                                Cell::new(convert(j.bytes_amt_provider as f64)),
+                                print_status(j.last_status),
+                            ]);
+                        }
+                        KipProviders::Smb(smb) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "ID",
+                                "Share",
+                                "User",
+                                "Selected Files",
+                                "Total Runs",
+                                "Last Run",
+                                "Bytes (on share)",
+                                "Status",
+                            ]);
+                            // Add row with job info
+                            table.add_row(vec![
+                                Cell::new(&j.name).fg(comfy_table::Color::Green),
+                                Cell::new(j.id),
+                                Cell::new(smb.location()),
+                                Cell::new(&smb.username),
+                                Cell::new(correct_files),
+                                Cell::new(j.total_runs),
+                                Cell::new(correct_last_run),
+                                Cell::new(convert(j.bytes_amt_provider as f64)),
+                                print_status(j.last_status),
+                            ]);
+                        }
+                        KipProviders::Azure(azure) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "ID",
+                                "Account",
+                                "Container",
+                                "Access Tier",
+                                "Selected Files",
+                                "Total Runs",
+                                "Last Run",
+                                "Bytes (in Azure)",
+                                "Status",
+                            ]);
+                            // Add row with job info
+                            table.add_row(vec![
+                                Cell::new(&j.name).fg(comfy_table::Color::Green),
+                                Cell::new(j.id),
+                                Cell::new(&azure.account),
+                                Cell::new(&azure.container),
+                                Cell::new(
+                                    azure
+                                        .access_tier
+                                        .map(|tier| tier.to_string())
+                                        .unwrap_or(String::from("Account default")),
+                                ),
+                                Cell::new(correct_files),
+                                Cell::new(j.total_runs),
+                                Cell::new(correct_last_run),
+                                Cell::new(convert(j.bytes_amt_provider as f64)),
+                                print_status(j.last_status),
+                            ]);
+                        }
+                        KipProviders::WebDav(webdav) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "ID",
+                                "URL",
+                                "Selected Files",
+                                "Total Runs",
+                                "Last Run",
+                                "Bytes (on server)",
+                                "Status",
+                            ]);
+                            // Add row with job info
+                            table.add_row(vec![
+                                Cell::new(&j.name).fg(comfy_table::Color::Green),
+                                Cell::new(j.id),
+                                Cell::new(&webdav.url),
+                                Cell::new(correct_files),
+                                Cell::new(j.total_runs),
+                                Cell::new(correct_last_run),
+                                Cell::new(convert(j.bytes_amt_provider as f64)),
+                                print_status(j.last_status),
+                            ]);
+                        }
+                    }
                     // Print the job table
                     println!("{table}");
                 } else if job.is_some() && run.is_some() {
@@ -1208,6 +1611,115 @@ fn main() {
                                 print_status(r.status),
                             ]);
                         }
+                        KipProviders::Local(local) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "Path",
+                                "Chunks Uploaded",
+                                "Bytes Uploaded",
+                                "Run Time",
+                                "Status",
+                            ]);
+                            // Add row with run info
+                            table.add_row(vec![
+                                Cell::new(format!("{}-{}", j.name, r.id))
+                                    .fg(comfy_table::Color::Green),
+                                Cell::new(local.root_path.display().to_string()),
+                                Cell::new(r.delta.len()),
+                                Cell::new(convert(r.bytes_uploaded as f64)),
+                                Cell::new(&r.time_elapsed),
+                                print_status(r.status),
+                            ]);
+                        }
+                        KipProviders::Sftp(sftp) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "Host",
+                                "Path",
+                                "Chunks Uploaded",
+                                "Bytes Uploaded",
+                                "Run Time",
+                                "Status",
+                            ]);
+                            // Add row with run info
+                            table.add_row(vec![
+                                Cell::new(format!("{}-{}", j.name, r.id))
+                                    .fg(comfy_table::Color::Green),
+                                Cell::new(format!("{}:{}", sftp.host, sftp.port)),
+                                Cell::new(&sftp.root_path),
+                                Cell::new(r.delta.len()),
+                                Cell::new(convert(r.bytes_uploaded as f64)),
+                                Cell::new(&r.time_elapsed),
+                                print_status(r.status),
+                            ]);
+                        }
+                        KipProviders::Smb(smb) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "Share",
+                                "Chunks Uploaded",
+                                "Bytes Uploaded",
+                                "Run Time",
+                                "Status",
+                            ]);
+                            // Add row with run info
+                            table.add_row(vec![
+                                Cell::new(format!("{}-{}", j.name, r.id))
+                                    .fg(comfy_table::Color::Green),
+                                Cell::new(smb.location()),
+                                Cell::new(r.delta.len()),
+                                Cell::new(convert(r.bytes_uploaded as f64)),
+                                Cell::new(&r.time_elapsed),
+                                print_status(r.status),
+                            ]);
+                        }
+                        KipProviders::Azure(azure) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "Account",
+                                "Container",
+                                "Chunks Uploaded",
+                                "Bytes Uploaded",
+                                "Run Time",
+                                "Status",
+                            ]);
+                            // Add row with run info
+                            table.add_row(vec![
+                                Cell::new(format!("{}-{}", j.name, r.id))
+                                    .fg(comfy_table::Color::Green),
+                                Cell::new(&azure.account),
+                                Cell::new(&azure.container),
+                                Cell::new(r.delta.len()),
+                                Cell::new(convert(r.bytes_uploaded as f64)),
+                                Cell::new(&r.time_elapsed),
+                                print_status(r.status),
+                            ]);
+                        }
+                        KipProviders::WebDav(webdav) => {
+                            // Create the header row
+                            table.set_header(&vec![
+                                "Name",
+                                "URL",
+                                "Chunks Uploaded",
+                                "Bytes Uploaded",
+                                "Run Time",
+                                "Status",
+                            ]);
+                            // Add row with run info
+                            table.add_row(vec![
+                                Cell::new(format!("{}-{}", j.name, r.id))
+                                    .fg(comfy_table::Color::Green),
+                                Cell::new(&webdav.url),
+                                Cell::new(r.delta.len()),
+                                Cell::new(convert(r.bytes_uploaded as f64)),
+                                Cell::new(&r.time_elapsed),
+                                print_status(r.status),
+                            ]);
+                        }
                     }
                     // Create a table for logs
                     let mut logs_table = Table::new();
@@ -1263,6 +1775,669 @@ fn main() {
     });
 }
 
+/// Prompts for a job's provider and stores its
+/// credentials in the keyring.
+fn prompt_provider(job: &str) -> KipProviders {
+    // Confirm if S3, Google Drive, USB, local directory, SFTP, SMB, Azure or WebDAV job
+    let provider_selection: usize = Select::with_theme(&ColorfulTheme::default())
+        .item("S3")
+        .item("Google Drive")
+        .item("USB")
+        .item("Local directory (NAS mount, second disk)")
+        .item("SFTP")
+        .item("SMB share")
+        .item("Azure Blob Storage")
+        .item("WebDAV (Nextcloud, ownCloud)")
+        .default(0)
+        .interact()
+        .expect("[ERR] unable to create provider selection menu.");
+    match provider_selection {
+        0 => {
+            // Get S3 access key from user input
+            print!("Please provide the S3 access key: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut s3_acc_key = String::new();
+            std::io::stdin()
+                .read_line(&mut s3_acc_key)
+                .expect("[ERR] failed to read S3 access key from stdin.");
+            // Store S3 access key onto local OS keyring
+            keyring_set_secret(&format!("com.ciehanski.kip.{job}.s3acc"), &s3_acc_key)
+                .unwrap_or_else(|e| {
+                    terminate!(
+                        5,
+                        "{} failed to push S3 access key onto keyring: {e}.",
+                        "[ERR]".red(),
+                    );
+                });
+            // Get S3 secret key from user input
+            let s3_sec_key = Password::new()
+                .with_prompt("Please provide the S3 secret key")
+                .interact()
+                .expect("[ERR] failed to create S3 secret key prompt.");
+            // Store S3 secret key onto local OS keyring
+            keyring_set_secret(&format!("com.ciehanski.kip.{job}.s3sec"), &s3_sec_key)
+                .unwrap_or_else(|e| {
+                    terminate!(
+                        5,
+                        "{} failed to push S3 secret key onto keyring: {e}.",
+                        "[ERR]".red(),
+                    );
+                });
+            // Get S3 bucket name from user input
+            print!("Please provide the S3 bucket name: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut s3_bucket_name = String::new();
+            std::io::stdin()
+                .read_line(&mut s3_bucket_name)
+                .expect("[ERR] failed to read S3 bucket name from stdin.");
+            // Get S3 bucket region from user input
+            print!("Please provide the S3 region: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut s3_region = String::new();
+            std::io::stdin()
+                .read_line(&mut s3_region)
+                .expect("[ERR] failed to read from stdin.");
+            // Self-hosted services often ignore the region,
+            // but requests must still be signed with one
+            let s3_region = match s3_region.trim() {
+                "" => "us-east-1",
+                region => region,
+            };
+            let mut s3 = KipS3::new(s3_bucket_name.trim_end(), Region::new(s3_region.to_owned()));
+            // Get the endpoint of S3-compatible services from user input
+            print!("Please provide the S3 endpoint URL (leave empty for AWS): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut s3_endpoint = String::new();
+            std::io::stdin()
+                .read_line(&mut s3_endpoint)
+                .expect("[ERR] failed to read from stdin.");
+            let s3_endpoint = s3_endpoint.trim();
+            if !s3_endpoint.is_empty() {
+                s3.endpoint = Some(s3_endpoint.to_owned());
+                s3.path_style = Confirm::new()
+                    .with_prompt("Use path-style bucket addressing? (needed by MinIO and Ceph)")
+                    .default(true)
+                    .interact()
+                    .expect("[ERR] failed to create path-style prompt.");
+                // Get the CA certificate of endpoints with
+                // self-signed certificates from user input
+                print!("Please provide the path to a CA certificate to trust (leave empty for the system's): ");
+                std::io::stdout()
+                    .flush()
+                    .expect("[ERR] failed to flush stdout.");
+                let mut ca_cert = String::new();
+                std::io::stdin()
+                    .read_line(&mut ca_cert)
+                    .expect("[ERR] failed to read from stdin.");
+                let ca_cert = ca_cert.trim();
+                if !ca_cert.is_empty() {
+                    let ca_cert = PathBuf::from(ca_cert);
+                    if !ca_cert.is_file() {
+                        terminate!(
+                            2,
+                            "{} CA certificate '{}' doesn't exist.",
+                            "[ERR]".red(),
+                            ca_cert.display()
+                        );
+                    }
+                    s3.ca_cert = Some(ca_cert);
+                }
+            }
+            // Get the storage class chunks are uploaded to from user input
+            let storage_classes = [
+                "Bucket default",
+                "STANDARD",
+                "STANDARD_IA",
+                "ONEZONE_IA",
+                "INTELLIGENT_TIERING",
+                "GLACIER_IR",
+                "GLACIER",
+                "DEEP_ARCHIVE",
+            ];
+            let storage_class = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("Which storage class should chunks be uploaded to?")
+                .items(&storage_classes)
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create storage class selection menu.");
+            if storage_class > 0 {
+                s3.storage_class = Some(storage_classes[storage_class].to_owned());
+            }
+            // Get the server-side encryption mode from user input
+            let sse = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("Which server-side encryption should S3 apply?")
+                .item("None")
+                .item("SSE-S3")
+                .item("SSE-KMS")
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create encryption selection menu.");
+            s3.sse = match sse {
+                1 => Some(KipS3Sse::Aes256),
+                2 => Some(KipS3Sse::Kms),
+                _ => None,
+            };
+            if s3.sse == Some(KipS3Sse::Kms) {
+                print!("Please provide the KMS key ID (leave empty for the AWS managed key): ");
+                std::io::stdout()
+                    .flush()
+                    .expect("[ERR] failed to flush stdout.");
+                let mut kms_key_id = String::new();
+                std::io::stdin()
+                    .read_line(&mut kms_key_id)
+                    .expect("[ERR] failed to read from stdin.");
+                let kms_key_id = kms_key_id.trim();
+                if !kms_key_id.is_empty() {
+                    s3.sse_kms_key_id = Some(kms_key_id.to_owned());
+                }
+            }
+            s3.tag_objects = Confirm::new()
+                .with_prompt("Tag uploaded chunks with the job name and run ID?")
+                .default(true)
+                .interact()
+                .expect("[ERR] failed to create tagging prompt.");
+            // Get the Object Lock mode from user input. Objects are
+            // locked for as long as the retention policy keeps runs.
+            let object_lock = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("Make uploads immutable with S3 Object Lock?")
+                .item("No")
+                .item("Governance mode")
+                .item("Compliance mode")
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create Object Lock selection menu.");
+            s3.object_lock = match object_lock {
+                1 => Some(KipObjectLockMode::Governance),
+                2 => Some(KipObjectLockMode::Compliance),
+                _ => None,
+            }
+            .map(|mode| KipObjectLock { mode, days: 0 });
+            // Create the job's provider
+            KipProviders::S3(s3)
+        }
+        1 => {
+            // Google Drive
+            // Get Google Drive client ID from user input
+            print!("Please provide the Google Drive OAuth client ID: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut gdrive_client_id = String::new();
+            std::io::stdin()
+                .read_line(&mut gdrive_client_id)
+                .expect("[ERR] failed to read Google Drive OAuth client ID from stdin.");
+            // Store Google Drive client ID onto local OS keyring
+            keyring_set_secret(
+                &format!("com.ciehanski.kip.{job}.gdriveid"),
+                &gdrive_client_id,
+            )
+            .unwrap_or_else(|e| {
+                terminate!(
+                    5,
+                    "{} failed to push Google Drive client ID onto keyring: {e}.",
+                    "[ERR]".red(),
+                );
+            });
+            // Get Google Drive client secret from user input
+            print!("Please provide the Google Drive OAuth client secret: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut gdrive_client_sec = String::new();
+            std::io::stdin()
+                .read_line(&mut gdrive_client_sec)
+                .expect("[ERR] failed to read Google Drive OAuth client secret from stdin.");
+            // Store Google Drive client ID onto local OS keyring
+            keyring_set_secret(
+                &format!("com.ciehanski.kip.{job}.gdrivesec"),
+                &gdrive_client_sec,
+            )
+            .unwrap_or_else(|e| {
+                terminate!(
+                    5,
+                    "{} failed to push Google Drive client ID onto keyring: {e}.",
+                    "[ERR]".red(),
+                );
+            });
+            // Get GDrive parent folder from user input
+            print!("Optionally, provide the parent folder ID: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut gdrive_folder = String::new();
+            std::io::stdin()
+                .read_line(&mut gdrive_folder)
+                .expect("[ERR] failed to read Google Drive parent folder ID from stdin.");
+            // Create the job's provider
+            KipProviders::Gdrive(KipGdrive::new(Some(gdrive_folder.trim_end())))
+        }
+        2 => {
+            // USB
+            let mut sys = System::new();
+            sys.refresh_disks_list();
+            let disks = sys.disks();
+            let disks_str: Vec<String> = disks
+                .iter()
+                .filter_map(|d| {
+                    let disk = d
+                        .name()
+                        .to_str()
+                        .expect("[ERR] unable to convert disk's OsStr to String");
+                    match disk {
+                        "Macintosh HD - Data" => None,
+                        "VM" => None,
+                        "Preboot" => None,
+                        "Update" => None,
+                        "Recovery" => None,
+                        "" => None,
+                        _ => Some(disk.to_owned()),
+                    }
+                })
+                .collect();
+            // Ensure USB devices were found
+            if disks_str.is_empty() {
+                terminate!(1, "no USB devices detected.");
+            };
+            // Confirm which USB device
+            let provider_selection: usize = Select::with_theme(&ColorfulTheme::default())
+                .items(&disks_str)
+                .default(0)
+                .interact()
+                .unwrap_or_else(|_| terminate!(1, "[ERR] unable to create USB selection menu"));
+            // Create the job's provider
+            KipProviders::Usb(KipUsb::new(
+                disks[provider_selection]
+                    .name()
+                    .to_str()
+                    .unwrap_or_else(|| {
+                        terminate!(1, "[ERR] unable to convert disk's OsStr to String");
+                    })
+                    .to_owned(),
+                disks[provider_selection].mount_point(),
+                disks[provider_selection].total_space(),
+                disks[provider_selection].available_space(),
+            ))
+        }
+        3 => {
+            // Get the directory's path from user input
+            print!("Please provide the path of the backup directory: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut local_path = String::new();
+            std::io::stdin()
+                .read_line(&mut local_path)
+                .expect("[ERR] failed to read from stdin.");
+            let local_path = PathBuf::from(local_path.trim());
+            std::fs::create_dir_all(&local_path).unwrap_or_else(|e| {
+                terminate!(
+                    2,
+                    "{} unable to create directory '{}': {e}.",
+                    "[ERR]".red(),
+                    local_path.display()
+                );
+            });
+            let local_path = local_path.canonicalize().unwrap_or_else(|e| {
+                terminate!(
+                    2,
+                    "{} unable to resolve directory '{}': {e}.",
+                    "[ERR]".red(),
+                    local_path.display()
+                );
+            });
+            // Create the job's provider
+            KipProviders::Local(KipLocal::new(local_path))
+        }
+        4 => {
+            // Get the SSH server's address from user input
+            print!("Please provide the SFTP server's host: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut sftp_host = String::new();
+            std::io::stdin()
+                .read_line(&mut sftp_host)
+                .expect("[ERR] failed to read from stdin.");
+            print!("Please provide the SFTP server's port (leave empty for 22): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut sftp_port = String::new();
+            std::io::stdin()
+                .read_line(&mut sftp_port)
+                .expect("[ERR] failed to read from stdin.");
+            let sftp_port: u16 = match sftp_port.trim() {
+                "" => 22,
+                port => port.parse().unwrap_or_else(|e| {
+                    terminate!(2, "{} invalid port '{port}': {e}.", "[ERR]".red());
+                }),
+            };
+            print!("Please provide the SFTP username: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut sftp_user = String::new();
+            std::io::stdin()
+                .read_line(&mut sftp_user)
+                .expect("[ERR] failed to read from stdin.");
+            // Get the authentication method from user input
+            let sftp_auth = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("How should kip authenticate?")
+                .item("Private key")
+                .item("Password")
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create authentication selection menu.");
+            let (sftp_auth, sftp_secret) = match sftp_auth {
+                0 => {
+                    print!("Please provide the path to the private key: ");
+                    std::io::stdout()
+                        .flush()
+                        .expect("[ERR] failed to flush stdout.");
+                    let mut private_key = String::new();
+                    std::io::stdin()
+                        .read_line(&mut private_key)
+                        .expect("[ERR] failed to read from stdin.");
+                    let private_key = PathBuf::from(private_key.trim());
+                    if !private_key.is_file() {
+                        terminate!(
+                            2,
+                            "{} private key '{}' doesn't exist.",
+                            "[ERR]".red(),
+                            private_key.display()
+                        );
+                    }
+                    let passphrase = Password::new()
+                        .with_prompt(
+                            "Please provide the private key's passphrase (leave empty if none)",
+                        )
+                        .allow_empty_password(true)
+                        .interact()
+                        .expect("[ERR] failed to create passphrase prompt.");
+                    (KipSftpAuth::Key { private_key }, passphrase)
+                }
+                _ => {
+                    let password = Password::new()
+                        .with_prompt("Please provide the SFTP password")
+                        .interact()
+                        .expect("[ERR] failed to create SFTP password prompt.");
+                    (KipSftpAuth::Password, password)
+                }
+            };
+            // Store the password or passphrase onto local OS keyring
+            keyring_set_secret(&format!("com.ciehanski.kip.{job}.sftpsec"), &sftp_secret)
+                .unwrap_or_else(|e| {
+                    terminate!(
+                        5,
+                        "{} failed to push SFTP secret onto keyring: {e}.",
+                        "[ERR]".red(),
+                    );
+                });
+            // Get the directory on the server from user input
+            print!("Please provide the directory on the server to back up to: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut sftp_path = String::new();
+            std::io::stdin()
+                .read_line(&mut sftp_path)
+                .expect("[ERR] failed to read from stdin.");
+            // Create the job's provider. The server's host key is
+            // verified against ~/.ssh/known_hosts.
+            KipProviders::Sftp(KipSftp::new(
+                sftp_host.trim(),
+                sftp_port,
+                sftp_user.trim(),
+                sftp_auth,
+                sftp_path.trim(),
+            ))
+        }
+        5 => {
+            // Get the SMB server's address from user input
+            print!("Please provide the SMB server's host: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut smb_host = String::new();
+            std::io::stdin()
+                .read_line(&mut smb_host)
+                .expect("[ERR] failed to read from stdin.");
+            print!("Please provide the SMB server's port (leave empty for {DEFAULT_SMB_PORT}): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut smb_port = String::new();
+            std::io::stdin()
+                .read_line(&mut smb_port)
+                .expect("[ERR] failed to read from stdin.");
+            let smb_port: u16 = match smb_port.trim() {
+                "" => DEFAULT_SMB_PORT,
+                port => port.parse().unwrap_or_else(|e| {
+                    terminate!(2, "{} invalid port '{port}': {e}.", "[ERR]".red());
+                }),
+            };
+            print!("Please provide the share's name: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut smb_share = String::new();
+            std::io::stdin()
+                .read_line(&mut smb_share)
+                .expect("[ERR] failed to read from stdin.");
+            print!("Please provide the directory in the share to back up to (leave empty for its root): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut smb_path = String::new();
+            std::io::stdin()
+                .read_line(&mut smb_path)
+                .expect("[ERR] failed to read from stdin.");
+            print!("Please provide the SMB username: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut smb_user = String::new();
+            std::io::stdin()
+                .read_line(&mut smb_user)
+                .expect("[ERR] failed to read from stdin.");
+            print!("Please provide the SMB workgroup or domain (leave empty for WORKGROUP): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut smb_workgroup = String::new();
+            std::io::stdin()
+                .read_line(&mut smb_workgroup)
+                .expect("[ERR] failed to read from stdin.");
+            let smb_workgroup = match smb_workgroup.trim() {
+                "" => "WORKGROUP",
+                workgroup => workgroup,
+            };
+            let smb_password = Password::new()
+                .with_prompt("Please provide the SMB password")
+                .interact()
+                .expect("[ERR] failed to create SMB password prompt.");
+            // Store the password onto local OS keyring
+            keyring_set_secret(&format!("com.ciehanski.kip.{job}.smbsec"), &smb_password)
+                .unwrap_or_else(|e| {
+                    terminate!(
+                        5,
+                        "{} failed to push SMB password onto keyring: {e}.",
+                        "[ERR]".red(),
+                    );
+                });
+            // Create the job's provider
+            KipProviders::Smb(KipSmb::new(
+                smb_host.trim(),
+                smb_port,
+                smb_share.trim(),
+                smb_user.trim(),
+                smb_workgroup,
+                smb_path.trim(),
+            ))
+        }
+        6 => {
+            // Get the storage account and container from user input
+            print!("Please provide the Azure storage account name: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut azure_account = String::new();
+            std::io::stdin()
+                .read_line(&mut azure_account)
+                .expect("[ERR] failed to read from stdin.");
+            print!("Please provide the Azure container name: ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut azure_container = String::new();
+            std::io::stdin()
+                .read_line(&mut azure_container)
+                .expect("[ERR] failed to read from stdin.");
+            // Get the authentication method from user input
+            let azure_auth = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("How should kip authenticate?")
+                .item("Shared key")
+                .item("SAS token")
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create authentication selection menu.");
+            let azure_auth = match azure_auth {
+                0 => KipAzureAuth::SharedKey,
+                _ => KipAzureAuth::Sas,
+            };
+            let azure_secret = Password::new()
+                .with_prompt(match azure_auth {
+                    KipAzureAuth::SharedKey => "Please provide the storage account's access key",
+                    KipAzureAuth::Sas => "Please provide the SAS token",
+                })
+                .interact()
+                .expect("[ERR] failed to create Azure secret prompt.");
+            // Store the shared key or SAS token onto local OS keyring
+            keyring_set_secret(&format!("com.ciehanski.kip.{job}.azuresec"), &azure_secret)
+                .unwrap_or_else(|e| {
+                    terminate!(
+                        5,
+                        "{} failed to push Azure secret onto keyring: {e}.",
+                        "[ERR]".red(),
+                    );
+                });
+            let mut azure = KipAzure::new(azure_account.trim(), azure_container.trim(), azure_auth);
+            // Get the endpoint of emulators and sovereign clouds from user input
+            print!("Please provide the blob endpoint URL (leave empty for Azure): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut azure_endpoint = String::new();
+            std::io::stdin()
+                .read_line(&mut azure_endpoint)
+                .expect("[ERR] failed to read from stdin.");
+            let azure_endpoint = azure_endpoint.trim();
+            if !azure_endpoint.is_empty() {
+                azure.endpoint = Some(azure_endpoint.to_owned());
+            }
+            // Get the access tier chunks are uploaded to from user input
+            let access_tier = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("Which access tier should chunks be uploaded to?")
+                .item("Account default")
+                .item("Hot")
+                .item("Cool")
+                .item("Archive")
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create access tier selection menu.");
+            azure.access_tier = match access_tier {
+                1 => Some(KipAzureTier::Hot),
+                2 => Some(KipAzureTier::Cool),
+                3 => Some(KipAzureTier::Archive),
+                _ => None,
+            };
+            // Create the job's provider
+            KipProviders::Azure(azure)
+        }
+        7 => {
+            // Get the collection's URL from user input
+            print!("Please provide the WebDAV folder's URL (ex: https://cloud.example.com/remote.php/dav/files/<user>/kip): ");
+            std::io::stdout()
+                .flush()
+                .expect("[ERR] failed to flush stdout.");
+            let mut webdav_url = String::new();
+            std::io::stdin()
+                .read_line(&mut webdav_url)
+                .expect("[ERR] failed to read from stdin.");
+            let webdav_url = webdav_url.trim();
+            if !webdav_url.starts_with("https://") && !webdav_url.starts_with("http://") {
+                terminate!(
+                    2,
+                    "{} invalid WebDAV URL '{webdav_url}', it must start with https://.",
+                    "[ERR]".red()
+                );
+            }
+            // Get the authentication method from user input
+            let webdav_auth = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("How should kip authenticate?")
+                .item("Username and password (or app password)")
+                .item("Bearer token")
+                .default(0)
+                .interact()
+                .expect("[ERR] unable to create authentication selection menu.");
+            let (webdav_auth, webdav_secret) = match webdav_auth {
+                0 => {
+                    print!("Please provide the WebDAV username: ");
+                    std::io::stdout()
+                        .flush()
+                        .expect("[ERR] failed to flush stdout.");
+                    let mut username = String::new();
+                    std::io::stdin()
+                        .read_line(&mut username)
+                        .expect("[ERR] failed to read from stdin.");
+                    let password = Password::new()
+                        .with_prompt("Please provide the WebDAV password")
+                        .interact()
+                        .expect("[ERR] failed to create WebDAV password prompt.");
+                    (
+                        KipWebDavAuth::Basic {
+                            username: username.trim().to_owned(),
+                        },
+                        password,
+                    )
+                }
+                _ => {
+                    let token = Password::new()
+                        .with_prompt("Please provide the bearer token")
+                        .interact()
+                        .expect("[ERR] failed to create bearer token prompt.");
+                    (KipWebDavAuth::Bearer, token)
+                }
+            };
+            // Store the password or token onto local OS keyring
+            keyring_set_secret(
+                &format!("com.ciehanski.kip.{job}.webdavsec"),
+                &webdav_secret,
+            )
+            .unwrap_or_else(|e| {
+                terminate!(
+                    5,
+                    "{} failed to push WebDAV secret onto keyring: {e}.",
+                    "[ERR]".red(),
+                );
+            });
+            // Create the job's provider
+            KipProviders::WebDav(KipWebDav::new(webdav_url, webdav_auth))
+        }
+        _ => {
+            terminate!(1, "Invalid selection. Please try again.");
+        }
+    }
+}
+
 // Confirm correct secret from user input
 fn confirm_secret(job_name: &str) -> String {
     let secret = Password::new()
@@ -1286,6 +2461,30 @@ fn confirm_secret(job_name: &str) -> String {
     secret
 }
 
+// Deletes a job's unused chunks and saves the result, even
+// if some chunks failed to delete
+async fn prune_job(md: &mut KipConfMetadata, job: &str) {
+    // Callers have already confirmed the job exists
+    let j = md.jobs.get_mut(job).unwrap();
+    let result = j.prune().await;
+    md.save().unwrap_or_else(|e| {
+        terminate!(7, "{} failed to save kip configuration: {e}", "[ERR]".red());
+    });
+    match result {
+        Ok(pruned) => println!(
+            "{} pruned {pruned} unused chunk(s) of job '{job}'.",
+            "[OK]".green()
+        ),
+        Err(e) => {
+            terminate!(
+                21,
+                "{} failed to prune chunks for {job}: {e}.",
+                "[ERR]".red()
+            );
+        }
+    }
+}
+
 //#[cfg(not(windows))]
 //pub fn is_hidden(entry: &walkdir::DirEntry) -> bool {
 //    entry
@@ -1317,6 +2516,8 @@ fn print_status(status: KipStatus) -> comfy_table::Cell {
         KipStatus::WARN => Cell::new("WARN").fg(comfy_table::Color::Yellow),
         KipStatus::IN_PROGRESS => Cell::new("IN_PROGRESS").fg(comfy_table::Color::Cyan),
         KipStatus::NEVER_RUN => Cell::new("NEVER_RUN").add_attribute(Attribute::Bold),
+        KipStatus::ABORTED => Cell::new("ABORTED").fg(comfy_table::Color::Yellow),
+        KipStatus::INTERRUPTED => Cell::new("INTERRUPTED").fg(comfy_table::Color::Yellow),
     }
 }
 
@@ -0,0 +1,322 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use crate::chunk::{chunker, FileChunk};
+use crate::compress::KipCompressOpts;
+use crate::crypto::{derive_chunk_key, keyed_hash};
+use crate::index::update_index;
+use crate::job::Job;
+use crate::providers::{KipClient, KipUploadOpts};
+use crate::run::{decrypt_decompress, encrypt_and_compress, hash_file, KipUploadMsg};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use chrono::prelude::*;
+use futures::StreamExt;
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::sync::mpsc::unbounded_channel;
+use tracing::{debug, instrument};
+
+/// Results of checking a job's chunks against its provider.
+/// Chunks are listed by their remote path.
+#[derive(Clone, Debug, Default)]
+pub struct KipCheckReport {
+    /// Amount of chunks referenced by the job's runs
+    pub referenced: usize,
+    /// Amount of chunks downloaded and verified
+    pub read: usize,
+    /// Referenced by a run but not stored in the provider
+    pub missing: BTreeSet<String>,
+    /// Stored but failed to decrypt or didn't match their hash
+    pub corrupt: BTreeSet<String>,
+    /// Stored but not referenced by any run
+    pub orphaned: BTreeSet<String>,
+}
+
+/// Results of repairing a job's missing and corrupt chunks
+#[derive(Clone, Debug, Default)]
+pub struct KipRepairReport {
+    /// Chunks uploaded again from local files
+    pub repaired: BTreeSet<String>,
+    /// Files with chunks that couldn't be uploaded again
+    pub damaged: BTreeSet<PathBuf>,
+}
+
+impl KipCheckReport {
+    /// Whether every referenced chunk can be restored.
+    /// Orphaned chunks only take up space.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+/// Compares the chunks referenced by a job's runs with the
+/// provider's listing. If read_percent is provided, that
+/// percentage of the stored chunks is also downloaded,
+/// decrypted and hashed.
+#[instrument(skip(secret))]
+pub async fn check_chunks(
+    job: &Job,
+    client: &KipClient,
+    secret: &str,
+    read_percent: Option<f64>,
+) -> Result<KipCheckReport> {
+    // Chunks record the compression they were stored with.
+    // Older ones use the newest run referencing them.
+    let mut referenced = BTreeMap::<&str, (&FileChunk, KipCompressOpts)>::new();
+    for r in job.runs.values() {
+        for kfc in r.delta.iter().chain(r.snapshot.iter()) {
+            for c in kfc.chunks.iter() {
+                referenced.insert(
+                    c.remote_path.as_str(),
+                    (c, c.compress.unwrap_or(r.compress)),
+                );
+            }
+        }
+    }
+    let stored = job
+        .provider
+        .list_chunks(client, job.id)
+        .await?
+        .into_iter()
+        .collect::<BTreeSet<String>>();
+    // The listing is the most up to date view of the provider
+    update_index(job.id, |index| {
+        index.chunks = stored.clone();
+        index.rebuilt = Utc::now();
+    })?;
+
+    let mut report = KipCheckReport {
+        referenced: referenced.len(),
+        ..Default::default()
+    };
+    for remote_path in referenced.keys() {
+        if !stored.contains(*remote_path) {
+            report.missing.insert(remote_path.to_string());
+        }
+    }
+    for remote_path in stored.iter() {
+        if !referenced.contains_key(remote_path.as_str()) {
+            report.orphaned.insert(remote_path.clone());
+        }
+    }
+
+    let Some(read_percent) = read_percent else {
+        return Ok(report);
+    };
+    let readable = referenced
+        .iter()
+        .filter(|(remote_path, _)| stored.contains(**remote_path))
+        .map(|(_, c)| *c)
+        .collect::<Vec<(&FileChunk, KipCompressOpts)>>();
+    let sample_len = sample_len(readable.len(), read_percent);
+    let sample = readable
+        .choose_multiple(&mut rand::thread_rng(), sample_len)
+        .copied()
+        .collect::<Vec<(&FileChunk, KipCompressOpts)>>();
+    let chunk_key = derive_chunk_key(secret, job.id)?;
+    let bandwidth = job.bandwidth();
+    for (c, compress) in sample {
+        debug!("reading chunk {}", c.remote_path);
+        report.read += 1;
+        let download = job.provider.download(client, &c.remote_path).await;
+        if let Ok(chunk_bytes) = &download {
+            bandwidth.acquire(chunk_bytes.len()).await;
+        }
+        let verified = match download {
+            // AEAD decryption fails if the chunk was modified
+            Ok(chunk_bytes) => match decrypt_decompress(&chunk_bytes, secret, compress).await {
+                Ok(plaintext) => keyed_hash(&chunk_key, &plaintext)? == c.hash,
+                Err(e) => {
+                    debug!("unable to decrypt chunk {}: {e}", c.remote_path);
+                    false
+                }
+            },
+            Err(e) => {
+                debug!("unable to download chunk {}: {e}", c.remote_path);
+                false
+            }
+        };
+        if !verified {
+            report.corrupt.insert(c.remote_path.clone());
+        }
+    }
+    Ok(report)
+}
+
+/// Re-uploads the bad chunks provided from the local files they
+/// came from. Files are only used if they still match the hash
+/// they were backed up with. Returns the chunks re-uploaded.
+#[instrument(skip(secret))]
+pub async fn repair_chunks(
+    job: &Job,
+    client: &KipClient,
+    secret: &str,
+    bad: &BTreeSet<String>,
+) -> Result<BTreeSet<String>> {
+    // Bad chunks by hash, stored again with the compression
+    // they were stored with
+    let mut bad_chunks = HashMap::<&str, (&str, KipCompressOpts)>::new();
+    let mut files = BTreeSet::<(&Path, &str)>::new();
+    for r in job.runs.values() {
+        for kfc in r.delta.iter().chain(r.snapshot.iter()) {
+            for c in kfc.chunks.iter() {
+                if bad.contains(&c.remote_path) {
+                    bad_chunks.insert(
+                        c.hash.as_str(),
+                        (c.remote_path.as_str(), c.compress.unwrap_or(r.compress)),
+                    );
+                    files.insert((kfc.file.path.as_path(), kfc.file.hash.as_str()));
+                }
+            }
+        }
+    }
+
+    let chunk_key = derive_chunk_key(secret, job.id)?;
+    let bandwidth = job.bandwidth();
+    // Only used by providers that report back a parent folder
+    let (tx, _rx) = unbounded_channel::<KipUploadMsg>();
+    let mut repaired = BTreeSet::<String>::new();
+    for (path, file_hash) in files {
+        if repaired.len() == bad.len() {
+            break;
+        }
+        // The file's chunks can only be recreated from the
+        // same contents that were backed up
+        match hash_file(path).await {
+            Ok(hash) if hash == file_hash => {}
+            Ok(_) => {
+                debug!("{} changed since it was backed up", path.display());
+                continue;
+            }
+            Err(e) => {
+                debug!("unable to read {}: {e}", path.display());
+                continue;
+            }
+        }
+        let mut chunker = chunker(File::open(path).await?);
+        let mut chunk_stream = Box::pin(chunker.as_stream());
+        while let Some(entry) = chunk_stream.next().await {
+            let entry = entry?;
+            let chunk = FileChunk::from_chunk_data(path, &entry, &chunk_key)?;
+            let Some((remote_path, compress)) = bad_chunks.get(chunk.hash.as_str()) else {
+                continue;
+            };
+            if repaired.contains(*remote_path) {
+                continue;
+            }
+            debug!("re-uploading chunk {remote_path}");
+            let encrypted_chunk =
+                Bytes::from(encrypt_and_compress(&entry.data, secret, *compress).await?);
+            bandwidth.acquire(encrypted_chunk.len()).await;
+            job.provider
+                .upload(
+                    client,
+                    KipUploadOpts::new(job.id, tx.clone()),
+                    &chunk,
+                    encrypted_chunk,
+                )
+                .await?;
+            repaired.insert(remote_path.to_string());
+        }
+    }
+    update_index(job.id, |index| {
+        index.chunks.extend(repaired.iter().cloned());
+    })?;
+    Ok(repaired)
+}
+
+/// Parses a percentage of chunks to read. Ex: 10% or 2.5
+pub fn parse_percent(percent: &str) -> Result<f64> {
+    let p: f64 = match percent.trim().trim_end_matches('%').parse() {
+        Ok(p) => p,
+        Err(e) => bail!("invalid percentage '{percent}': {e}"),
+    };
+    if p.is_nan() || p <= 0.0 || p > 100.0 {
+        bail!("percentage '{percent}' must be above 0% and at most 100%")
+    }
+    Ok(p)
+}
+
+/// Returns how many of len chunks make up percent of them.
+/// At least one chunk is read if there are any.
+fn sample_len(len: usize, percent: f64) -> usize {
+    let sample = (len as f64 * percent / 100.0).ceil() as usize;
+    sample.clamp(len.min(1), len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::KipFileChunked;
+    use crate::compress::{KipCompressAlg, KipCompressLevel};
+    use crate::providers::usb::KipUsb;
+    use crate::providers::KipProviders;
+    use crate::run::Run;
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("10%").unwrap(), 10.0);
+        assert_eq!(parse_percent("2.5").unwrap(), 2.5);
+        assert!(parse_percent("0%").is_err());
+        assert!(parse_percent("101%").is_err());
+        assert!(parse_percent("ten").is_err());
+    }
+
+    #[test]
+    fn test_sample_len() {
+        assert_eq!(sample_len(0, 10.0), 0);
+        assert_eq!(sample_len(5, 10.0), 1);
+        assert_eq!(sample_len(50, 10.0), 5);
+        assert_eq!(sample_len(50, 100.0), 50);
+    }
+
+    #[tokio::test]
+    async fn test_check_chunks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", tmp_dir.path(), 0, 0));
+        let compress = KipCompressOpts::new(false, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        let chunk_key = derive_chunk_key("hunter2", j.id).unwrap();
+        let chunk_dir = tmp_dir.path().join(format!("{}/chunks", j.id));
+        std::fs::create_dir_all(&chunk_dir).unwrap();
+        // One intact chunk, one corrupt, one missing and one orphaned
+        let mut kfc = KipFileChunked::new("/a.txt", "a", 3);
+        for (data, stored) in [("kip", "kip"), ("pik", "tampered"), ("ipk", "")] {
+            let hash = keyed_hash(&chunk_key, data.as_bytes()).unwrap();
+            if !stored.is_empty() {
+                let ciphertext =
+                    crate::crypto::encrypt_bytes(stored.as_bytes(), "hunter2").unwrap();
+                std::fs::write(chunk_dir.join(format!("{hash}.chunk")), ciphertext).unwrap();
+            }
+            let mut c = FileChunk::new("/a.txt", hash.clone(), 0, 3, 3);
+            c.set_remote_path(format!("{}/chunks/{hash}.chunk", j.id));
+            kfc.add_chunk(c);
+        }
+        std::fs::write(chunk_dir.join("orphan.chunk"), b"orphan").unwrap();
+        let mut r = Run::new(1, compress);
+        r.snapshot.push(kfc);
+        j.runs.insert(1, r);
+
+        let client = KipClient::None;
+        let report = check_chunks(&j, &client, "hunter2", None).await.unwrap();
+        assert_eq!(report.referenced, 3);
+        assert_eq!(report.read, 0);
+        assert_eq!(report.missing.len(), 1);
+        assert!(report.corrupt.is_empty());
+        assert_eq!(
+            report.orphaned.into_iter().collect::<Vec<String>>(),
+            vec![format!("{}/chunks/orphan.chunk", j.id)]
+        );
+
+        let report = check_chunks(&j, &client, "hunter2", Some(100.0))
+            .await
+            .unwrap();
+        assert_eq!(report.read, 2);
+        assert_eq!(report.corrupt.len(), 1);
+        assert!(!report.is_ok());
+    }
+}
@@ -0,0 +1,264 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use crate::chunk::KipFileChunked;
+use crate::compress::KipCompressOpts;
+use crate::job::KipFileState;
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, read_to_string, remove_file, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const KIP_CHECKPOINT_DIR: &str = "checkpoints";
+
+/// Entries appended to a run's checkpoint journal, one per line
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum KipCheckpointEntry {
+    Run {
+        run_id: u64,
+        compress: KipCompressOpts,
+        started: DateTime<Utc>,
+    },
+    Chunk {
+        hash: String,
+        bytes: u64,
+    },
+    File {
+        kfc: KipFileChunked,
+        state: KipFileState,
+    },
+}
+
+/// Progress of a job's unfinished run, journaled to disk as
+/// chunks are uploaded and files finish so that a run
+/// interrupted by a crash or reboot can be resumed.
+#[derive(Debug)]
+pub struct KipCheckpoint {
+    pub job_id: Uuid,
+    pub run_id: u64,
+    pub compress: KipCompressOpts,
+    pub started: DateTime<Utc>,
+    /// Hashes of the chunks already uploaded
+    pub chunks: HashSet<String>,
+    pub bytes_uploaded: u64,
+    /// Files already finished and their state at the time
+    pub files: HashMap<PathBuf, (KipFileChunked, KipFileState)>,
+    path: PathBuf,
+    journal: Mutex<File>,
+}
+
+impl KipCheckpoint {
+    /// Checkpoint directory:
+    /// Linux:   /home/alice/.config/kip/checkpoints
+    /// Windows: C:\Users\Alice\AppData\Roaming\ciehanski\kip\checkpoints
+    /// macOS:   /Users/Alice/Library/Application Support/com.ciehanski.kip/checkpoints
+    pub fn default_dir() -> Result<PathBuf> {
+        match ProjectDirs::from("com", "ciehanski", "kip") {
+            Some(proj_dirs) => Ok(proj_dirs.config_dir().join(KIP_CHECKPOINT_DIR)),
+            None => bail!("unable to determine kip configuration directory"),
+        }
+    }
+
+    /// Whether a job has an unfinished run to resume
+    pub fn exists(job_id: Uuid) -> bool {
+        match Self::default_dir() {
+            Ok(dir) => checkpoint_path(&dir, job_id).exists(),
+            Err(_) => false,
+        }
+    }
+
+    /// Starts journaling a new run
+    pub fn create(job_id: Uuid, run_id: u64, compress: KipCompressOpts) -> Result<Self> {
+        Self::create_in(Self::default_dir()?, job_id, run_id, compress)
+    }
+
+    pub fn create_in<P: AsRef<Path>>(
+        dir: P,
+        job_id: Uuid,
+        run_id: u64,
+        compress: KipCompressOpts,
+    ) -> Result<Self> {
+        create_dir_all(dir.as_ref())?;
+        let path = checkpoint_path(dir.as_ref(), job_id);
+        let checkpoint = Self {
+            job_id,
+            run_id,
+            compress,
+            started: Utc::now(),
+            chunks: HashSet::new(),
+            bytes_uploaded: 0,
+            files: HashMap::new(),
+            journal: Mutex::new(File::create(&path)?),
+            path,
+        };
+        checkpoint.append(&KipCheckpointEntry::Run {
+            run_id,
+            compress,
+            started: checkpoint.started,
+        })?;
+        Ok(checkpoint)
+    }
+
+    /// Opens the journal of a job's unfinished run, if any
+    pub fn open(job_id: Uuid) -> Result<Option<Self>> {
+        Self::open_in(Self::default_dir()?, job_id)
+    }
+
+    pub fn open_in<P: AsRef<Path>>(dir: P, job_id: Uuid) -> Result<Option<Self>> {
+        let path = checkpoint_path(dir.as_ref(), job_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let journal = read_to_string(&path)?;
+        let mut lines = journal.split_inclusive('\n');
+        let header = lines.next().unwrap_or_default();
+        let Ok(KipCheckpointEntry::Run {
+            run_id,
+            compress,
+            started,
+        }) = serde_json::from_str(header)
+        else {
+            warn!("discarding unreadable checkpoint {}", path.display());
+            remove_file(&path)?;
+            return Ok(None);
+        };
+        let mut checkpoint = Self {
+            job_id,
+            run_id,
+            compress,
+            started,
+            chunks: HashSet::new(),
+            bytes_uploaded: 0,
+            files: HashMap::new(),
+            journal: Mutex::new(OpenOptions::new().append(true).open(&path)?),
+            path,
+        };
+        let mut valid_len = header.len();
+        for line in lines {
+            // The last entry may have been cut off mid-write
+            let entry = match serde_json::from_str::<KipCheckpointEntry>(line) {
+                Ok(entry) if line.ends_with('\n') => entry,
+                _ => {
+                    debug!("ignoring incomplete checkpoint entry");
+                    break;
+                }
+            };
+            valid_len += line.len();
+            match entry {
+                KipCheckpointEntry::Run { .. } => {}
+                KipCheckpointEntry::Chunk { hash, bytes } => {
+                    if checkpoint.chunks.insert(hash) {
+                        checkpoint.bytes_uploaded += bytes;
+                    }
+                }
+                KipCheckpointEntry::File { kfc, state } => {
+                    checkpoint.files.insert(kfc.file.path.clone(), (kfc, state));
+                }
+            }
+        }
+        // New entries are appended after the last complete one
+        if valid_len < journal.len() {
+            match checkpoint.journal.get_mut() {
+                Ok(file) => file.set_len(valid_len.try_into()?)?,
+                Err(e) => bail!("checkpoint journal lock poisoned: {e}"),
+            }
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Records a chunk as uploaded
+    pub fn record_chunk(&self, hash: &str, bytes: u64) -> Result<()> {
+        self.append(&KipCheckpointEntry::Chunk {
+            hash: hash.to_string(),
+            bytes,
+        })
+    }
+
+    /// Records a file as finished
+    pub fn record_file(&self, kfc: &KipFileChunked, state: &KipFileState) -> Result<()> {
+        self.append(&KipCheckpointEntry::File {
+            kfc: kfc.clone(),
+            state: state.clone(),
+        })
+    }
+
+    /// Discards the journal once its run is finished
+    pub fn remove(self) -> Result<()> {
+        drop(self.journal);
+        remove_file(&self.path)?;
+        Ok(())
+    }
+
+    fn append(&self, entry: &KipCheckpointEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut journal = match self.journal.lock() {
+            Ok(journal) => journal,
+            Err(e) => bail!("checkpoint journal lock poisoned: {e}"),
+        };
+        journal.write_all(&line)?;
+        journal.sync_data()?;
+        Ok(())
+    }
+}
+
+fn checkpoint_path(dir: &Path, job_id: Uuid) -> PathBuf {
+    dir.join(format!("{job_id}.journal"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{KipCompressAlg, KipCompressLevel};
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let job_id = Uuid::new_v4();
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        assert!(KipCheckpoint::open_in(tmp_dir.path(), job_id)
+            .unwrap()
+            .is_none());
+        let checkpoint = KipCheckpoint::create_in(tmp_dir.path(), job_id, 3, compress).unwrap();
+        checkpoint.record_chunk("a", 10).unwrap();
+        checkpoint.record_chunk("b", 20).unwrap();
+        let file = tmp_dir.path().join("a.txt");
+        std::fs::write(&file, b"kip").unwrap();
+        let state = KipFileState::new(&file.metadata().unwrap(), "hash").unwrap();
+        checkpoint
+            .record_file(&KipFileChunked::new(&file, "hash", 3), &state)
+            .unwrap();
+        drop(checkpoint);
+        // A crash mid-write leaves a partial entry behind
+        let path = checkpoint_path(tmp_dir.path(), job_id);
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        journal.write_all(b"{\"type\":\"chu").unwrap();
+
+        let checkpoint = KipCheckpoint::open_in(tmp_dir.path(), job_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.run_id, 3);
+        assert_eq!(checkpoint.compress, compress);
+        assert_eq!(checkpoint.bytes_uploaded, 30);
+        assert!(checkpoint.chunks.contains("b"));
+        assert_eq!(checkpoint.files[&file].1, state);
+        // Entries recorded after resuming aren't lost
+        checkpoint.record_chunk("c", 5).unwrap();
+        drop(checkpoint);
+        let checkpoint = KipCheckpoint::open_in(tmp_dir.path(), job_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.bytes_uploaded, 35);
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+}
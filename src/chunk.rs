@@ -2,21 +2,22 @@
 // Copyright (c) 2020 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
+use crate::compress::KipCompressOpts;
+use crate::crypto::keyed_hash;
 use crate::job::KipFile;
+use crate::meta::KipFileMeta;
 use anyhow::Result;
-use crypto_hash::{hex_digest, Algorithm};
-use fastcdc::v2020::AsyncStreamCDC;
+use fastcdc::v2020::{AsyncStreamCDC, ChunkData};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use tokio_stream::StreamExt;
+use tokio::io::AsyncRead;
 
 // 1 MB is min chunk size
 const MIN_SIZE: u32 = 1024 * 1024;
 // 4 MB is average chunk size
 const AVG_SIZE: u32 = 4 * 1024 * 1024;
 // 10 MB is max chunk size
-const MAX_SIZE: u32 = 10 * 1024 * 1024;
+pub(crate) const MAX_SIZE: u32 = 10 * 1024 * 1024;
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash, Serialize)]
 pub struct FileChunk {
@@ -26,6 +27,10 @@ pub struct FileChunk {
     pub offset: usize,
     pub length: usize,
     pub end: usize,
+    /// Compression the chunk was stored with. Chunks from
+    /// before this was recorded use their run's.
+    #[serde(default)]
+    pub compress: Option<KipCompressOpts>,
 }
 
 impl FileChunk {
@@ -43,18 +48,47 @@ impl FileChunk {
             offset,
             length,
             end,
+            compress: None,
         }
     }
 
+    /// Creates a FileChunk from a chunker's output. Each chunk is
+    /// named by a keyed hash of its plaintext so that unchanged
+    /// data produces the same chunk on every run.
+    pub fn from_chunk_data<P: Into<PathBuf>>(
+        local_path: P,
+        data: &ChunkData,
+        chunk_key: &[u8],
+    ) -> Result<Self> {
+        let offset: usize = data.offset.try_into()?;
+        Ok(Self::new(
+            local_path,
+            keyed_hash(chunk_key, &data.data)?,
+            offset,
+            data.length,
+            offset + data.length,
+        ))
+    }
+
     pub fn set_remote_path<S: Into<String>>(&mut self, remote_path: S) {
         self.remote_path = remote_path.into();
     }
+
+    pub fn set_compress(&mut self, compress: KipCompressOpts) {
+        self.compress = Some(compress);
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct KipFileChunked {
     pub file: KipFile,
-    pub chunks: HashMap<String, FileChunk>,
+    /// Ordered by offset. The same hash may appear more than
+    /// once if a file contains repeated data.
+    pub chunks: Vec<FileChunk>,
+    /// Set by 'kip repair' when some of the file's chunks
+    /// are missing or corrupt and couldn't be re-uploaded
+    #[serde(default)]
+    pub damaged: bool,
 }
 
 impl KipFileChunked {
@@ -70,14 +104,23 @@ impl KipFileChunked {
                 path: path.as_ref().to_path_buf(),
                 hash: file_hash.into(),
                 len,
+                meta: KipFileMeta::default(),
             },
-            chunks: HashMap::new(),
+            chunks: Vec::new(),
+            damaged: false,
         }
     }
 
     pub fn add_chunk(&mut self, chunk: FileChunk) {
-        let hash = chunk.hash.clone();
-        self.chunks.insert(hash, chunk);
+        self.chunks.push(chunk);
+    }
+
+    /// Records the run's compression on chunks stored
+    /// before compression was recorded per chunk
+    pub fn set_default_compress(&mut self, compress: KipCompressOpts) {
+        for c in self.chunks.iter_mut().filter(|c| c.compress.is_none()) {
+            c.compress = Some(compress);
+        }
     }
 
     // Checks if a certain file backed up in a specific run
@@ -98,120 +141,64 @@ impl KipFileChunked {
     }
 }
 
-/// chunk_compress_encrypt takes an array of bytes and chunks
-/// the contents according to the MIN, AVG, and MAX consts above.
-pub async fn chunk_file<P: AsRef<Path>>(
-    path: P,
-    file_hash: String,
-    len: usize,
-    bytes: &[u8],
-) -> Result<(KipFileChunked, HashMap<FileChunk, &[u8]>)> {
-    // Create a new chunker & stream over bytes
-    let mut chunker = AsyncStreamCDC::new(bytes, MIN_SIZE, AVG_SIZE, MAX_SIZE);
-    let mut stream = Box::pin(chunker.as_stream());
-
-    // For each chunk generated, add it to chunks collection to return
-    let mut chunks = HashMap::new();
-    let mut kcf = KipFileChunked::new(path.as_ref(), file_hash, len);
-
-    while let Some(result) = stream.next().await {
-        let entry = result?;
-        let end = entry.offset as usize + entry.length;
-        let chunk_bytes = &bytes[entry.offset as usize..end];
-        let chunk_hash = hex_digest(Algorithm::SHA256, &entry.data);
-        // Create new FileChunk
-        let chunk = FileChunk::new(
-            path.as_ref(),
-            chunk_hash,
-            entry.offset.try_into()?,
-            entry.length,
-            end,
-        );
-        // Insert newly created chunk for return
-        chunks.insert(chunk.clone(), chunk_bytes);
-        kcf.add_chunk(chunk);
-    }
-    // Ship it
-    Ok((kcf, chunks))
+/// Creates a chunker over a file's plaintext which splits its
+/// contents according to the MIN, AVG, and MAX consts above. The
+/// source is read as chunks are requested, so only the chunk being
+/// processed is held in memory.
+pub fn chunker<R: AsyncRead + Unpin>(source: R) -> AsyncStreamCDC<R> {
+    AsyncStreamCDC::new(source, MIN_SIZE, AVG_SIZE, MAX_SIZE)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::fs::read;
+    use tokio::fs::File;
+    use tokio_stream::StreamExt;
+
+    async fn chunk_test_file(path: &str) -> Vec<(FileChunk, Vec<u8>)> {
+        let file = File::open(path).await.unwrap();
+        let mut chunker = chunker(file);
+        let mut stream = Box::pin(chunker.as_stream());
+        let mut chunks = vec![];
+        while let Some(entry) = stream.next().await {
+            let entry = entry.unwrap();
+            let chunk = FileChunk::from_chunk_data(path, &entry, b"hunter2").unwrap();
+            chunks.push((chunk, entry.data));
+        }
+        chunks
+    }
 
     #[tokio::test]
     async fn test_chunk_single_chunk_file() {
-        let mut contents = vec![];
-        if !cfg!(windows) {
+        let chunks = if !cfg!(windows) {
             // Unix, Mac, Linux, etc
-            let content_result = read("test/vandy.jpg").await;
-            assert!(content_result.is_ok());
-            let mut cr = content_result.unwrap();
-            contents.append(&mut cr);
+            chunk_test_file("test/vandy.jpg").await
         } else {
             // Windows
-            let content_result = read(r".\test\vandy.jpg").await;
-            assert!(content_result.is_ok());
-            let mut cr = content_result.unwrap();
-            contents.append(&mut cr);
-        }
-        let chunk_hmap_result = chunk_file(
-            &Path::new("test/vandy.jpg"),
-            String::new(),
-            contents.len(),
-            &contents,
-        )
-        .await;
-        assert!(chunk_hmap_result.is_ok());
-        let (_, chunk_hmap) = chunk_hmap_result.unwrap();
-        assert_eq!(chunk_hmap.len(), 1);
-        for (c, _) in chunk_hmap.iter() {
+            chunk_test_file(r".\test\vandy.jpg").await
+        };
+        assert_eq!(chunks.len(), 1);
+        for (c, _) in chunks.iter() {
             assert_eq!(
                 c.hash,
-                "97ad4887a60dfa689660bad732f92a2871dedf97add169267c43e2955415488d".to_string()
+                "432b54252431c1807bcc59407c0214f56a75de793eb2a0108c5778cfbbcc525c".to_string()
             );
         }
     }
 
     #[tokio::test]
     async fn test_chunk_multi_chunk_file() {
-        let content_result = read("test/dummyfile").await;
-        assert!(content_result.is_ok());
-        let contents = content_result.unwrap();
-        let chunk_hmap_result = chunk_file(
-            &Path::new("test/dummyfile"),
-            String::new(),
-            contents.len(),
-            &contents,
-        )
-        .await;
-        assert!(chunk_hmap_result.is_ok());
-        let (_, chunk_hmap) = chunk_hmap_result.unwrap();
-        assert!(chunk_hmap.len() > 1);
-        println!("{:?}", chunk_hmap);
-        for (c, _) in chunk_hmap.iter() {
-            if c.offset == 0 {
-                assert_eq!(
-                    c.hash,
-                    "86d20d2ebc85db4b54db98e8a9f415225d3b230ff7ee7e07fb8927e16818b6ab".to_string()
-                );
-            } else if c.offset == 7159842 {
-                assert_eq!(
-                    c.hash,
-                    "0231275934f66653679a7575e96a08495a9ff4234f10eb61ae76f8ef9e3a78fb".to_string()
-                );
-            } else if c.offset == 23903350 {
-                assert_eq!(
-                    c.hash,
-                    "1913dd7777d0039cf92f904148fa2d3b14b009d5553fb8fb5889bb9b27136e91".to_string()
-                );
-            } else if c.offset == 15112420 {
-                assert_eq!(
-                    c.hash,
-                    "c3e624f3d44945495fa59b446e2b883e5b2178e2dd4da4052d5c6613b95d3469".to_string()
-                );
-            }
+        let chunks = chunk_test_file("test/dummyfile").await;
+        assert!(chunks.len() > 1);
+        println!("{:?}", chunks.iter().map(|(c, _)| c).collect::<Vec<_>>());
+        let mut offset = 0;
+        for (c, cb) in chunks.iter() {
+            // Chunks are contiguous and named by the keyed
+            // hash of their plaintext
+            assert_eq!(c.offset, offset);
+            assert_eq!(cb.len(), c.length);
+            assert_eq!(c.hash, keyed_hash(b"hunter2", cb).unwrap());
+            offset = c.end;
         }
     }
 }
@@ -77,6 +77,9 @@ pub enum Subcommands {
         /// Name of the job you want to start
         #[clap(value_parser)]
         job: String,
+        /// Hash every file, even if its size and timestamps are unchanged
+        #[clap(long = "force-rehash", action)]
+        force_rehash: bool,
     },
 
     /// Starts a restore of a job
@@ -93,6 +96,114 @@ pub enum Subcommands {
         output_folder: Option<String>,
     },
 
+    /// Rebuilds a job and its runs from a provider's manifests
+    #[clap(arg_required_else_help = true)]
+    Recover {
+        /// Name to give the recovered job
+        #[clap(value_parser)]
+        job: String,
+    },
+
+    /// Verifies a job's chunks are stored in its provider
+    #[clap(arg_required_else_help = true)]
+    Check {
+        /// Name of the job you want to check
+        #[clap(value_parser)]
+        job: String,
+        /// Download and verify every chunk
+        #[clap(long = "read-data", action, conflicts_with = "read_data_subset")]
+        read_data: bool,
+        /// Download and verify a random percentage of chunks. Ex: 10%
+        #[clap(long = "read-data-subset", value_parser)]
+        read_data_subset: Option<String>,
+    },
+
+    /// Re-uploads a job's missing or corrupt chunks from local files
+    #[clap(arg_required_else_help = true)]
+    Repair {
+        /// Name of the job you want to repair
+        #[clap(value_parser)]
+        job: String,
+        /// Download and verify every chunk to find corrupt ones
+        #[clap(long = "read-data", action)]
+        read_data: bool,
+    },
+
+    /// Sets which runs of a job are kept by 'kip forget'
+    #[clap(arg_required_else_help = true)]
+    Retention {
+        /// Name of the job you want to set the retention policy for
+        #[clap(value_parser)]
+        job: String,
+        /// Keep the latest n runs
+        #[clap(long = "keep-last", value_parser)]
+        keep_last: Option<usize>,
+        /// Keep the latest run of each of the last n hours
+        #[clap(long = "keep-hourly", value_parser)]
+        keep_hourly: Option<usize>,
+        /// Keep the latest run of each of the last n days
+        #[clap(long = "keep-daily", value_parser)]
+        keep_daily: Option<usize>,
+        /// Keep the latest run of each of the last n weeks
+        #[clap(long = "keep-weekly", value_parser)]
+        keep_weekly: Option<usize>,
+        /// Keep the latest run of each of the last n months
+        #[clap(long = "keep-monthly", value_parser)]
+        keep_monthly: Option<usize>,
+        /// Keep the latest run of each of the last n years
+        #[clap(long = "keep-yearly", value_parser)]
+        keep_yearly: Option<usize>,
+        /// Keep every run started within a duration. Ex: 30days
+        #[clap(long = "keep-within", value_parser)]
+        keep_within: Option<String>,
+        /// Always keep a run, regardless of the policy
+        #[clap(long = "keep-forever", value_parser)]
+        keep_forever: Option<usize>,
+        /// Remove the job's retention policy
+        #[clap(long = "clear", action)]
+        clear: bool,
+    },
+
+    /// Limits a job's upload and restore speed
+    #[clap(arg_required_else_help = true)]
+    Limit {
+        /// Name of the job you want to limit
+        #[clap(value_parser)]
+        job: String,
+        /// Speed limit in KB/s. Omit or use 0 to remove the limit
+        #[clap(value_parser)]
+        rate: Option<u64>,
+    },
+
+    /// Removes the runs a job's retention policy doesn't keep
+    #[clap(arg_required_else_help = true)]
+    Forget {
+        /// Name of the job you want to forget runs of
+        #[clap(value_parser)]
+        job: String,
+        /// Delete the forgotten runs' chunks that are no longer used
+        #[clap(short = 'p', long = "prune", action)]
+        prune: bool,
+        /// List the runs that would be forgotten without removing them
+        #[clap(long = "dry-run", action)]
+        dry_run: bool,
+    },
+
+    /// Deletes chunks that no remaining run of a job uses
+    #[clap(arg_required_else_help = true)]
+    Prune {
+        /// Name of the job you want to prune
+        #[clap(value_parser)]
+        job: String,
+    },
+
+    /// Manages a job's local chunk index
+    #[clap(arg_required_else_help = true)]
+    Index {
+        #[clap(subcommand)]
+        command: IndexCommands,
+    },
+
     /// Pauses all job uploads until manually resumed
     #[clap(arg_required_else_help = true)]
     Pause {
@@ -132,6 +243,17 @@ pub enum Subcommands {
     Daemon {},
 }
 
+#[derive(Debug, Subcommand)]
+pub enum IndexCommands {
+    /// Rebuilds a job's chunk index from its provider
+    #[clap(arg_required_else_help = true)]
+    Rebuild {
+        /// Name of the job you want to rebuild the index of
+        #[clap(value_parser)]
+        job: String,
+    },
+}
+
 #[cfg(test)]
 mod tests {
     use assert_cmd::Command;
@@ -7,7 +7,7 @@ use async_compression::tokio::write::*;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct KipCompressOpts {
     pub enabled: bool,
     pub alg: KipCompressAlg,
@@ -24,7 +24,7 @@ impl KipCompressOpts {
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum KipCompressAlg {
     Zstd,
@@ -33,7 +33,7 @@ pub enum KipCompressAlg {
     Brotli,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum KipCompressLevel {
     Fastest,
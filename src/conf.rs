@@ -2,9 +2,14 @@
 // Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
+use crate::checkpoint::KipCheckpoint;
 use crate::compress::{KipCompressAlg, KipCompressLevel};
+use crate::control::is_running;
 use crate::crypto::keyring_get_secret;
-use crate::job::Job;
+use crate::job::{Job, KipStatus};
+use crate::providers::s3::{DEFAULT_PART_CONCURRENCY, DEFAULT_PART_SIZE};
+use crate::retry::DEFAULT_RETRY_ATTEMPTS;
+use crate::run::KipRunOpts;
 use crate::smtp::{KipSmtpOpts, KipSmtpProtocols};
 use anyhow::{bail, Result};
 use chrono::prelude::*;
@@ -64,6 +69,58 @@ pub struct KipConfOpts {
     /// Sets the verbosity of debug logs.
     /// default: Info
     pub debug_level: KipDebugLevel,
+    /// Rough ceiling (in MB) of the memory kip uses to chunk,
+    /// compress and encrypt files during a backup.
+    /// default: 512
+    #[serde(default = "default_memory_limit")]
+    pub memory_limit: u64,
+    /// Upload and restore speed limit (in KB/s) shared by
+    /// every job. 0 means unlimited.
+    /// default: 0
+    #[serde(default)]
+    pub bandwidth_limit: u64,
+    /// How many times a provider request that failed with
+    /// a timeout, server error or throttling is retried.
+    /// default: 5
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    /// Size (in MB) of the parts S3 objects are uploaded and
+    /// downloaded in. Objects up to this size are sent whole.
+    /// default: 8
+    #[serde(default = "default_s3_part_size")]
+    pub s3_part_size: u64,
+    /// How many parts of an S3 object are transferred at once.
+    /// default: 4
+    #[serde(default = "default_s3_part_concurrency")]
+    pub s3_part_concurrency: usize,
+}
+
+impl KipConfOpts {
+    /// Returns the memory limit in bytes
+    pub fn memory_limit_bytes(&self) -> u64 {
+        self.memory_limit.saturating_mul(1024 * 1024)
+    }
+
+    /// Returns the bandwidth limit in bytes per second
+    pub fn bandwidth_limit_bytes(&self) -> u64 {
+        self.bandwidth_limit.saturating_mul(1024)
+    }
+}
+
+fn default_memory_limit() -> u64 {
+    512
+}
+
+fn default_retry_attempts() -> u32 {
+    DEFAULT_RETRY_ATTEMPTS
+}
+
+fn default_s3_part_size() -> u64 {
+    DEFAULT_PART_SIZE
+}
+
+fn default_s3_part_concurrency() -> usize {
+    DEFAULT_PART_CONCURRENCY
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -91,6 +148,11 @@ impl KipConf {
                 email_notification: false,
                 run_on_low_battery: false,
                 debug_level: KipDebugLevel::INFO,
+                memory_limit: default_memory_limit(),
+                bandwidth_limit: 0,
+                retry_attempts: default_retry_attempts(),
+                s3_part_size: default_s3_part_size(),
+                s3_part_concurrency: default_s3_part_concurrency(),
             },
             smtp_config: KipSmtpOpts {
                 username: String::from("kip@gmail.com"),
@@ -113,7 +175,8 @@ impl KipConf {
                 let kc: KipConf = toml::from_slice(&kc_file)?;
                 if proj_dirs.config_dir().join(KIP_METADATA).exists() {
                     let md_file = read(proj_dirs.config_dir().join(KIP_METADATA))?;
-                    let md = serde_json::from_slice(&md_file)?;
+                    let mut md: KipConfMetadata = serde_json::from_slice(&md_file)?;
+                    md.reconcile_statuses();
                     return Ok((Arc::new(kc), Arc::new(RwLock::new(md))));
                 }
             }
@@ -166,26 +229,53 @@ impl KipConfMetadata {
         }
     }
 
+    /// Jobs left IN_PROGRESS by a kip process that exited before
+    /// its run finished are marked INTERRUPTED if the run can be
+    /// resumed, or ERR otherwise.
+    pub fn reconcile_statuses(&mut self) {
+        for j in self.jobs.values_mut() {
+            if j.last_status != KipStatus::IN_PROGRESS || is_running(j.id).unwrap_or(true) {
+                continue;
+            }
+            j.last_status = if KipCheckpoint::exists(j.id) {
+                KipStatus::INTERRUPTED
+            } else {
+                KipStatus::ERR
+            };
+        }
+    }
+
     /// Requires "Always Allow" access to your keyring entries for kip
     pub async fn poll_backup_jobs(&mut self, kc: &KipConf) -> Result<()> {
         if !self.jobs.is_empty() {
             for (_, j) in self.jobs.iter_mut() {
-                if j.paused {
+                if j.is_paused() {
                     continue;
                 }
+                // Interrupted runs are resumed right away
+                let interrupted = KipCheckpoint::exists(j.id) && !is_running(j.id)?;
                 // Get last run start duration
-                let run = match j.runs.get(&j.runs.len()) {
-                    Some(r) => r,
-                    None => {
-                        continue;
+                let due = match j.runs.values().next_back() {
+                    Some(run) => {
+                        let dur_since_run_start = Utc::now().signed_duration_since(run.started);
+                        dur_since_run_start.num_minutes()
+                            >= kc.settings.backup_interval.try_into()?
                     }
+                    None => false,
                 };
-                let dur_since_run_start = Utc::now().signed_duration_since(run.started);
                 // If the duration since the last run started is more than
                 // the configured backup interval, start an upload run
-                let secret = keyring_get_secret(&format!("com.ciehanski.kip.{}", &j.name))?;
-                if dur_since_run_start.num_minutes() >= kc.settings.backup_interval.try_into()? {
-                    j.start_run(&secret, kc.settings.follow_symlinks).await?;
+                if interrupted || due {
+                    let secret = keyring_get_secret(&format!("com.ciehanski.kip.{}", &j.name))?;
+                    j.start_run(
+                        &secret,
+                        KipRunOpts::new(
+                            kc.settings.follow_symlinks,
+                            false,
+                            kc.settings.memory_limit_bytes(),
+                        ),
+                    )
+                    .await?;
                 }
             }
         }
@@ -0,0 +1,399 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use anyhow::{bail, Result};
+use directories::ProjectDirs;
+use std::fs::{create_dir_all, read_to_string, remove_file, write, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use uuid::Uuid;
+
+const KIP_CONTROL_DIR: &str = "control";
+// How often a running job checks for abort and pause requests
+const KIP_CONTROL_POLL: Duration = Duration::from_secs(1);
+// Stale locks are removed and creating the lock retried this many times
+const KIP_LOCK_ATTEMPTS: usize = 3;
+// How long a new lock may stay empty before it's considered stale
+const KIP_LOCK_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Held by the process running a job. Its lock marker tells
+/// other kip processes the job is running, and its signals
+/// follow the abort and pause requests they make.
+#[derive(Debug)]
+pub struct KipRunControl {
+    job_id: Uuid,
+    dir: PathBuf,
+    signals: KipRunSignals,
+    watcher: JoinHandle<()>,
+}
+
+/// Abort and pause requests, as seen by a running run
+#[derive(Clone, Debug)]
+pub struct KipRunSignals {
+    cancel: CancellationToken,
+    paused: watch::Receiver<bool>,
+}
+
+impl KipRunControl {
+    /// Control directory:
+    /// Linux:   /home/alice/.config/kip/control
+    /// Windows: C:\Users\Alice\AppData\Roaming\ciehanski\kip\control
+    /// macOS:   /Users/Alice/Library/Application Support/com.ciehanski.kip/control
+    pub fn default_dir() -> Result<PathBuf> {
+        match ProjectDirs::from("com", "ciehanski", "kip") {
+            Some(proj_dirs) => Ok(proj_dirs.config_dir().join(KIP_CONTROL_DIR)),
+            None => bail!("unable to determine kip configuration directory"),
+        }
+    }
+
+    /// Marks a job as running. Fails if another
+    /// process is already running it.
+    pub fn acquire(job_id: Uuid) -> Result<Self> {
+        Self::acquire_in(Self::default_dir()?, job_id)
+    }
+
+    pub fn acquire_in<P: AsRef<Path>>(dir: P, job_id: Uuid) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        create_dir_all(&dir)?;
+        create_lock(&lock_path(&dir, job_id))?;
+        // Abort requests left behind by a previous run
+        // don't apply to this one
+        let abort_path = abort_path(&dir, job_id);
+        if abort_path.exists() {
+            remove_file(&abort_path)?;
+        }
+
+        // Pause requests outlive the process that was paused
+        let pause_path = pause_path(&dir, job_id);
+        let token = CancellationToken::new();
+        let (paused_tx, paused_rx) = watch::channel(pause_path.exists());
+        let watcher_token = token.clone();
+        let watcher = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(KIP_CONTROL_POLL);
+            loop {
+                interval.tick().await;
+                if abort_path.exists() {
+                    debug!("abort requested for {job_id}");
+                    watcher_token.cancel();
+                    break;
+                }
+                let paused = pause_path.exists();
+                paused_tx.send_if_modified(|p| {
+                    if *p == paused {
+                        return false;
+                    }
+                    debug!("pause of {job_id} set to {paused}");
+                    *p = paused;
+                    true
+                });
+            }
+        });
+        Ok(Self {
+            job_id,
+            dir,
+            signals: KipRunSignals {
+                cancel: token,
+                paused: paused_rx,
+            },
+            watcher,
+        })
+    }
+
+    pub fn signals(&self) -> KipRunSignals {
+        self.signals.clone()
+    }
+}
+
+impl KipRunSignals {
+    pub fn is_aborted(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Completes once the run should abort
+    pub async fn aborted(&self) {
+        self.cancel.cancelled().await
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Waits until the run is resumed or aborted
+    pub async fn wait_while_paused(&self) {
+        let mut paused = self.paused.clone();
+        tokio::select! {
+            _ = paused.wait_for(|p| !p) => {}
+            _ = self.cancel.cancelled() => {}
+        }
+    }
+}
+
+impl Drop for KipRunControl {
+    fn drop(&mut self) {
+        self.watcher.abort();
+        let _ = remove_file(lock_path(&self.dir, self.job_id));
+        let _ = remove_file(abort_path(&self.dir, self.job_id));
+    }
+}
+
+/// Asks the process running a job to abort it. Returns
+/// false if the job isn't running.
+pub fn request_abort(job_id: Uuid) -> Result<bool> {
+    request_abort_in(KipRunControl::default_dir()?, job_id)
+}
+
+pub fn request_abort_in<P: AsRef<Path>>(dir: P, job_id: Uuid) -> Result<bool> {
+    if !is_running_in(dir.as_ref(), job_id) {
+        return Ok(false);
+    }
+    write(abort_path(dir.as_ref(), job_id), "")?;
+    Ok(true)
+}
+
+/// Pauses a job until 'kip resume' is run. A run in progress
+/// stops uploading once its in-flight chunks finish.
+pub fn request_pause(job_id: Uuid) -> Result<()> {
+    request_pause_in(KipRunControl::default_dir()?, job_id)
+}
+
+pub fn request_pause_in<P: AsRef<Path>>(dir: P, job_id: Uuid) -> Result<()> {
+    create_dir_all(dir.as_ref())?;
+    write(pause_path(dir.as_ref(), job_id), "")?;
+    Ok(())
+}
+
+/// Lets a paused job and its paused run continue
+pub fn clear_pause(job_id: Uuid) -> Result<()> {
+    clear_pause_in(KipRunControl::default_dir()?, job_id)
+}
+
+pub fn clear_pause_in<P: AsRef<Path>>(dir: P, job_id: Uuid) -> Result<()> {
+    let path = pause_path(dir.as_ref(), job_id);
+    if path.exists() {
+        remove_file(path)?;
+    }
+    Ok(())
+}
+
+pub fn is_paused(job_id: Uuid) -> bool {
+    match KipRunControl::default_dir() {
+        Ok(dir) => pause_path(&dir, job_id).exists(),
+        Err(_) => false,
+    }
+}
+
+/// Whether a live kip process holds the job's lock
+pub fn is_running(job_id: Uuid) -> Result<bool> {
+    Ok(is_running_in(&KipRunControl::default_dir()?, job_id))
+}
+
+/// Creates the lock with this process' PID. Creation fails if the
+/// lock already exists, so only one process can acquire it.
+fn create_lock(lock_path: &Path) -> Result<()> {
+    for _ in 0..KIP_LOCK_ATTEMPTS {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(lock_path)
+        {
+            Ok(mut lock) => {
+                if let Err(e) = lock.write_all(std::process::id().to_string().as_bytes()) {
+                    let _ = remove_file(lock_path);
+                    bail!(e)
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if !lock_is_stale(lock_path) {
+                    bail!("job is already running in another kip process")
+                }
+                // Left behind by a process that crashed
+                debug!("removing stale lock {}", lock_path.display());
+                match remove_file(lock_path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => bail!(e),
+                }
+            }
+            Err(e) => bail!(e),
+        }
+    }
+    bail!("job is already running in another kip process")
+}
+
+/// A lock is stale once its process is gone. An empty lock was
+/// just created and its PID is still being written, unless
+/// it's been empty for a while.
+fn lock_is_stale(lock_path: &Path) -> bool {
+    let Ok(pid) = read_to_string(lock_path) else {
+        // Released in the meantime
+        return true;
+    };
+    match pid.trim().parse::<u32>() {
+        Ok(pid) => !process_alive(pid),
+        Err(_) => lock_path
+            .metadata()
+            .and_then(|md| md.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default() > KIP_LOCK_WRITE_TIMEOUT)
+            .unwrap_or(true),
+    }
+}
+
+fn is_running_in(dir: &Path, job_id: Uuid) -> bool {
+    match read_to_string(lock_path(dir, job_id)) {
+        Ok(pid) => match pid.trim().parse::<u32>() {
+            Ok(pid) => process_alive(pid),
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Locks left behind by a process that crashed are stale
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists
+    // SAFETY: kill takes no pointers, and signal 0
+    // performs error checking without sending a signal
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // The process exists but belongs to another user
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+    // SAFETY: OpenProcess takes no pointers and returns
+    // a null handle if the process can't be opened
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle == 0 {
+        // The process exists but belongs to another user
+        return std::io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED as i32);
+    }
+    let mut exit_code = 0u32;
+    // SAFETY: the handle was opened above and exit_code
+    // outlives the call
+    let queried = unsafe { GetExitCodeProcess(handle, &mut exit_code) };
+    // SAFETY: the handle is open and isn't used afterwards
+    unsafe { CloseHandle(handle) };
+    // Exited processes stay queryable while handles to them are open
+    queried != 0 && exit_code == STILL_ACTIVE as u32
+}
+
+#[cfg(not(any(unix, windows)))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+fn lock_path(dir: &Path, job_id: Uuid) -> PathBuf {
+    dir.join(format!("{job_id}.lock"))
+}
+
+fn abort_path(dir: &Path, job_id: Uuid) -> PathBuf {
+    dir.join(format!("{job_id}.abort"))
+}
+
+fn pause_path(dir: &Path, job_id: Uuid) -> PathBuf {
+    dir.join(format!("{job_id}.pause"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PID of a child that exited and was waited on
+    fn exited_pid() -> u32 {
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[tokio::test]
+    async fn test_run_control_abort() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let job_id = Uuid::new_v4();
+        // Nothing to abort until the job is running
+        assert!(!request_abort_in(tmp_dir.path(), job_id).unwrap());
+        let control = KipRunControl::acquire_in(tmp_dir.path(), job_id).unwrap();
+        assert!(KipRunControl::acquire_in(tmp_dir.path(), job_id).is_err());
+        assert!(request_abort_in(tmp_dir.path(), job_id).unwrap());
+        tokio::time::timeout(Duration::from_secs(5), control.signals().aborted())
+            .await
+            .unwrap();
+        assert!(control.signals().is_aborted());
+        // Markers are removed once the run is done
+        drop(control);
+        assert!(!lock_path(tmp_dir.path(), job_id).exists());
+        assert!(!abort_path(tmp_dir.path(), job_id).exists());
+        assert!(KipRunControl::acquire_in(tmp_dir.path(), job_id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_run_control_pause() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let job_id = Uuid::new_v4();
+        let control = KipRunControl::acquire_in(tmp_dir.path(), job_id).unwrap();
+        let signals = control.signals();
+        assert!(!signals.is_paused());
+        request_pause_in(tmp_dir.path(), job_id).unwrap();
+        let mut paused = signals.paused.clone();
+        tokio::time::timeout(Duration::from_secs(5), paused.wait_for(|p| *p))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(signals.is_paused());
+        clear_pause_in(tmp_dir.path(), job_id).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), signals.wait_while_paused())
+            .await
+            .unwrap();
+        assert!(!signals.is_paused());
+        // Pauses survive the process holding the job
+        request_pause_in(tmp_dir.path(), job_id).unwrap();
+        drop(control);
+        let control = KipRunControl::acquire_in(tmp_dir.path(), job_id).unwrap();
+        assert!(control.signals().is_paused());
+    }
+
+    #[tokio::test]
+    async fn test_run_control_stale_lock() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let job_id = Uuid::new_v4();
+        let lock = lock_path(tmp_dir.path(), job_id);
+        // A lock whose PID is still being written is held
+        write(&lock, "").unwrap();
+        assert!(KipRunControl::acquire_in(tmp_dir.path(), job_id).is_err());
+        // A lock left behind by a process that's gone is replaced
+        write(&lock, exited_pid().to_string()).unwrap();
+        let control = KipRunControl::acquire_in(tmp_dir.path(), job_id).unwrap();
+        assert_eq!(
+            read_to_string(&lock).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(control);
+    }
+
+    #[test]
+    fn test_process_alive() {
+        assert!(process_alive(std::process::id()));
+        assert!(!process_alive(exited_pid()));
+    }
+}
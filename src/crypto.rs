@@ -6,12 +6,16 @@ use aead::{Aead, AeadCore, AeadInPlace, KeyInit, OsRng};
 use anyhow::{bail, Result};
 use argon2::{self, Config, Variant, Version};
 use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use crypto_hash::{digest, Algorithm, Hasher};
 use keyring::Entry;
 use rand::Rng;
+use std::io::Write;
+use uuid::Uuid;
 use zeroize::Zeroize;
 
 const SALT_LEN: usize = 32;
 const NONCE_LEN: usize = 24;
+const HMAC_BLOCK_LEN: usize = 64;
 const ARGON_CONF: Config = Config {
     variant: Variant::Argon2id,
     version: Version::Version13,
@@ -104,6 +108,10 @@ pub fn encrypt_bytes(plaintext: &[u8], secret: &str) -> Result<Vec<u8>> {
 }
 
 pub fn decrypt(ciphertext: &[u8], secret: &str) -> Result<Vec<u8>> {
+    // Truncated ciphertext can't hold its salt & nonce
+    if ciphertext.len() < SALT_LEN + NONCE_LEN {
+        bail!("unable to decrypt ciphertext: too short")
+    }
     // Split off salt & nonce from ciphertext for decryption
     let (salt_cipher, nonce) = ciphertext.split_at(ciphertext.len() - NONCE_LEN);
     let (ciphertext_cut, salt) = salt_cipher.split_at(salt_cipher.len() - SALT_LEN);
@@ -130,6 +138,50 @@ pub fn decrypt(ciphertext: &[u8], secret: &str) -> Result<Vec<u8>> {
     Ok(plaintext)
 }
 
+/// Derives the key used to name a job's chunks. The job's ID is
+/// used as the salt so identical plaintext only dedupes within a job.
+pub fn derive_chunk_key(secret: &str, job_id: Uuid) -> Result<Vec<u8>> {
+    let chunk_key = argon2::hash_raw(secret.as_bytes(), job_id.as_bytes(), &ARGON_CONF)?;
+    Ok(chunk_key)
+}
+
+/// Computes the HMAC-SHA256 of data with the provided key and
+/// returns it hex encoded. Used to name chunks by their plaintext
+/// without leaking the plaintext's hash to the provider.
+pub fn keyed_hash(key: &[u8], data: &[u8]) -> Result<String> {
+    // Keys longer than the block size are hashed first
+    let mut block_key = [0u8; HMAC_BLOCK_LEN];
+    if key.len() > HMAC_BLOCK_LEN {
+        let hashed_key = digest(Algorithm::SHA256, key);
+        block_key[..hashed_key.len()].copy_from_slice(&hashed_key);
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut ipad = block_key.map(|b| b ^ 0x36);
+    let mut opad = block_key.map(|b| b ^ 0x5c);
+    // H((K ^ ipad) || data)
+    let mut inner = Hasher::new(Algorithm::SHA256);
+    inner.write_all(&ipad)?;
+    inner.write_all(data)?;
+    let inner_hash = inner.finish();
+    // H((K ^ opad) || H((K ^ ipad) || data))
+    let mut outer = Hasher::new(Algorithm::SHA256);
+    outer.write_all(&opad)?;
+    outer.write_all(&inner_hash)?;
+    let mac = hex_encode(&outer.finish());
+    // Zeroize variables
+    block_key.zeroize();
+    ipad.zeroize();
+    opad.zeroize();
+    // Ship it
+    Ok(mac)
+}
+
+/// Hex encodes bytes, matching the output of crypto_hash's hex_digest.
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 pub fn keyring_set_secret(
     job_name: &str,
     password: &str,
@@ -228,6 +280,30 @@ mod tests {
         assert_eq!(ciphertext, b"secret")
     }
 
+    #[test]
+    fn test_keyed_hash() {
+        // RFC 4231 test case 2
+        let mac_result = keyed_hash(b"Jefe", b"what do ya want for nothing?");
+        assert!(mac_result.is_ok());
+        assert_eq!(
+            mac_result.unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        )
+    }
+
+    #[test]
+    fn test_derive_chunk_key() {
+        let job_id = Uuid::new_v4();
+        let key_result = derive_chunk_key("hunter2", job_id);
+        assert!(key_result.is_ok());
+        let key = key_result.unwrap();
+        assert_eq!(key.len(), 32);
+        // Same secret and job always derive the same key
+        assert_eq!(key, derive_chunk_key("hunter2", job_id).unwrap());
+        // Different jobs derive different keys
+        assert_ne!(key, derive_chunk_key("hunter2", Uuid::new_v4()).unwrap())
+    }
+
     #[test]
     fn test_keyring_set_get() {
         // To use mock credential store call this during
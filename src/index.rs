@@ -0,0 +1,244 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use crate::providers::{KipClient, KipProviders};
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+use uuid::Uuid;
+
+const KIP_INDEX_DIR: &str = "index";
+
+/// A local cache of the chunks known to be stored in a job's
+/// provider, by remote path. Uploads are checked against it
+/// instead of listing the provider for every chunk.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KipChunkIndex {
+    pub job_id: Uuid,
+    /// Last time the index was saved
+    pub updated: DateTime<Utc>,
+    /// Last time the index was rebuilt from the provider
+    pub rebuilt: DateTime<Utc>,
+    pub chunks: BTreeSet<String>,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl KipChunkIndex {
+    /// Index directory:
+    /// Linux:   /home/alice/.config/kip/index
+    /// Windows: C:\Users\Alice\AppData\Roaming\ciehanski\kip\index
+    /// macOS:   /Users/Alice/Library/Application Support/com.ciehanski.kip/index
+    pub fn default_dir() -> Result<PathBuf> {
+        match ProjectDirs::from("com", "ciehanski", "kip") {
+            Some(proj_dirs) => Ok(proj_dirs.config_dir().join(KIP_INDEX_DIR)),
+            None => bail!("unable to determine kip configuration directory"),
+        }
+    }
+
+    /// Opens a job's index, if one has been built
+    pub fn open(job_id: Uuid) -> Result<Option<Self>> {
+        Self::open_in(Self::default_dir()?, job_id)
+    }
+
+    pub fn open_in<P: AsRef<Path>>(dir: P, job_id: Uuid) -> Result<Option<Self>> {
+        let path = index_path(dir.as_ref(), job_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut index: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        index.dir = dir.as_ref().to_path_buf();
+        // Chunks uploaded since the last save
+        let journal_path = journal_path(dir.as_ref(), job_id);
+        if journal_path.exists() {
+            for line in read_to_string(journal_path)?.split_inclusive('\n') {
+                // The last entry may have been cut off mid-write
+                if let Some(remote_path) = line.strip_suffix('\n') {
+                    index.insert(remote_path);
+                }
+            }
+        }
+        Ok(Some(index))
+    }
+
+    /// Recreates a job's index from the provider's listing
+    pub async fn rebuild(
+        provider: &KipProviders,
+        client: &KipClient,
+        job_id: Uuid,
+    ) -> Result<Self> {
+        Self::rebuild_in(Self::default_dir()?, provider, client, job_id).await
+    }
+
+    pub async fn rebuild_in<P: AsRef<Path>>(
+        dir: P,
+        provider: &KipProviders,
+        client: &KipClient,
+        job_id: Uuid,
+    ) -> Result<Self> {
+        debug!("rebuilding chunk index for {job_id}");
+        let mut index = Self {
+            job_id,
+            updated: Utc::now(),
+            rebuilt: Utc::now(),
+            chunks: provider
+                .list_chunks(client, job_id)
+                .await?
+                .into_iter()
+                .collect(),
+            dir: dir.as_ref().to_path_buf(),
+        };
+        index.save()?;
+        Ok(index)
+    }
+
+    /// Opens a job's index, building it from the
+    /// provider's listing the first time
+    pub async fn open_or_rebuild(
+        provider: &KipProviders,
+        client: &KipClient,
+        job_id: Uuid,
+    ) -> Result<Self> {
+        match Self::open(job_id)? {
+            Some(index) => Ok(index),
+            None => Self::rebuild(provider, client, job_id).await,
+        }
+    }
+
+    pub fn contains(&self, remote_path: &str) -> bool {
+        self.chunks.contains(remote_path)
+    }
+
+    pub fn insert<S: Into<String>>(&mut self, remote_path: S) {
+        self.chunks.insert(remote_path.into());
+    }
+
+    /// Adds a chunk as soon as it's uploaded. It's journaled
+    /// to disk until the next save so a run that crashes
+    /// doesn't leave the index stale.
+    pub fn record(&mut self, remote_path: &str) -> Result<()> {
+        create_dir_all(&self.dir)?;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journal_path(&self.dir, self.job_id))?;
+        journal.write_all(format!("{remote_path}\n").as_bytes())?;
+        journal.sync_data()?;
+        self.insert(remote_path);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, remote_path: &str) {
+        self.chunks.remove(remote_path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Writes the index to a temporary file first so an
+    /// interrupted save doesn't leave a truncated index
+    pub fn save(&mut self) -> Result<()> {
+        create_dir_all(&self.dir)?;
+        self.updated = Utc::now();
+        let path = index_path(&self.dir, self.job_id);
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        rename(tmp_path, path)?;
+        // The journal's chunks are now in the index
+        match remove_file(journal_path(&self.dir, self.job_id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Updates a job's index if one has been built. Indexes are
+/// only built by runs and 'kip index rebuild'.
+pub fn update_index<F: FnOnce(&mut KipChunkIndex)>(job_id: Uuid, f: F) -> Result<()> {
+    if let Some(mut index) = KipChunkIndex::open(job_id)? {
+        f(&mut index);
+        index.save()?;
+    }
+    Ok(())
+}
+
+fn index_path(dir: &Path, job_id: Uuid) -> PathBuf {
+    dir.join(format!("{job_id}.json"))
+}
+
+fn journal_path(dir: &Path, job_id: Uuid) -> PathBuf {
+    dir.join(format!("{job_id}.journal"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::usb::KipUsb;
+
+    #[tokio::test]
+    async fn test_index_rebuild_and_save() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let index_dir = tmp_dir.path().join("index");
+        let usb_dir = tmp_dir.path().join("usb");
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", &usb_dir, 0, 0));
+        let job_id = Uuid::new_v4();
+        assert!(KipChunkIndex::open_in(&index_dir, job_id)
+            .unwrap()
+            .is_none());
+        // Built from the chunks already stored
+        let chunk_dir = usb_dir.join(format!("{job_id}/chunks"));
+        std::fs::create_dir_all(&chunk_dir).unwrap();
+        std::fs::write(chunk_dir.join("a.chunk"), b"a").unwrap();
+        let mut index = KipChunkIndex::rebuild_in(&index_dir, &provider, &KipClient::None, job_id)
+            .await
+            .unwrap();
+        assert!(index.contains(&format!("{job_id}/chunks/a.chunk")));
+        // Kept up to date by uploads and deletes
+        index.insert(format!("{job_id}/chunks/b.chunk"));
+        index.remove(&format!("{job_id}/chunks/a.chunk"));
+        index.save().unwrap();
+        let index = KipChunkIndex::open_in(&index_dir, job_id).unwrap().unwrap();
+        assert_eq!(index.len(), 1);
+        assert!(index.contains(&format!("{job_id}/chunks/b.chunk")));
+    }
+
+    #[tokio::test]
+    async fn test_index_record() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let index_dir = tmp_dir.path().join("index");
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", tmp_dir.path(), 0, 0));
+        let job_id = Uuid::new_v4();
+        let mut index = KipChunkIndex::rebuild_in(&index_dir, &provider, &KipClient::None, job_id)
+            .await
+            .unwrap();
+        // Recorded chunks survive a crash before the next save
+        index.record(&format!("{job_id}/chunks/a.chunk")).unwrap();
+        index.record(&format!("{job_id}/chunks/b.chunk")).unwrap();
+        let journal = journal_path(&index_dir, job_id);
+        let mut file = OpenOptions::new().append(true).open(&journal).unwrap();
+        file.write_all(b"cut-off").unwrap();
+        drop(index);
+        let mut index = KipChunkIndex::open_in(&index_dir, job_id).unwrap().unwrap();
+        assert_eq!(index.len(), 2);
+        assert!(index.contains(&format!("{job_id}/chunks/a.chunk")));
+        // Saving folds the journal into the index
+        index.save().unwrap();
+        assert!(!journal.exists());
+        let index = KipChunkIndex::open_in(&index_dir, job_id).unwrap().unwrap();
+        assert_eq!(index.len(), 2);
+    }
+}
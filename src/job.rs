@@ -2,25 +2,43 @@
 // Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
+use crate::check::{check_chunks, repair_chunks, KipCheckReport, KipRepairReport};
+use crate::checkpoint::KipCheckpoint;
+use crate::chunk::KipFileChunked;
 use crate::compress::KipCompressOpts;
+use crate::control::{is_paused, request_abort, KipRunControl};
 use crate::crypto::{keyring_delete_secret, keyring_get_secret};
+use crate::index::{update_index, KipChunkIndex};
+use crate::limiter::KipBandwidth;
+use crate::manifest::KipManifest;
+use crate::meta::KipFileMeta;
+use crate::providers::azure::AZURE_SECRET_VAR;
+use crate::providers::sftp::SFTP_SECRET_VAR;
+use crate::providers::smb::SMB_SECRET_VAR;
+use crate::providers::webdav::WEBDAV_SECRET_VAR;
 use crate::providers::KipProviders;
-use crate::run::{open_file, Run};
+use crate::retention::KipRetention;
+use crate::run::{hash_file, KipRunOpts, Run};
 use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
 use colored::*;
 use crypto_hash::{hex_digest, Algorithm};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::env;
 use std::fmt::{Debug, Display};
+use std::fs::Metadata;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tracing::instrument;
+use tracing::{debug, instrument, warn};
 use uuid::Uuid;
 use walkdir::WalkDir;
 
+/// Days S3 Object Lock holds objects for when the job's
+/// retention policy doesn't keep runs for a set time
+pub const DEFAULT_OBJECT_LOCK_DAYS: u32 = 30;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Job {
     pub id: Uuid,
@@ -31,7 +49,22 @@ pub struct Job {
     pub files_amt: u64,
     pub excluded_files: Vec<PathBuf>,
     pub excluded_file_types: Vec<String>,
+    /// Last known state of every file backed up by the job,
+    /// including the files within directories.
+    #[serde(default)]
+    pub file_states: BTreeMap<PathBuf, KipFileState>,
     pub runs: BTreeMap<usize, Run>,
+    /// Which runs 'kip forget' keeps
+    #[serde(default)]
+    pub retention: KipRetention,
+    /// Upload and restore speed limit of the job in KB/s.
+    /// 0 means the job is only held to the global limit.
+    #[serde(default)]
+    pub bandwidth_limit: u64,
+    /// Remote paths of chunks referenced by forgotten runs,
+    /// deleted by 'kip prune' if no remaining run uses them.
+    #[serde(default)]
+    pub pending_prune: BTreeSet<String>,
     pub bytes_amt_provider: u64,
     pub first_run: DateTime<Utc>,
     pub last_run: DateTime<Utc>,
@@ -67,7 +100,11 @@ impl Job {
             files_amt: 0,
             excluded_files: Vec::new(),
             excluded_file_types: Vec::new(),
+            file_states: BTreeMap::new(),
             runs: BTreeMap::new(),
+            retention: KipRetention::default(),
+            pending_prune: BTreeSet::new(),
+            bandwidth_limit: 0,
             bytes_amt_provider: 0,
             first_run: time_init,
             last_run: time_init,
@@ -78,32 +115,97 @@ impl Job {
         }
     }
 
+    /// Whether the job is paused. Pauses made while another
+    /// process was running the job are only recorded by its
+    /// pause marker.
+    pub fn is_paused(&self) -> bool {
+        self.paused || is_paused(self.id)
+    }
+
+    /// The bandwidth limits the job's transfers are held to
+    pub fn bandwidth(&self) -> KipBandwidth {
+        KipBandwidth::for_job(self.id, self.bandwidth_limit.saturating_mul(1024))
+    }
+
+    /// Locks objects for as long as the retention policy keeps
+    /// runs, if the job uploads with S3 Object Lock
+    pub fn sync_object_lock(&mut self) -> Result<()> {
+        if let KipProviders::S3(s3) = &mut self.provider {
+            if let Some(lock) = &mut s3.object_lock {
+                lock.days = self
+                    .retention
+                    .max_age_days()?
+                    .unwrap_or(DEFAULT_OBJECT_LOCK_DAYS)
+                    .max(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the job's S3 bucket has Object Lock
+    /// enabled, if the job uploads with it
+    pub async fn check_object_lock(&self) -> Result<()> {
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            self.provider.check_object_lock(&client).await
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        result
+    }
+
     pub fn provider_name(&self) -> &str {
         match &self.provider {
             KipProviders::S3(s3) => &s3.aws_bucket,
             KipProviders::Usb(usb) => &usb.name,
             KipProviders::Gdrive(_) => "Google Drive",
+            KipProviders::Local(local) => local.root_path.to_str().unwrap_or("Local"),
+            KipProviders::Sftp(sftp) => &sftp.host,
+            KipProviders::Smb(smb) => &smb.host,
+            KipProviders::Azure(azure) => &azure.container,
+            KipProviders::WebDav(webdav) => &webdav.url,
         }
     }
 
-    pub async fn start_run(&mut self, secret: &str, follow_links: bool) -> Result<()> {
+    pub async fn start_run(&mut self, secret: &str, opts: KipRunOpts) -> Result<()> {
         // Check and confirm that job is not paused
-        if self.paused {
+        if self.is_paused() {
             bail!(
                 "unable to run. '{}' is paused. Please run 'kip resume {}' to resume job.",
                 self.name,
                 self.name
             )
         }
-        // Create new run
-        let mut r = Run::new(
-            self.total_runs + 1,
-            KipCompressOpts::new(
-                self.compress.enabled,
-                self.compress.alg,
-                self.compress.level,
-            ),
-        );
+        // Mark the job as running so 'kip abort' can
+        // reach it from another process
+        let control = KipRunControl::acquire(self.id)?;
+        // Resume the run a previous process didn't finish,
+        // otherwise create new run
+        let checkpoint = match KipCheckpoint::open(self.id)? {
+            Some(checkpoint) => {
+                println!(
+                    "{} resuming run {} of job '{}'.",
+                    "[INFO]".yellow(),
+                    checkpoint.run_id,
+                    self.name
+                );
+                checkpoint
+            }
+            None => KipCheckpoint::create(
+                self.id,
+                self.total_runs + 1,
+                KipCompressOpts::new(
+                    self.compress.enabled,
+                    self.compress.alg,
+                    self.compress.level,
+                ),
+            )?,
+        };
+        let checkpoint = Arc::new(checkpoint);
+        let mut r = Run::new(checkpoint.run_id, checkpoint.compress);
+        r.bytes_uploaded = checkpoint.bytes_uploaded;
         // Create Arc of current job to avoid
         // clones for each run
         let job_arc = Arc::new(self.clone());
@@ -112,19 +214,52 @@ impl Job {
         // Set provider env vars for backup
         self.set_provider_env_vars()?;
         // Tell the run to start uploading
-        match r.start(job_arc, secret.to_string(), follow_links).await {
+        match r
+            .start(
+                job_arc,
+                secret.to_string(),
+                opts,
+                control.signals(),
+                Arc::clone(&checkpoint),
+            )
+            .await
+        {
+            Ok(_) if r.status == KipStatus::ABORTED => {
+                // Reset provider env vars to nil
+                self.zeroize_provider_env_vars();
+                remove_checkpoint(checkpoint);
+                // Keep the files the run finished, but not their
+                // file states, so they're checked again next run
+                self.last_status = KipStatus::ABORTED;
+                self.replace_attempt(r.id.try_into()?);
+                self.bytes_amt_provider += r.bytes_uploaded;
+                self.runs.insert(r.id.try_into()?, r);
+                self.total_runs += 1;
+                self.last_run = Utc::now();
+                if self.first_run.format("%Y-%m-%d %H:%M:%S").to_string() == "1970-01-01 00:00:00" {
+                    self.first_run = Utc::now();
+                }
+                println!(
+                    "{} job '{}' upload to '{}' aborted.",
+                    "[INFO]".yellow(),
+                    &self.name,
+                    self.get_provider(),
+                );
+            }
             Ok(_) => {
                 // Reset provider env vars to nil
                 self.zeroize_provider_env_vars();
+                remove_checkpoint(checkpoint);
                 // Set job status equal to run's status
                 self.last_status = r.status;
+                // A failed attempt is replaced even if
+                // resuming it found nothing to upload
+                self.replace_attempt(r.id.try_into()?);
                 // Print all logs from run
                 if self.last_status != KipStatus::OK_SKIPPED {
                     self.bytes_amt_provider += r.bytes_uploaded;
                     // Get new file hashes
-                    self.get_file_hashes(follow_links).await?;
-                    // Add run to job only if anything was uploaded
-                    self.runs.insert(r.id.try_into()?, r);
+                    self.get_file_hashes(opts, &r.file_states).await?;
                     self.total_runs += 1;
                     self.last_run = Utc::now();
                     if self.first_run.format("%Y-%m-%d %H:%M:%S").to_string()
@@ -132,6 +267,22 @@ impl Job {
                     {
                         self.first_run = Utc::now();
                     }
+                    // Store the run's manifest with the provider so the
+                    // job can be recovered without local metadata
+                    if let Err(e) = self.upload_manifest(&r, secret).await {
+                        let log = format!(
+                            "[{}] {}-{} ⇉ unable to upload manifest: {e}.",
+                            Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                            self.name,
+                            r.id,
+                        );
+                        println!("{log}");
+                        r.logs.push(log);
+                        r.status = KipStatus::WARN;
+                        self.last_status = KipStatus::WARN;
+                    }
+                    // Add run to job only if anything was uploaded
+                    self.runs.insert(r.id.try_into()?, r);
                     println!(
                         "{} job '{}' completed uploading to '{}' successfully.",
                         "[OK]".green(),
@@ -139,12 +290,26 @@ impl Job {
                         self.get_provider(),
                     );
                 } else {
+                    // The latest run is still the job's backup, so its
+                    // manifest stays locked as long as its chunks
+                    if let Some(latest) = self.runs.values().last() {
+                        if let Err(e) = self.extend_manifest_lock(latest).await {
+                            println!(
+                                "{} unable to extend the lock of run {}'s manifest: {e}.",
+                                "[WARN]".yellow(),
+                                latest.id,
+                            );
+                        }
+                    }
                     println!("{} skipped, no file changes detected.", "[INFO]".yellow());
                 }
             }
             Err(e) => {
                 // Reset provider env vars to nil
                 self.zeroize_provider_env_vars();
+                // The checkpoint is kept so the next run resumes
+                // this one instead of uploading everything again
+                self.replace_attempt(r.id.try_into()?);
                 // Set job status equal to run's status
                 self.bytes_amt_provider += r.bytes_uploaded;
                 // Set job status
@@ -168,6 +333,18 @@ impl Job {
         Ok(())
     }
 
+    /// Removes an earlier failed attempt at a resumed run, and
+    /// what it added to the job's totals, before recording the
+    /// run again. The run's uploaded bytes include the attempt's.
+    fn replace_attempt(&mut self, run_id: usize) {
+        if let Some(attempt) = self.runs.remove(&run_id) {
+            self.total_runs = self.total_runs.saturating_sub(1);
+            self.bytes_amt_provider = self
+                .bytes_amt_provider
+                .saturating_sub(attempt.bytes_uploaded);
+        }
+    }
+
     /// Performs a restore on the run specified for a job
     pub async fn start_restore(&self, run: usize, secret: &str, output_folder: &str) -> Result<()> {
         // Get run from job
@@ -205,38 +382,397 @@ impl Job {
         Ok(())
     }
 
+    /// Encrypts the run's manifest and uploads it to the job's provider
+    async fn upload_manifest(&self, run: &Run, secret: &str) -> Result<()> {
+        let manifest = KipManifest::new(self, run).encrypt(secret)?;
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            self.provider
+                .upload_manifest(&client, self.id, run.id, &manifest)
+                .await
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        result?;
+        Ok(())
+    }
+
+    /// Extends the Object Lock of a run's manifest to the
+    /// retain-until date of objects uploaded now
+    async fn extend_manifest_lock(&self, run: &Run) -> Result<()> {
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            self.provider
+                .extend_manifest_lock(&client, self.id, run.id)
+                .await
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        result
+    }
+
+    /// Rebuilds jobs and their runs from the manifests stored
+    /// in a provider. Only manifests that decrypt with the secret
+    /// are returned, grouped by job. The name is only used to look
+    /// up the provider's credentials in the keyring.
+    pub async fn recover(name: &str, provider: KipProviders, secret: &str) -> Result<Vec<Job>> {
+        set_provider_env_vars(name, &provider)?;
+        let manifests = async {
+            let client = provider.get_client().await?;
+            let mut manifests = Vec::<KipManifest>::new();
+            for remote_path in provider.list_manifests(&client).await? {
+                let manifest_bytes = provider.download(&client, &remote_path).await?;
+                // Manifests of other jobs are encrypted with other secrets
+                match KipManifest::decrypt(&manifest_bytes, secret) {
+                    Ok(m) => manifests.push(m),
+                    Err(e) => debug!("skipping manifest {remote_path}: {e}"),
+                }
+            }
+            Ok::<_, anyhow::Error>(manifests)
+        }
+        .await;
+        // Reset provider env vars to nil
+        zeroize_provider_env_vars(&provider);
+        let mut manifests = manifests?;
+
+        // Replay the manifests in run order so the newest
+        // job metadata wins
+        manifests.sort_by_key(|m| m.run.id);
+        let mut jobs = BTreeMap::<Uuid, Job>::new();
+        for manifest in manifests {
+            let mut job = manifest.job;
+            if let Some(prev) = jobs.remove(&job.id) {
+                job.runs = prev.runs;
+                job.first_run = prev.first_run;
+            }
+            // The provider given may have been moved or renamed
+            job.provider = provider.clone();
+            job.last_status = manifest.run.status;
+            job.runs.insert(manifest.run.id.try_into()?, manifest.run);
+            jobs.insert(job.id, job);
+        }
+        Ok(jobs.into_values().collect())
+    }
+
+    /// Returns the job's files as of its latest run
+    pub fn latest_snapshot(&self) -> Vec<KipFileChunked> {
+        self.snapshot_at(usize::MAX)
+    }
+
+    /// Returns the job's files as they were at the run specified.
+    /// Runs from before snapshots were recorded only stored the
+    /// files that changed, so their deltas are layered on top of
+    /// the previous state instead. Chunks without recorded
+    /// compression get the compression of their run.
+    pub fn snapshot_at(&self, run: usize) -> Vec<KipFileChunked> {
+        let mut files = BTreeMap::<PathBuf, KipFileChunked>::new();
+        for (_, r) in self.runs.range(..=run) {
+            let entry = |kfc: &KipFileChunked| {
+                let mut kfc = kfc.clone();
+                kfc.set_default_compress(r.compress);
+                (kfc.file.path.clone(), kfc)
+            };
+            if !r.snapshot.is_empty() {
+                files.clear();
+                files.extend(r.snapshot.iter().map(entry));
+            } else {
+                files.extend(r.delta.iter().map(entry));
+            }
+        }
+        files.into_values().collect()
+    }
+
     #[instrument]
-    pub async fn purge_file(&mut self, f: &str) -> Result<()> {
+    pub async fn purge_file(&mut self, f: &str, follow_links: bool) -> Result<()> {
         // Find all the runs that contain this file's chunks
         // and remove them from S3.
-        let fpath = Path::new(&f).canonicalize()?;
+        let fpath = resolve_entry(f, follow_links)?;
         self.set_provider_env_vars()?;
 
         // Create job's provider client
         let client = self.provider.get_client().await?;
 
+        // Chunks are deduplicated across files, so only delete
+        // the chunks no other file references
+        let shared_chunks: HashSet<&str> = self
+            .runs
+            .values()
+            .flat_map(|r| r.delta.iter().chain(r.snapshot.iter()))
+            .filter(|kfc| kfc.file.path != fpath)
+            .flat_map(|kfc| kfc.chunks.iter())
+            .map(|c| c.hash.as_str())
+            .collect();
+        let mut deleted = HashSet::<&str>::new();
+        let mut deleted_paths = Vec::<&str>::new();
         for run in self.runs.iter() {
-            for kfc in run.1.delta.iter() {
+            for kfc in run.1.delta.iter().chain(run.1.snapshot.iter()) {
                 if kfc.file.path == fpath {
                     // Convert chunks into async stream
-                    let mut chunks_stream = tokio_stream::iter(kfc.chunks.values());
+                    let mut chunks_stream = tokio_stream::iter(kfc.chunks.iter());
                     // Delete each chunk from provider
                     while let Some(chunk) = chunks_stream.next().await {
+                        if shared_chunks.contains(chunk.hash.as_str())
+                            || !deleted.insert(chunk.hash.as_str())
+                        {
+                            continue;
+                        }
                         self.provider.delete(&client, &chunk.remote_path).await?;
+                        deleted_paths.push(chunk.remote_path.as_str());
                     }
                 }
             }
         }
+        update_index(self.id, |index| {
+            for remote_path in deleted_paths {
+                index.remove(remote_path);
+            }
+        })?;
 
         // Reset provider env vars to nil
         self.zeroize_provider_env_vars();
-        // Set job metadata
-        self.bytes_amt_provider -= fpath.metadata()?.len();
+        // The file's chunks are gone, so no run can restore it
+        for r in self.runs.values_mut() {
+            r.delta.retain(|kfc| kfc.file.path != fpath);
+            r.snapshot.retain(|kfc| kfc.file.path != fpath);
+        }
+        // Set job metadata. Links have no chunks.
+        if !fpath.symlink_metadata()?.file_type().is_symlink() {
+            self.bytes_amt_provider = self
+                .bytes_amt_provider
+                .saturating_sub(fpath.metadata()?.len());
+        }
         Ok(())
     }
 
-    pub fn abort(&mut self) {
-        unimplemented!();
+    /// Returns the IDs of the runs the job's retention
+    /// policy doesn't keep.
+    pub fn runs_to_forget(&self) -> Result<Vec<usize>> {
+        if self.retention.is_empty() {
+            bail!(
+                "no retention policy set. Please run 'kip retention {}' to set one.",
+                self.name
+            )
+        }
+        let keep = self.retention.runs_to_keep(&self.runs, Utc::now())?;
+        Ok(self
+            .runs
+            .keys()
+            .filter(|id| !keep.contains(id))
+            .copied()
+            .collect())
+    }
+
+    /// Removes the runs the job's retention policy doesn't keep,
+    /// along with their manifests. Their chunks are left for
+    /// 'kip prune' since other runs may still reference them.
+    #[instrument]
+    pub async fn forget(&mut self) -> Result<Vec<usize>> {
+        let forget = self.runs_to_forget()?;
+        if forget.is_empty() {
+            return Ok(forget);
+        }
+        // A run in progress would save the forgotten runs
+        // back, so the job can't be running meanwhile
+        let _control = KipRunControl::acquire(self.id)?;
+
+        // Runs from before snapshots were recorded rely on earlier
+        // runs' deltas, so store their full snapshot before any
+        // earlier run is removed
+        let legacy = self
+            .runs
+            .iter()
+            .filter(|(id, r)| r.snapshot.is_empty() && !forget.contains(id))
+            .map(|(id, _)| (*id, self.snapshot_at(*id)))
+            .collect::<Vec<(usize, Vec<KipFileChunked>)>>();
+        for (id, snapshot) in legacy {
+            if let Some(r) = self.runs.get_mut(&id) {
+                r.snapshot = snapshot;
+            }
+        }
+
+        // Delete the forgotten runs' manifests so
+        // 'kip recover' doesn't bring them back
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            for id in forget.iter() {
+                self.provider
+                    .delete_manifest(&client, self.id, (*id).try_into()?)
+                    .await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        result?;
+
+        for id in forget.iter() {
+            if let Some(r) = self.runs.remove(id) {
+                self.pending_prune.extend(
+                    r.delta
+                        .iter()
+                        .chain(r.snapshot.iter())
+                        .flat_map(|kfc| kfc.chunks.iter())
+                        .map(|c| c.remote_path.clone()),
+                );
+            }
+        }
+        Ok(forget)
+    }
+
+    /// Deletes the chunks of forgotten runs that no remaining
+    /// run references. Chunks under an S3 Object Lock that hasn't
+    /// expired are kept until a later prune. Returns the amount
+    /// of chunks deleted.
+    #[instrument]
+    pub async fn prune(&mut self) -> Result<usize> {
+        // A run in progress may reuse the chunks being deleted
+        let _control = KipRunControl::acquire(self.id)?;
+        let referenced = self
+            .runs
+            .values()
+            .flat_map(|r| r.delta.iter().chain(r.snapshot.iter()))
+            .flat_map(|kfc| kfc.chunks.iter())
+            .map(|c| c.remote_path.as_str())
+            .collect::<HashSet<&str>>();
+        // An interrupted run skips the chunks it already
+        // uploaded once it's resumed, so they're kept too
+        let checkpointed = match KipCheckpoint::open(self.id)? {
+            Some(checkpoint) => checkpoint
+                .chunks
+                .iter()
+                .cloned()
+                .chain(
+                    checkpoint
+                        .files
+                        .values()
+                        .flat_map(|(kfc, _)| kfc.chunks.iter())
+                        .map(|c| c.hash.clone()),
+                )
+                .collect::<HashSet<String>>(),
+            None => HashSet::new(),
+        };
+        // Chunks still referenced by a run are no longer pending
+        let unreferenced = self
+            .pending_prune
+            .iter()
+            .filter(|remote_path| !referenced.contains(remote_path.as_str()))
+            .filter(|remote_path| !checkpointed.contains(chunk_hash(remote_path)))
+            .cloned()
+            .collect::<Vec<String>>();
+        if unreferenced.is_empty() {
+            self.pending_prune
+                .retain(|remote_path| !referenced.contains(remote_path.as_str()));
+            return Ok(0);
+        }
+
+        self.set_provider_env_vars()?;
+        let mut deleted = BTreeSet::<String>::new();
+        let result = async {
+            let client = self.provider.get_client().await?;
+            for remote_path in unreferenced {
+                if self.provider.prune_chunk(&client, &remote_path).await? {
+                    deleted.insert(remote_path);
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        update_index(self.id, |index| {
+            for remote_path in deleted.iter() {
+                index.remove(remote_path);
+            }
+        })?;
+        // Chunks that failed to delete or are still
+        // locked are retried next prune
+        let pruned = deleted.len();
+        self.pending_prune.retain(|remote_path| {
+            !referenced.contains(remote_path.as_str()) && !deleted.contains(remote_path)
+        });
+        result?;
+        Ok(pruned)
+    }
+
+    /// Checks that every chunk the job's runs reference is
+    /// stored in its provider, optionally reading a percentage
+    /// of them back to verify their contents.
+    pub async fn check(&self, secret: &str, read_percent: Option<f64>) -> Result<KipCheckReport> {
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            check_chunks(self, &client, secret, read_percent).await
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        result
+    }
+
+    /// Recreates the job's local chunk index from its provider's
+    /// listing, replacing any index already built.
+    pub async fn rebuild_index(&self) -> Result<KipChunkIndex> {
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            KipChunkIndex::rebuild(&self.provider, &client, self.id).await
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        result
+    }
+
+    /// Re-uploads missing and corrupt chunks from the job's local
+    /// files. Files whose chunks can't be re-uploaded are marked as
+    /// damaged in every run so restores skip them.
+    pub async fn repair(
+        &mut self,
+        secret: &str,
+        read_percent: Option<f64>,
+    ) -> Result<KipRepairReport> {
+        let check = self.check(secret, read_percent).await?;
+        let bad = check
+            .missing
+            .into_iter()
+            .chain(check.corrupt)
+            .collect::<BTreeSet<String>>();
+        self.set_provider_env_vars()?;
+        let result = async {
+            let client = self.provider.get_client().await?;
+            repair_chunks(self, &client, secret, &bad).await
+        }
+        .await;
+        // Reset provider env vars to nil
+        self.zeroize_provider_env_vars();
+        let repaired = result?;
+
+        // Files are only damaged while any of their chunks are
+        let mut damaged = BTreeSet::<PathBuf>::new();
+        for r in self.runs.values_mut() {
+            for kfc in r.delta.iter_mut().chain(r.snapshot.iter_mut()) {
+                kfc.damaged = kfc
+                    .chunks
+                    .iter()
+                    .any(|c| bad.contains(&c.remote_path) && !repaired.contains(&c.remote_path));
+                if kfc.damaged {
+                    damaged.insert(kfc.file.path.clone());
+                }
+            }
+        }
+        Ok(KipRepairReport { repaired, damaged })
+    }
+
+    /// Asks the kip process running this job, if any, to abort
+    /// its run. Returns false if the job isn't running.
+    pub fn abort(&self) -> Result<bool> {
+        request_abort(self.id)
     }
 
     /// Get correct number of files in job (not just...
@@ -251,7 +787,7 @@ impl Job {
                     }
                     correct_files_num += 1;
                 }
-            } else if f.path.exists() {
+            } else if f.path.exists() || f.is_link(follow_links) {
                 correct_files_num += 1;
             }
         }
@@ -259,28 +795,48 @@ impl Job {
         Ok(())
     }
 
-    /// Read each file in the job and store their SHA256 hashes
-    async fn get_file_hashes(&mut self, follow_links: bool) -> Result<()> {
+    /// Store the SHA256 hashes of each file in the job. Files read
+    /// during the run, or whose stat data is unchanged, aren't read again.
+    async fn get_file_hashes(
+        &mut self,
+        opts: KipRunOpts,
+        run_states: &BTreeMap<PathBuf, KipFileState>,
+    ) -> Result<()> {
+        let prev_states = std::mem::take(&mut self.file_states);
+        let mut file_states = BTreeMap::<PathBuf, KipFileState>::new();
         for kf in self.files.iter_mut() {
+            // Links are tracked by the path they point to
+            if kf.is_link(opts.follow_links) {
+                let target = std::fs::read_link(&kf.path)?;
+                kf.set_hash(hex_digest(
+                    Algorithm::SHA256,
+                    target.to_string_lossy().as_bytes(),
+                ));
+                continue;
+            }
             // Set File Hash
             if kf.is_file()? {
-                let file = open_file(&kf.path, kf.len.try_into()?).await?;
-                let hash = hex_digest(Algorithm::SHA256, &file);
-                kf.set_hash(hash);
+                let state = file_state(&kf.path, &prev_states, run_states, opts).await?;
+                kf.set_hash(state.hash.clone());
+                file_states.insert(kf.path.clone(), state);
             } else {
                 // Set Directory Hash
                 let mut dir_hash_str = String::new();
-                for entry in WalkDir::new(&kf.path).follow_links(follow_links) {
+                for entry in WalkDir::new(&kf.path)
+                    .follow_links(opts.follow_links)
+                    .sort_by_file_name()
+                {
                     let entry = entry?;
                     if entry.metadata()?.is_dir() {
                         continue;
                     }
-                    let f = open_file(entry.path(), entry.metadata()?.len()).await?;
-                    let hash = hex_digest(Algorithm::SHA1, &f);
+                    // Track each of the dir's files on its own
+                    let state = file_state(entry.path(), &prev_states, run_states, opts).await?;
                     // Push the subfile's path (if renamed, moved)
                     dir_hash_str.push_str(&entry.path().display().to_string());
                     // Push the subfile's hash (if updated, changed)
-                    dir_hash_str.push_str(&hash);
+                    dir_hash_str.push_str(&state.hash);
+                    file_states.insert(entry.path().to_path_buf(), state);
                 }
                 // Hash the colletion of dir's files and their hashes
                 let dir_hash = hex_digest(Algorithm::SHA256, dir_hash_str.as_bytes());
@@ -288,39 +844,12 @@ impl Job {
                 kf.set_hash(dir_hash);
             }
         }
+        self.file_states = file_states;
         Ok(())
     }
 
     fn set_provider_env_vars(&self) -> Result<()> {
-        match &self.provider {
-            KipProviders::S3(s3) => {
-                let s3acc = keyring_get_secret(&format!("com.ciehanski.kip.{}.s3acc", self.name))
-                    .context("couldnt get s3acc from keyring")?;
-                let s3acc = s3acc.trim_end();
-                let s3sec = keyring_get_secret(&format!("com.ciehanski.kip.{}.s3sec", self.name))
-                    .context("couldn't get s3sec from keyring")?;
-                let s3sec = s3sec.trim_end();
-                // Set AWS env vars to user's keys
-                env::set_var("AWS_ACCESS_KEY_ID", s3acc);
-                env::set_var("AWS_SECRET_ACCESS_KEY", s3sec);
-                env::set_var("AWS_REGION", &s3.aws_region);
-            }
-            KipProviders::Gdrive(_) => {
-                let gdrive_id =
-                    keyring_get_secret(&format!("com.ciehanski.kip.{}.gdriveid", self.name))
-                        .context("couldnt get gdriveid from keyring")?;
-                let gdrive_id = gdrive_id.trim_end();
-                let gdrive_sec =
-                    keyring_get_secret(&format!("com.ciehanski.kip.{}.gdrivesec", self.name))
-                        .context("couldn't get gdrivesec from keyring")?;
-                let gdrive_sec = gdrive_sec.trim_end();
-                // Set AWS env vars to user's keys
-                env::set_var("GOOGLE_DRIVE_CLIENT_ID", gdrive_id);
-                env::set_var("GOOGLE_DRIVE_CLIENT_SECRET", gdrive_sec);
-            }
-            _ => {}
-        }
-        Ok(())
+        set_provider_env_vars(&self.name, &self.provider)
     }
 
     pub fn delete_keyring_entries(&self) -> Result<()> {
@@ -339,6 +868,22 @@ impl Job {
                 keyring_delete_secret(&format!("com.ciehanski.kip.{}.gdrivesec", self.name))
                     .context("couldn't delete Gdrive secret key from keyring")?;
             }
+            KipProviders::Sftp(_) => {
+                keyring_delete_secret(&format!("com.ciehanski.kip.{}.sftpsec", self.name))
+                    .context("couldn't delete SFTP secret from keyring")?;
+            }
+            KipProviders::Smb(_) => {
+                keyring_delete_secret(&format!("com.ciehanski.kip.{}.smbsec", self.name))
+                    .context("couldn't delete SMB password from keyring")?;
+            }
+            KipProviders::Azure(_) => {
+                keyring_delete_secret(&format!("com.ciehanski.kip.{}.azuresec", self.name))
+                    .context("couldn't delete Azure secret from keyring")?;
+            }
+            KipProviders::WebDav(_) => {
+                keyring_delete_secret(&format!("com.ciehanski.kip.{}.webdavsec", self.name))
+                    .context("couldn't delete WebDAV secret from keyring")?;
+            }
             _ => {}
         }
         Ok(())
@@ -346,18 +891,7 @@ impl Job {
 
     /// Reset provider env vars to nil
     pub fn zeroize_provider_env_vars(&self) {
-        match &self.provider {
-            KipProviders::S3(_) => {
-                env::set_var("AWS_ACCESS_KEY_ID", "");
-                env::set_var("AWS_SECRET_ACCESS_KEY", "");
-                env::set_var("AWS_REGION", "");
-            }
-            KipProviders::Gdrive(_) => {
-                env::set_var("GOOGLE_DRIVE_CLIENT_ID", "");
-                env::set_var("GOOGLE_DRIVE_CLIENT_SECRET", "");
-            }
-            _ => {}
-        }
+        zeroize_provider_env_vars(&self.provider)
     }
 
     fn get_provider(&self) -> String {
@@ -371,7 +905,128 @@ impl Job {
                     "My Drive/".to_string()
                 }
             }
+            KipProviders::Local(local) => local.root_path.display().to_string(),
+            KipProviders::Sftp(sftp) => format!("{}:{}", sftp.host, sftp.root_path),
+            KipProviders::Smb(smb) => smb.location(),
+            KipProviders::Azure(azure) => format!("{}/{}", azure.account, azure.container),
+            KipProviders::WebDav(webdav) => webdav.url.clone(),
+        }
+    }
+}
+
+/// Returns a file's current state, only reading and hashing the
+/// file if neither the run nor the job have an up to date state
+async fn file_state(
+    path: &Path,
+    prev_states: &BTreeMap<PathBuf, KipFileState>,
+    run_states: &BTreeMap<PathBuf, KipFileState>,
+    opts: KipRunOpts,
+) -> Result<KipFileState> {
+    let md = path.metadata()?;
+    if let Some(state) = run_states.get(path).filter(|s| s.matches(&md)) {
+        return Ok(state.clone());
+    }
+    if let Some(state) = prev_states
+        .get(path)
+        .filter(|s| !opts.force_rehash && s.matches(&md))
+    {
+        return Ok(state.clone());
+    }
+    KipFileState::new(&md, hash_file(path).await?)
+}
+
+/// Loads a job's provider credentials from the keyring into env vars
+fn set_provider_env_vars(job_name: &str, provider: &KipProviders) -> Result<()> {
+    match provider {
+        KipProviders::S3(s3) => {
+            let s3acc = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.s3acc"))
+                .context("couldnt get s3acc from keyring")?;
+            let s3acc = s3acc.trim_end();
+            let s3sec = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.s3sec"))
+                .context("couldn't get s3sec from keyring")?;
+            let s3sec = s3sec.trim_end();
+            // Set AWS env vars to user's keys
+            env::set_var("AWS_ACCESS_KEY_ID", s3acc);
+            env::set_var("AWS_SECRET_ACCESS_KEY", s3sec);
+            env::set_var("AWS_REGION", &s3.aws_region);
+        }
+        KipProviders::Gdrive(_) => {
+            let gdrive_id = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.gdriveid"))
+                .context("couldnt get gdriveid from keyring")?;
+            let gdrive_id = gdrive_id.trim_end();
+            let gdrive_sec = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.gdrivesec"))
+                .context("couldn't get gdrivesec from keyring")?;
+            let gdrive_sec = gdrive_sec.trim_end();
+            // Set AWS env vars to user's keys
+            env::set_var("GOOGLE_DRIVE_CLIENT_ID", gdrive_id);
+            env::set_var("GOOGLE_DRIVE_CLIENT_SECRET", gdrive_sec);
+        }
+        KipProviders::Sftp(_) => {
+            let sftp_sec = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.sftpsec"))
+                .context("couldn't get sftpsec from keyring")?;
+            // Set the password or private key passphrase
+            env::set_var(SFTP_SECRET_VAR, sftp_sec.trim_end());
+        }
+        KipProviders::Smb(_) => {
+            let smb_sec = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.smbsec"))
+                .context("couldn't get smbsec from keyring")?;
+            env::set_var(SMB_SECRET_VAR, smb_sec.trim_end());
+        }
+        KipProviders::Azure(_) => {
+            let azure_sec = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.azuresec"))
+                .context("couldn't get azuresec from keyring")?;
+            // Set the shared key or SAS token
+            env::set_var(AZURE_SECRET_VAR, azure_sec.trim_end());
+        }
+        KipProviders::WebDav(_) => {
+            let webdav_sec = keyring_get_secret(&format!("com.ciehanski.kip.{job_name}.webdavsec"))
+                .context("couldn't get webdavsec from keyring")?;
+            // Set the password or bearer token
+            env::set_var(WEBDAV_SECRET_VAR, webdav_sec.trim_end());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Reset provider env vars to nil
+fn zeroize_provider_env_vars(provider: &KipProviders) {
+    match provider {
+        KipProviders::S3(_) => {
+            env::set_var("AWS_ACCESS_KEY_ID", "");
+            env::set_var("AWS_SECRET_ACCESS_KEY", "");
+            env::set_var("AWS_REGION", "");
+        }
+        KipProviders::Gdrive(_) => {
+            env::set_var("GOOGLE_DRIVE_CLIENT_ID", "");
+            env::set_var("GOOGLE_DRIVE_CLIENT_SECRET", "");
+        }
+        KipProviders::Sftp(_) => {
+            env::set_var(SFTP_SECRET_VAR, "");
+        }
+        KipProviders::Smb(_) => {
+            env::set_var(SMB_SECRET_VAR, "");
+        }
+        KipProviders::Azure(_) => {
+            env::set_var(AZURE_SECRET_VAR, "");
+        }
+        KipProviders::WebDav(_) => {
+            env::set_var(WEBDAV_SECRET_VAR, "");
+        }
+        _ => {}
+    }
+}
+
+/// Discards a finished run's checkpoint. A checkpoint left
+/// behind only makes the next run resume this one.
+fn remove_checkpoint(checkpoint: Arc<KipCheckpoint>) {
+    match Arc::try_unwrap(checkpoint) {
+        Ok(checkpoint) => {
+            if let Err(e) = checkpoint.remove() {
+                warn!("unable to remove run checkpoint: {e}");
+            }
         }
+        Err(_) => warn!("run checkpoint still in use"),
     }
 }
 
@@ -384,6 +1039,8 @@ pub enum KipStatus {
     WARN,
     IN_PROGRESS,
     NEVER_RUN,
+    ABORTED,
+    INTERRUPTED,
 }
 
 impl Display for KipStatus {
@@ -395,6 +1052,8 @@ impl Display for KipStatus {
             KipStatus::WARN => write!(f, "{}", "WARN".yellow()),
             KipStatus::IN_PROGRESS => write!(f, "{}", "IN_PROGRESS".cyan()),
             KipStatus::NEVER_RUN => write!(f, "{}", "NEVER_RUN".bold()),
+            KipStatus::ABORTED => write!(f, "{}", "ABORTED".yellow()),
+            KipStatus::INTERRUPTED => write!(f, "{}", "INTERRUPTED".yellow()),
         }
     }
 }
@@ -405,12 +1064,21 @@ pub struct KipFile {
     pub path: PathBuf,
     pub hash: String,
     pub len: usize,
+    /// Permissions, ownership, timestamps and the
+    /// like, restored along with the file's contents
+    #[serde(default)]
+    pub meta: KipFileMeta,
 }
 
 impl KipFile {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        // Get len at time of creation
-        let len: usize = path.as_ref().metadata()?.len().try_into()?;
+        // Get len at time of creation. Symlinks that
+        // point nowhere are backed up as links.
+        let md = match path.as_ref().metadata() {
+            Ok(md) => md,
+            Err(_) => path.as_ref().symlink_metadata()?,
+        };
+        let len: usize = md.len().try_into()?;
         Ok(KipFile {
             name: path
                 .as_ref()
@@ -421,6 +1089,7 @@ impl KipFile {
             path: path.as_ref().to_path_buf(),
             hash: String::new(),
             len,
+            meta: KipFileMeta::default(),
         })
     }
 
@@ -446,13 +1115,107 @@ impl KipFile {
     pub fn is_file(&self) -> Result<bool> {
         Ok(self.path.metadata()?.is_file())
     }
+
+    /// Whether the entry is a symlink backed
+    /// up as a link rather than its target
+    pub fn is_link(&self, follow_links: bool) -> bool {
+        !follow_links
+            && self
+                .path
+                .symlink_metadata()
+                .map(|md| md.file_type().is_symlink())
+                .unwrap_or(false)
+    }
+}
+
+/// Returns the hash a chunk is named by from its remote path
+fn chunk_hash(remote_path: &str) -> &str {
+    let name = remote_path.rsplit('/').next().unwrap_or(remote_path);
+    name.strip_suffix(".chunk").unwrap_or(name)
+}
+
+/// Resolves a path being added to a job. Unless links are
+/// followed, a symlink keeps its own path so it's backed up
+/// as a link rather than as the file it points to.
+pub fn resolve_entry<P: AsRef<Path>>(path: P, follow_links: bool) -> Result<PathBuf> {
+    let path = path.as_ref();
+    if follow_links || !path.symlink_metadata()?.file_type().is_symlink() {
+        return Ok(path.canonicalize()?);
+    }
+    let name = path.file_name().context("symlink has no file name")?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+        _ => env::current_dir()?,
+    };
+    Ok(parent.join(name))
+}
+
+/// The state of a file when it was last backed up
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KipFileState {
+    pub len: u64,
+    pub mtime: DateTime<Utc>,
+    #[serde(default)]
+    pub ctime: Option<DateTime<Utc>>,
+    pub inode: u64,
+    pub hash: String,
+}
+
+impl KipFileState {
+    pub fn new<S: Into<String>>(md: &Metadata, hash: S) -> Result<Self> {
+        Ok(Self {
+            len: md.len(),
+            mtime: DateTime::<Utc>::from(md.modified()?),
+            ctime: ctime(md),
+            inode: inode(md),
+            hash: hash.into(),
+        })
+    }
+
+    /// Checks if a file's stat data is the same as when
+    /// this state was recorded
+    pub fn matches(&self, md: &Metadata) -> bool {
+        let mtime = match md.modified() {
+            Ok(mt) => DateTime::<Utc>::from(mt),
+            Err(_) => return false,
+        };
+        self.len == md.len()
+            && self.mtime == mtime
+            && self.ctime == ctime(md)
+            && self.inode == inode(md)
+    }
+}
+
+#[cfg(unix)]
+fn ctime(md: &Metadata) -> Option<DateTime<Utc>> {
+    use std::os::unix::fs::MetadataExt;
+    Utc.timestamp_opt(md.ctime(), md.ctime_nsec().try_into().ok()?)
+        .single()
+}
+
+#[cfg(not(unix))]
+fn ctime(_md: &Metadata) -> Option<DateTime<Utc>> {
+    None
+}
+
+#[cfg(unix)]
+fn inode(md: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    md.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_md: &Metadata) -> u64 {
+    0
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunk::FileChunk;
     use crate::compress::{KipCompressAlg, KipCompressLevel, KipCompressOpts};
-    use crate::providers::s3::KipS3;
+    use crate::providers::s3::{KipObjectLock, KipObjectLockMode, KipS3};
+    use crate::providers::usb::KipUsb;
     use aws_sdk_s3::config::Region;
 
     #[test]
@@ -489,6 +1252,268 @@ mod tests {
         assert_eq!(j.files_amt, 4)
     }
 
+    #[test]
+    fn test_snapshot_at() {
+        let provider = KipProviders::S3(KipS3::new("test1", Region::new("us-east-1".to_owned())));
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        // Runs without snapshots only recorded their deltas
+        let mut r1 = Run::new(1, compress);
+        r1.delta.push(KipFileChunked::new("/a.txt", "a1", 1));
+        r1.delta.push(KipFileChunked::new("/b.txt", "b1", 1));
+        let mut r2 = Run::new(2, compress);
+        r2.delta.push(KipFileChunked::new("/a.txt", "a2", 1));
+        let mut r3 = Run::new(3, compress);
+        r3.snapshot.push(KipFileChunked::new("/b.txt", "b1", 1));
+        j.runs.insert(1, r1);
+        j.runs.insert(2, r2);
+        j.runs.insert(3, r3);
+        let hashes = |files: Vec<KipFileChunked>| {
+            files
+                .into_iter()
+                .map(|kfc| kfc.file.hash)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(hashes(j.snapshot_at(1)), vec!["a1", "b1"]);
+        assert_eq!(hashes(j.snapshot_at(2)), vec!["a2", "b1"]);
+        assert_eq!(hashes(j.snapshot_at(3)), vec!["b1"]);
+        assert_eq!(hashes(j.latest_snapshot()), vec!["b1"]);
+    }
+
+    #[test]
+    fn test_snapshot_at_compress() {
+        let provider = KipProviders::S3(KipS3::new("test1", Region::new("us-east-1".to_owned())));
+        let zstd = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let gzip = KipCompressOpts::new(true, KipCompressAlg::Gzip, KipCompressLevel::Fastest);
+        let mut j = Job::new("testing1", provider, gzip);
+        // A chunk stored before compression was recorded
+        // per chunk, reused after the settings changed
+        let mut kfc = KipFileChunked::new("/a.txt", "a1", 1);
+        kfc.add_chunk(FileChunk::new("/a.txt", "abc123", 0, 1, 1));
+        let mut r1 = Run::new(1, zstd);
+        r1.snapshot.push(kfc);
+        j.runs.insert(1, r1);
+        let mut r2 = Run::new(2, gzip);
+        let reused = j.latest_snapshot().remove(0);
+        let mut new_chunk = FileChunk::new("/b.txt", "def456", 0, 1, 1);
+        new_chunk.set_compress(gzip);
+        let mut b = KipFileChunked::new("/b.txt", "b1", 1);
+        b.add_chunk(new_chunk);
+        r2.snapshot.push(reused);
+        r2.snapshot.push(b);
+        j.runs.insert(2, r2);
+
+        let compress = |files: Vec<KipFileChunked>| {
+            files
+                .into_iter()
+                .flat_map(|kfc| kfc.chunks)
+                .map(|c| c.compress)
+                .collect::<Vec<Option<KipCompressOpts>>>()
+        };
+        assert_eq!(compress(j.snapshot_at(1)), vec![Some(zstd)]);
+        assert_eq!(compress(j.snapshot_at(2)), vec![Some(zstd), Some(gzip)]);
+        // Chunks from older versions deserialize without it
+        let chunk: FileChunk = serde_json::from_str(
+            r#"{"local_path":"/a.txt","remote_path":"","hash":"abc123","offset":0,"length":1,"end":1}"#,
+        )
+        .unwrap();
+        assert_eq!(chunk.compress, None);
+    }
+
+    #[test]
+    fn test_sync_object_lock() {
+        let mut s3 = KipS3::new("test1", Region::new("us-east-1".to_owned()));
+        s3.object_lock = Some(KipObjectLock {
+            mode: KipObjectLockMode::Governance,
+            days: 0,
+        });
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", KipProviders::S3(s3), compress);
+        let lock_days = |j: &Job| match &j.provider {
+            KipProviders::S3(s3) => s3.object_lock.map(|lock| lock.days),
+            _ => None,
+        };
+        // Without a time-based rule, objects get the default lock
+        j.sync_object_lock().unwrap();
+        assert_eq!(lock_days(&j), Some(DEFAULT_OBJECT_LOCK_DAYS));
+        j.retention.keep_weekly = Some(8);
+        j.sync_object_lock().unwrap();
+        assert_eq!(lock_days(&j), Some(56));
+    }
+
+    #[tokio::test]
+    async fn test_forget_and_prune() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", tmp_dir.path(), 0, 0));
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        let jid = j.id;
+        let chunk_dir = tmp_dir.path().join(format!("{jid}/chunks"));
+        std::fs::create_dir_all(&chunk_dir).unwrap();
+        let kfc = |path: &str, hashes: &[&str]| {
+            let mut kfc = KipFileChunked::new(path, path, 1);
+            for h in hashes {
+                std::fs::write(chunk_dir.join(format!("{h}.chunk")), h).unwrap();
+                let mut c = FileChunk::new(path, *h, 0, 1, 1);
+                c.set_remote_path(format!("{jid}/chunks/{h}.chunk"));
+                kfc.add_chunk(c);
+            }
+            kfc
+        };
+        // Runs from before snapshots were recorded
+        let mut r1 = Run::new(1, compress);
+        r1.delta.push(kfc("/a.txt", &["shared", "old"]));
+        let mut r2 = Run::new(2, compress);
+        r2.delta.push(kfc("/b.txt", &["shared"]));
+        j.runs.insert(1, r1);
+        j.runs.insert(2, r2);
+        // Nothing is forgotten without a policy
+        assert!(j.runs_to_forget().is_err());
+        j.retention.keep_last = Some(1);
+        assert_eq!(j.forget().await.unwrap(), vec![1]);
+        assert_eq!(j.runs.keys().copied().collect::<Vec<usize>>(), vec![2]);
+        // The remaining run still restores the first run's file,
+        // so none of its chunks can be pruned
+        assert_eq!(j.runs[&2].snapshot.len(), 2);
+        assert_eq!(j.prune().await.unwrap(), 0);
+        assert!(chunk_dir.join("old.chunk").exists());
+        // Once no run has the file, its chunks are pruned
+        let mut r3 = Run::new(3, compress);
+        r3.snapshot.push(kfc("/b.txt", &["shared"]));
+        j.runs.insert(3, r3);
+        assert_eq!(j.forget().await.unwrap(), vec![2]);
+        assert_eq!(j.prune().await.unwrap(), 1);
+        assert!(!chunk_dir.join("old.chunk").exists());
+        assert!(chunk_dir.join("shared.chunk").exists());
+        assert!(j.pending_prune.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prune_running_and_checkpointed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", tmp_dir.path(), 0, 0));
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        let jid = j.id;
+        let chunk_dir = tmp_dir.path().join(format!("{jid}/chunks"));
+        std::fs::create_dir_all(&chunk_dir).unwrap();
+        for h in ["old", "resumed"] {
+            std::fs::write(chunk_dir.join(format!("{h}.chunk")), h).unwrap();
+            j.pending_prune.insert(format!("{jid}/chunks/{h}.chunk"));
+        }
+        j.runs.insert(1, Run::new(1, compress));
+        j.runs.insert(2, Run::new(2, compress));
+        j.retention.keep_last = Some(1);
+        // Nothing is forgotten or pruned while the job is running
+        let control = KipRunControl::acquire(jid).unwrap();
+        assert!(j.forget().await.is_err());
+        assert_eq!(j.runs.len(), 2);
+        assert!(j.prune().await.is_err());
+        drop(control);
+        // Chunks an interrupted run uploaded are kept
+        // until it's resumed
+        let checkpoint = KipCheckpoint::create(jid, 1, compress).unwrap();
+        checkpoint.record_chunk("resumed", 1).unwrap();
+        assert_eq!(j.prune().await.unwrap(), 1);
+        checkpoint.remove().unwrap();
+        assert!(!chunk_dir.join("old.chunk").exists());
+        assert!(chunk_dir.join("resumed.chunk").exists());
+        assert_eq!(j.pending_prune.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_file_snapshot() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", tmp_dir.path(), 0, 0));
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        j.bytes_amt_provider = 100;
+        let jid = j.id;
+        let chunk_dir = tmp_dir.path().join(format!("{jid}/chunks"));
+        std::fs::create_dir_all(&chunk_dir).unwrap();
+        let file_dir = tmp_dir.path().join("files");
+        std::fs::create_dir_all(&file_dir).unwrap();
+        let kfc = |name: &str, hashes: &[&str]| {
+            let path = file_dir.join(name);
+            std::fs::write(&path, "k").unwrap();
+            let path = path.canonicalize().unwrap();
+            let mut kfc = KipFileChunked::new(&path, name, 1);
+            for h in hashes {
+                std::fs::write(chunk_dir.join(format!("{h}.chunk")), h).unwrap();
+                let mut c = FileChunk::new(&path, *h, 0, 1, 1);
+                c.set_remote_path(format!("{jid}/chunks/{h}.chunk"));
+                kfc.add_chunk(c);
+            }
+            kfc
+        };
+        // Files carried over from an earlier run are only
+        // in the run's snapshot, not its delta
+        let mut r1 = Run::new(1, compress);
+        r1.snapshot.push(kfc("a.txt", &["a_only", "shared"]));
+        r1.snapshot.push(kfc("b.txt", &["shared"]));
+        j.runs.insert(1, r1);
+        j.purge_file(&file_dir.join("a.txt").display().to_string(), false)
+            .await
+            .unwrap();
+        assert!(!chunk_dir.join("a_only.chunk").exists());
+        assert!(chunk_dir.join("shared.chunk").exists());
+        assert_eq!(j.runs[&1].snapshot.len(), 1);
+        assert_eq!(j.bytes_amt_provider, 99);
+        // Runs from before snapshots were recorded forget it too
+        let mut r2 = Run::new(2, compress);
+        r2.delta.push(kfc("c.txt", &["c_only"]));
+        j.runs.insert(2, r2);
+        j.bytes_amt_provider = 0;
+        j.purge_file(&file_dir.join("c.txt").display().to_string(), false)
+            .await
+            .unwrap();
+        assert!(j.runs[&2].delta.is_empty());
+        assert!(j.snapshot_at(2).iter().all(|kfc| kfc.file.name != "c.txt"));
+        assert_eq!(j.bytes_amt_provider, 0);
+    }
+
+    #[tokio::test]
+    async fn test_repair() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let usb_dir = tmp_dir.path().join("usb");
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", &usb_dir, 0, 0));
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        std::fs::create_dir_all(usb_dir.join(format!("{}/chunks", j.id))).unwrap();
+        let chunk_key = crate::crypto::derive_chunk_key("hunter2", j.id).unwrap();
+        // Back up two files without storing their chunks
+        let mut r = Run::new(1, compress);
+        for name in ["kept.txt", "changed.txt"] {
+            let path = tmp_dir.path().join(name);
+            std::fs::write(&path, name).unwrap();
+            let mut kfc = KipFileChunked::new(&path, hash_file(&path).await.unwrap(), name.len());
+            let mut chunker = crate::chunk::chunker(tokio::fs::File::open(&path).await.unwrap());
+            let mut chunk_stream = Box::pin(chunker.as_stream());
+            while let Some(entry) = chunk_stream.next().await {
+                let mut c = FileChunk::from_chunk_data(&path, &entry.unwrap(), &chunk_key).unwrap();
+                c.set_remote_path(format!("{}/chunks/{}.chunk", j.id, c.hash));
+                kfc.add_chunk(c);
+            }
+            r.snapshot.push(kfc);
+        }
+        j.runs.insert(1, r);
+        // Only the unchanged file can be used to repair its chunks
+        std::fs::write(tmp_dir.path().join("changed.txt"), "modified").unwrap();
+        let report = j.repair("hunter2", None).await.unwrap();
+        assert_eq!(report.repaired.len(), 1);
+        assert_eq!(
+            report.damaged.into_iter().collect::<Vec<PathBuf>>(),
+            vec![tmp_dir.path().join("changed.txt")]
+        );
+        assert!(!j.runs[&1].snapshot[0].damaged);
+        assert!(j.runs[&1].snapshot[1].damaged);
+        // The repaired chunk decrypts to the original contents
+        let check = j.check("hunter2", Some(100.0)).await.unwrap();
+        assert_eq!(check.read, 1);
+        assert!(check.corrupt.is_empty());
+        assert_eq!(check.missing.len(), 1);
+    }
+
     #[tokio::test]
     async fn test_get_file_hashes() {
         let provider = KipProviders::S3(KipS3::new("test1", Region::new("us-east-1".to_owned())));
@@ -510,7 +1535,9 @@ mod tests {
             j.files
                 .push(KipFile::new(PathBuf::from(r".\test\random.txt")).unwrap());
         }
-        let hash_result = j.get_file_hashes(false).await;
+        let hash_result = j
+            .get_file_hashes(KipRunOpts::default(), &BTreeMap::new())
+            .await;
         assert!(hash_result.is_ok());
         assert_eq!(
             j.files[0].hash,
@@ -522,6 +1549,34 @@ mod tests {
         )
     }
 
+    #[tokio::test]
+    async fn test_get_file_hashes_cached() {
+        let provider = KipProviders::S3(KipS3::new("test1", Region::new("us-east-1".to_owned())));
+        let mut j = Job::new(
+            "testing1",
+            provider,
+            KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best),
+        );
+        let path = PathBuf::from("test/random.txt");
+        j.files.push(KipFile::new(&path).unwrap());
+        // Unchanged stat data reuses the last known hash
+        let md = path.metadata().unwrap();
+        j.file_states
+            .insert(path.clone(), KipFileState::new(&md, "cached").unwrap());
+        j.get_file_hashes(KipRunOpts::default(), &BTreeMap::new())
+            .await
+            .unwrap();
+        assert_eq!(j.files[0].hash, "cached");
+        // Unless a rehash is forced
+        j.get_file_hashes(KipRunOpts::new(false, true, 0), &BTreeMap::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            j.files[0].hash,
+            "44b4cdaf713dfaf961dedb34f07e15604f75eb049c83067ab35bf388b369dbf3"
+        );
+    }
+
     #[tokio::test]
     async fn test_get_file_hashes_dir() {
         let provider = KipProviders::S3(KipS3::new("test1", Region::new("us-east-1".to_owned())));
@@ -539,11 +1594,19 @@ mod tests {
             j.files
                 .push(KipFile::new(PathBuf::from(r".\test\test_dir\")).unwrap());
         }
-        let hash_result = j.get_file_hashes(false).await;
+        let hash_result = j
+            .get_file_hashes(KipRunOpts::default(), &BTreeMap::new())
+            .await;
         assert!(hash_result.is_ok());
         assert_eq!(
             j.files[0].hash,
-            "d9317775d9b1dccdad75fa47b521b47d2079e813ff290d74a277944efb701909"
-        )
+            "f1a1615ff5aa5d1df596bf3707ff176cfd8054a669932e7f07f3bfc79de6033a"
+        );
+        // Every file within the dir is tracked on its own
+        assert_eq!(j.file_states.len(), 4);
+        for (path, state) in j.file_states.iter() {
+            assert_eq!(state.len, path.metadata().unwrap().len());
+            assert_eq!(state.hash.len(), 64);
+        }
     }
 }
@@ -4,13 +4,22 @@
 
 #![warn(clippy::all)]
 
+pub mod check;
+pub mod checkpoint;
 pub mod chunk;
 pub mod cli;
 pub mod compress;
 pub mod conf;
+pub mod control;
 pub mod crypto;
+pub mod index;
 pub mod job;
+pub mod limiter;
+pub mod manifest;
+pub mod meta;
 pub mod providers;
+pub mod retention;
+pub mod retry;
 pub mod run;
 pub mod smtp;
 
@@ -0,0 +1,189 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// A token bucket shared by every task drawing from it.
+/// Tokens are bytes for bandwidth limits and requests for
+/// provider API limits.
+#[derive(Debug)]
+pub struct KipRateLimiter {
+    /// Tokens added per second
+    rate: f64,
+    /// Most tokens the bucket holds, allowing short bursts
+    burst: f64,
+    bucket: Mutex<KipBucket>,
+}
+
+#[derive(Debug)]
+struct KipBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl KipRateLimiter {
+    /// Allows amount tokens every period
+    pub fn new(amount: u64, period: Duration) -> Self {
+        let amount = amount.max(1) as f64;
+        Self {
+            rate: amount / period.as_secs_f64().max(f64::EPSILON),
+            burst: amount,
+            bucket: Mutex::new(KipBucket {
+                tokens: amount,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Allows amount tokens every second
+    pub fn per_second(amount: u64) -> Self {
+        Self::new(amount, Duration::from_secs(1))
+    }
+
+    /// Waits until n tokens are available and takes them
+    pub async fn acquire(&self, n: u64) {
+        let wait = self.reserve(n, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes n tokens and returns how long to wait until they've
+    /// been refilled. Requests larger than the bucket are let
+    /// through once the bucket would have refilled that much, so
+    /// the average rate still holds.
+    fn reserve(&self, n: u64, now: Instant) -> Duration {
+        let mut bucket = match self.bucket.lock() {
+            Ok(bucket) => bucket,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+        bucket.tokens -= n as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+}
+
+// Bandwidth limit shared by every job, in bytes per second
+static GLOBAL_BANDWIDTH: RwLock<Option<Arc<KipRateLimiter>>> = RwLock::new(None);
+// Bandwidth limits of jobs, shared by their concurrent runs
+static JOB_BANDWIDTH: OnceLock<Mutex<HashMap<Uuid, (u64, Arc<KipRateLimiter>)>>> = OnceLock::new();
+
+/// Sets the bandwidth limit shared by every job. 0 removes it.
+pub fn set_global_bandwidth(bytes_per_sec: u64) {
+    let limiter = match bytes_per_sec {
+        0 => None,
+        bps => Some(Arc::new(KipRateLimiter::per_second(bps))),
+    };
+    match GLOBAL_BANDWIDTH.write() {
+        Ok(mut global) => *global = limiter,
+        Err(poisoned) => *poisoned.into_inner() = limiter,
+    }
+}
+
+/// The bandwidth limits a job's uploads and restores are under
+#[derive(Clone, Debug, Default)]
+pub struct KipBandwidth {
+    global: Option<Arc<KipRateLimiter>>,
+    job: Option<Arc<KipRateLimiter>>,
+}
+
+impl KipBandwidth {
+    /// Returns the global limit and the job's own limit, if it
+    /// has one. 0 means the job isn't limited on its own.
+    pub fn for_job(job_id: Uuid, bytes_per_sec: u64) -> Self {
+        let global = match GLOBAL_BANDWIDTH.read() {
+            Ok(global) => global.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let jobs = JOB_BANDWIDTH.get_or_init(|| Mutex::new(HashMap::new()));
+        let mut jobs = match jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let job = match bytes_per_sec {
+            0 => {
+                jobs.remove(&job_id);
+                None
+            }
+            bps => {
+                let (limit, limiter) = jobs
+                    .entry(job_id)
+                    .or_insert_with(|| (bps, Arc::new(KipRateLimiter::per_second(bps))));
+                // The job's limit was changed since it was created
+                if *limit != bps {
+                    *limit = bps;
+                    *limiter = Arc::new(KipRateLimiter::per_second(bps));
+                }
+                Some(Arc::clone(limiter))
+            }
+        };
+        Self { global, job }
+    }
+
+    /// Waits until n bytes can be sent or received
+    pub async fn acquire(&self, n: usize) {
+        let n = n as u64;
+        if let Some(global) = &self.global {
+            global.acquire(n).await;
+        }
+        if let Some(job) = &self.job {
+            job.acquire(n).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_reserve() {
+        let limiter = KipRateLimiter::new(100, Duration::from_secs(1));
+        let now = Instant::now();
+        // The bucket starts full
+        assert_eq!(limiter.reserve(100, now), Duration::ZERO);
+        // Then waits for it to refill
+        assert_eq!(limiter.reserve(50, now), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(50, now), Duration::from_secs(1));
+        // Time passed pays back what was borrowed
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limiter.reserve(100, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_limiter_period() {
+        // 20,000 requests every 100 seconds
+        let limiter = KipRateLimiter::new(20_000, Duration::from_secs(100));
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(20_000, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(200, now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_job_bandwidth() {
+        let job_id = Uuid::new_v4();
+        let a = KipBandwidth::for_job(job_id, 1024);
+        let b = KipBandwidth::for_job(job_id, 1024);
+        // Runs of the same job share their limit
+        assert!(Arc::ptr_eq(
+            a.job.as_ref().unwrap(),
+            b.job.as_ref().unwrap()
+        ));
+        let c = KipBandwidth::for_job(job_id, 2048);
+        assert!(!Arc::ptr_eq(
+            a.job.as_ref().unwrap(),
+            c.job.as_ref().unwrap()
+        ));
+        assert!(KipBandwidth::for_job(job_id, 0).job.is_none());
+    }
+}
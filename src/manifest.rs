@@ -0,0 +1,67 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use crate::crypto::{decrypt, encrypt_bytes};
+use crate::job::Job;
+use crate::run::Run;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A manifest is an encrypted copy of a job's metadata and
+/// one of its runs. One is stored on the provider next to the
+/// job's chunks for every run so that a job can be rebuilt
+/// without the local kip_metadata.json.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KipManifest {
+    pub job: Job,
+    pub run: Run,
+}
+
+impl KipManifest {
+    pub fn new(job: &Job, run: &Run) -> Self {
+        // Other runs have their own manifests
+        let mut job = job.clone();
+        job.runs.clear();
+        Self {
+            job,
+            run: run.clone(),
+        }
+    }
+
+    /// Serializes and encrypts the manifest with the job's secret
+    pub fn encrypt(&self, secret: &str) -> Result<Vec<u8>> {
+        let manifest_json = serde_json::to_vec(self)?;
+        encrypt_bytes(&manifest_json, secret)
+    }
+
+    /// Decrypts and deserializes a manifest downloaded from a provider
+    pub fn decrypt(ciphertext: &[u8], secret: &str) -> Result<Self> {
+        let manifest_json = decrypt(ciphertext, secret).context("unable to decrypt manifest")?;
+        serde_json::from_slice(&manifest_json).context("unable to parse manifest")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{KipCompressAlg, KipCompressLevel, KipCompressOpts};
+    use crate::providers::usb::KipUsb;
+    use crate::providers::KipProviders;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let provider = KipProviders::Usb(KipUsb::new("test_usb", "/tmp", 0, 0));
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut j = Job::new("testing1", provider, compress);
+        let r = Run::new(1, compress);
+        j.runs.insert(1, r.clone());
+        let manifest = KipManifest::new(&j, &r);
+        assert!(manifest.job.runs.is_empty());
+        let ciphertext = manifest.encrypt("hunter2").unwrap();
+        let decrypted = KipManifest::decrypt(&ciphertext, "hunter2").unwrap();
+        assert_eq!(decrypted.job.id, j.id);
+        assert_eq!(decrypted.run.id, 1);
+        assert!(KipManifest::decrypt(&ciphertext, "hunter3").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, FileTimes, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KipFileKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A file's metadata at the time it was backed up. Fields
+/// the running OS doesn't support are left empty and
+/// skipped on restore.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KipFileMeta {
+    pub kind: KipFileKind,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime: Option<DateTime<Utc>>,
+    pub atime: Option<DateTime<Utc>>,
+    pub symlink_target: Option<PathBuf>,
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl KipFileMeta {
+    /// Reads a file's metadata without following symlinks
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        Self::from_metadata(path, &path.symlink_metadata()?)
+    }
+
+    /// Builds a file's metadata from a stat already taken. A
+    /// followed symlink is recorded as the file it points to.
+    pub fn from_metadata<P: AsRef<Path>>(path: P, md: &Metadata) -> Result<Self> {
+        let path = path.as_ref();
+        let kind = if md.file_type().is_symlink() {
+            KipFileKind::Symlink
+        } else if md.is_dir() {
+            KipFileKind::Dir
+        } else {
+            KipFileKind::File
+        };
+        let symlink_target = match kind {
+            KipFileKind::Symlink => Some(std::fs::read_link(path)?),
+            _ => None,
+        };
+        let mut meta = Self {
+            kind,
+            mtime: md.modified().ok().map(DateTime::<Utc>::from),
+            atime: md.accessed().ok().map(DateTime::<Utc>::from),
+            symlink_target,
+            ..Default::default()
+        };
+        meta.read_os_meta(path, md);
+        Ok(meta)
+    }
+
+    #[cfg(unix)]
+    fn read_os_meta(&mut self, path: &Path, md: &Metadata) {
+        use std::os::unix::fs::MetadataExt;
+        self.mode = Some(md.mode() & 0o7777);
+        self.uid = Some(md.uid());
+        self.gid = Some(md.gid());
+        // Not every file system supports extended attributes
+        match xattr::list(path) {
+            Ok(names) => {
+                for name in names {
+                    if let (Some(n), Ok(Some(value))) = (name.to_str(), xattr::get(path, &name)) {
+                        self.xattrs.insert(n.to_string(), value);
+                    }
+                }
+            }
+            Err(e) => debug!("unable to list xattrs of {}: {e}", path.display()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn read_os_meta(&mut self, _path: &Path, _md: &Metadata) {}
+
+    /// Applies the metadata to a restored file. Ownership is
+    /// only restored when running as root.
+    pub fn restore<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.restore_xattrs(path);
+        self.restore_owner(path)?;
+        // Symlinks' own timestamps and modes can't be set portably
+        if self.kind == KipFileKind::Symlink {
+            return Ok(());
+        }
+        let mut times = FileTimes::new();
+        if let Some(mtime) = self.mtime {
+            times = times.set_modified(SystemTime::from(mtime));
+        }
+        if let Some(atime) = self.atime {
+            times = times.set_accessed(SystemTime::from(atime));
+        }
+        if self.mtime.is_some() || self.atime.is_some() {
+            File::open(path)?.set_times(times)?;
+        }
+        // Set the mode last in case it removes read access
+        self.restore_mode(path)
+    }
+
+    /// Extended attributes the restored file system
+    /// doesn't support are skipped
+    #[cfg(unix)]
+    fn restore_xattrs(&self, path: &Path) {
+        for (name, value) in self.xattrs.iter() {
+            if let Err(e) = xattr::set(path, name, value) {
+                debug!("unable to set xattr {name} on {}: {e}", path.display());
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn restore_xattrs(&self, _path: &Path) {}
+
+    #[cfg(unix)]
+    fn restore_owner(&self, path: &Path) -> Result<()> {
+        // Ownership is restored before the mode since
+        // changing it clears setuid and setgid bits
+        if is_root() && (self.uid.is_some() || self.gid.is_some()) {
+            std::os::unix::fs::lchown(path, self.uid, self.gid)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn restore_owner(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn restore_mode(&self, path: &Path) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn restore_mode(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid is always successful
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_meta_roundtrip() {
+        let tmp_dir = tempdir().unwrap();
+        let src = tmp_dir.path().join("src.txt");
+        let dst = tmp_dir.path().join("dst.txt");
+        std::fs::write(&src, b"kip").unwrap();
+        std::fs::write(&dst, b"kip").unwrap();
+        let mtime = Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap();
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(SystemTime::from(mtime))
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let meta = KipFileMeta::new(&src).unwrap();
+        assert_eq!(meta.kind, KipFileKind::File);
+        assert_eq!(meta.mtime, Some(mtime));
+        meta.restore(&dst).unwrap();
+        assert_eq!(KipFileMeta::new(&dst).unwrap().mtime, Some(mtime));
+        #[cfg(unix)]
+        assert_eq!(KipFileMeta::new(&dst).unwrap().mode, Some(0o640));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_meta_symlink() {
+        let tmp_dir = tempdir().unwrap();
+        let link = tmp_dir.path().join("link");
+        std::os::unix::fs::symlink("target.txt", &link).unwrap();
+        let meta = KipFileMeta::new(&link).unwrap();
+        assert_eq!(meta.kind, KipFileKind::Symlink);
+        assert_eq!(meta.symlink_target, Some(PathBuf::from("target.txt")));
+    }
+}
@@ -2,167 +2,581 @@
 // Copyright (c) 2023 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
-use super::KipUploadOpts;
+use super::{KipArchived, KipUploadOpts};
 use crate::chunk::FileChunk;
-use crate::compress::{
-    decompress_brotli, decompress_gzip, decompress_lzma, decompress_zstd, KipCompressAlg,
-    KipCompressOpts,
-};
-use crate::crypto::decrypt;
-use crate::job::KipFile;
+use crate::limiter::KipRateLimiter;
 use crate::providers::KipProvider;
-use crate::run::KipUploadMsg;
+use crate::retry::{transient, KipRetryOpts};
 use anyhow::{bail, Result};
 use async_trait::async_trait;
-use linya::{Bar, Progress};
-use memmap2::MmapOptions;
+use azure_core::error::ErrorKind;
+use azure_core::{RetryOptions, StatusCode};
+use azure_storage::{CloudLocation, StorageCredentials};
+use azure_storage_blobs::prelude::*;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::create_dir_all;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, warn};
 use uuid::Uuid;
-use walkdir::WalkDir;
 
+/// Env var the shared key or SAS token is loaded
+/// into from the keyring
+pub const AZURE_SECRET_VAR: &str = "KIP_AZURE_SECRET";
+
+// Chunks larger than this are uploaded as several blocks
+const BLOCK_SIZE: usize = 8 * 1024 * 1024;
+// Blocks of a chunk sent at once
+const BLOCK_CONCURRENCY: usize = 4;
+// Archived blobs checked at once before a restore
+const REHYDRATE_CONCURRENCY: usize = 16;
+
+/// A container in an Azure Blob Storage account
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct KipAzure {
-    pub blob_name: String,
+    pub account: String,
     pub container: String,
+    pub auth: KipAzureAuth,
+    /// Blob endpoint of the account, such as Azurite's
+    /// http://127.0.0.1:10000/devstoreaccount1. None uses Azure.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Access tier chunks are uploaded to. None uses the
+    /// account's default.
+    #[serde(default)]
+    pub access_tier: Option<KipAzureTier>,
+}
+
+/// How kip authenticates to the account. The shared
+/// key or SAS token is stored in the keyring.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KipAzureAuth {
+    /// One of the storage account's access keys
+    SharedKey,
+    /// A SAS token granting access to the container
+    Sas,
+}
+
+/// Blob access tiers
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KipAzureTier {
+    Hot,
+    Cool,
+    /// Blobs must be rehydrated, which takes hours,
+    /// before they can be downloaded
+    Archive,
+}
+
+impl std::fmt::Display for KipAzureTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hot => write!(f, "Hot"),
+            Self::Cool => write!(f, "Cool"),
+            Self::Archive => write!(f, "Archive"),
+        }
+    }
+}
+
+impl From<KipAzureTier> for AccessTier {
+    fn from(tier: KipAzureTier) -> Self {
+        match tier {
+            KipAzureTier::Hot => AccessTier::Hot,
+            KipAzureTier::Cool => AccessTier::Cool,
+            KipAzureTier::Archive => AccessTier::Archive,
+        }
+    }
 }
 
-impl KipUsb {
+impl KipAzure {
     // 20,000 API requests per second
     // https://learn.microsoft.com/en-us/azure/azure-resource-manager/management/azure-subscription-service-limits#storage-limits
-    const _API_RATE_LIMIT: u64 = 3500;
-    const _API_RATE_LIMIT_PERIOD: u64 = 1;
+    const API_RATE_LIMIT: u64 = 20_000;
+    const API_RATE_LIMIT_PERIOD: u64 = 1;
 
-    pub fn new<S: Into<String>, P: AsRef<Path>>(blob_name: S, container: S) -> Self {
+    pub fn new<S: Into<String>>(account: S, container: S, auth: KipAzureAuth) -> Self {
         Self {
-            blob_name: blob_name.into(),
+            account: account.into(),
             container: container.into(),
+            auth,
+            endpoint: None,
+            access_tier: None,
         }
     }
-}
 
-#[async_trait]
-impl KipProvider for KipAzure {
-    type Item = PutBlockBlobResponse;
+    /// Builds a client for the container. The shared key
+    /// or SAS token is read from the environment.
+    pub fn client(&self) -> Result<ContainerClient> {
+        let secret = std::env::var(AZURE_SECRET_VAR).unwrap_or_default();
+        let credentials = match self.auth {
+            KipAzureAuth::SharedKey => StorageCredentials::access_key(self.account.clone(), secret),
+            KipAzureAuth::Sas => StorageCredentials::sas_token(secret.trim_start_matches('?'))?,
+        };
+        let builder = match &self.endpoint {
+            Some(uri) => ClientBuilder::with_location(
+                CloudLocation::Custom {
+                    account: self.account.clone(),
+                    uri: uri.trim_end_matches('/').to_owned(),
+                },
+                credentials,
+            ),
+            None => ClientBuilder::new(self.account.clone(), credentials),
+        };
+        // Failed requests are retried by kip
+        Ok(builder
+            .retry(RetryOptions::none())
+            .container_client(&self.container))
+    }
 
-    async fn upload<'b>(
-        &mut self,
-        opts: KipUploadOpts,
-        chunks_map: HashMap<FileChunk, &'b [u8]>,
-        msg_tx: UnboundedSender<KipUploadMsg>,
-        progress: Arc<Mutex<Progress>>,
-        bar: &Bar,
+    /// Limits the requests made to Azure by every job
+    fn api_limiter() -> &'static KipRateLimiter {
+        static LIMITER: OnceLock<KipRateLimiter> = OnceLock::new();
+        LIMITER.get_or_init(|| {
+            KipRateLimiter::new(
+                Self::API_RATE_LIMIT,
+                Duration::from_secs(Self::API_RATE_LIMIT_PERIOD),
+            )
+        })
+    }
+
+    /// Returns the name of every chunk stored for a job
+    pub async fn list_chunks(&self, client: &ContainerClient, job_id: Uuid) -> Result<Vec<String>> {
+        self.list_blobs(client, Some(format!("{job_id}/chunks/")))
+            .await
+    }
+
+    /// Lists the names of the container's blobs, or of those
+    /// starting with prefix
+    async fn list_blobs(
+        &self,
+        client: &ContainerClient,
+        prefix: Option<String>,
+    ) -> Result<Vec<String>> {
+        let mut list = client.list_blobs();
+        if let Some(prefix) = prefix {
+            list = list.prefix(prefix);
+        }
+        let mut pages = list.into_stream();
+        let mut names = Vec::<String>::new();
+        loop {
+            Self::api_limiter().acquire(1).await;
+            let Some(page) = pages.next().await else {
+                break;
+            };
+            let page = page.map_err(azure_error)?;
+            names.extend(page.blobs.blobs().map(|blob| blob.name.clone()));
+        }
+        Ok(names)
+    }
+
+    /// Rehydrates the archived blobs among names so they can be
+    /// downloaded. Blobs in the Archive tier are moved to the Cool
+    /// tier, which takes hours. Returns the names still being
+    /// rehydrated, so it's called until none are, and the ones
+    /// that can't be rehydrated.
+    pub async fn restore_archived(
+        &self,
+        client: &ContainerClient,
+        names: &[String],
+    ) -> Result<KipArchived> {
+        let mut archived = KipArchived::default();
+        if self.access_tier != Some(KipAzureTier::Archive) {
+            return Ok(archived);
+        }
+        let mut results = futures::stream::iter(names)
+            .map(|name| async move {
+                let rehydrating = KipRetryOpts::configured()
+                    .retry(
+                        &format!("rehydration of {name}"),
+                        |_, _| {},
+                        || self.rehydrate(client, name),
+                    )
+                    .await;
+                (name, rehydrating)
+            })
+            .buffer_unordered(REHYDRATE_CONCURRENCY);
+        while let Some((name, rehydrating)) = results.next().await {
+            match rehydrating {
+                Ok(true) => archived.pending.push(name.clone()),
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("unable to rehydrate {name}: {e}");
+                    archived.unavailable.push(name.clone());
+                }
+            }
+        }
+        Ok(archived)
+    }
+
+    /// Rehydrates a blob if it's archived. Returns whether
+    /// it's still being rehydrated.
+    async fn rehydrate(&self, client: &ContainerClient, name: &str) -> Result<bool> {
+        let blob = client.blob_client(name);
+        Self::api_limiter().acquire(1).await;
+        let props = blob.get_properties().await.map_err(azure_error)?;
+        if props.blob.properties.access_tier != Some(AccessTier::Archive) {
+            return Ok(false);
+        }
+        // Rehydration is already underway
+        if props.blob.properties.archive_status.is_some() {
+            return Ok(true);
+        }
+        debug!("requesting rehydration of {name}");
+        Self::api_limiter().acquire(1).await;
+        blob.set_blob_tier(AccessTier::Cool)
+            .rehydrate_priority(RehydratePriority::Standard)
+            .await
+            .map_err(azure_error)?;
+        Ok(true)
+    }
+
+    /// Uploads a blob in a single request, or in blocks if it's
+    /// larger than the block size
+    async fn put(
+        &self,
+        client: &ContainerClient,
+        name: String,
+        body: Bytes,
+        tier: Option<KipAzureTier>,
+    ) -> Result<()> {
+        let blob = client.blob_client(name);
+        if body.len() > BLOCK_SIZE {
+            return self.put_blocks(&blob, body, tier).await;
+        }
+        let mut put = blob
+            .put_block_blob(body)
+            .content_type("application/octet-stream");
+        if let Some(tier) = tier {
+            put = put.access_tier(AccessTier::from(tier));
+        }
+        Self::api_limiter().acquire(1).await;
+        put.await.map_err(azure_error)?;
+        Ok(())
+    }
+
+    /// Uploads a blob as blocks, several at a time, then commits
+    /// them. Blocks are slices of the blob's bytes, so they aren't
+    /// copied. Azure discards blocks that are never committed.
+    async fn put_blocks(
+        &self,
+        blob: &BlobClient,
+        body: Bytes,
+        tier: Option<KipAzureTier>,
     ) -> Result<()> {
-        // First we retrieve the account name and access key from environment variables.
-        let account = std::env::var("STORAGE_ACCOUNT").expect("missing STORAGE_ACCOUNT");
-        let access_key = std::env::var("STORAGE_ACCESS_KEY").expect("missing STORAGE_ACCOUNT_KEY");
-        let storage_credentials = StorageCredentials::Key(account.clone(), access_key);
-        let azure_client = ClientBuilder::new(account, storage_credentials)
-            .blob_client(&self.container, self.blob_name);
-
-        // Upload each chunk
-        for (chunk, chunk_bytes) in chunks_map {
-            // Get amount of bytes uploaded in this chunk
-            // after compression and encryption
-            let ce_bytes_len = chunk_bytes.len();
-            // Upload
-            azure_client
-                .put_block_blob(chunk_bytes)
-                .content_type("application/octet-stream")
+        let name = blob.blob_name();
+        let block_ids: Vec<BlockId> =
+            futures::stream::iter((0..body.len()).step_by(BLOCK_SIZE).enumerate())
+                .map(|(i, start)| {
+                    let block = body.slice(start..(start + BLOCK_SIZE).min(body.len()));
+                    // Every block ID of a blob must be the same length
+                    let block_id = BlockId::new(format!("{i:08}"));
+                    async move {
+                        // Blocks are retried on their own so a failed
+                        // block doesn't restart the whole upload
+                        KipRetryOpts::configured()
+                            .retry(
+                                &format!("upload of block {i} of {name}"),
+                                |_, _| {},
+                                || {
+                                    let block = block.clone();
+                                    let block_id = block_id.clone();
+                                    async move {
+                                        Self::api_limiter().acquire(1).await;
+                                        blob.put_block(block_id, block).await.map_err(azure_error)
+                                    }
+                                },
+                            )
+                            .await?;
+                        Ok::<_, anyhow::Error>(block_id)
+                    }
+                })
+                .buffered(BLOCK_CONCURRENCY)
+                .try_collect()
                 .await?;
-            // Push chunk onto chunks hashmap for return
-            msg_tx.send(KipUploadMsg::FileChunk(chunk))?;
-            // Increment progress bar by chunk bytes len
-            progress.lock().await.inc_and_draw(bar, ce_bytes_len);
-            msg_tx.send(KipUploadMsg::BytesUploaded(ce_bytes_len.try_into()?))?;
+
+        let block_list = BlockList {
+            blocks: block_ids
+                .into_iter()
+                .map(BlobBlockType::new_uncommitted)
+                .collect(),
+        };
+        let mut commit = blob
+            .put_block_list(block_list)
+            .content_type("application/octet-stream");
+        if let Some(tier) = tier {
+            commit = commit.access_tier(AccessTier::from(tier));
         }
+        Self::api_limiter().acquire(1).await;
+        commit.await.map_err(azure_error)?;
         Ok(())
     }
+}
 
-    async fn download(&self, f: &str, secret: &str, compress: KipCompressOpts) -> Result<Vec<u8>> {
-        // Read result from S3 and convert to bytes
-        let path = Path::new(f);
-        let mut bytes = vec![];
-        if path.metadata()?.len() > (500 * 1024 * 1024) {
-            // SAFETY: unsafe used here for mmap
-            let mmap = unsafe {
-                MmapOptions::new()
-                    .populate()
-                    .map(&File::open(path).await?)?
-            };
-            bytes.extend_from_slice(&mmap[..]);
-        } else {
-            bytes.extend_from_slice(&tokio::fs::read(path).await?);
-        }
-        // Decrypt result_bytes
-        let decrypted = match decrypt(&bytes, secret) {
-            Ok(dc) => dc,
-            Err(e) => {
-                bail!("failed to decrypt file: {}.", e)
-            }
+#[async_trait]
+impl KipProvider for KipAzure {
+    type Client = ContainerClient;
+    type Item = String;
+
+    async fn upload(
+        &self,
+        client: Option<&Self::Client>,
+        opts: KipUploadOpts,
+        chunk: &FileChunk,
+        chunk_bytes: Bytes,
+    ) -> Result<usize> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
         };
-        // Decompress decrypted bytes
-        let mut decompressed = Vec::<u8>::new();
-        if compress.enabled {
-            match compress.alg {
-                KipCompressAlg::Zstd => decompressed = decompress_zstd(&decrypted).await?,
-                KipCompressAlg::Lzma => decompressed = decompress_lzma(&decrypted).await?,
-                KipCompressAlg::Gzip => decompressed = decompress_gzip(&decrypted).await?,
-                KipCompressAlg::Brotli => decompressed = decompress_brotli(&decrypted).await?,
-            }
-        } else {
-            decompressed.extend_from_slice(&decrypted);
+        let uploaded = chunk_bytes.len();
+        self.put(
+            azure,
+            format!("{}/chunks/{}.chunk", opts.job_id, chunk.hash),
+            chunk_bytes,
+            self.access_tier,
+        )
+        .await?;
+        Ok(uploaded)
+    }
+
+    async fn download(&self, client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
+        };
+        Self::api_limiter().acquire(1).await;
+        azure
+            .blob_client(file_name)
+            .get_content()
+            .await
+            .map_err(azure_error)
+    }
+
+    async fn delete(&self, client: Option<&Self::Client>, file_name: &str) -> Result<()> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
+        };
+        Self::api_limiter().acquire(1).await;
+        match azure.blob_client(file_name).delete().await {
+            Ok(_) => Ok(()),
+            // Already deleted by an earlier attempt
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(azure_error(e)),
         }
-        // Return downloaded & decrypted bytes
-        Ok(decompressed)
     }
 
-    async fn delete(&self, file_name: &str) -> Result<()> {
-        let path = Path::new(file_name);
-        if path.is_dir() {
-            tokio::fs::remove_dir_all(path).await?;
-        } else {
-            tokio::fs::remove_file(path).await?;
+    async fn contains(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        hash: &str,
+    ) -> Result<bool> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
+        };
+        Self::api_limiter().acquire(1).await;
+        match azure
+            .blob_client(format!("{job_id}/chunks/{hash}.chunk"))
+            .get_properties()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(azure_error(e)),
         }
-        Ok(())
     }
 
-    async fn contains(&self, job_id: Uuid, hash: &str) -> Result<bool> {
-        // Check S3 for duplicates of chunk
-        let file_objs = self.list_all(job_id).await?;
-        // If the S3 bucket is empty, no need to check for duplicate chunks
-        if !file_objs.is_empty() {
-            for obj in file_objs {
-                if obj.hash == hash {
-                    // Duplicate chunk found, return true
-                    return Ok(true);
-                }
-            }
+    async fn list_all(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+    ) -> Result<Vec<Self::Item>> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
+        };
+        self.list_chunks(azure, job_id).await
+    }
+
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
+        };
+        // Manifests stay in the account's default tier so
+        // recovering a job doesn't wait on a rehydration
+        self.put(
+            azure,
+            format!("{job_id}/manifests/{run_id}.manifest"),
+            Bytes::copy_from_slice(manifest_bytes),
+            None,
+        )
+        .await?;
+        Ok(manifest_bytes.len())
+    }
+
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>> {
+        let Some(azure) = client else {
+            bail!("azure client not provided")
+        };
+        Ok(self
+            .list_blobs(azure, None)
+            .await?
+            .into_iter()
+            .filter(|name| is_manifest_name(name))
+            .collect())
+    }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        // The run's manifest may never have been uploaded
+        self.delete(client, &format!("{job_id}/manifests/{run_id}.manifest"))
+            .await
+    }
+}
+
+/// Checks whether a blob name is a run manifest.
+/// Ex: f339aae7-e994-4fb4-b6aa-623681df99aa/manifests/1.manifest
+fn is_manifest_name(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('/').collect();
+    parts.len() == 3 && parts[1] == "manifests" && parts[2].ends_with(".manifest")
+}
+
+/// Whether a response status may succeed if retried:
+/// timeouts, throttling and 5xx responses
+fn is_transient_status(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+/// Marks Azure errors that may succeed if retried as transient:
+/// dropped connections and retryable responses
+fn azure_error(e: azure_core::Error) -> anyhow::Error {
+    let is_transient = match e.kind() {
+        ErrorKind::HttpResponse { status, .. } => is_transient_status(u16::from(*status)),
+        ErrorKind::Io => true,
+        _ => false,
+    };
+    match is_transient {
+        true => transient(e),
+        false => e.into(),
+    }
+}
+
+fn is_not_found(e: &azure_core::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::HttpResponse {
+            status: StatusCode::NotFound,
+            ..
         }
-        Ok(false)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_manifest_name() {
+        assert!(is_manifest_name(
+            "f339aae7-e994-4fb4-b6aa-623681df99aa/manifests/1.manifest"
+        ));
+        assert!(!is_manifest_name(
+            "f339aae7-e994-4fb4-b6aa-623681df99aa/chunks/abc123.chunk"
+        ));
     }
 
-    async fn list_all(&self, job_id: Uuid) -> Result<Vec<Self::Item>> {
-        let mut kfs = Vec::<KipFile>::new();
-        let path_fmt = format!("{}/{}/chunks/", self.root_path.display(), job_id);
-        let path = Path::new(&path_fmt).canonicalize()?;
-        for entry in WalkDir::new(path).follow_links(true) {
-            let entry = entry?;
-            // If a directory, skip
-            if entry.path().metadata()?.is_dir() {
-                continue;
-            }
-            // Is a file, create KipFile and pusht to vec
-            let entry_kf = KipFile::new(entry.path().canonicalize()?);
-            kfs.push(entry_kf);
+    #[test]
+    fn test_is_transient_status() {
+        assert!(is_transient_status(503));
+        assert!(is_transient_status(429));
+        assert!(!is_transient_status(403));
+        assert!(!is_transient_status(404));
+    }
+
+    // Runs against the Azurite emulator:
+    // docker run -p 10000:10000 mcr.microsoft.com/azure-storage/azurite azurite-blob --blobHost 0.0.0.0
+    // KIP_TEST_AZURE_ENDPOINT=http://127.0.0.1:10000/devstoreaccount1 cargo test test_azurite -- --ignored
+    #[tokio::test]
+    #[ignore = "needs the Azurite emulator"]
+    async fn test_azurite() {
+        let endpoint =
+            std::env::var("KIP_TEST_AZURE_ENDPOINT").expect("KIP_TEST_AZURE_ENDPOINT must be set");
+        // Azurite's well-known development account
+        std::env::set_var(
+            AZURE_SECRET_VAR,
+            "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==",
+        );
+        let job_id = Uuid::new_v4();
+        let mut azure = KipAzure::new(
+            "devstoreaccount1".to_owned(),
+            format!("kip-test-{}", &job_id.to_string()[..8]),
+            KipAzureAuth::SharedKey,
+        );
+        azure.endpoint = Some(endpoint);
+        azure.access_tier = Some(KipAzureTier::Cool);
+        let client = azure.client().unwrap();
+        client.create().await.unwrap();
+
+        // Small chunks are uploaded in one request, large
+        // ones as several blocks
+        let small = Bytes::from_static(b"kip");
+        let large = Bytes::from(vec![7u8; BLOCK_SIZE * 2 + 1]);
+        for (hash, bytes) in [("abc123", &small), ("def456", &large)] {
+            let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+            let chunk = FileChunk::new("test/random.txt", hash, 0, bytes.len(), bytes.len());
+            let uploaded = azure
+                .upload(
+                    Some(&client),
+                    KipUploadOpts::new(job_id, tx),
+                    &chunk,
+                    bytes.clone(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(uploaded, bytes.len());
+            assert!(azure.contains(Some(&client), job_id, hash).await.unwrap());
+            let name = format!("{job_id}/chunks/{hash}.chunk");
+            assert_eq!(
+                azure.download(Some(&client), &name).await.unwrap(),
+                bytes.to_vec()
+            );
+        }
+        assert!(!azure
+            .contains(Some(&client), job_id, "789abc")
+            .await
+            .unwrap());
+        assert_eq!(azure.list_chunks(&client, job_id).await.unwrap().len(), 2);
+        // Cool blobs don't need rehydrating
+        let chunks = azure.list_chunks(&client, job_id).await.unwrap();
+        let archived = azure.restore_archived(&client, &chunks).await.unwrap();
+        assert!(archived.pending.is_empty());
+        assert!(archived.unavailable.is_empty());
+
+        azure
+            .upload_manifest(Some(&client), job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        assert_eq!(
+            azure.list_manifests(Some(&client)).await.unwrap(),
+            vec![format!("{job_id}/manifests/1.manifest")]
+        );
+        for name in chunks {
+            azure.delete(Some(&client), &name).await.unwrap();
+            // Deleting twice isn't an error
+            azure.delete(Some(&client), &name).await.unwrap();
         }
-        Ok(kfs)
+        azure
+            .delete_manifest(Some(&client), job_id, 1)
+            .await
+            .unwrap();
+        assert!(azure.list_chunks(&client, job_id).await.unwrap().is_empty());
+        client.delete().await.unwrap();
     }
 }
@@ -4,10 +4,13 @@
 
 use super::KipUploadOpts;
 use crate::chunk::FileChunk;
+use crate::limiter::KipRateLimiter;
 use crate::providers::KipProvider;
+use crate::retry::transient;
 use crate::run::KipUploadMsg;
 use anyhow::{bail, Result};
 use async_trait::async_trait;
+use bytes::Bytes;
 use directories::ProjectDirs;
 use drive3::api::{File, Scope};
 use drive3::hyper::client::HttpConnector;
@@ -17,6 +20,8 @@ use google_drive3 as drive3;
 use serde::{Deserialize, Serialize};
 use std::default::Default;
 use std::io::Cursor;
+use std::sync::OnceLock;
+use std::time::Duration;
 use tracing::debug;
 use uuid::Uuid;
 
@@ -27,8 +32,8 @@ pub struct KipGdrive {
 
 impl KipGdrive {
     // 20,000 API requests per 100 seconds
-    const _API_RATE_LIMIT: u64 = 20_000;
-    const _API_RATE_LIMIT_PERIOD: u64 = 100;
+    const API_RATE_LIMIT: u64 = 20_000;
+    const API_RATE_LIMIT_PERIOD: u64 = 100;
     // OAuth Client Settings
     const REDIRECT_URI: &'static str = "http://127.0.0.1";
     const AUTH_URI: &'static str = "https://accounts.google.com/o/oauth2/auth";
@@ -49,6 +54,61 @@ impl KipGdrive {
             }
         }
     }
+
+    /// Limits the requests made to Google Drive by every job
+    fn api_limiter() -> &'static KipRateLimiter {
+        static LIMITER: OnceLock<KipRateLimiter> = OnceLock::new();
+        LIMITER.get_or_init(|| {
+            KipRateLimiter::new(
+                Self::API_RATE_LIMIT,
+                Duration::from_secs(Self::API_RATE_LIMIT_PERIOD),
+            )
+        })
+    }
+
+    /// Returns the remote path of every chunk stored in the
+    /// job's folder, formatted the same as FileChunk.remote_path
+    pub async fn list_chunks(
+        &self,
+        hub: &DriveHub<HttpsConnector<HttpConnector>>,
+    ) -> Result<Vec<String>> {
+        let Some(parent_folder) = &self.parent_folder else {
+            // Nothing has been uploaded yet
+            return Ok(vec![]);
+        };
+        let mut chunks = Vec::<String>::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut req = hub
+                .files()
+                .list()
+                .q(&format!("'{parent_folder}' in parents and trashed = false"))
+                .supports_all_drives(true)
+                .spaces("drive")
+                .page_size(Self::LIST_PAGE_SIZE)
+                .include_items_from_all_drives(true);
+            if let Some(pt) = page_token.as_deref() {
+                req = req.page_token(pt);
+            }
+            Self::api_limiter().acquire(1).await;
+            let (_, file_list) = req.doit().await.map_err(gdrive_error)?;
+            if let Some(files) = file_list.files {
+                chunks.extend(
+                    files
+                        .into_iter()
+                        .filter_map(|f| f.name)
+                        .filter(|n| n.ends_with(".chunk"))
+                        .map(|n| format!("{parent_folder}/chunks/{n}")),
+                );
+            }
+            // Handle pagination
+            page_token = file_list.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(chunks)
+    }
 }
 
 #[async_trait]
@@ -56,12 +116,12 @@ impl KipProvider for KipGdrive {
     type Client = DriveHub<HttpsConnector<HttpConnector>>;
     type Item = File;
 
-    async fn upload<'b>(
+    async fn upload(
         &self,
         client: Option<&Self::Client>,
         opts: KipUploadOpts,
         chunk: &FileChunk,
-        chunk_bytes: &'b [u8],
+        chunk_bytes: Bytes,
     ) -> Result<usize> {
         if let Some(hub) = client {
             // Check if job's parent folder exists in gdrive
@@ -73,6 +133,7 @@ impl KipProvider for KipGdrive {
                     mime_type: Some("application/vnd.google-apps.folder".to_string()),
                     ..Default::default()
                 };
+                Self::api_limiter().acquire(1).await;
                 let (_, result) = hub
                     .files()
                     .create(req)
@@ -85,7 +146,8 @@ impl KipProvider for KipGdrive {
                         Cursor::new(vec![]),
                         "application/vnd.google-apps.folder".parse().unwrap(),
                     )
-                    .await?;
+                    .await
+                    .map_err(gdrive_error)?;
                 // Set parent_folder to returned folder ID
                 let job_folder = result.id.unwrap();
                 let req = File {
@@ -94,6 +156,7 @@ impl KipProvider for KipGdrive {
                     mime_type: Some("application/vnd.google-apps.folder".to_string()),
                     ..Default::default()
                 };
+                Self::api_limiter().acquire(1).await;
                 let (_, result) = hub
                     .files()
                     .create(req)
@@ -106,7 +169,8 @@ impl KipProvider for KipGdrive {
                         Cursor::new(vec![]),
                         "application/vnd.google-apps.folder".parse().unwrap(),
                     )
-                    .await?;
+                    .await
+                    .map_err(gdrive_error)?;
                 opts.msg_tx
                     .send(KipUploadMsg::GdriveParentFolder(result.id.unwrap()))?;
             }
@@ -119,6 +183,7 @@ impl KipProvider for KipGdrive {
                 parents: Some(vec![self.parent_folder.to_owned().unwrap()]),
                 ..Default::default()
             };
+            Self::api_limiter().acquire(1).await;
             let (_, _result) = hub
                 .files()
                 .create(req)
@@ -131,7 +196,8 @@ impl KipProvider for KipGdrive {
                     Cursor::new(chunk_bytes),
                     "application/octet-stream".parse().unwrap(),
                 )
-                .await?;
+                .await
+                .map_err(gdrive_error)?;
             Ok(ce_bytes_len)
         } else {
             bail!("gdrive client not provided")
@@ -149,20 +215,15 @@ impl KipProvider for KipGdrive {
                 .acknowledge_abuse(true)
                 .param("alt", "media");
             // Send request and parse response into Vec<u8>
+            Self::api_limiter().acquire(1).await;
             let result_bytes = match req.doit().await {
-                Ok((resp, _)) => Vec::from(hyper::body::to_bytes(resp.into_body()).await?),
-                Err(e) => match e {
-                    Error::HttpError(_)
-                    | Error::Io(_)
-                    | Error::MissingAPIKey
-                    | Error::MissingToken(_)
-                    | Error::Cancelled
-                    | Error::UploadSizeLimitExceeded(_, _)
-                    | Error::Failure(_)
-                    | Error::BadRequest(_)
-                    | Error::FieldClash(_)
-                    | Error::JsonDecodeError(_, _) => bail!("{e}"),
-                },
+                Ok((resp, _)) => Vec::from(
+                    // The connection dropped partway through the download
+                    hyper::body::to_bytes(resp.into_body())
+                        .await
+                        .map_err(transient)?,
+                ),
+                Err(e) => return Err(gdrive_error(e)),
             };
             // Return downloaded chunk bytes
             Ok(result_bytes)
@@ -174,20 +235,10 @@ impl KipProvider for KipGdrive {
     async fn delete(&self, client: Option<&Self::Client>, file_name: &str) -> Result<()> {
         if let Some(hub) = client {
             // Delete file
+            Self::api_limiter().acquire(1).await;
             match hub.files().delete(file_name).doit().await {
                 Ok(_) => Ok(()),
-                Err(e) => match e {
-                    Error::HttpError(_)
-                    | Error::Io(_)
-                    | Error::MissingAPIKey
-                    | Error::MissingToken(_)
-                    | Error::Cancelled
-                    | Error::UploadSizeLimitExceeded(_, _)
-                    | Error::Failure(_)
-                    | Error::BadRequest(_)
-                    | Error::FieldClash(_)
-                    | Error::JsonDecodeError(_, _) => bail!("{e}"),
-                },
+                Err(e) => Err(gdrive_error(e)),
             }
         } else {
             bail!("gdrive client not provided")
@@ -200,22 +251,29 @@ impl KipProvider for KipGdrive {
         _job_id: Uuid,
         hash: &str,
     ) -> Result<bool> {
-        // Check Google Drive for duplicates of chunk
-        let gdrive_objs = self.list_all(client, _job_id).await?;
-        // If Google Drive is empty, no need to check for duplicate chunks
-        if !gdrive_objs.is_empty() {
-            for obj in gdrive_objs {
-                if let Some(gd_name) = obj.name {
-                    if gd_name.contains(hash) {
-                        // Duplicate chunk found, return true
-                        return Ok(true);
-                    }
-                } else {
-                    bail!("unable to get chunk name from Google Drive")
-                }
-            }
+        if let Some(hub) = client {
+            // Query for the chunk by name rather than
+            // listing the whole drive
+            let Some(parent_folder) = &self.parent_folder else {
+                return Ok(false);
+            };
+            Self::api_limiter().acquire(1).await;
+            let (_, file_list) = hub
+                .files()
+                .list()
+                .q(&format!(
+                    "name = '{hash}.chunk' and '{parent_folder}' in parents and trashed = false"
+                ))
+                .supports_all_drives(true)
+                .spaces("drive")
+                .include_items_from_all_drives(true)
+                .doit()
+                .await
+                .map_err(gdrive_error)?;
+            Ok(!file_list.files.unwrap_or_default().is_empty())
+        } else {
+            bail!("gdrive client not provided")
         }
-        Ok(false)
     }
 
     async fn list_all(
@@ -235,6 +293,7 @@ impl KipProvider for KipGdrive {
                 .include_team_drive_items(false)
                 .include_items_from_all_drives(true);
             // Send request
+            Self::api_limiter().acquire(1).await;
             let gdrive_contents = match result.doit().await {
                 Ok((_, file_list)) => {
                     let mut filtered = match file_list.files {
@@ -247,6 +306,7 @@ impl KipProvider for KipGdrive {
                     // Handle pagination
                     let mut paginated = file_list.next_page_token;
                     while let Some(pcf) = paginated {
+                        Self::api_limiter().acquire(1).await;
                         let (_, paginated_result) = hub
                             .files()
                             .list()
@@ -258,7 +318,8 @@ impl KipProvider for KipGdrive {
                             .include_team_drive_items(false)
                             .include_items_from_all_drives(true)
                             .doit()
-                            .await?;
+                            .await
+                            .map_err(gdrive_error)?;
                         if let Some(prc) = paginated_result.files {
                             filtered.extend(
                                 prc.into_iter()
@@ -269,18 +330,7 @@ impl KipProvider for KipGdrive {
                     }
                     filtered
                 }
-                Err(e) => match e {
-                    Error::HttpError(_)
-                    | Error::Io(_)
-                    | Error::MissingAPIKey
-                    | Error::MissingToken(_)
-                    | Error::Cancelled
-                    | Error::UploadSizeLimitExceeded(_, _)
-                    | Error::Failure(_)
-                    | Error::BadRequest(_)
-                    | Error::FieldClash(_)
-                    | Error::JsonDecodeError(_, _) => bail!("{e}"),
-                },
+                Err(e) => return Err(gdrive_error(e)),
             };
             // Only check chunks that are within this job's
             // folder in Gdrive
@@ -306,6 +356,155 @@ impl KipProvider for KipGdrive {
             bail!("gdrive client not provided")
         }
     }
+
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        if let Some(hub) = client {
+            // Google Drive has no paths, so the job ID
+            // is kept in the manifest's name
+            let req = File {
+                name: Some(format!("{job_id}.{run_id}.manifest")),
+                parents: self.parent_folder.to_owned().map(|pf| vec![pf]),
+                ..Default::default()
+            };
+            Self::api_limiter().acquire(1).await;
+            hub.files()
+                .create(req)
+                .add_scope(Scope::File)
+                .use_content_as_indexable_text(false)
+                .supports_all_drives(false)
+                .keep_revision_forever(false)
+                .ignore_default_visibility(true)
+                .upload(
+                    Cursor::new(manifest_bytes),
+                    "application/octet-stream".parse().unwrap(),
+                )
+                .await
+                .map_err(gdrive_error)?;
+            Ok(manifest_bytes.len())
+        } else {
+            bail!("gdrive client not provided")
+        }
+    }
+
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>> {
+        if let Some(hub) = client {
+            let mut manifests = Vec::<String>::new();
+            let mut page_token: Option<String> = None;
+            loop {
+                let mut req = hub
+                    .files()
+                    .list()
+                    .q("name contains '.manifest' and trashed = false")
+                    .supports_all_drives(true)
+                    .spaces("drive")
+                    .page_size(Self::LIST_PAGE_SIZE)
+                    .include_items_from_all_drives(true);
+                if let Some(pt) = page_token.as_deref() {
+                    req = req.page_token(pt);
+                }
+                Self::api_limiter().acquire(1).await;
+                let (_, file_list) = req.doit().await.map_err(gdrive_error)?;
+                if let Some(files) = file_list.files {
+                    // Downloads are done by file ID
+                    manifests.extend(
+                        files
+                            .into_iter()
+                            .filter(|f| {
+                                f.name
+                                    .as_deref()
+                                    .map(|n| n.ends_with(".manifest"))
+                                    .unwrap_or(false)
+                            })
+                            .filter_map(|f| f.id),
+                    );
+                }
+                // Handle pagination
+                page_token = file_list.next_page_token;
+                if page_token.is_none() {
+                    break;
+                }
+            }
+            Ok(manifests)
+        } else {
+            bail!("gdrive client not provided")
+        }
+    }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        if let Some(hub) = client {
+            // Manifests are deleted by file ID, so look them up by name
+            Self::api_limiter().acquire(1).await;
+            let (_, file_list) = hub
+                .files()
+                .list()
+                .q(&format!(
+                    "name = '{job_id}.{run_id}.manifest' and trashed = false"
+                ))
+                .supports_all_drives(true)
+                .spaces("drive")
+                .include_items_from_all_drives(true)
+                .doit()
+                .await
+                .map_err(gdrive_error)?;
+            for id in file_list
+                .files
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|f| f.id)
+            {
+                self.delete(client, &id).await?;
+            }
+            Ok(())
+        } else {
+            bail!("gdrive client not provided")
+        }
+    }
+}
+
+// Reasons Drive gives when a request is throttled or
+// it's briefly unable to serve it
+const GDRIVE_TRANSIENT_REASONS: [&str; 4] = [
+    "rateLimitExceeded",
+    "userRateLimitExceeded",
+    "backendError",
+    "internalError",
+];
+
+/// Marks Drive errors that may succeed if retried as transient:
+/// dropped connections, 5xx and 429 responses and rate limit
+/// errors, which Drive also returns as a 403
+fn gdrive_error(e: Error) -> anyhow::Error {
+    let is_transient = match &e {
+        Error::HttpError(_) | Error::Io(_) => true,
+        Error::Failure(resp) => resp.status().is_server_error() || resp.status().as_u16() == 429,
+        Error::BadRequest(body) => {
+            let code = body["error"]["code"].as_u64().unwrap_or_default();
+            let mut reasons = body["error"]["errors"].as_array().into_iter().flatten();
+            code >= 500
+                || code == 429
+                || reasons.any(|r| {
+                    r["reason"]
+                        .as_str()
+                        .is_some_and(|reason| GDRIVE_TRANSIENT_REASONS.contains(&reason))
+                })
+        }
+        _ => false,
+    };
+    match is_transient {
+        true => transient(e),
+        false => e.into(),
+    }
 }
 
 pub async fn generate_gdrive_hub() -> Result<DriveHub<HttpsConnector<HttpConnector>>> {
@@ -0,0 +1,630 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use super::KipUploadOpts;
+use crate::chunk::FileChunk;
+use crate::providers::KipProvider;
+use crate::retry::is_transient;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{create_dir_all, File};
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+use uuid::Uuid;
+use walkdir::WalkDir;
+use zeroize::Zeroizing;
+
+// Files being written are named .<name>.<id>.tmp until
+// they're complete
+const TMP_EXTENSION: &str = "tmp";
+
+/// Any directory, such as an NFS or SMB mount, a second
+/// disk or a temporary directory
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KipLocal {
+    pub root_path: PathBuf,
+}
+
+impl KipLocal {
+    pub fn new<P: AsRef<Path>>(root_path: P) -> Self {
+        Self {
+            root_path: root_path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Chunk and manifest paths are stored relative to the
+    /// root so the directory can be mounted elsewhere
+    fn resolve_path(&self, remote_path: &str) -> PathBuf {
+        self.root_path.join(remote_path)
+    }
+
+    /// Returns the path of every chunk stored for a job,
+    /// relative to the root
+    pub async fn list_chunks(&self, job_id: Uuid) -> Result<Vec<String>> {
+        self.list_all(None, job_id).await
+    }
+
+    /// Removes the temporary files of writes a job left
+    /// unfinished, such as when kip crashed mid-upload.
+    /// Returns how many were removed.
+    pub async fn remove_partial_writes(&self, job_id: Uuid) -> Result<usize> {
+        let job_dir = self.root_path.join(job_id.to_string());
+        if !job_dir.exists() {
+            return Ok(0);
+        }
+        let mut removed: usize = 0;
+        for entry in WalkDir::new(job_dir) {
+            let entry = entry?;
+            if entry.file_type().is_file() && is_tmp_file(&entry.file_name().to_string_lossy()) {
+                debug!("removing partial write {}", entry.path().display());
+                tokio::fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl KipProvider for KipLocal {
+    type Client = ();
+    type Item = String;
+
+    async fn upload(
+        &self,
+        _client: Option<&Self::Client>,
+        opts: KipUploadOpts,
+        chunk: &FileChunk,
+        chunk_bytes: Bytes,
+    ) -> Result<usize> {
+        let path = self.resolve_path(&chunk_path(opts.job_id, &chunk.hash));
+        write_atomic(&path, &chunk_bytes).await?;
+        Ok(chunk_bytes.len())
+    }
+
+    async fn download(&self, _client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.resolve_path(file_name)).await?)
+    }
+
+    async fn delete(&self, _client: Option<&Self::Client>, file_name: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.resolve_path(file_name)).await {
+            Ok(_) => Ok(()),
+            // Already deleted by an earlier attempt
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn contains(
+        &self,
+        _client: Option<&Self::Client>,
+        job_id: Uuid,
+        hash: &str,
+    ) -> Result<bool> {
+        Ok(self.resolve_path(&chunk_path(job_id, hash)).exists())
+    }
+
+    async fn list_all(
+        &self,
+        _client: Option<&Self::Client>,
+        job_id: Uuid,
+    ) -> Result<Vec<Self::Item>> {
+        let chunks_dir = self.root_path.join(format!("{job_id}/chunks"));
+        // Nothing has been uploaded yet
+        if !chunks_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut chunks = Vec::<String>::new();
+        // Chunks live at <root>/<job_id>/chunks/<shard>/<hash>.chunk
+        for entry in WalkDir::new(chunks_dir).min_depth(2).max_depth(2) {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type().is_file() || is_tmp_file(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let shard = path
+                .parent()
+                .and_then(|p| p.file_name())
+                .and_then(|p| p.to_str());
+            let name = path.file_name().and_then(|n| n.to_str());
+            if let (Some(shard), Some(name)) = (shard, name) {
+                if name.ends_with(".chunk") {
+                    chunks.push(format!("{job_id}/chunks/{shard}/{name}"));
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    async fn upload_manifest<'b>(
+        &self,
+        _client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        let path = self.resolve_path(&format!("{job_id}/manifests/{run_id}.manifest"));
+        write_atomic(&path, manifest_bytes).await?;
+        Ok(manifest_bytes.len())
+    }
+
+    async fn list_manifests(&self, _client: Option<&Self::Client>) -> Result<Vec<String>> {
+        let mut manifests = Vec::<String>::new();
+        // Manifests live at <root>/<job_id>/manifests/<run_id>.manifest
+        for entry in WalkDir::new(&self.root_path).min_depth(3).max_depth(3) {
+            let entry = entry?;
+            let path = entry.path();
+            let in_manifests = path
+                .parent()
+                .and_then(|p| p.file_name())
+                .map(|p| p == "manifests")
+                .unwrap_or(false);
+            if !entry.file_type().is_file()
+                || !in_manifests
+                || path.extension().unwrap_or_default() != "manifest"
+            {
+                continue;
+            }
+            let job_id = path
+                .parent()
+                .and_then(|p| p.parent())
+                .and_then(|p| p.file_name())
+                .and_then(|p| p.to_str());
+            let name = path.file_name().and_then(|n| n.to_str());
+            if let (Some(job_id), Some(name)) = (job_id, name) {
+                manifests.push(format!("{job_id}/manifests/{name}"));
+            }
+        }
+        Ok(manifests)
+    }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        // The run's manifest may never have been uploaded
+        self.delete(client, &format!("{job_id}/manifests/{run_id}.manifest"))
+            .await
+    }
+}
+
+/// Returns a chunk's path relative to the root. Chunks are
+/// sharded into 256 directories by the first two characters
+/// of their hash so no directory holds millions of files.
+/// Ex: <job_id>/chunks/00/001d46082763b930e5b9f0c52d16841b.chunk
+pub fn chunk_path(job_id: Uuid, hash: &str) -> String {
+    let shard = hash.get(..2).unwrap_or("00");
+    format!("{job_id}/chunks/{shard}/{hash}.chunk")
+}
+
+/// Returns the name a file is written under until it's
+/// complete. Ex: .<hash>.chunk.<id>.tmp
+pub(crate) fn tmp_name(name: &str) -> String {
+    format!(".{name}.{}.{TMP_EXTENSION}", Uuid::new_v4().simple())
+}
+
+/// Whether a file is the temporary file of an unfinished write
+pub(crate) fn is_tmp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(&format!(".{TMP_EXTENSION}"))
+}
+
+/// A directory on a server reached through a blocking client
+/// library, such as over SFTP or SMB. Paths are absolute on
+/// the server and separated by slashes.
+pub(crate) trait KipRemoteFs: Clone + Send + 'static {
+    type Session: Send + 'static;
+
+    /// Opens a connection to the server
+    fn connect(&self, secret: &str) -> Result<Self::Session>;
+    /// Returns the path on the server of a chunk or manifest
+    fn resolve_path(&self, remote_path: &str) -> String;
+    /// Whether a file or directory exists
+    fn exists(&self, session: &Self::Session, path: &str) -> Result<bool>;
+    fn is_dir(&self, session: &Self::Session, path: &str) -> bool;
+    fn mkdir(&self, session: &Self::Session, path: &str) -> Result<()>;
+    /// Lists a directory's entries as (name, is_dir), or
+    /// nothing if it doesn't exist
+    fn read_dir(&self, session: &Self::Session, dir: &str) -> Result<Vec<(String, bool)>>;
+    fn read(&self, session: &Self::Session, path: &str) -> Result<Vec<u8>>;
+    /// Creates or truncates a file and writes the bytes to it
+    fn write(&self, session: &Self::Session, path: &str, bytes: &[u8]) -> Result<()>;
+    /// Renames a file, replacing the destination
+    fn rename(&self, session: &Self::Session, from: &str, to: &str) -> Result<()>;
+    /// Removes a file. Files that don't exist are ignored.
+    fn unlink(&self, session: &Self::Session, path: &str) -> Result<()>;
+
+    /// Writes a file so it's either complete or missing. The
+    /// bytes are written to a temporary file next to it, which
+    /// is then renamed over the destination.
+    fn write_atomic(&self, session: &Self::Session, path: &str, bytes: &[u8]) -> Result<()> {
+        let Some((dir, name)) = path.rsplit_once('/') else {
+            bail!("invalid path {path}")
+        };
+        self.create_dir_all(session, dir)?;
+        let tmp_path = format!("{dir}/{}", tmp_name(name));
+        let result = self
+            .write(session, &tmp_path, bytes)
+            .and_then(|_| self.rename(session, &tmp_path, path));
+        if result.is_err() {
+            let _ = self.unlink(session, &tmp_path);
+        }
+        result
+    }
+
+    /// Creates a directory and its missing parents
+    fn create_dir_all(&self, session: &Self::Session, dir: &str) -> Result<()> {
+        let mut path = String::new();
+        for component in dir.split('/').filter(|c| !c.is_empty()) {
+            if !path.is_empty() || dir.starts_with('/') {
+                path.push('/');
+            }
+            path.push_str(component);
+            if self.exists(session, &path).unwrap_or(false) {
+                continue;
+            }
+            if let Err(e) = self.mkdir(session, &path) {
+                // Another upload may have just created it
+                if !self.is_dir(session, &path) {
+                    return Err(e).with_context(|| format!("unable to create {path}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path of every chunk stored for a job,
+    /// relative to the root directory
+    fn list_chunks(&self, session: &Self::Session, job_id: Uuid) -> Result<Vec<String>> {
+        // Chunks live at <root>/<job_id>/chunks/<shard>/<hash>.chunk
+        let chunks_dir = self.resolve_path(&format!("{job_id}/chunks"));
+        let mut chunks = Vec::<String>::new();
+        for (shard, is_dir) in self.read_dir(session, &chunks_dir)? {
+            if !is_dir {
+                continue;
+            }
+            for (name, is_dir) in self.read_dir(session, &format!("{chunks_dir}/{shard}"))? {
+                if !is_dir && !is_tmp_file(&name) && name.ends_with(".chunk") {
+                    chunks.push(format!("{job_id}/chunks/{shard}/{name}"));
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Returns the path of every job's manifests, relative
+    /// to the root directory
+    fn list_manifests(&self, session: &Self::Session) -> Result<Vec<String>> {
+        // Manifests live at <root>/<job_id>/manifests/<run_id>.manifest
+        let mut manifests = Vec::<String>::new();
+        for (job_id, is_dir) in self.read_dir(session, &self.resolve_path(""))? {
+            if !is_dir {
+                continue;
+            }
+            let dir = self.resolve_path(&format!("{job_id}/manifests"));
+            for (name, is_dir) in self.read_dir(session, &dir)? {
+                if !is_dir && !is_tmp_file(&name) && name.ends_with(".manifest") {
+                    manifests.push(format!("{job_id}/manifests/{name}"));
+                }
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Removes the temporary files of writes a job left
+    /// unfinished, such as when kip crashed mid-upload.
+    /// Returns how many were removed.
+    fn remove_partial_writes(&self, session: &Self::Session, job_id: Uuid) -> Result<usize> {
+        let chunks_dir = self.resolve_path(&format!("{job_id}/chunks"));
+        let mut dirs = vec![self.resolve_path(&format!("{job_id}/manifests"))];
+        for (shard, is_dir) in self.read_dir(session, &chunks_dir)? {
+            if is_dir {
+                dirs.push(format!("{chunks_dir}/{shard}"));
+            }
+        }
+        let mut removed: usize = 0;
+        for dir in dirs {
+            for (name, is_dir) in self.read_dir(session, &dir)? {
+                if !is_dir && is_tmp_file(&name) {
+                    debug!("removing partial write {name}");
+                    self.unlink(session, &format!("{dir}/{name}"))?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// A connection to a KipRemoteFs server shared by every
+/// request of a run. It's opened on first use and reopened
+/// if the connection drops.
+pub struct KipRemoteClient<S> {
+    conn: Arc<Mutex<Option<S>>>,
+    secret: Arc<Zeroizing<String>>,
+}
+
+impl<S> KipRemoteClient<S> {
+    /// Reads the password or passphrase from an env var
+    pub fn from_env(var: &str) -> Self {
+        Self {
+            conn: Arc::new(Mutex::new(None)),
+            secret: Arc::new(Zeroizing::new(std::env::var(var).unwrap_or_default())),
+        }
+    }
+}
+
+impl<S> Clone for KipRemoteClient<S> {
+    fn clone(&self) -> Self {
+        Self {
+            conn: Arc::clone(&self.conn),
+            secret: Arc::clone(&self.secret),
+        }
+    }
+}
+
+/// Runs a request on the client's connection, opening one
+/// first if needed. The client libraries block, so requests
+/// run on tokio's blocking threads, one at a time per
+/// connection. Connections that dropped are closed so the
+/// retry reconnects.
+pub(crate) async fn request<R, T, F>(
+    remote: &R,
+    client: &KipRemoteClient<R::Session>,
+    op: F,
+) -> Result<T>
+where
+    R: KipRemoteFs,
+    T: Send + 'static,
+    F: FnOnce(&R, &R::Session) -> Result<T> + Send + 'static,
+{
+    let remote = remote.clone();
+    let client = client.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = match client.conn.lock() {
+            Ok(conn) => conn,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let session = match conn.take() {
+            Some(session) => session,
+            None => remote.connect(&client.secret)?,
+        };
+        let result = op(&remote, &session);
+        match &result {
+            Err(e) if is_transient(e) => debug!("closing connection: {e}"),
+            _ => *conn = Some(session),
+        }
+        result
+    })
+    .await?
+}
+
+/// Writes a file so it's either complete or missing, never
+/// partially written. The bytes are written to a temporary
+/// file in the same directory, synced, and renamed over the
+/// destination. The directory is then synced so the rename
+/// survives a crash.
+async fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("invalid path {}", path.display())
+    };
+    create_dir_all(dir).await?;
+    let tmp_path = dir.join(tmp_name(&name.to_string_lossy()));
+    let result = async {
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(bytes).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&tmp_path, path).await?;
+        sync_dir(dir).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    result
+}
+
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+// Directories can't be opened as files on Windows,
+// where the rename is already durable once it returns
+#[cfg(not(unix))]
+async fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_path() {
+        let job_id = Uuid::nil();
+        assert_eq!(
+            chunk_path(job_id, "001d4608"),
+            format!("{job_id}/chunks/00/001d4608.chunk")
+        );
+        assert_eq!(
+            chunk_path(job_id, "a"),
+            format!("{job_id}/chunks/00/a.chunk")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("a/b/c.manifest");
+        write_atomic(&path, b"kip").await.unwrap();
+        write_atomic(&path, b"kip2").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"kip2");
+        // No temporary files are left behind
+        let files = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn test_tmp_file() {
+        let name = tmp_name("abc.chunk");
+        assert!(name.starts_with(".abc.chunk."));
+        assert!(is_tmp_file(&name));
+        assert!(!is_tmp_file("abc.chunk"));
+        assert!(!is_tmp_file("abc.tmp"));
+    }
+
+    // A KipRemoteFs on the local disk
+    #[derive(Clone)]
+    struct TestFs(String);
+
+    impl KipRemoteFs for TestFs {
+        type Session = ();
+
+        fn connect(&self, _secret: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn resolve_path(&self, remote_path: &str) -> String {
+            format!("{}/{remote_path}", self.0)
+        }
+
+        fn exists(&self, _session: &(), path: &str) -> Result<bool> {
+            Ok(Path::new(path).exists())
+        }
+
+        fn is_dir(&self, _session: &(), path: &str) -> bool {
+            Path::new(path).is_dir()
+        }
+
+        fn mkdir(&self, _session: &(), path: &str) -> Result<()> {
+            Ok(std::fs::create_dir(path)?)
+        }
+
+        fn read_dir(&self, _session: &(), dir: &str) -> Result<Vec<(String, bool)>> {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                return Ok(vec![]);
+            };
+            entries
+                .map(|entry| {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    Ok((name, entry.file_type()?.is_dir()))
+                })
+                .collect()
+        }
+
+        fn read(&self, _session: &(), path: &str) -> Result<Vec<u8>> {
+            Ok(std::fs::read(path)?)
+        }
+
+        fn write(&self, _session: &(), path: &str, bytes: &[u8]) -> Result<()> {
+            Ok(std::fs::write(path, bytes)?)
+        }
+
+        fn rename(&self, _session: &(), from: &str, to: &str) -> Result<()> {
+            Ok(std::fs::rename(from, to)?)
+        }
+
+        fn unlink(&self, _session: &(), path: &str) -> Result<()> {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_remote_fs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fs = TestFs(tmp_dir.path().to_string_lossy().to_string());
+        let job_id = Uuid::new_v4();
+        let remote_path = chunk_path(job_id, "abc123");
+        fs.write_atomic(&(), &fs.resolve_path(&remote_path), b"kip")
+            .unwrap();
+        let manifest = format!("{job_id}/manifests/1.manifest");
+        fs.write_atomic(&(), &fs.resolve_path(&manifest), b"manifest")
+            .unwrap();
+        fs.write_atomic(&(), &fs.resolve_path(&manifest), b"manifest2")
+            .unwrap();
+        assert_eq!(
+            fs.read(&(), &fs.resolve_path(&manifest)).unwrap(),
+            b"manifest2"
+        );
+
+        // Writes interrupted by a crash are cleaned up
+        // and never listed
+        let partial = fs.resolve_path(&format!("{job_id}/chunks/ab/{}", tmp_name("abd.chunk")));
+        fs.write(&(), &partial, b"ki").unwrap();
+        assert_eq!(fs.list_chunks(&(), job_id).unwrap(), vec![remote_path]);
+        assert_eq!(fs.list_manifests(&()).unwrap(), vec![manifest]);
+        assert_eq!(fs.remove_partial_writes(&(), job_id).unwrap(), 1);
+        assert!(!fs.exists(&(), &partial).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_local_provider() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let local = KipLocal::new(tmp_dir.path());
+        let job_id = Uuid::new_v4();
+        assert!(local.list_chunks(job_id).await.unwrap().is_empty());
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let chunk = FileChunk::new("test/random.txt", "abc123", 0, 3, 3);
+        let uploaded = local
+            .upload(
+                None,
+                KipUploadOpts::new(job_id, tx),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 3);
+        let remote_path = chunk_path(job_id, "abc123");
+        assert!(tmp_dir.path().join(&remote_path).exists());
+        assert!(local.contains(None, job_id, "abc123").await.unwrap());
+        assert!(!local.contains(None, job_id, "def456").await.unwrap());
+        assert_eq!(
+            local.list_chunks(job_id).await.unwrap(),
+            vec![remote_path.clone()]
+        );
+        assert_eq!(local.download(None, &remote_path).await.unwrap(), b"kip");
+
+        local
+            .upload_manifest(None, job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        assert_eq!(
+            local.list_manifests(None).await.unwrap(),
+            vec![format!("{job_id}/manifests/1.manifest")]
+        );
+
+        // Writes interrupted by a crash are cleaned up
+        // and never listed
+        let partial = tmp_dir
+            .path()
+            .join(format!("{job_id}/chunks/ab/.abc.chunk.0.{TMP_EXTENSION}"));
+        std::fs::create_dir_all(partial.parent().unwrap()).unwrap();
+        std::fs::write(&partial, b"ki").unwrap();
+        assert_eq!(local.list_chunks(job_id).await.unwrap().len(), 1);
+        assert_eq!(local.remove_partial_writes(job_id).await.unwrap(), 1);
+        assert!(!partial.exists());
+
+        local.delete(None, &remote_path).await.unwrap();
+        // Deleting twice isn't an error
+        local.delete(None, &remote_path).await.unwrap();
+        local.delete_manifest(None, job_id, 1).await.unwrap();
+        assert!(local.list_chunks(job_id).await.unwrap().is_empty());
+        assert!(local.list_manifests(None).await.unwrap().is_empty());
+    }
+}
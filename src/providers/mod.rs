@@ -2,22 +2,35 @@
 // Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
+pub mod azure;
 pub mod gdrive;
+pub mod local;
 pub mod s3;
+pub mod sftp;
+pub mod smb;
 pub mod usb;
-// pub mod smb;
+pub mod webdav;
 
+use self::azure::KipAzure;
 use self::gdrive::KipGdrive;
+use self::local::KipLocal;
 use self::s3::KipS3;
+use self::sftp::{KipSftp, KipSftpClient, SFTP_SECRET_VAR};
+use self::smb::{KipSmb, KipSmbClient, SMB_SECRET_VAR};
 use self::usb::KipUsb;
+use self::webdav::{KipWebDav, KipWebDavClient};
 use crate::chunk::FileChunk;
+use crate::retry::KipRetryOpts;
 use crate::run::KipUploadMsg;
 use anyhow::{bail, Result};
 use async_trait::async_trait;
+use azure_storage_blobs::prelude::ContainerClient;
+use bytes::Bytes;
 use google_drive3::hyper::client::HttpConnector;
 use google_drive3::{hyper_rustls::HttpsConnector, DriveHub};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender;
+use tracing::debug;
 use uuid::Uuid;
 
 #[async_trait]
@@ -25,17 +38,31 @@ pub trait KipProvider {
     type Client;
     type Item;
 
-    async fn upload<'b>(
+    async fn upload(
         &self,
         client: Option<&Self::Client>,
         opts: KipUploadOpts,
         chunk: &FileChunk,
-        chunk_bytes: &'b [u8],
+        chunk_bytes: Bytes,
     ) -> Result<usize>;
     async fn download(&self, client: Option<&Self::Client>, source: &str) -> Result<Vec<u8>>;
     async fn delete(&self, client: Option<&Self::Client>, remote_path: &str) -> Result<()>;
     async fn contains(&self, client: Option<&Self::Client>, job: Uuid, hash: &str) -> Result<bool>;
     async fn list_all(&self, client: Option<&Self::Client>, job: Uuid) -> Result<Vec<Self::Item>>;
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job: Uuid,
+        run: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize>;
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>>;
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job: Uuid,
+        run: u64,
+    ) -> Result<()>;
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -43,6 +70,11 @@ pub enum KipProviders {
     S3(KipS3),
     Usb(KipUsb),
     Gdrive(KipGdrive),
+    Local(KipLocal),
+    Sftp(KipSftp),
+    Smb(KipSmb),
+    Azure(KipAzure),
+    WebDav(KipWebDav),
 }
 
 impl KipProviders {
@@ -54,15 +86,45 @@ impl KipProviders {
                 .parent_folder
                 .clone()
                 .unwrap_or(String::from("Google Drive")),
+            Self::Local(local) => local.root_path.display().to_string(),
+            Self::Sftp(sftp) => format!("{}@{}", sftp.username, sftp.host),
+            Self::Smb(smb) => smb.location(),
+            Self::Azure(azure) => format!("{}/{}", azure.account, azure.container),
+            Self::WebDav(webdav) => webdav.url.clone(),
         }
     }
 
-    pub async fn upload<'b>(
+    /// Uploads a chunk, retrying transient failures. Each retry
+    /// is reported to the run so it's recorded in its logs.
+    pub async fn upload(
+        &self,
+        client: &KipClient,
+        opts: KipUploadOpts,
+        chunk: &FileChunk,
+        chunk_bytes: Bytes,
+    ) -> Result<usize> {
+        let msg_tx = opts.msg_tx.clone();
+        KipRetryOpts::configured()
+            .retry(
+                &format!("upload of chunk {}", chunk.hash),
+                |attempt, e| {
+                    let _ = msg_tx.send(KipUploadMsg::Retry {
+                        hash: chunk.hash.clone(),
+                        attempt,
+                        error: e.to_string(),
+                    });
+                },
+                move || self.try_upload(client, opts.clone(), chunk, chunk_bytes.clone()),
+            )
+            .await
+    }
+
+    async fn try_upload(
         &self,
         client: &KipClient,
         opts: KipUploadOpts,
         chunk: &FileChunk,
-        chunk_bytes: &'b [u8],
+        chunk_bytes: Bytes,
     ) -> Result<usize> {
         match self {
             Self::S3(s3) => match client {
@@ -73,7 +135,45 @@ impl KipProviders {
                     bail!("s3 client not provided")
                 }
             },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(azure_client) => {
+                    azure
+                        .upload(Some(azure_client), opts, chunk, chunk_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(webdav_client) => {
+                    webdav
+                        .upload(Some(webdav_client), opts, chunk, chunk_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
             Self::Usb(usb) => usb.upload(None, opts, chunk, chunk_bytes).await,
+            Self::Local(local) => local.upload(None, opts, chunk, chunk_bytes).await,
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(sftp_client) => {
+                    sftp.upload(Some(sftp_client), opts, chunk, chunk_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(smb_client) => {
+                    smb.upload(Some(smb_client), opts, chunk, chunk_bytes).await
+                }
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
             Self::Gdrive(gdrive) => match client {
                 KipClient::Gdrive(gdrive_client) => {
                     gdrive
@@ -88,6 +188,16 @@ impl KipProviders {
     }
 
     pub async fn download<'b>(&self, client: &KipClient, file_name: &str) -> Result<Vec<u8>> {
+        KipRetryOpts::configured()
+            .retry(
+                &format!("download of {file_name}"),
+                |_, _| {},
+                || self.try_download(client, file_name),
+            )
+            .await
+    }
+
+    async fn try_download(&self, client: &KipClient, file_name: &str) -> Result<Vec<u8>> {
         match self {
             Self::S3(s3) => match client {
                 KipClient::S3(client) => s3.download(Some(client), file_name).await,
@@ -95,7 +205,32 @@ impl KipProviders {
                     bail!("s3 client not provided")
                 }
             },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => azure.download(Some(client), file_name).await,
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(client) => webdav.download(Some(client), file_name).await,
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
             Self::Usb(usb) => usb.download(None, file_name).await,
+            Self::Local(local) => local.download(None, file_name).await,
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => sftp.download(Some(client), file_name).await,
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => smb.download(Some(client), file_name).await,
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
             Self::Gdrive(gdrive) => match client {
                 KipClient::Gdrive(client) => gdrive.download(Some(client), file_name).await,
                 _ => {
@@ -106,6 +241,16 @@ impl KipProviders {
     }
 
     pub async fn delete(&self, client: &KipClient, remote_path: &str) -> Result<()> {
+        KipRetryOpts::configured()
+            .retry(
+                &format!("delete of {remote_path}"),
+                |_, _| {},
+                || self.try_delete(client, remote_path),
+            )
+            .await
+    }
+
+    async fn try_delete(&self, client: &KipClient, remote_path: &str) -> Result<()> {
         match self {
             Self::S3(s3) => match client {
                 KipClient::S3(client) => s3.delete(Some(client), remote_path).await,
@@ -113,7 +258,32 @@ impl KipProviders {
                     bail!("s3 client not provided")
                 }
             },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => azure.delete(Some(client), remote_path).await,
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(client) => webdav.delete(Some(client), remote_path).await,
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
             Self::Usb(usb) => usb.delete(None, remote_path).await,
+            Self::Local(local) => local.delete(None, remote_path).await,
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => sftp.delete(Some(client), remote_path).await,
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => smb.delete(Some(client), remote_path).await,
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
             Self::Gdrive(gdrive) => match client {
                 KipClient::Gdrive(client) => gdrive.delete(Some(client), remote_path).await,
                 _ => {
@@ -123,17 +293,436 @@ impl KipProviders {
         }
     }
 
+    /// Deletes a chunk no run references anymore. Jobs using S3
+    /// Object Lock only delete chunks whose lock has expired, and
+    /// false is returned for the ones still locked.
+    pub async fn prune_chunk(&self, client: &KipClient, remote_path: &str) -> Result<bool> {
+        match self {
+            Self::S3(s3) if s3.object_lock.is_some() => match client {
+                KipClient::S3(client) => {
+                    KipRetryOpts::configured()
+                        .retry(
+                            &format!("prune of {remote_path}"),
+                            |_, _| {},
+                            || s3.delete_expired(client, remote_path),
+                        )
+                        .await
+                }
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            _ => {
+                self.delete(client, remote_path).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Extends the Object Lock of the chunks a new run reuses to
+    /// the run's retain-until date. Only S3 locks objects.
+    pub async fn extend_locks(&self, client: &KipClient, remote_paths: &[String]) -> Result<()> {
+        match self {
+            Self::S3(s3) if s3.object_lock.is_some() => match client {
+                KipClient::S3(client) => s3.extend_locks(client, remote_paths).await,
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Extends the Object Lock of a run's manifest, such as when
+    /// a run with no changes leaves it as the job's latest
+    pub async fn extend_manifest_lock(
+        &self,
+        client: &KipClient,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        self.extend_locks(client, &[s3::manifest_key(job_id, run_id)])
+            .await
+    }
+
+    pub async fn upload_manifest<'b>(
+        &self,
+        client: &KipClient,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        KipRetryOpts::configured()
+            .retry(
+                &format!("upload of manifest {job_id}-{run_id}"),
+                |_, _| {},
+                || self.try_upload_manifest(client, job_id, run_id, manifest_bytes),
+            )
+            .await
+    }
+
+    async fn try_upload_manifest(
+        &self,
+        client: &KipClient,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &[u8],
+    ) -> Result<usize> {
+        match self {
+            Self::S3(s3) => match client {
+                KipClient::S3(client) => {
+                    s3.upload_manifest(Some(client), job_id, run_id, manifest_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => {
+                    azure
+                        .upload_manifest(Some(client), job_id, run_id, manifest_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(client) => {
+                    webdav
+                        .upload_manifest(Some(client), job_id, run_id, manifest_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
+            Self::Usb(usb) => {
+                usb.upload_manifest(None, job_id, run_id, manifest_bytes)
+                    .await
+            }
+            Self::Local(local) => {
+                local
+                    .upload_manifest(None, job_id, run_id, manifest_bytes)
+                    .await
+            }
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => {
+                    sftp.upload_manifest(Some(client), job_id, run_id, manifest_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => {
+                    smb.upload_manifest(Some(client), job_id, run_id, manifest_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
+            Self::Gdrive(gdrive) => match client {
+                KipClient::Gdrive(client) => {
+                    gdrive
+                        .upload_manifest(Some(client), job_id, run_id, manifest_bytes)
+                        .await
+                }
+                _ => {
+                    bail!("gdrive client not provided")
+                }
+            },
+        }
+    }
+
+    /// Returns the remote path of every manifest stored
+    /// in the provider, for any job.
+    pub async fn list_manifests(&self, client: &KipClient) -> Result<Vec<String>> {
+        KipRetryOpts::configured()
+            .retry(
+                "listing of manifests",
+                |_, _| {},
+                || self.try_list_manifests(client),
+            )
+            .await
+    }
+
+    async fn try_list_manifests(&self, client: &KipClient) -> Result<Vec<String>> {
+        match self {
+            Self::S3(s3) => match client {
+                KipClient::S3(client) => s3.list_manifests(Some(client)).await,
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => azure.list_manifests(Some(client)).await,
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(client) => webdav.list_manifests(Some(client)).await,
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
+            Self::Usb(usb) => usb.list_manifests(None).await,
+            Self::Local(local) => local.list_manifests(None).await,
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => sftp.list_manifests(Some(client)).await,
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => smb.list_manifests(Some(client)).await,
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
+            Self::Gdrive(gdrive) => match client {
+                KipClient::Gdrive(client) => gdrive.list_manifests(Some(client)).await,
+                _ => {
+                    bail!("gdrive client not provided")
+                }
+            },
+        }
+    }
+
+    pub async fn delete_manifest(
+        &self,
+        client: &KipClient,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        KipRetryOpts::configured()
+            .retry(
+                &format!("delete of manifest {job_id}-{run_id}"),
+                |_, _| {},
+                || self.try_delete_manifest(client, job_id, run_id),
+            )
+            .await
+    }
+
+    async fn try_delete_manifest(
+        &self,
+        client: &KipClient,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        match self {
+            Self::S3(s3) => match client {
+                KipClient::S3(client) => s3.delete_manifest(Some(client), job_id, run_id).await,
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => {
+                    azure.delete_manifest(Some(client), job_id, run_id).await
+                }
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(client) => {
+                    webdav.delete_manifest(Some(client), job_id, run_id).await
+                }
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
+            Self::Usb(usb) => usb.delete_manifest(None, job_id, run_id).await,
+            Self::Local(local) => local.delete_manifest(None, job_id, run_id).await,
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => sftp.delete_manifest(Some(client), job_id, run_id).await,
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => smb.delete_manifest(Some(client), job_id, run_id).await,
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
+            Self::Gdrive(gdrive) => match client {
+                KipClient::Gdrive(client) => {
+                    gdrive.delete_manifest(Some(client), job_id, run_id).await
+                }
+                _ => {
+                    bail!("gdrive client not provided")
+                }
+            },
+        }
+    }
+
+    /// Returns the remote path of every chunk stored in
+    /// the provider for a job.
+    pub async fn list_chunks(&self, client: &KipClient, job_id: Uuid) -> Result<Vec<String>> {
+        KipRetryOpts::configured()
+            .retry(
+                &format!("listing of job {job_id}'s chunks"),
+                |_, _| {},
+                || self.try_list_chunks(client, job_id),
+            )
+            .await
+    }
+
+    async fn try_list_chunks(&self, client: &KipClient, job_id: Uuid) -> Result<Vec<String>> {
+        match self {
+            Self::S3(s3) => match client {
+                KipClient::S3(client) => s3.list_chunks(client, job_id).await,
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => azure.list_chunks(client, job_id).await,
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::WebDav(webdav) => match client {
+                KipClient::WebDav(client) => webdav.list_chunks(client, job_id).await,
+                _ => {
+                    bail!("webdav client not provided")
+                }
+            },
+            Self::Usb(usb) => usb.list_chunks(job_id).await,
+            Self::Local(local) => local.list_chunks(job_id).await,
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => sftp.list_chunks(client, job_id).await,
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => smb.list_chunks(client, job_id).await,
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
+            Self::Gdrive(gdrive) => match client {
+                KipClient::Gdrive(client) => gdrive.list_chunks(client).await,
+                _ => {
+                    bail!("gdrive client not provided")
+                }
+            },
+        }
+    }
+
+    /// Cleans up uploads a job left unfinished in the provider:
+    /// S3's incomplete multipart uploads and the temporary files
+    /// of local, SFTP and SMB directories.
+    pub async fn abort_incomplete_uploads(&self, client: &KipClient, job_id: Uuid) -> Result<()> {
+        match self {
+            Self::S3(s3) => match client {
+                KipClient::S3(client) => {
+                    let aborted = s3.abort_incomplete_uploads(client, job_id).await?;
+                    if aborted > 0 {
+                        debug!("aborted {aborted} incomplete multipart uploads");
+                    }
+                    Ok(())
+                }
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            Self::Local(local) => {
+                let removed = local.remove_partial_writes(job_id).await?;
+                if removed > 0 {
+                    debug!("removed {removed} partially written files");
+                }
+                Ok(())
+            }
+            Self::Sftp(sftp) => match client {
+                KipClient::Sftp(client) => {
+                    let removed = sftp.remove_partial_writes(client, job_id).await?;
+                    if removed > 0 {
+                        debug!("removed {removed} partially written files");
+                    }
+                    Ok(())
+                }
+                _ => {
+                    bail!("sftp client not provided")
+                }
+            },
+            Self::Smb(smb) => match client {
+                KipClient::Smb(client) => {
+                    let removed = smb.remove_partial_writes(client, job_id).await?;
+                    if removed > 0 {
+                        debug!("removed {removed} partially written files");
+                    }
+                    Ok(())
+                }
+                _ => {
+                    bail!("smb client not provided")
+                }
+            },
+            // Azure discards uncommitted blocks on its own
+            Self::Usb(_) | Self::Gdrive(_) | Self::Azure(_) | Self::WebDav(_) => Ok(()),
+        }
+    }
+
+    /// Restores the archived chunks among remote_paths so they
+    /// can be downloaded. Only S3 and Azure archive chunks, in
+    /// S3's Glacier storage classes and Azure's Archive tier.
+    pub async fn restore_archived(
+        &self,
+        client: &KipClient,
+        remote_paths: &[String],
+    ) -> Result<KipArchived> {
+        match self {
+            Self::S3(s3) => match client {
+                KipClient::S3(client) => s3.restore_archived(client, remote_paths).await,
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            Self::Azure(azure) => match client {
+                KipClient::Azure(client) => azure.restore_archived(client, remote_paths).await,
+                _ => {
+                    bail!("azure client not provided")
+                }
+            },
+            Self::Usb(_)
+            | Self::Gdrive(_)
+            | Self::Local(_)
+            | Self::Sftp(_)
+            | Self::Smb(_)
+            | Self::WebDav(_) => Ok(KipArchived::default()),
+        }
+    }
+
+    /// Fails if the job uploads with S3 Object Lock but
+    /// its bucket doesn't have Object Lock enabled
+    pub async fn check_object_lock(&self, client: &KipClient) -> Result<()> {
+        match self {
+            Self::S3(s3) if s3.object_lock.is_some() => match client {
+                KipClient::S3(client) => s3.check_object_lock(client).await,
+                _ => {
+                    bail!("s3 client not provided")
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
     pub async fn get_client(&self) -> Result<KipClient> {
         Ok(match self {
-            KipProviders::S3(ref s3) => {
-                let s3_conf = aws_config::from_env()
-                    .region(aws_sdk_s3::config::Region::new(s3.aws_region.clone()))
-                    .credentials_cache(aws_credential_types::cache::CredentialsCache::lazy())
-                    .load()
-                    .await;
-                KipClient::S3(aws_sdk_s3::Client::new(&s3_conf))
-            }
+            KipProviders::S3(ref s3) => KipClient::S3(s3.client().await?),
             KipProviders::Usb(_) => KipClient::None,
+            KipProviders::Local(_) => KipClient::None,
+            KipProviders::Sftp(_) => KipClient::Sftp(KipSftpClient::from_env(SFTP_SECRET_VAR)),
+            KipProviders::Smb(_) => KipClient::Smb(KipSmbClient::from_env(SMB_SECRET_VAR)),
+            KipProviders::Azure(ref azure) => KipClient::Azure(azure.client()?),
+            KipProviders::WebDav(_) => KipClient::WebDav(KipWebDavClient::from_env()?),
             KipProviders::Gdrive(_) => {
                 KipClient::Gdrive(crate::providers::gdrive::generate_gdrive_hub().await?)
             }
@@ -145,6 +734,10 @@ impl KipProviders {
 pub enum KipClient {
     S3(aws_sdk_s3::Client),
     Gdrive(DriveHub<HttpsConnector<HttpConnector>>),
+    Sftp(KipSftpClient),
+    Smb(KipSmbClient),
+    Azure(ContainerClient),
+    WebDav(KipWebDavClient),
     None,
 }
 
@@ -153,19 +746,166 @@ impl std::fmt::Debug for KipClient {
         match self {
             Self::S3(_) => write!(f, "S3"),
             Self::Gdrive(_) => write!(f, "Gdrive"),
+            Self::Sftp(_) => write!(f, "Sftp"),
+            Self::Smb(_) => write!(f, "Smb"),
+            Self::Azure(_) => write!(f, "Azure"),
+            Self::WebDav(_) => write!(f, "WebDav"),
             Self::None => write!(f, "None"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct KipUploadOpts {
     pub job_id: Uuid,
     pub msg_tx: UnboundedSender<KipUploadMsg>,
+    /// Name of the job and ID of the run uploading the chunk
+    pub run: Option<(String, u64)>,
 }
 
 impl KipUploadOpts {
     pub fn new(job_id: Uuid, msg_tx: UnboundedSender<KipUploadMsg>) -> Self {
-        Self { job_id, msg_tx }
+        Self {
+            job_id,
+            msg_tx,
+            run: None,
+        }
+    }
+
+    pub fn with_run<S: Into<String>>(mut self, job_name: S, run_id: u64) -> Self {
+        self.run = Some((job_name.into(), run_id));
+        self
+    }
+}
+
+/// Archived chunks a restore is waiting on
+#[derive(Debug, Default)]
+pub struct KipArchived {
+    /// Chunks still being restored from archive storage
+    pub pending: Vec<String>,
+    /// Chunks that are missing or couldn't be restored
+    pub unavailable: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::webdav::KipWebDavAuth;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    type Requests = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+    // Answers every request with 201 Created and records
+    // its method, path and body
+    async fn serve_webdav(mut stream: TcpStream, requests: Requests) {
+        let mut buf = Vec::<u8>::new();
+        loop {
+            let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                let mut read = [0u8; 4096];
+                match stream.read(&mut read).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&read[..n]),
+                }
+                continue;
+            };
+            let head = String::from_utf8_lossy(&buf[..end]).to_string();
+            let mut lines = head.lines();
+            let mut request_line = lines.next().unwrap_or_default().split(' ');
+            let method = request_line.next().unwrap_or_default().to_string();
+            let path = request_line.next().unwrap_or_default().to_string();
+            let len = lines
+                .filter_map(|l| l.split_once(':'))
+                .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            while buf.len() < end + 4 + len {
+                let mut read = [0u8; 4096];
+                match stream.read(&mut read).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&read[..n]),
+                }
+            }
+            let body = buf[end + 4..end + 4 + len].to_vec();
+            buf.drain(..end + 4 + len);
+            requests.lock().unwrap().push((method, path, body));
+            if stream
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webdav_dispatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Requests::default();
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_webdav(stream, server_requests.clone()));
+            }
+        });
+
+        let provider = KipProviders::WebDav(KipWebDav::new(
+            format!("http://{addr}/kip"),
+            KipWebDavAuth::Bearer,
+        ));
+        let client = KipClient::WebDav(KipWebDavClient::from_env().unwrap());
+        let job_id = Uuid::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let chunk = FileChunk::new("test/random.txt", "abc123", 0, 3, 3);
+        let uploaded = provider
+            .upload(
+                &client,
+                KipUploadOpts::new(job_id, tx.clone()),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 3);
+        let uploaded = provider
+            .upload_manifest(&client, job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 8);
+
+        let puts = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(method, _, _)| method == "PUT")
+            .map(|(_, path, body)| (path.clone(), body.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            puts,
+            vec![
+                (
+                    format!("/kip/{job_id}/chunks/ab/abc123.chunk"),
+                    b"kip".to_vec()
+                ),
+                (
+                    format!("/kip/{job_id}/manifests/1.manifest"),
+                    b"manifest".to_vec()
+                ),
+            ]
+        );
+
+        // Refused without a WebDAV client
+        assert!(provider
+            .upload(
+                &KipClient::None,
+                KipUploadOpts::new(job_id, tx),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .is_err());
     }
 }
@@ -2,37 +2,795 @@
 // Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
-use super::KipUploadOpts;
+use super::{KipArchived, KipUploadOpts};
 use crate::chunk::FileChunk;
+use crate::limiter::KipRateLimiter;
 use crate::providers::KipProvider;
-use anyhow::{bail, Result};
+use crate::retry::{transient, KipRetryOpts};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use aws_sdk_s3::config::Region;
-use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::Object;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::primitives::{ByteStream, DateTime};
+use aws_sdk_s3::types::{
+    ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, Object, ObjectLockEnabled,
+    ObjectLockMode, ObjectLockRetention, ObjectLockRetentionMode, RestoreRequest,
+    ServerSideEncryption, StorageClass,
+};
 use aws_sdk_s3::Client as S3Client;
+use aws_smithy_client::erase::DynConnector;
+use aws_smithy_client::http_connector::HttpConnector;
+use aws_smithy_client::hyper_ext;
 use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncReadExt;
-use tracing::debug;
+use tracing::{debug, warn};
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct KipS3 {
     pub aws_bucket: String,
     pub aws_region: String,
+    /// Endpoint of an S3-compatible service, such as MinIO,
+    /// Ceph, Wasabi or Backblaze B2. None uses AWS.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Address buckets as endpoint/bucket rather than
+    /// bucket.endpoint, which most self-hosted services need
+    #[serde(default)]
+    pub path_style: bool,
+    /// PEM file of the CA certificates trusted instead of
+    /// the system's, for endpoints with self-signed certificates
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    /// Storage class chunks are uploaded to. Ex: STANDARD_IA,
+    /// GLACIER_IR, DEEP_ARCHIVE. None uses the bucket's default.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    /// Server-side encryption S3 applies on top of kip's own
+    #[serde(default)]
+    pub sse: Option<KipS3Sse>,
+    /// KMS key used for SSE-KMS. None uses the AWS managed key.
+    #[serde(default)]
+    pub sse_kms_key_id: Option<String>,
+    /// Tag chunks with the name of their job and the
+    /// run that uploaded them
+    #[serde(default)]
+    pub tag_objects: bool,
+    /// Object Lock retention chunks and manifests are uploaded
+    /// with. kip never deletes a locked job's objects before
+    /// their lock expires.
+    #[serde(default)]
+    pub object_lock: Option<KipObjectLock>,
+}
+
+/// Server-side encryption modes
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KipS3Sse {
+    /// SSE-S3, with keys managed by S3
+    Aes256,
+    /// SSE-KMS, with keys managed by AWS KMS
+    Kms,
+}
+
+impl std::fmt::Display for KipS3Sse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Aes256 => write!(f, "SSE-S3"),
+            Self::Kms => write!(f, "SSE-KMS"),
+        }
+    }
+}
+
+/// S3 Object Lock retention applied to every object uploaded
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct KipObjectLock {
+    pub mode: KipObjectLockMode,
+    /// Days objects stay locked after they're uploaded,
+    /// derived from the job's retention policy
+    pub days: u32,
+}
+
+/// Object Lock retention modes
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KipObjectLockMode {
+    /// Users with the s3:BypassGovernanceRetention
+    /// permission can still delete locked objects
+    Governance,
+    /// No one, including the root user, can delete
+    /// locked objects
+    Compliance,
+}
+
+impl std::fmt::Display for KipObjectLockMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Governance => write!(f, "governance"),
+            Self::Compliance => write!(f, "compliance"),
+        }
+    }
+}
+
+/// Storage class and tags of an object being uploaded
+#[derive(Debug, Default)]
+struct KipObjectAttrs {
+    storage_class: Option<StorageClass>,
+    tagging: Option<String>,
+}
+
+// How long objects restored from Glacier stay readable
+const RESTORE_DAYS: i32 = 7;
+// Archived objects checked at once before a restore
+const RESTORE_CONCURRENCY: usize = 16;
+// Objects whose lock is extended at once after a run
+const LOCK_CONCURRENCY: usize = 16;
+
+// S3 rejects parts smaller than 5 MiB, except the last
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// Default part size (in MB) of multipart uploads and ranged downloads
+pub const DEFAULT_PART_SIZE: u64 = 8;
+/// Default number of parts sent or fetched at once
+pub const DEFAULT_PART_CONCURRENCY: usize = 4;
+
+static PART_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_PART_SIZE * 1024 * 1024);
+static PART_CONCURRENCY: AtomicUsize = AtomicUsize::new(DEFAULT_PART_CONCURRENCY);
+
+/// Sets the part size (in MB) objects are uploaded and downloaded
+/// in, and how many parts are transferred at once
+pub fn set_multipart_opts(part_size: u64, concurrency: usize) {
+    PART_SIZE.store(
+        part_size.saturating_mul(1024 * 1024).max(MIN_PART_SIZE),
+        Ordering::Relaxed,
+    );
+    PART_CONCURRENCY.store(concurrency.max(1), Ordering::Relaxed);
+}
+
+fn part_size() -> u64 {
+    PART_SIZE.load(Ordering::Relaxed)
+}
+
+fn part_concurrency() -> usize {
+    PART_CONCURRENCY.load(Ordering::Relaxed)
 }
 
 impl KipS3 {
     // 3,500 API requests per second
-    const _API_RATE_LIMIT: u64 = 3500;
-    const _API_RATE_LIMIT_PERIOD: u64 = 1;
+    const API_RATE_LIMIT: u64 = 3500;
+    const API_RATE_LIMIT_PERIOD: u64 = 1;
 
     pub fn new<S: Into<String>>(aws_bucket: S, aws_region: Region) -> Self {
         Self {
             aws_bucket: aws_bucket.into(),
             aws_region: aws_region.to_string(),
+            endpoint: None,
+            path_style: false,
+            ca_cert: None,
+            storage_class: None,
+            sse: None,
+            sse_kms_key_id: None,
+            tag_objects: false,
+            object_lock: None,
+        }
+    }
+
+    /// Builds an S3 client for the bucket's region and endpoint.
+    /// Credentials are read from the environment.
+    pub async fn client(&self) -> Result<S3Client> {
+        let mut loader = aws_config::from_env()
+            .region(Region::new(self.aws_region.clone()))
+            .credentials_cache(aws_credential_types::cache::CredentialsCache::lazy());
+        if let Some(endpoint) = &self.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        if let Some(ca_cert) = &self.ca_cert {
+            loader = loader.http_connector(HttpConnector::Prebuilt(Some(
+                https_connector(ca_cert).with_context(|| {
+                    format!("unable to load CA certificate {}", ca_cert.display())
+                })?,
+            )));
+        }
+        let sdk_conf = loader.load().await;
+        let s3_conf = aws_sdk_s3::config::Builder::from(&sdk_conf)
+            .force_path_style(self.path_style)
+            .build();
+        Ok(S3Client::from_conf(s3_conf))
+    }
+
+    /// Limits the requests made to S3 by every job
+    fn api_limiter() -> &'static KipRateLimiter {
+        static LIMITER: OnceLock<KipRateLimiter> = OnceLock::new();
+        LIMITER.get_or_init(|| {
+            KipRateLimiter::new(
+                Self::API_RATE_LIMIT,
+                Duration::from_secs(Self::API_RATE_LIMIT_PERIOD),
+            )
+        })
+    }
+
+    /// Returns the key of every chunk stored for a job
+    pub async fn list_chunks(&self, client: &S3Client, job_id: Uuid) -> Result<Vec<String>> {
+        Ok(self
+            .list_all(Some(client), job_id)
+            .await?
+            .into_iter()
+            .filter_map(|obj| obj.key)
+            .filter(|key| key.contains("/chunks/"))
+            .collect())
+    }
+
+    /// Aborts the multipart uploads a job left unfinished, such as
+    /// when kip crashed mid-upload. S3 keeps, and bills for, their
+    /// parts until they're aborted. Returns how many were aborted.
+    pub async fn abort_incomplete_uploads(&self, client: &S3Client, job_id: Uuid) -> Result<usize> {
+        let mut aborted: usize = 0;
+        let mut key_marker: Option<String> = None;
+        let mut upload_id_marker: Option<String> = None;
+        loop {
+            Self::api_limiter().acquire(1).await;
+            let result = client
+                .list_multipart_uploads()
+                .bucket(&self.aws_bucket)
+                .prefix(format!("{job_id}/"))
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await
+                .map_err(s3_error)?;
+            for upload in result.uploads.unwrap_or_default() {
+                let (Some(key), Some(upload_id)) = (upload.key, upload.upload_id) else {
+                    continue;
+                };
+                debug!("aborting incomplete multipart upload of {key}");
+                Self::api_limiter().acquire(1).await;
+                client
+                    .abort_multipart_upload()
+                    .bucket(&self.aws_bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                aborted += 1;
+            }
+            // Handle pagination
+            if !result.is_truncated {
+                break;
+            }
+            key_marker = result.next_key_marker;
+            upload_id_marker = result.next_upload_id_marker;
+        }
+        Ok(aborted)
+    }
+
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        self.sse.map(|sse| match sse {
+            KipS3Sse::Aes256 => ServerSideEncryption::Aes256,
+            KipS3Sse::Kms => ServerSideEncryption::AwsKms,
+        })
+    }
+
+    fn kms_key_id(&self) -> Option<String> {
+        match self.sse {
+            Some(KipS3Sse::Kms) => self.sse_kms_key_id.clone(),
+            _ => None,
+        }
+    }
+
+    /// Object Lock mode and retain-until date of an object
+    /// uploaded now
+    fn lock_retention(&self) -> (Option<ObjectLockMode>, Option<DateTime>) {
+        match self.object_lock {
+            Some(lock) => {
+                let mode = match lock.mode {
+                    KipObjectLockMode::Governance => ObjectLockMode::Governance,
+                    KipObjectLockMode::Compliance => ObjectLockMode::Compliance,
+                };
+                let until =
+                    SystemTime::now() + Duration::from_secs(u64::from(lock.days) * 24 * 60 * 60);
+                (Some(mode), Some(DateTime::from(until)))
+            }
+            None => (None, None),
+        }
+    }
+
+    /// Extends the Object Lock of objects a new run depends on to
+    /// the retain-until date of objects uploaded now, so they
+    /// can't be deleted before the run expires
+    pub async fn extend_locks(&self, client: &S3Client, keys: &[String]) -> Result<()> {
+        let (Some(lock), (_, Some(until))) = (self.object_lock, self.lock_retention()) else {
+            return Ok(());
+        };
+        let mode = match lock.mode {
+            KipObjectLockMode::Governance => ObjectLockRetentionMode::Governance,
+            KipObjectLockMode::Compliance => ObjectLockRetentionMode::Compliance,
+        };
+        futures::stream::iter(keys)
+            .map(Ok)
+            .try_for_each_concurrent(LOCK_CONCURRENCY, |key| {
+                let mode = mode.clone();
+                async move {
+                    KipRetryOpts::configured()
+                        .retry(
+                            &format!("lock extension of {key}"),
+                            |_, _| {},
+                            || self.extend_lock(client, key, mode.clone(), until),
+                        )
+                        .await
+                }
+            })
+            .await
+    }
+
+    /// Extends an object's lock to until. Locks can't be
+    /// shortened, so objects locked longer are left alone.
+    async fn extend_lock(
+        &self,
+        client: &S3Client,
+        key: &str,
+        mode: ObjectLockRetentionMode,
+        until: DateTime,
+    ) -> Result<()> {
+        Self::api_limiter().acquire(1).await;
+        let current = client
+            .get_object_retention()
+            .bucket(&self.aws_bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error)?
+            .retention
+            .and_then(|retention| retention.retain_until_date);
+        let Some(until) = extended_retention(current, until) else {
+            return Ok(());
+        };
+        debug!("extending the lock of {key}");
+        Self::api_limiter().acquire(1).await;
+        client
+            .put_object_retention()
+            .bucket(&self.aws_bucket)
+            .key(key)
+            .retention(
+                ObjectLockRetention::builder()
+                    .mode(mode)
+                    .retain_until_date(until)
+                    .build(),
+            )
+            .set_checksum_algorithm(self.checksum_algorithm())
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    /// S3 requires a checksum on uploads with Object Lock retention
+    fn checksum_algorithm(&self) -> Option<ChecksumAlgorithm> {
+        self.object_lock.map(|_| ChecksumAlgorithm::Crc32)
+    }
+
+    /// Fails unless the bucket has Object Lock enabled. It can
+    /// only be enabled when the bucket is created.
+    pub async fn check_object_lock(&self, client: &S3Client) -> Result<()> {
+        Self::api_limiter().acquire(1).await;
+        let enabled = match client
+            .get_object_lock_configuration()
+            .bucket(&self.aws_bucket)
+            .send()
+            .await
+        {
+            Ok(result) => result
+                .object_lock_configuration
+                .and_then(|conf| conf.object_lock_enabled)
+                .is_some_and(|enabled| enabled == ObjectLockEnabled::Enabled),
+            // Buckets created without Object Lock have no configuration
+            Err(SdkError::ServiceError(e))
+                if e.err().code() == Some("ObjectLockConfigurationNotFoundError") =>
+            {
+                false
+            }
+            Err(e) => {
+                return Err(
+                    s3_error(e).context("unable to get the bucket's Object Lock configuration")
+                )
+            }
+        };
+        if !enabled {
+            bail!(
+                "bucket {} doesn't have Object Lock enabled. It must be enabled when the bucket is created.",
+                self.aws_bucket
+            )
+        }
+        Ok(())
+    }
+
+    /// Deletes every version of an object once the Object Lock of
+    /// each has expired. Returns false without deleting anything if
+    /// one is still locked. Object Lock buckets are versioned, so
+    /// deleting just the key would only hide its versions behind
+    /// a delete marker.
+    pub async fn delete_expired(&self, client: &S3Client, key: &str) -> Result<bool> {
+        let now = DateTime::from(SystemTime::now());
+        let mut versions = Vec::<String>::new();
+        let mut key_marker: Option<String> = None;
+        let mut version_id_marker: Option<String> = None;
+        loop {
+            Self::api_limiter().acquire(1).await;
+            let result = client
+                .list_object_versions()
+                .bucket(&self.aws_bucket)
+                .prefix(key)
+                .set_key_marker(key_marker)
+                .set_version_id_marker(version_id_marker)
+                .send()
+                .await
+                .map_err(s3_error)?;
+            for version in result.versions.unwrap_or_default() {
+                if version.key.as_deref() != Some(key) {
+                    continue;
+                }
+                let Some(version_id) = version.version_id else {
+                    continue;
+                };
+                Self::api_limiter().acquire(1).await;
+                let head = client
+                    .head_object()
+                    .bucket(&self.aws_bucket)
+                    .key(key)
+                    .version_id(&version_id)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                if head
+                    .object_lock_retain_until_date
+                    .is_some_and(|until| until > now)
+                {
+                    debug!(
+                        "{key} is locked until {:?}",
+                        head.object_lock_retain_until_date
+                    );
+                    return Ok(false);
+                }
+                versions.push(version_id);
+            }
+            // Delete markers left by deletes made outside of kip
+            versions.extend(
+                result
+                    .delete_markers
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|marker| marker.key.as_deref() == Some(key))
+                    .filter_map(|marker| marker.version_id),
+            );
+            // Handle pagination
+            if !result.is_truncated {
+                break;
+            }
+            key_marker = result.next_key_marker;
+            version_id_marker = result.next_version_id_marker;
+        }
+        for version_id in versions {
+            Self::api_limiter().acquire(1).await;
+            client
+                .delete_object()
+                .bucket(&self.aws_bucket)
+                .key(key)
+                .version_id(version_id)
+                .send()
+                .await
+                .map_err(s3_error)?;
+        }
+        Ok(true)
+    }
+
+    /// Storage class and tags of a chunk uploaded by a run
+    fn chunk_attrs(&self, opts: &KipUploadOpts) -> KipObjectAttrs {
+        let tagging = match (self.tag_objects, &opts.run) {
+            (true, Some((job_name, run_id))) => {
+                Some(format!("kip-job={}&kip-run={run_id}", encode_tag(job_name)))
+            }
+            (true, None) => Some(format!("kip-job-id={}", opts.job_id)),
+            (false, _) => None,
+        };
+        KipObjectAttrs {
+            storage_class: self.storage_class.as_deref().map(StorageClass::from),
+            tagging,
+        }
+    }
+
+    /// Whether chunks are uploaded to an archive storage class,
+    /// Glacier Flexible Retrieval or Deep Archive
+    pub fn archives(&self) -> bool {
+        matches!(
+            self.storage_class.as_deref().map(StorageClass::from),
+            Some(StorageClass::Glacier | StorageClass::DeepArchive)
+        )
+    }
+
+    /// Restores the archived objects among keys so they can be
+    /// downloaded. Objects in Glacier Flexible Retrieval and Deep
+    /// Archive must be restored first, which takes hours. Returns
+    /// the keys still being restored, so it's called until none
+    /// are, and the ones that can't be restored.
+    pub async fn restore_archived(
+        &self,
+        client: &S3Client,
+        keys: &[String],
+    ) -> Result<KipArchived> {
+        let mut archived = KipArchived::default();
+        if !self.archives() {
+            return Ok(archived);
+        }
+        let mut results = futures::stream::iter(keys)
+            .map(|key| async move {
+                let restoring = KipRetryOpts::configured()
+                    .retry(
+                        &format!("restore of {key}"),
+                        |_, _| {},
+                        || self.restore_object(client, key),
+                    )
+                    .await;
+                (key, restoring)
+            })
+            .buffer_unordered(RESTORE_CONCURRENCY);
+        while let Some((key, restoring)) = results.next().await {
+            match restoring {
+                Ok(true) => archived.pending.push(key.clone()),
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("unable to restore {key}: {e}");
+                    archived.unavailable.push(key.clone());
+                }
+            }
+        }
+        Ok(archived)
+    }
+
+    /// Requests a restore of an object if it's archived.
+    /// Returns whether it's still being restored.
+    async fn restore_object(&self, client: &S3Client, key: &str) -> Result<bool> {
+        Self::api_limiter().acquire(1).await;
+        let head = client
+            .head_object()
+            .bucket(&self.aws_bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let archived = matches!(
+            head.storage_class,
+            Some(StorageClass::Glacier | StorageClass::DeepArchive)
+        );
+        if !archived {
+            return Ok(false);
+        }
+        match head.restore.as_deref() {
+            // Not yet requested
+            None => {
+                debug!("requesting restore of {key}");
+                Self::api_limiter().acquire(1).await;
+                client
+                    .restore_object()
+                    .bucket(&self.aws_bucket)
+                    .key(key)
+                    .restore_request(RestoreRequest::builder().days(RESTORE_DAYS).build())
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                Ok(true)
+            }
+            Some(restore) => Ok(restore.contains("ongoing-request=\"true\"")),
+        }
+    }
+
+    /// Uploads an object in a single request, or in parts
+    /// if it's larger than the part size
+    async fn put(
+        &self,
+        s3: &S3Client,
+        key: String,
+        body: Bytes,
+        attrs: KipObjectAttrs,
+    ) -> Result<()> {
+        if body.len() as u64 > part_size() {
+            return self.put_multipart(s3, key, body, attrs).await;
+        }
+        let (lock_mode, lock_until) = self.lock_retention();
+        Self::api_limiter().acquire(1).await;
+        s3.put_object()
+            .bucket(&self.aws_bucket)
+            .key(key)
+            .content_length(body.len().try_into()?)
+            .content_type("application/octet-stream")
+            .set_storage_class(attrs.storage_class)
+            .set_tagging(attrs.tagging)
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.kms_key_id())
+            .set_object_lock_mode(lock_mode)
+            .set_object_lock_retain_until_date(lock_until)
+            .set_checksum_algorithm(self.checksum_algorithm())
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    /// Uploads an object in parts, several at a time. Parts
+    /// are slices of the object's bytes, so they aren't copied.
+    async fn put_multipart(
+        &self,
+        s3: &S3Client,
+        key: String,
+        body: Bytes,
+        attrs: KipObjectAttrs,
+    ) -> Result<()> {
+        let (lock_mode, lock_until) = self.lock_retention();
+        Self::api_limiter().acquire(1).await;
+        let created = s3
+            .create_multipart_upload()
+            .bucket(&self.aws_bucket)
+            .key(&key)
+            .content_type("application/octet-stream")
+            .set_storage_class(attrs.storage_class)
+            .set_tagging(attrs.tagging)
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.kms_key_id())
+            .set_object_lock_mode(lock_mode)
+            .set_object_lock_retain_until_date(lock_until)
+            .set_checksum_algorithm(self.checksum_algorithm())
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let Some(upload_id) = created.upload_id else {
+            bail!("S3 didn't return an upload ID for {key}")
+        };
+        // Aborts the upload if a part fails or the run is
+        // aborted, so S3 doesn't keep the parts already sent
+        let mut pending = KipPendingUpload {
+            client: s3.clone(),
+            bucket: self.aws_bucket.clone(),
+            key: key.clone(),
+            upload_id: upload_id.clone(),
+            completed: false,
+        };
+
+        let part_size: usize = part_size().try_into()?;
+        let checksum_algorithm = self.checksum_algorithm();
+        let (bucket, key, upload_id, checksum_algorithm) =
+            (&self.aws_bucket, &key, &upload_id, &checksum_algorithm);
+        let mut parts: Vec<CompletedPart> =
+            futures::stream::iter((0..body.len()).step_by(part_size).enumerate())
+                .map(|(i, start)| {
+                    let part = body.slice(start..(start + part_size).min(body.len()));
+                    async move {
+                        let part_number = i32::try_from(i + 1)?;
+                        // Parts are retried on their own so a failed
+                        // part doesn't restart the whole upload
+                        let uploaded = KipRetryOpts::configured()
+                            .retry(
+                                &format!("upload of part {part_number} of {key}"),
+                                |_, _| {},
+                                || {
+                                    let part = part.clone();
+                                    let checksum_algorithm = checksum_algorithm.clone();
+                                    async move {
+                                        Self::api_limiter().acquire(1).await;
+                                        s3.upload_part()
+                                            .bucket(bucket)
+                                            .key(key)
+                                            .upload_id(upload_id)
+                                            .part_number(part_number)
+                                            .content_length(part.len().try_into()?)
+                                            .set_checksum_algorithm(checksum_algorithm)
+                                            .body(ByteStream::from(part))
+                                            .send()
+                                            .await
+                                            .map_err(s3_error)
+                                    }
+                                },
+                            )
+                            .await?;
+                        Ok::<_, anyhow::Error>(
+                            CompletedPart::builder()
+                                .set_e_tag(uploaded.e_tag)
+                                .set_checksum_crc32(uploaded.checksum_crc32)
+                                .part_number(part_number)
+                                .build(),
+                        )
+                    }
+                })
+                .buffer_unordered(part_concurrency())
+                .try_collect()
+                .await?;
+        parts.sort_by_key(|p| p.part_number());
+
+        Self::api_limiter().acquire(1).await;
+        s3.complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(s3_error)?;
+        pending.completed = true;
+        Ok(())
+    }
+
+    /// Reads bytes start..end of an object. Also returns the
+    /// object's size, if S3 reported it.
+    async fn get_range(
+        &self,
+        s3: &S3Client,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<(Vec<u8>, Option<u64>)> {
+        Self::api_limiter().acquire(1).await;
+        let result = s3
+            .get_object()
+            .bucket(&self.aws_bucket)
+            .key(key)
+            .range(format!("bytes={start}-{}", end.saturating_sub(1)))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let size = result.content_range.as_deref().and_then(content_range_size);
+        // Read result from S3 and convert to bytes
+        let mut range_bytes = Vec::<u8>::new();
+        result
+            .body
+            .into_async_read()
+            .read_to_end(&mut range_bytes)
+            .await?;
+        Ok((range_bytes, size))
+    }
+}
+
+/// A multipart upload that's aborted if dropped before it completes
+struct KipPendingUpload {
+    client: S3Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    completed: bool,
+}
+
+impl Drop for KipPendingUpload {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
         }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let client = self.client.clone();
+        let bucket = std::mem::take(&mut self.bucket);
+        let key = std::mem::take(&mut self.key);
+        let upload_id = std::mem::take(&mut self.upload_id);
+        runtime.spawn(async move {
+            debug!("aborting multipart upload of {key}");
+            KipS3::api_limiter().acquire(1).await;
+            if let Err(e) = client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(&key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                warn!("unable to abort multipart upload of {key}: {e}");
+            }
+        });
     }
 }
 
@@ -41,25 +799,25 @@ impl KipProvider for KipS3 {
     type Client = S3Client;
     type Item = Object;
 
-    async fn upload<'b>(
+    async fn upload(
         &self,
         client: Option<&Self::Client>,
         opts: KipUploadOpts,
         chunk: &FileChunk,
-        chunk_bytes: &'b [u8],
+        chunk_bytes: Bytes,
     ) -> Result<usize> {
         if let Some(s3) = client {
             // Get chunk_bytes len
             let ce_bytes_len = chunk_bytes.len();
             // Upload
-            s3.put_object()
-                .bucket(self.aws_bucket.clone())
-                .key(format!("{}/chunks/{}.chunk", opts.job_id, chunk.hash))
-                .content_length(ce_bytes_len.try_into()?)
-                .content_type("application/octet-stream")
-                .body(ByteStream::from(Bytes::copy_from_slice(chunk_bytes)))
-                .send()
-                .await?;
+            let attrs = self.chunk_attrs(&opts);
+            self.put(
+                s3,
+                format!("{}/chunks/{}.chunk", opts.job_id, chunk.hash),
+                chunk_bytes,
+                attrs,
+            )
+            .await?;
             Ok(ce_bytes_len)
         } else {
             bail!("s3 client not provided")
@@ -68,19 +826,25 @@ impl KipProvider for KipS3 {
 
     async fn download(&self, client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
         if let Some(s3) = client {
-            let result = s3
-                .get_object()
-                .bucket(self.aws_bucket.clone())
-                .key(file_name.to_string())
-                .send()
-                .await?;
-            // Read result from S3 and convert to bytes
-            let mut result_bytes = Vec::<u8>::new();
-            result
-                .body
-                .into_async_read()
-                .read_to_end(&mut result_bytes)
-                .await?;
+            // The first range also returns the object's size
+            let part_size = part_size();
+            let (first, size) = self.get_range(s3, file_name, 0, part_size).await?;
+            let size = size.unwrap_or(first.len() as u64);
+            if first.len() as u64 >= size {
+                return Ok(first);
+            }
+            // Fetch the remaining ranges in parallel, in order
+            let mut result_bytes = Vec::<u8>::with_capacity(size.try_into()?);
+            result_bytes.extend_from_slice(&first);
+            let mut ranges =
+                futures::stream::iter((part_size..size).step_by(part_size.try_into()?))
+                    .map(|start| {
+                        self.get_range(s3, file_name, start, (start + part_size).min(size))
+                    })
+                    .buffered(part_concurrency());
+            while let Some((range, _)) = ranges.try_next().await? {
+                result_bytes.extend_from_slice(&range);
+            }
             Ok(result_bytes)
         } else {
             bail!("s3 client not provided")
@@ -88,13 +852,23 @@ impl KipProvider for KipS3 {
     }
 
     async fn delete(&self, client: Option<&Self::Client>, file_name: &str) -> Result<()> {
+        // Locked objects are only removed by prunes, once
+        // their lock has expired
+        if self.object_lock.is_some() {
+            bail!(
+                "bucket {} uses S3 Object Lock, kip doesn't delete its objects",
+                self.aws_bucket
+            )
+        }
         if let Some(s3) = client {
             // Delete
+            Self::api_limiter().acquire(1).await;
             s3.delete_object()
                 .bucket(self.aws_bucket.clone())
                 .key(file_name.to_string())
                 .send()
-                .await?;
+                .await
+                .map_err(s3_error)?;
             Ok(())
         } else {
             bail!("s3 client not provided")
@@ -107,22 +881,28 @@ impl KipProvider for KipS3 {
         job_id: Uuid,
         hash: &str,
     ) -> Result<bool> {
-        // Check S3 for duplicates of chunk
-        let s3_objs = self.list_all(client, job_id).await?;
-        // If the S3 bucket is empty, no need to check for duplicate chunks
-        if !s3_objs.is_empty() {
-            for obj in s3_objs {
-                if let Some(obj_key) = obj.key {
-                    if obj_key.contains(hash) {
-                        // Duplicate chunk found, return true
-                        return Ok(true);
-                    }
-                } else {
-                    debug!("unable to get chunk name from S3");
+        if let Some(s3) = client {
+            // Look up the chunk's key directly rather than
+            // listing the whole bucket
+            Self::api_limiter().acquire(1).await;
+            let result = s3
+                .head_object()
+                .bucket(&self.aws_bucket)
+                .key(format!("{job_id}/chunks/{hash}.chunk"))
+                .send()
+                .await;
+            match result {
+                Ok(_) => Ok(true),
+                Err(SdkError::ServiceError(e))
+                    if matches!(e.err(), HeadObjectError::NotFound(_)) =>
+                {
+                    Ok(false)
                 }
+                Err(e) => Err(s3_error(e).context(format!("unable to check S3 for chunk {hash}"))),
             }
+        } else {
+            bail!("s3 client not provided")
         }
-        Ok(false)
     }
 
     async fn list_all(
@@ -131,67 +911,105 @@ impl KipProvider for KipS3 {
         job_id: Uuid,
     ) -> Result<Vec<Self::Item>> {
         if let Some(s3) = client {
-            let result = s3
-                .list_objects_v2()
-                .bucket(self.aws_bucket.clone())
-                .send()
-                .await?;
-            // Convert S3 result into Vec<S3::Object> which can
-            // be used to manipulate the list of files in S3
-            let s3_contents = match result.contents {
-                Some(rc) => {
-                    let mut filtered = rc
-                        .into_iter()
-                        .filter(|obj| filter_job_id(obj.key(), job_id))
-                        .collect::<Vec<Object>>();
-                    // Handle pagination
-                    let mut cont_token = result.next_continuation_token;
-                    while let Some(token) = cont_token {
-                        let paginated_result = s3
-                            .list_objects_v2()
-                            .bucket(self.aws_bucket.clone())
-                            .continuation_token(token)
-                            .send()
-                            .await?;
-                        if let Some(prc) = paginated_result.contents {
-                            filtered.extend(
-                                prc.into_iter()
-                                    .filter(|obj| filter_job_id(obj.key(), job_id)),
-                            );
-                        }
-                        cont_token = paginated_result.next_continuation_token;
-                    }
-                    filtered
+            // Only this job's chunks are listed, a page at a time
+            let mut chunks = Vec::<Object>::new();
+            let mut cont_token = None;
+            loop {
+                Self::api_limiter().acquire(1).await;
+                let result = s3
+                    .list_objects_v2()
+                    .bucket(self.aws_bucket.clone())
+                    .prefix(format!("{job_id}/chunks/"))
+                    .set_continuation_token(cont_token)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                chunks.extend(result.contents.unwrap_or_default());
+                cont_token = result.next_continuation_token;
+                if cont_token.is_none() {
+                    break;
                 }
-                None => {
-                    // S3 bucket was empty, return an empty Vec
-                    return Ok(vec![]);
+            }
+            Ok(chunks)
+        } else {
+            bail!("s3 client not provided")
+        }
+    }
+
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        if let Some(s3) = client {
+            let manifest_len = manifest_bytes.len();
+            // Upload next to the job's chunks. Manifests stay in the
+            // bucket's default storage class so recovering a job
+            // doesn't wait on an archive restore.
+            self.put(
+                s3,
+                manifest_key(job_id, run_id),
+                Bytes::copy_from_slice(manifest_bytes),
+                KipObjectAttrs::default(),
+            )
+            .await?;
+            Ok(manifest_len)
+        } else {
+            bail!("s3 client not provided")
+        }
+    }
+
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>> {
+        if let Some(s3) = client {
+            let mut manifests = Vec::<String>::new();
+            let mut cont_token: Option<String> = None;
+            loop {
+                Self::api_limiter().acquire(1).await;
+                let result = s3
+                    .list_objects_v2()
+                    .bucket(self.aws_bucket.clone())
+                    .set_continuation_token(cont_token)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                if let Some(rc) = result.contents {
+                    manifests.extend(
+                        rc.into_iter()
+                            .filter_map(|obj| obj.key)
+                            .filter(|key| is_manifest_key(key)),
+                    );
+                }
+                // Handle pagination
+                cont_token = result.next_continuation_token;
+                if cont_token.is_none() {
+                    break;
                 }
-            };
-            // Only check chunks that are within this job's
-            // folder in S3
-            // let mut job_contents = vec![];
-            // for obj in s3_contents {
-            //     if let Some(key) = obj.key.clone() {
-            //         // We expect jid to be Some since key was not nil
-            //         if let Some((jid, _)) = key.split_once('/') {
-            //             if jid == job_id.to_string() {
-            //                 job_contents.push(obj);
-            //             };
-            //         } else {
-            //             // error splitting obj key returned from S3
-            //             bail!("error splitting chunk name from S3")
-            //         };
-            //     } else {
-            //         // error, no obj key returned from S3
-            //         bail!("unable to get chunk name from S3")
-            //     }
-            // }
-            Ok(s3_contents)
+            }
+            Ok(manifests)
         } else {
             bail!("s3 client not provided")
         }
     }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        let key = manifest_key(job_id, run_id);
+        match (client, self.object_lock) {
+            (Some(s3), Some(_)) => {
+                if !self.delete_expired(s3, &key).await? {
+                    warn!("manifest {key} is still locked, 'kip recover' may bring its run back");
+                }
+                Ok(())
+            }
+            _ => self.delete(client, &key).await,
+        }
+    }
 }
 
 /// Retrieves the hash from an S3 object name and returns
@@ -211,19 +1029,106 @@ pub fn strip_hash_from_s3(s3_path: &str) -> Result<String> {
     }
 }
 
-fn filter_job_id(provider_path: Option<&str>, job_id: Uuid) -> bool {
-    if let Some(key) = provider_path {
-        if let Some((jid, _)) = key.split_once('/') {
-            if jid == job_id.to_string() {
-                return true;
-            };
-        } else {
-            debug!("error splitting chunk name from S3");
+/// Checks whether an S3 object key is a run manifest.
+/// Ex: f339aae7-e994-4fb4-b6aa-623681df99aa/manifests/1.manifest
+fn is_manifest_key(key: &str) -> bool {
+    let parts: Vec<&str> = key.split('/').collect();
+    parts.len() == 3 && parts[1] == "manifests" && parts[2].ends_with(".manifest")
+}
+
+/// Returns the key of a run's manifest
+pub fn manifest_key(job_id: Uuid, run_id: u64) -> String {
+    format!("{job_id}/manifests/{run_id}.manifest")
+}
+
+/// Returns the date an object's lock is extended to,
+/// or None if it's already locked until then
+fn extended_retention(current: Option<DateTime>, until: DateTime) -> Option<DateTime> {
+    match current {
+        Some(current) if current.secs() >= until.secs() => None,
+        _ => Some(until),
+    }
+}
+
+/// HTTPS connector trusting only the CA certificates in a PEM file
+fn https_connector(ca_cert: &Path) -> Result<DynConnector> {
+    let pem = std::fs::read(ca_cert)?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice())? {
+        roots.add(&rustls::Certificate(cert))?;
+    }
+    if roots.is_empty() {
+        bail!("no certificates found")
+    }
+    let tls_conf = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls_conf)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(DynConnector::new(
+        hyper_ext::Adapter::builder().build(https),
+    ))
+}
+
+/// Percent-encodes a tag value for the x-amz-tagging header
+fn encode_tag(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Returns an object's size from a Content-Range header.
+/// Ex: bytes 0-8388607/20971520
+fn content_range_size(content_range: &str) -> Option<u64> {
+    content_range.rsplit_once('/')?.1.parse().ok()
+}
+
+// Error codes S3 returns when it's throttling requests
+// or briefly unable to serve them
+const S3_TRANSIENT_CODES: [&str; 5] = [
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "RequestTimeout",
+    "InternalError",
+];
+
+/// Marks S3 errors that may succeed if retried as transient:
+/// timeouts, dropped connections, 5xx and 429 responses and
+/// throttling error codes
+fn s3_error<E>(e: SdkError<E>) -> anyhow::Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let is_transient = match &e {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
         }
-    } else {
-        debug!("unable to get chunk name from S3");
+        SdkError::ServiceError(se) => {
+            let status = se.raw().status();
+            status.is_server_error()
+                || status.as_u16() == 429
+                || se
+                    .err()
+                    .code()
+                    .is_some_and(|code| S3_TRANSIENT_CODES.contains(&code))
+        }
+        _ => false,
+    };
+    match is_transient {
+        true => transient(e),
+        false => e.into(),
     }
-    false
 }
 
 #[cfg(test)]
@@ -239,4 +1144,174 @@ mod tests {
             "001d46082763b930e5b9f0c52d16841b443bfbcd52af6cd475cb0182548da33a"
         )
     }
+
+    #[test]
+    fn test_is_manifest_key() {
+        assert!(is_manifest_key(
+            "f339aae7-e994-4fb4-b6aa-623681df99aa/manifests/1.manifest"
+        ));
+        assert!(!is_manifest_key("f339aae7-e994-4fb4-b6aa-623681df99aa/chunks/001d46082763b930e5b9f0c52d16841b443bfbcd52af6cd475cb0182548da33a.chunk"));
+    }
+
+    #[test]
+    fn test_encode_tag() {
+        assert_eq!(encode_tag("documents_backup"), "documents_backup");
+        assert_eq!(encode_tag("my docs&more=1"), "my%20docs%26more%3D1");
+    }
+
+    #[test]
+    fn test_content_range_size() {
+        assert_eq!(
+            content_range_size("bytes 0-8388607/20971520"),
+            Some(20971520)
+        );
+        assert_eq!(content_range_size("bytes 0-2/3"), Some(3));
+        // The size may be unknown
+        assert_eq!(content_range_size("bytes 0-8388607/*"), None);
+    }
+
+    #[test]
+    fn test_s3_defaults_to_aws() {
+        // Jobs created before endpoints were configurable
+        let s3: KipS3 =
+            serde_json::from_str(r#"{"aws_bucket":"kip","aws_region":"us-east-1"}"#).unwrap();
+        assert!(s3.endpoint.is_none());
+        assert!(!s3.path_style);
+        assert!(s3.ca_cert.is_none());
+        assert!(s3.storage_class.is_none());
+        assert!(s3.sse.is_none());
+        assert!(!s3.tag_objects);
+        assert!(s3.object_lock.is_none());
+    }
+
+    #[test]
+    fn test_archives() {
+        let mut s3 = KipS3::new("kip", Region::new("us-east-1"));
+        assert!(!s3.archives());
+        s3.storage_class = Some(String::from("STANDARD_IA"));
+        assert!(!s3.archives());
+        s3.storage_class = Some(String::from("GLACIER"));
+        assert!(s3.archives());
+        s3.storage_class = Some(String::from("DEEP_ARCHIVE"));
+        assert!(s3.archives());
+    }
+
+    #[test]
+    fn test_object_lock_retention() {
+        let mut s3 = KipS3::new("kip", Region::new("us-east-1"));
+        assert_eq!(s3.lock_retention(), (None, None));
+        assert!(s3.checksum_algorithm().is_none());
+        s3.object_lock = Some(KipObjectLock {
+            mode: KipObjectLockMode::Compliance,
+            days: 30,
+        });
+        let now = DateTime::from(SystemTime::now());
+        let (mode, until) = s3.lock_retention();
+        assert_eq!(mode, Some(ObjectLockMode::Compliance));
+        let locked_secs = until.unwrap().secs() - now.secs();
+        assert!((30 * 24 * 60 * 60..=30 * 24 * 60 * 60 + 5).contains(&locked_secs));
+        assert_eq!(s3.checksum_algorithm(), Some(ChecksumAlgorithm::Crc32));
+
+        // Objects reused by a new run are locked until its
+        // retain-until date, but never for less time
+        let until = until.unwrap();
+        let day = 24 * 60 * 60;
+        let older = DateTime::from_secs(now.secs() + 10 * day);
+        let newer = DateTime::from_secs(now.secs() + 40 * day);
+        assert_eq!(extended_retention(Some(older), until), Some(until));
+        assert_eq!(extended_retention(None, until), Some(until));
+        assert_eq!(extended_retention(Some(newer), until), None);
+        assert_eq!(extended_retention(Some(until), until), None);
+    }
+
+    // Runs against an S3-compatible server, such as MinIO:
+    // docker run -p 9000:9000 minio/minio server /data
+    // KIP_TEST_S3_ENDPOINT=http://localhost:9000 AWS_ACCESS_KEY_ID=minioadmin
+    // AWS_SECRET_ACCESS_KEY=minioadmin cargo test test_s3_compatible_endpoint -- --ignored
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible server"]
+    async fn test_s3_compatible_endpoint() {
+        let endpoint =
+            std::env::var("KIP_TEST_S3_ENDPOINT").expect("KIP_TEST_S3_ENDPOINT must be set");
+        let job_id = Uuid::new_v4();
+        let mut s3 = KipS3::new(
+            format!("kip-test-{}", &job_id.to_string()[..8]),
+            Region::new("us-east-1"),
+        );
+        s3.endpoint = Some(endpoint);
+        s3.path_style = true;
+        s3.ca_cert = std::env::var("KIP_TEST_S3_CA_CERT").ok().map(PathBuf::from);
+        let client = s3.client().await.unwrap();
+        client
+            .create_bucket()
+            .bucket(&s3.aws_bucket)
+            .send()
+            .await
+            .unwrap();
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let chunk = FileChunk::new("test/random.txt", "abc123", 0, 3, 3);
+        let uploaded = s3
+            .upload(
+                Some(&client),
+                KipUploadOpts::new(job_id, tx),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 3);
+        assert!(s3.contains(Some(&client), job_id, "abc123").await.unwrap());
+        assert!(!s3.contains(Some(&client), job_id, "def456").await.unwrap());
+        let remote_path = format!("{job_id}/chunks/abc123.chunk");
+        assert_eq!(
+            s3.list_chunks(&client, job_id).await.unwrap(),
+            vec![remote_path.clone()]
+        );
+        assert_eq!(
+            s3.download(Some(&client), &remote_path).await.unwrap(),
+            b"kip"
+        );
+        s3.upload_manifest(Some(&client), job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        assert_eq!(
+            s3.list_manifests(Some(&client)).await.unwrap(),
+            vec![format!("{job_id}/manifests/1.manifest")]
+        );
+
+        // Objects larger than a part are sent and fetched in parts
+        set_multipart_opts(5, 2);
+        let large: Vec<u8> = (0..12 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let chunk = FileChunk::new("test/random.txt", "large", 0, large.len(), large.len());
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        s3.upload(
+            Some(&client),
+            KipUploadOpts::new(job_id, tx),
+            &chunk,
+            Bytes::from(large.clone()),
+        )
+        .await
+        .unwrap();
+        let large_path = format!("{job_id}/chunks/large.chunk");
+        assert_eq!(
+            s3.download(Some(&client), &large_path).await.unwrap(),
+            large
+        );
+        s3.delete(Some(&client), &large_path).await.unwrap();
+        assert_eq!(
+            s3.abort_incomplete_uploads(&client, job_id).await.unwrap(),
+            0
+        );
+
+        s3.delete(Some(&client), &remote_path).await.unwrap();
+        s3.delete_manifest(Some(&client), job_id, 1).await.unwrap();
+        assert!(s3.list_all(Some(&client), job_id).await.unwrap().is_empty());
+        client
+            .delete_bucket()
+            .bucket(&s3.aws_bucket)
+            .send()
+            .await
+            .unwrap();
+    }
 }
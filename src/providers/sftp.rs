@@ -0,0 +1,506 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use super::local::{chunk_path, request, KipRemoteClient, KipRemoteFs};
+use super::KipUploadOpts;
+use crate::chunk::FileChunk;
+use crate::providers::KipProvider;
+use crate::retry::transient;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use directories::BaseDirs;
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Env var the password or private key passphrase is
+/// loaded into from the keyring
+pub const SFTP_SECRET_VAR: &str = "KIP_SFTP_SECRET";
+// Requests taking longer than this fail and are retried
+const SFTP_TIMEOUT_MS: u32 = 60_000;
+
+/// A directory on a server reached over SSH
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KipSftp {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub auth: KipSftpAuth,
+    /// Directory on the server chunks and manifests
+    /// are stored in
+    pub root_path: String,
+    /// known_hosts file the server's host key is verified
+    /// against. None uses ~/.ssh/known_hosts.
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// How kip authenticates to the server. The password or
+/// the private key's passphrase is stored in the keyring.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KipSftpAuth {
+    Password,
+    Key { private_key: PathBuf },
+}
+
+/// An SFTP session shared by every request of a run
+pub type KipSftpClient = KipRemoteClient<Sftp>;
+
+impl KipSftp {
+    pub fn new<S: Into<String>>(
+        host: S,
+        port: u16,
+        username: S,
+        auth: KipSftpAuth,
+        root_path: S,
+    ) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            username: username.into(),
+            auth,
+            root_path: root_path.into(),
+            known_hosts: None,
+        }
+    }
+
+    fn known_hosts_path(&self) -> Result<PathBuf> {
+        match &self.known_hosts {
+            Some(path) => Ok(path.clone()),
+            None => match BaseDirs::new() {
+                Some(dirs) => Ok(dirs.home_dir().join(".ssh").join("known_hosts")),
+                None => bail!("unable to determine home directory"),
+            },
+        }
+    }
+
+    /// Fails unless the server's host key matches the one
+    /// recorded for it in known_hosts
+    fn verify_host_key(&self, session: &Session) -> Result<()> {
+        let Some((key, _)) = session.host_key() else {
+            bail!("{} didn't send a host key", self.host)
+        };
+        let path = self.known_hosts_path()?;
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => bail!(
+                "{} isn't in {}. Connect to it with ssh once to verify and record its host key.",
+                self.host,
+                path.display()
+            ),
+            CheckResult::Mismatch => bail!(
+                "the host key of {} doesn't match the one in {}. It may have been changed, or the connection intercepted.",
+                self.host,
+                path.display()
+            ),
+            CheckResult::Failure => bail!("unable to verify the host key of {}", self.host),
+        }
+    }
+
+    /// Returns the path of every chunk stored for a job,
+    /// relative to the root directory
+    pub async fn list_chunks(&self, client: &KipSftpClient, job_id: Uuid) -> Result<Vec<String>> {
+        self.list_all(Some(client), job_id).await
+    }
+
+    /// Removes the temporary files of writes a job left
+    /// unfinished, such as when kip crashed mid-upload.
+    /// Returns how many were removed.
+    pub async fn remove_partial_writes(
+        &self,
+        client: &KipSftpClient,
+        job_id: Uuid,
+    ) -> Result<usize> {
+        request(self, client, move |sftp, session| {
+            KipRemoteFs::remove_partial_writes(sftp, session, job_id)
+        })
+        .await
+    }
+}
+
+impl KipRemoteFs for KipSftp {
+    type Session = Sftp;
+
+    /// Opens an SFTP session, verifying the server's host key
+    /// before authenticating
+    fn connect(&self, secret: &str) -> Result<Sftp> {
+        debug!("connecting to {}:{}", self.host, self.port);
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("unable to connect to {}:{}", self.host, self.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(SFTP_TIMEOUT_MS);
+        session.handshake().map_err(sftp_error)?;
+        self.verify_host_key(&session)?;
+        match &self.auth {
+            KipSftpAuth::Password => session.userauth_password(&self.username, secret),
+            KipSftpAuth::Key { private_key } => session.userauth_pubkey_file(
+                &self.username,
+                None,
+                private_key,
+                Some(secret).filter(|s| !s.is_empty()),
+            ),
+        }
+        .with_context(|| format!("unable to authenticate as {}", self.username))?;
+        if !session.authenticated() {
+            bail!("unable to authenticate as {}", self.username)
+        }
+        session.sftp().map_err(sftp_error)
+    }
+
+    /// Chunk and manifest paths are stored relative to the
+    /// root directory
+    fn resolve_path(&self, remote_path: &str) -> String {
+        format!("{}/{remote_path}", self.root_path.trim_end_matches('/'))
+    }
+
+    fn exists(&self, session: &Sftp, path: &str) -> Result<bool> {
+        match session.stat(Path::new(path)) {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(sftp_error(e)),
+        }
+    }
+
+    fn is_dir(&self, session: &Sftp, path: &str) -> bool {
+        session
+            .stat(Path::new(path))
+            .is_ok_and(|stat| stat.is_dir())
+    }
+
+    fn mkdir(&self, session: &Sftp, path: &str) -> Result<()> {
+        session.mkdir(Path::new(path), 0o700).map_err(sftp_error)
+    }
+
+    fn read_dir(&self, session: &Sftp, dir: &str) -> Result<Vec<(String, bool)>> {
+        let entries = match session.readdir(Path::new(dir)) {
+            Ok(entries) => entries,
+            Err(e) if is_not_found(&e) => return Ok(vec![]),
+            Err(e) => return Err(sftp_error(e)),
+        };
+        Ok(entries
+            .into_iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_str()?.to_owned();
+                Some((name, stat.is_dir()))
+            })
+            .collect())
+    }
+
+    fn read(&self, session: &Sftp, path: &str) -> Result<Vec<u8>> {
+        let mut file = session.open(Path::new(path)).map_err(sftp_error)?;
+        let mut bytes = Vec::<u8>::new();
+        file.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write(&self, session: &Sftp, path: &str, bytes: &[u8]) -> Result<()> {
+        let mut file = session
+            .open_mode(
+                Path::new(path),
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o600,
+                OpenType::File,
+            )
+            .map_err(sftp_error)?;
+        file.write_all(bytes)?;
+        file.close().map_err(sftp_error)
+    }
+
+    fn rename(&self, session: &Sftp, from: &str, to: &str) -> Result<()> {
+        // SFTPv3 servers such as OpenSSH won't rename over an
+        // existing file, so the destination is removed first
+        self.unlink(session, to)?;
+        session
+            .rename(Path::new(from), Path::new(to), None)
+            .map_err(sftp_error)
+    }
+
+    fn unlink(&self, session: &Sftp, path: &str) -> Result<()> {
+        match session.unlink(Path::new(path)) {
+            Ok(_) => Ok(()),
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(sftp_error(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl KipProvider for KipSftp {
+    type Client = KipSftpClient;
+    type Item = String;
+
+    async fn upload(
+        &self,
+        client: Option<&Self::Client>,
+        opts: KipUploadOpts,
+        chunk: &FileChunk,
+        chunk_bytes: Bytes,
+    ) -> Result<usize> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        let remote_path = chunk_path(opts.job_id, &chunk.hash);
+        request(self, client, move |sftp, session| {
+            sftp.write_atomic(session, &sftp.resolve_path(&remote_path), &chunk_bytes)?;
+            Ok(chunk_bytes.len())
+        })
+        .await
+    }
+
+    async fn download(&self, client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        let path = self.resolve_path(file_name);
+        request(self, client, move |sftp, session| sftp.read(session, &path)).await
+    }
+
+    async fn delete(&self, client: Option<&Self::Client>, file_name: &str) -> Result<()> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        // Chunks already deleted by an earlier attempt are ignored
+        let path = self.resolve_path(file_name);
+        request(self, client, move |sftp, session| {
+            sftp.unlink(session, &path)
+        })
+        .await
+    }
+
+    async fn contains(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        hash: &str,
+    ) -> Result<bool> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        let path = self.resolve_path(&chunk_path(job_id, hash));
+        request(self, client, move |sftp, session| {
+            sftp.exists(session, &path)
+        })
+        .await
+    }
+
+    async fn list_all(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+    ) -> Result<Vec<Self::Item>> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        request(self, client, move |sftp, session| {
+            KipRemoteFs::list_chunks(sftp, session, job_id)
+        })
+        .await
+    }
+
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        let path = self.resolve_path(&format!("{job_id}/manifests/{run_id}.manifest"));
+        let manifest_bytes = manifest_bytes.to_vec();
+        request(self, client, move |sftp, session| {
+            sftp.write_atomic(session, &path, &manifest_bytes)?;
+            Ok(manifest_bytes.len())
+        })
+        .await
+    }
+
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>> {
+        let Some(client) = client else {
+            bail!("sftp client not provided")
+        };
+        request(self, client, move |sftp, session| {
+            KipRemoteFs::list_manifests(sftp, session)
+        })
+        .await
+    }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        // The run's manifest may never have been uploaded
+        self.delete(client, &format!("{job_id}/manifests/{run_id}.manifest"))
+            .await
+    }
+}
+
+// libssh2 error codes of dropped or timed out connections
+const SESSION_TRANSIENT_CODES: [i32; 5] = [
+    -7,  // LIBSSH2_ERROR_SOCKET_SEND
+    -9,  // LIBSSH2_ERROR_TIMEOUT
+    -13, // LIBSSH2_ERROR_SOCKET_DISCONNECT
+    -30, // LIBSSH2_ERROR_SOCKET_TIMEOUT
+    -43, // LIBSSH2_ERROR_SOCKET_RECV
+];
+// SFTP status codes of lost connections
+const SFTP_TRANSIENT_CODES: [i32; 2] = [
+    6, // LIBSSH2_FX_NO_CONNECTION
+    7, // LIBSSH2_FX_CONNECTION_LOST
+];
+
+/// Marks errors of dropped or timed out connections as
+/// transient, so the request is retried on a new session
+fn sftp_error(e: ssh2::Error) -> anyhow::Error {
+    let is_transient = match e.code() {
+        ErrorCode::Session(code) => SESSION_TRANSIENT_CODES.contains(&code),
+        ErrorCode::SFTP(code) => SFTP_TRANSIENT_CODES.contains(&code),
+    };
+    match is_transient {
+        true => transient(e),
+        false => e.into(),
+    }
+}
+
+fn is_not_found(e: &ssh2::Error) -> bool {
+    // LIBSSH2_FX_NO_SUCH_FILE and LIBSSH2_FX_NO_SUCH_PATH
+    matches!(e.code(), ErrorCode::SFTP(2 | 10))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::is_transient;
+
+    #[test]
+    fn test_sftp_paths() {
+        let mut sftp = KipSftp::new(
+            "backup.example.com",
+            22,
+            "kip",
+            KipSftpAuth::Password,
+            "/srv/kip/",
+        );
+        assert_eq!(
+            sftp.resolve_path("a/manifests/1.manifest"),
+            "/srv/kip/a/manifests/1.manifest"
+        );
+        sftp.known_hosts = Some(PathBuf::from("/etc/ssh/ssh_known_hosts"));
+        assert_eq!(
+            sftp.known_hosts_path().unwrap(),
+            PathBuf::from("/etc/ssh/ssh_known_hosts")
+        );
+    }
+
+    #[test]
+    fn test_sftp_error() {
+        let dropped = ssh2::Error::new(ErrorCode::Session(-13), "socket disconnected");
+        assert!(is_transient(&sftp_error(dropped)));
+        let denied = ssh2::Error::new(ErrorCode::SFTP(3), "permission denied");
+        assert!(!is_transient(&sftp_error(denied)));
+        assert!(is_not_found(&ssh2::Error::new(
+            ErrorCode::SFTP(2),
+            "no such file"
+        )));
+    }
+
+    // Runs against an SSH server, such as:
+    // docker run -p 2222:2222 -e USER_NAME=kip -e USER_PASSWORD=kip
+    //   -e PASSWORD_ACCESS=true lscr.io/linuxserver/openssh-server
+    // ssh-keyscan -p 2222 localhost > /tmp/known_hosts
+    // KIP_TEST_SFTP_HOST=localhost KIP_TEST_SFTP_PORT=2222 KIP_TEST_SFTP_USER=kip
+    // KIP_SFTP_SECRET=kip KIP_TEST_SFTP_KNOWN_HOSTS=/tmp/known_hosts cargo test test_sftp_server -- --ignored
+    #[tokio::test]
+    #[ignore = "needs an SSH server"]
+    async fn test_sftp_server() {
+        let host = std::env::var("KIP_TEST_SFTP_HOST").expect("KIP_TEST_SFTP_HOST must be set");
+        let port = std::env::var("KIP_TEST_SFTP_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(22);
+        let user = std::env::var("KIP_TEST_SFTP_USER").unwrap();
+        let job_id = Uuid::new_v4();
+        let mut sftp = KipSftp::new(
+            host,
+            port,
+            user,
+            KipSftpAuth::Password,
+            format!("kip-test-{}", &job_id.to_string()[..8]),
+        );
+        sftp.known_hosts = std::env::var("KIP_TEST_SFTP_KNOWN_HOSTS")
+            .ok()
+            .map(PathBuf::from);
+        let client = KipSftpClient::from_env(SFTP_SECRET_VAR);
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let chunk = FileChunk::new("test/random.txt", "abc123", 0, 3, 3);
+        let uploaded = sftp
+            .upload(
+                Some(&client),
+                KipUploadOpts::new(job_id, tx),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 3);
+        assert!(sftp
+            .contains(Some(&client), job_id, "abc123")
+            .await
+            .unwrap());
+        assert!(!sftp
+            .contains(Some(&client), job_id, "def456")
+            .await
+            .unwrap());
+        let remote_path = chunk_path(job_id, "abc123");
+        assert_eq!(
+            sftp.list_chunks(&client, job_id).await.unwrap(),
+            vec![remote_path.clone()]
+        );
+        assert_eq!(
+            sftp.download(Some(&client), &remote_path).await.unwrap(),
+            b"kip"
+        );
+        sftp.upload_manifest(Some(&client), job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        assert_eq!(
+            sftp.list_manifests(Some(&client)).await.unwrap(),
+            vec![format!("{job_id}/manifests/1.manifest")]
+        );
+        // Rewriting a manifest replaces it
+        sftp.upload_manifest(Some(&client), job_id, 1, b"manifest v2")
+            .await
+            .unwrap();
+        assert_eq!(
+            sftp.download(Some(&client), &format!("{job_id}/manifests/1.manifest"))
+                .await
+                .unwrap(),
+            b"manifest v2"
+        );
+        assert_eq!(
+            sftp.remove_partial_writes(&client, job_id).await.unwrap(),
+            0
+        );
+
+        sftp.delete(Some(&client), &remote_path).await.unwrap();
+        sftp.delete_manifest(Some(&client), job_id, 1)
+            .await
+            .unwrap();
+        assert!(sftp.list_chunks(&client, job_id).await.unwrap().is_empty());
+    }
+}
@@ -2,168 +2,438 @@
 // Copyright (c) 2023 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
-use super::{KipProvider, ProgressBar};
-use crate::compress::KipCompressionOpts;
-use crate::crypto::encrypt;
-use crate::job::KipFile;
-use crate::providers::FileChunk;
-use anyhow::{bail, Result};
+use super::local::{chunk_path, request, KipRemoteClient, KipRemoteFs};
+use super::KipUploadOpts;
+use crate::chunk::FileChunk;
+use crate::providers::KipProvider;
+use crate::retry::transient;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use linya::{Bar, Progress};
-use pavao::{SmbClient, SmbFile, SmbCredentials, SmbOpenOptions, SmbOptions};
-use std::collections::HashMap;
-use std::io;
-use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use bytes::Bytes;
+use pavao::{
+    SmbClient, SmbCredentials, SmbDirentType, SmbError, SmbMode, SmbOpenOptions, SmbOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+use tracing::debug;
 use uuid::Uuid;
 
-struct KipSmb {
-    pub server: SocketAddr,
+/// Env var the password is loaded into from the keyring
+pub const SMB_SECRET_VAR: &str = "KIP_SMB_SECRET";
+/// Port SMB2 and SMB3 servers listen on
+pub const DEFAULT_SMB_PORT: u16 = 445;
+
+/// A directory in a share on a Samba or Windows server,
+/// reached over SMB2 or SMB3
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KipSmb {
+    pub host: String,
+    pub port: u16,
     pub share: String,
     pub username: String,
     pub workgroup: String,
-    pub destination: PathBuf,
+    /// Directory in the share chunks and manifests are
+    /// stored in. Empty uses the share's root.
+    pub root_path: String,
 }
 
+/// An SMB connection shared by every request of a run
+pub type KipSmbClient = KipRemoteClient<SmbClient>;
+
 impl KipSmb {
     pub fn new<S: Into<String>>(
-        server: SocketAddr,
+        host: S,
+        port: u16,
         share: S,
         username: S,
         workgroup: S,
-        destination: PathBuf,
+        root_path: S,
     ) -> Self {
         Self {
-            server,
+            host: host.into(),
+            port,
             share: share.into(),
             username: username.into(),
             workgroup: workgroup.into(),
-            destination,
+            root_path: root_path.into(),
+        }
+    }
+
+    /// The root directory as a UNC-style path,
+    /// ex: //nas.local/backups/kip
+    pub fn location(&self) -> String {
+        let mut location = format!("//{}/{}", self.host, self.share.trim_matches('/'));
+        let root = self.root_path.trim_matches('/');
+        if !root.is_empty() {
+            location.push('/');
+            location.push_str(root);
+        }
+        location
+    }
+
+    /// Returns the path of every chunk stored for a job,
+    /// relative to the root directory
+    pub async fn list_chunks(&self, client: &KipSmbClient, job_id: Uuid) -> Result<Vec<String>> {
+        self.list_all(Some(client), job_id).await
+    }
+
+    /// Removes the temporary files of writes a job left
+    /// unfinished, such as when kip crashed mid-upload.
+    /// Returns how many were removed.
+    pub async fn remove_partial_writes(
+        &self,
+        client: &KipSmbClient,
+        job_id: Uuid,
+    ) -> Result<usize> {
+        request(self, client, move |smb, session| {
+            KipRemoteFs::remove_partial_writes(smb, session, job_id)
+        })
+        .await
+    }
+}
+
+impl KipRemoteFs for KipSmb {
+    type Session = SmbClient;
+
+    /// Connects to the share
+    fn connect(&self, secret: &str) -> Result<SmbClient> {
+        debug!("connecting to {}", self.location());
+        SmbClient::new(
+            SmbCredentials::default()
+                .server(format!("smb://{}:{}", self.host, self.port))
+                .share(format!("/{}", self.share.trim_matches('/')))
+                .username(&self.username)
+                .password(secret)
+                .workgroup(&self.workgroup),
+            SmbOptions::default()
+                .case_sensitive(true)
+                .one_share_per_server(true),
+        )
+        .map_err(smb_error)
+        .with_context(|| format!("unable to connect to {}", self.location()))
+    }
+
+    /// Chunk and manifest paths are stored relative to the
+    /// root directory. Paths in the share start with a slash.
+    fn resolve_path(&self, remote_path: &str) -> String {
+        let root = self.root_path.trim_matches('/');
+        let remote_path = remote_path.trim_matches('/');
+        match (root.is_empty(), remote_path.is_empty()) {
+            (true, _) => format!("/{remote_path}"),
+            (false, true) => format!("/{root}"),
+            (false, false) => format!("/{root}/{remote_path}"),
+        }
+    }
+
+    fn exists(&self, session: &SmbClient, path: &str) -> Result<bool> {
+        match session.stat(path) {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(smb_error(e)),
+        }
+    }
+
+    fn is_dir(&self, session: &SmbClient, path: &str) -> bool {
+        session.list_dir(path).is_ok()
+    }
+
+    fn mkdir(&self, session: &SmbClient, path: &str) -> Result<()> {
+        session.mkdir(path, SmbMode::from(0o700)).map_err(smb_error)
+    }
+
+    fn read_dir(&self, session: &SmbClient, dir: &str) -> Result<Vec<(String, bool)>> {
+        let entries = match session.list_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if is_not_found(&e) => return Ok(vec![]),
+            Err(e) => return Err(smb_error(e)),
+        };
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.name() != "." && entry.name() != "..")
+            .filter_map(|entry| match entry.get_type() {
+                SmbDirentType::Dir => Some((entry.name().to_owned(), true)),
+                SmbDirentType::File => Some((entry.name().to_owned(), false)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn read(&self, session: &SmbClient, path: &str) -> Result<Vec<u8>> {
+        let mut file = session
+            .open_with(path, SmbOpenOptions::default().read(true))
+            .map_err(smb_error)?;
+        let mut bytes = Vec::<u8>::new();
+        file.read_to_end(&mut bytes).map_err(io_error)?;
+        Ok(bytes)
+    }
+
+    fn write(&self, session: &SmbClient, path: &str, bytes: &[u8]) -> Result<()> {
+        let mut file = session
+            .open_with(
+                path,
+                SmbOpenOptions::default()
+                    .create(true)
+                    .write(true)
+                    .truncate(true),
+            )
+            .map_err(smb_error)?;
+        file.write_all(bytes).map_err(io_error)?;
+        file.flush().map_err(io_error)
+    }
+
+    fn rename(&self, session: &SmbClient, from: &str, to: &str) -> Result<()> {
+        session.rename(from, to).map_err(smb_error)
+    }
+
+    fn unlink(&self, session: &SmbClient, path: &str) -> Result<()> {
+        match session.unlink(path) {
+            Ok(_) => Ok(()),
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(smb_error(e)),
         }
     }
 }
 
 #[async_trait]
 impl KipProvider for KipSmb {
-    type Item = SmbFile;
+    type Client = KipSmbClient;
+    type Item = String;
 
     async fn upload(
         &self,
-        f: &Path,
-        chunks_map: HashMap<FileChunk, &[u8]>,
+        client: Option<&Self::Client>,
+        opts: KipUploadOpts,
+        chunk: &FileChunk,
+        chunk_bytes: Bytes,
+    ) -> Result<usize> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
+        };
+        let remote_path = chunk_path(opts.job_id, &chunk.hash);
+        request(self, client, move |smb, session| {
+            smb.write_atomic(session, &smb.resolve_path(&remote_path), &chunk_bytes)?;
+            Ok(chunk_bytes.len())
+        })
+        .await
+    }
+
+    async fn download(&self, client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
+        };
+        let path = self.resolve_path(file_name);
+        request(self, client, move |smb, session| smb.read(session, &path)).await
+    }
+
+    async fn delete(&self, client: Option<&Self::Client>, file_name: &str) -> Result<()> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
+        };
+        // Chunks already deleted by an earlier attempt are ignored
+        let path = self.resolve_path(file_name);
+        request(self, client, move |smb, session| smb.unlink(session, &path)).await
+    }
+
+    async fn contains(
+        &self,
+        client: Option<&Self::Client>,
         job_id: Uuid,
-        secret: &str,
-        compress: KipCompressionOpts,
-        progress: Arc<Mutex<Progress>>,
-        bar: &Bar,
-    ) -> Result<(Vec<FileChunk>, u64)> {
-        // Setup SMB client
-        let server = format!("{}:{}", self.server.ip(), self.server.port());
-        let client = SmbClient::new(
-            SmbCredentials::default()
-                .server(server)
-                .share(self.share)
-                .username(self.username)
-                .password(password)
-                .workgroup(self.workgroup),
-            SmbOptions::default().one_share_per_server(true),
-        )?;
-        // Upload each chunk
-        let mut chunks = vec![];
-        let mut bytes_uploaded: u64 = 0;
-        for (mut chunk, chunk_bytes) in chunks_map {
-            // Always compress before encryption
-            let mut compressed = Vec::<u8>::new();
-            if compress.enabled {
-                match compress.alg {
-                    KipCompAlg::Zstd => compressed = compress_zstd(chunk_bytes).await?,
-                    KipCompAlg::Lzma => compressed = compress_lzma(chunk_bytes).await?,
-                    KipCompAlg::Gzip => compressed = compress_gzip(chunk_bytes).await?,
-                    KipCompAlg::Brotli => compressed = compress_brotli(chunk_bytes).await?,
-                }
-            } else {
-                compressed.extend_from_slice(chunk_bytes);
-            }
-            // Encrypt chunk
-            let encrypted = match encrypt(&compressed, secret) {
-                Ok(ec) => ec,
-                Err(e) => {
-                    bail!("failed to encrypt chunk: {}.", e)
-                }
-            };
-            // Get amount of bytes uploaded in this chunk
-            // after compression and encryption
-            let ce_bytes_len = encrypted.len();
-            // Upload
-            let smb_path = format!(
-                "{}\{}\chunks\{}.chunk",
-                self.destination.into(),
-                job_id,
-                chunk.hash
-            );
-            // Open file to write
-            let mut writer =
-                client.open_with(smb_path, SmbOpenOptions::default().create(true).write(true))?;
-            // Write chunk
-            let _ = io::copy(&mut chunk_bytes, &mut writer)?;
-            // Push chunk onto chunks hashmap for return
-            chunk.local_path = f.canonicalize()?;
-            chunks.push(chunk);
-            // Increment progress bar for this file by one
-            // since one chunk was uploaded
-            progress.lock().await.inc_and_draw(bar, chunk_bytes.len());
-            let ce_bytes_len_u64: u64 = ce_bytes_len.try_into()?;
-            bytes_uploaded += ce_bytes_len_u64;
-        }
-        Ok((chunks, bytes_uploaded))
+        hash: &str,
+    ) -> Result<bool> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
+        };
+        let path = self.resolve_path(&chunk_path(job_id, hash));
+        request(self, client, move |smb, session| smb.exists(session, &path)).await
     }
 
-    async fn download(
+    async fn list_all(
         &self,
-        f: &str,
-        secret: &str,
-        compress: KipCompressionOpts,
-    ) -> Result<Vec<u8>> {
-        // Setup SMB client
-        let server = format!("{}:{}", self.server.ip(), self.server.port());
-        let client = SmbClient::new(
-            SmbCredentials::default()
-                .server(server)
-                .share(self.share)
-                .username(self.username)
-                .password(password)
-                .workgroup(self.workgroup),
-            SmbOptions::default().one_share_per_server(true),
-        )?;
-        // Read result from SMB and convert to bytes
-        let result_bytes = match client.open_with(f, SmbOpenOptions::default().read(true)) {
-            Ok(rb) => rb,
-            Err(e) => {bail!("failed to read file from SMB: {}", e)}
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+    ) -> Result<Vec<Self::Item>> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
         };
-        // Decrypt result_bytes
-        let decrypted = match decrypt(&bytes, secret) {
-            Ok(dc) => dc,
-            Err(e) => {
-                bail!("failed to decrypt file: {}.", e)
-            }
+        request(self, client, move |smb, session| {
+            KipRemoteFs::list_chunks(smb, session, job_id)
+        })
+        .await
+    }
+
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
         };
-        // Decompress decrypted bytes
-        let mut decompressed = Vec::<u8>::new();
-        if compress.enabled {
-            match compress.alg {
-                KipCompAlg::Zstd => decompressed = decompress_zstd(&decrypted).await?,
-                KipCompAlg::Lzma => decompressed = decompress_lzma(&decrypted).await?,
-                KipCompAlg::Gzip => decompressed = decompress_gzip(&decrypted).await?,
-                KipCompAlg::Brotli => decompressed = decompress_brotli(&decrypted).await?,
-            }
-        } else {
-            decompressed.extend_from_slice(&decrypted);
-        }
-        // Drop read lock on chunk
-        drop(result_bytes);
-        // Return downloaded & decrypted bytes
-        Ok(decompressed)
+        let path = self.resolve_path(&format!("{job_id}/manifests/{run_id}.manifest"));
+        let manifest_bytes = manifest_bytes.to_vec();
+        request(self, client, move |smb, session| {
+            smb.write_atomic(session, &path, &manifest_bytes)?;
+            Ok(manifest_bytes.len())
+        })
+        .await
+    }
+
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>> {
+        let Some(client) = client else {
+            bail!("smb client not provided")
+        };
+        request(self, client, move |smb, session| {
+            KipRemoteFs::list_manifests(smb, session)
+        })
+        .await
+    }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        // The run's manifest may never have been uploaded
+        self.delete(client, &format!("{job_id}/manifests/{run_id}.manifest"))
+            .await
+    }
+}
+
+/// Marks errors of dropped or timed out connections as
+/// transient, so the request is retried on a new connection
+fn smb_error(e: SmbError) -> anyhow::Error {
+    match e {
+        SmbError::Io(io) => io_error(io),
+        e => e.into(),
+    }
+}
+
+fn io_error(e: std::io::Error) -> anyhow::Error {
+    // libsmbclient reports a lost connection as ENOTCONN,
+    // which is_transient doesn't retry on its own
+    match e.kind() {
+        ErrorKind::NotConnected => transient(e),
+        _ => e.into(),
+    }
+}
+
+fn is_not_found(e: &SmbError) -> bool {
+    matches!(e, SmbError::Io(io) if io.kind() == ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::is_transient;
+
+    #[test]
+    fn test_smb_paths() {
+        let mut smb = KipSmb::new(
+            "nas.local",
+            DEFAULT_SMB_PORT,
+            "backups",
+            "kip",
+            "WORKGROUP",
+            "/kip/",
+        );
+        assert_eq!(smb.location(), "//nas.local/backups/kip");
+        assert_eq!(
+            smb.resolve_path("a/manifests/1.manifest"),
+            "/kip/a/manifests/1.manifest"
+        );
+        assert_eq!(smb.resolve_path(""), "/kip");
+        smb.root_path = String::new();
+        assert_eq!(smb.location(), "//nas.local/backups");
+        assert_eq!(smb.resolve_path("a/chunks"), "/a/chunks");
+        assert_eq!(smb.resolve_path(""), "/");
+    }
+
+    #[test]
+    fn test_smb_error() {
+        let dropped = std::io::Error::from(ErrorKind::NotConnected);
+        assert!(is_transient(&smb_error(SmbError::Io(dropped))));
+        let reset = std::io::Error::from(ErrorKind::ConnectionReset);
+        assert!(is_transient(&smb_error(SmbError::Io(reset))));
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert!(!is_transient(&smb_error(SmbError::Io(denied))));
+        let missing = std::io::Error::from(ErrorKind::NotFound);
+        assert!(is_not_found(&SmbError::Io(missing)));
+    }
+
+    // Runs against a Samba server, such as:
+    // docker run -p 445:445 dperson/samba -u "kip;kip" -s "kip;/share;yes;no;no;kip"
+    // KIP_TEST_SMB_HOST=localhost KIP_TEST_SMB_SHARE=kip KIP_TEST_SMB_USER=kip
+    // KIP_SMB_SECRET=kip cargo test test_smb_server -- --ignored
+    #[tokio::test]
+    #[ignore = "needs a Samba server"]
+    async fn test_smb_server() {
+        let host = std::env::var("KIP_TEST_SMB_HOST").expect("KIP_TEST_SMB_HOST must be set");
+        let port = std::env::var("KIP_TEST_SMB_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(DEFAULT_SMB_PORT);
+        let share = std::env::var("KIP_TEST_SMB_SHARE").unwrap();
+        let user = std::env::var("KIP_TEST_SMB_USER").unwrap();
+        let job_id = Uuid::new_v4();
+        let smb = KipSmb::new(
+            host,
+            port,
+            share,
+            user,
+            String::from("WORKGROUP"),
+            format!("kip-test-{}", &job_id.to_string()[..8]),
+        );
+        let client = KipSmbClient::from_env(SMB_SECRET_VAR);
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let chunk = FileChunk::new("test/random.txt", "abc123", 0, 3, 3);
+        let uploaded = smb
+            .upload(
+                Some(&client),
+                KipUploadOpts::new(job_id, tx),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 3);
+        assert!(smb.contains(Some(&client), job_id, "abc123").await.unwrap());
+        assert!(!smb.contains(Some(&client), job_id, "def456").await.unwrap());
+        let remote_path = chunk_path(job_id, "abc123");
+        assert_eq!(
+            smb.list_chunks(&client, job_id).await.unwrap(),
+            vec![remote_path.clone()]
+        );
+        assert_eq!(
+            smb.download(Some(&client), &remote_path).await.unwrap(),
+            b"kip"
+        );
+        // Manifests are replaced when uploaded again
+        smb.upload_manifest(Some(&client), job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        smb.upload_manifest(Some(&client), job_id, 1, b"manifest2")
+            .await
+            .unwrap();
+        assert_eq!(
+            smb.list_manifests(Some(&client)).await.unwrap(),
+            vec![format!("{job_id}/manifests/1.manifest")]
+        );
+        assert_eq!(
+            smb.download(Some(&client), &format!("{job_id}/manifests/1.manifest"))
+                .await
+                .unwrap(),
+            b"manifest2"
+        );
+        assert_eq!(smb.remove_partial_writes(&client, job_id).await.unwrap(), 0);
+
+        smb.delete(Some(&client), &remote_path).await.unwrap();
+        // Deleting twice isn't an error
+        smb.delete(Some(&client), &remote_path).await.unwrap();
+        smb.delete_manifest(Some(&client), job_id, 1).await.unwrap();
+        assert!(smb.list_chunks(&client, job_id).await.unwrap().is_empty());
     }
 }
@@ -8,6 +8,7 @@ use crate::job::KipFile;
 use crate::providers::KipProvider;
 use anyhow::Result;
 use async_trait::async_trait;
+use bytes::Bytes;
 use memmap2::MmapOptions;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
@@ -41,6 +42,33 @@ impl KipUsb {
             used_capacity,
         }
     }
+
+    /// Chunk and manifest paths are stored relative to the
+    /// drive's root so the drive can be mounted elsewhere.
+    fn resolve_path(&self, remote_path: &str) -> PathBuf {
+        self.root_path.join(remote_path)
+    }
+
+    /// Returns the path of every chunk stored for a job,
+    /// relative to the drive's root
+    pub async fn list_chunks(&self, job_id: Uuid) -> Result<Vec<String>> {
+        // Nothing has been uploaded yet
+        if !self.root_path.join(format!("{job_id}/chunks")).exists() {
+            return Ok(vec![]);
+        }
+        let root = self.root_path.canonicalize()?;
+        Ok(self
+            .list_all(None, job_id)
+            .await?
+            .into_iter()
+            .filter_map(|kf| {
+                kf.path
+                    .strip_prefix(&root)
+                    .ok()
+                    .map(|p| p.display().to_string())
+            })
+            .collect())
+    }
 }
 
 #[async_trait]
@@ -48,12 +76,12 @@ impl KipProvider for KipUsb {
     type Client = ();
     type Item = KipFile;
 
-    async fn upload<'b>(
+    async fn upload(
         &self,
         _client: Option<&Self::Client>,
         opts: KipUploadOpts,
         chunk: &FileChunk,
-        chunk_bytes: &'b [u8],
+        chunk_bytes: Bytes,
     ) -> Result<usize> {
         // Create all parent dirs if missing
         create_dir_all(Path::new(&format!(
@@ -76,13 +104,14 @@ impl KipProvider for KipUsb {
         let mut cfile = File::create(usb_path.clone()).await?;
         // Copy encrypted and compressed chunk bytes into newly created
         // chunk file
-        cfile.write_all(chunk_bytes).await?;
+        cfile.write_all(&chunk_bytes).await?;
         Ok(ce_bytes_len)
     }
 
     async fn download(&self, _client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
-        // Read result from S3 and convert to bytes
-        let path = Path::new(file_name);
+        // Read result from the USB drive
+        let path = self.resolve_path(file_name);
+        let path = path.as_path();
         let bytes = if path.metadata()?.len() > crate::MAX_OPEN_FILE_LEN {
             debug!("opening {} with mmap", path.display());
             // SAFETY: unsafe used here for mmap
@@ -101,7 +130,7 @@ impl KipProvider for KipUsb {
     }
 
     async fn delete(&self, _client: Option<&Self::Client>, file_name: &str) -> Result<()> {
-        let path = Path::new(file_name);
+        let path = self.resolve_path(file_name);
         if path.is_dir() {
             tokio::fs::remove_dir_all(path).await?;
         } else {
@@ -112,22 +141,14 @@ impl KipProvider for KipUsb {
 
     async fn contains(
         &self,
-        client: Option<&Self::Client>,
+        _client: Option<&Self::Client>,
         job_id: Uuid,
         hash: &str,
     ) -> Result<bool> {
-        // Check S3 for duplicates of chunk
-        let file_objs = self.list_all(client, job_id).await?;
-        // If the S3 bucket is empty, no need to check for duplicate chunks
-        if !file_objs.is_empty() {
-            for obj in file_objs {
-                if obj.hash == hash {
-                    // Duplicate chunk found, return true
-                    return Ok(true);
-                }
-            }
-        }
-        Ok(false)
+        Ok(self
+            .root_path
+            .join(format!("{job_id}/chunks/{hash}.chunk"))
+            .exists())
     }
 
     async fn list_all(
@@ -150,4 +171,59 @@ impl KipProvider for KipUsb {
         }
         Ok(kfs)
     }
+
+    async fn upload_manifest<'b>(
+        &self,
+        _client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        // Create all parent dirs if missing
+        let manifest_dir = self.root_path.join(format!("{job_id}/manifests/"));
+        create_dir_all(&manifest_dir).await?;
+        let mut mfile = File::create(manifest_dir.join(format!("{run_id}.manifest"))).await?;
+        mfile.write_all(manifest_bytes).await?;
+        mfile.sync_all().await?;
+        Ok(manifest_bytes.len())
+    }
+
+    async fn list_manifests(&self, _client: Option<&Self::Client>) -> Result<Vec<String>> {
+        let mut manifests = Vec::<String>::new();
+        // Manifests live at <root>/<job_id>/manifests/<run_id>.manifest
+        for entry in WalkDir::new(&self.root_path).min_depth(3).max_depth(3) {
+            let entry = entry?;
+            let path = entry.path();
+            let in_manifests = path
+                .parent()
+                .and_then(|p| p.file_name())
+                .map(|p| p == "manifests")
+                .unwrap_or(false);
+            if !entry.file_type().is_file()
+                || !in_manifests
+                || path.extension().unwrap_or_default() != "manifest"
+            {
+                continue;
+            }
+            if let Ok(rel) = path.strip_prefix(&self.root_path) {
+                manifests.push(rel.display().to_string());
+            }
+        }
+        Ok(manifests)
+    }
+
+    async fn delete_manifest(
+        &self,
+        _client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        let path = self.resolve_path(&format!("{job_id}/manifests/{run_id}.manifest"));
+        match tokio::fs::remove_file(path).await {
+            Ok(_) => Ok(()),
+            // The run's manifest may never have been uploaded
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
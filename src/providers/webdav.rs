@@ -0,0 +1,649 @@
+//
+// Copyright (c) 2023 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use super::local::chunk_path;
+use super::KipUploadOpts;
+use crate::chunk::FileChunk;
+use crate::providers::KipProvider;
+use crate::retry::transient;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Env var the password or bearer token is loaded
+/// into from the keyring
+pub const WEBDAV_SECRET_VAR: &str = "KIP_WEBDAV_SECRET";
+
+// Objects larger than this are uploaded in chunks
+// on servers that support it
+const UPLOAD_CHUNK_SIZE: usize = 10 * 1024 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// Lists a collection's members, but not theirs
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#;
+
+/// A collection on a WebDAV server, such as a folder
+/// in Nextcloud or ownCloud
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KipWebDav {
+    /// URL of the collection chunks and manifests are stored in.
+    /// Ex: https://cloud.example.com/remote.php/dav/files/alice/kip
+    pub url: String,
+    pub auth: KipWebDavAuth,
+}
+
+/// How kip authenticates to the server. The password
+/// or token is stored in the keyring.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum KipWebDavAuth {
+    Basic { username: String },
+    Bearer,
+}
+
+/// An HTTP client shared by every request of a run. It
+/// remembers the collections it created so they aren't
+/// created again for every chunk.
+#[derive(Clone)]
+pub struct KipWebDavClient {
+    http: reqwest::Client,
+    secret: Arc<Zeroizing<String>>,
+    collections: Arc<Mutex<HashSet<String>>>,
+}
+
+impl KipWebDavClient {
+    /// Reads the password or token from the environment
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()?,
+            secret: Arc::new(Zeroizing::new(
+                std::env::var(WEBDAV_SECRET_VAR).unwrap_or_default(),
+            )),
+            collections: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    fn created(&self, url: &str) -> bool {
+        match self.collections.lock() {
+            Ok(collections) => collections.contains(url),
+            Err(poisoned) => poisoned.into_inner().contains(url),
+        }
+    }
+
+    fn set_created(&self, url: String) {
+        match self.collections.lock() {
+            Ok(mut collections) => collections.insert(url),
+            Err(poisoned) => poisoned.into_inner().insert(url),
+        };
+    }
+}
+
+impl KipWebDav {
+    pub fn new<S: Into<String>>(url: S, auth: KipWebDavAuth) -> Self {
+        Self {
+            url: url.into(),
+            auth,
+        }
+    }
+
+    /// Chunk and manifest paths are stored relative to the
+    /// collection's URL
+    fn resolve_url(&self, remote_path: &str) -> String {
+        let base = self.url.trim_end_matches('/');
+        match remote_path.trim_matches('/') {
+            "" => format!("{base}/"),
+            path => format!("{base}/{path}"),
+        }
+    }
+
+    /// Nextcloud and ownCloud accept large files in chunks sent
+    /// to the user's uploads collection. Returns its URL if the
+    /// collection is in a user's files.
+    /// Ex: https://cloud.example.com/remote.php/dav/uploads/alice
+    fn uploads_url(&self) -> Option<String> {
+        let (server, files) = self.url.split_once("/remote.php/dav/files/")?;
+        let user = files.split('/').next().filter(|u| !u.is_empty())?;
+        Some(format!("{server}/remote.php/dav/uploads/{user}"))
+    }
+
+    fn request(&self, client: &KipWebDavClient, method: Method, url: &str) -> RequestBuilder {
+        let request = client.http.request(method, url);
+        match &self.auth {
+            KipWebDavAuth::Basic { username } => {
+                request.basic_auth(username, Some(client.secret.as_str()))
+            }
+            KipWebDavAuth::Bearer => request.bearer_auth(client.secret.as_str()),
+        }
+    }
+
+    /// Sends a request, failing unless the server responds with
+    /// one of the expected statuses
+    async fn send(&self, request: RequestBuilder, expected: &[StatusCode]) -> Result<Response> {
+        let response = request.send().await.map_err(webdav_error)?;
+        let status = response.status();
+        if expected.contains(&status) || (expected.is_empty() && status.is_success()) {
+            return Ok(response);
+        }
+        let e = anyhow::anyhow!("WebDAV server responded with {status}");
+        match is_transient_status(status) {
+            true => Err(transient(e)),
+            false => Err(e),
+        }
+    }
+
+    /// Creates a collection and its missing parents below the
+    /// root collection
+    async fn create_collections(&self, client: &KipWebDavClient, remote_dir: &str) -> Result<()> {
+        let mut path = String::new();
+        for component in remote_dir.split('/').filter(|c| !c.is_empty()) {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(component);
+            let url = self.resolve_url(&path);
+            if client.created(&url) {
+                continue;
+            }
+            // 405 Method Not Allowed means it already exists
+            self.send(
+                self.request(client, Method::from_bytes(b"MKCOL")?, &format!("{url}/")),
+                &[StatusCode::CREATED, StatusCode::METHOD_NOT_ALLOWED],
+            )
+            .await?;
+            client.set_created(url);
+        }
+        Ok(())
+    }
+
+    /// Lists the members of a collection, or nothing if it
+    /// doesn't exist. Returns each member's name and whether
+    /// it's a collection.
+    async fn list(
+        &self,
+        client: &KipWebDavClient,
+        remote_dir: &str,
+    ) -> Result<Vec<(String, bool)>> {
+        let url = self.resolve_url(remote_dir);
+        let url = format!("{}/", url.trim_end_matches('/'));
+        let response = self
+            .send(
+                self.request(client, Method::from_bytes(b"PROPFIND")?, &url)
+                    .header("Depth", "1")
+                    .header("Content-Type", "application/xml")
+                    .body(PROPFIND_BODY),
+                &[StatusCode::MULTI_STATUS, StatusCode::NOT_FOUND],
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        let xml = response.text().await.map_err(webdav_error)?;
+        let dir_path = Url::parse(&url)?.path().trim_end_matches('/').to_owned();
+        Ok(parse_propfind(&xml)?
+            .into_iter()
+            .filter_map(|(href, is_collection)| {
+                // The collection itself is listed among its members
+                let href_path = match Url::parse(&href) {
+                    Ok(href) => href.path().to_owned(),
+                    Err(_) => href,
+                };
+                let href_path = href_path.trim_end_matches('/');
+                if href_path == dir_path {
+                    return None;
+                }
+                let name = href_path.rsplit('/').next()?.to_owned();
+                Some((name, is_collection))
+            })
+            .collect())
+    }
+
+    /// Uploads a file in a single request, or in chunks if it's
+    /// large and the server supports it
+    async fn put(&self, client: &KipWebDavClient, remote_path: &str, body: Bytes) -> Result<()> {
+        if let Some((dir, _)) = remote_path.rsplit_once('/') {
+            self.create_collections(client, dir).await?;
+        }
+        let url = self.resolve_url(remote_path);
+        if body.len() > UPLOAD_CHUNK_SIZE {
+            if let Some(uploads_url) = self.uploads_url() {
+                return self.put_chunked(client, &uploads_url, &url, body).await;
+            }
+        }
+        self.send(
+            self.request(client, Method::PUT, &url)
+                .header("Content-Type", "application/octet-stream")
+                .body(body),
+            &[],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Uploads a file in chunks to an upload collection, then has
+    /// the server assemble them at the destination. Chunks are
+    /// slices of the file's bytes, so they aren't copied.
+    async fn put_chunked(
+        &self,
+        client: &KipWebDavClient,
+        uploads_url: &str,
+        url: &str,
+        body: Bytes,
+    ) -> Result<()> {
+        let upload_url = format!("{uploads_url}/kip-{}", Uuid::new_v4().simple());
+        debug!("uploading {url} in chunks to {upload_url}");
+        self.send(
+            self.request(client, Method::from_bytes(b"MKCOL")?, &upload_url)
+                .header("Destination", url),
+            &[StatusCode::CREATED],
+        )
+        .await?;
+        let result = async {
+            for (i, start) in (0..body.len()).step_by(UPLOAD_CHUNK_SIZE).enumerate() {
+                let chunk = body.slice(start..(start + UPLOAD_CHUNK_SIZE).min(body.len()));
+                // Chunks are numbered from 1 and assembled in order
+                self.send(
+                    self.request(client, Method::PUT, &format!("{upload_url}/{:05}", i + 1))
+                        .header("Destination", url)
+                        .body(chunk),
+                    &[],
+                )
+                .await?;
+            }
+            self.send(
+                self.request(
+                    client,
+                    Method::from_bytes(b"MOVE")?,
+                    &format!("{upload_url}/.file"),
+                )
+                .header("Destination", url)
+                .header("OC-Total-Length", body.len())
+                .header("Overwrite", "T"),
+                &[],
+            )
+            .await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        // The server discards chunks of failed uploads
+        // eventually, but there's no need to wait
+        if result.is_err() {
+            let _ = self
+                .request(client, Method::DELETE, &upload_url)
+                .send()
+                .await;
+        }
+        result
+    }
+
+    /// Returns the path of every chunk stored for a job,
+    /// relative to the collection
+    pub async fn list_chunks(&self, client: &KipWebDavClient, job_id: Uuid) -> Result<Vec<String>> {
+        self.list_all(Some(client), job_id).await
+    }
+}
+
+#[async_trait]
+impl KipProvider for KipWebDav {
+    type Client = KipWebDavClient;
+    type Item = String;
+
+    async fn upload(
+        &self,
+        client: Option<&Self::Client>,
+        opts: KipUploadOpts,
+        chunk: &FileChunk,
+        chunk_bytes: Bytes,
+    ) -> Result<usize> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        let uploaded = chunk_bytes.len();
+        self.put(client, &chunk_path(opts.job_id, &chunk.hash), chunk_bytes)
+            .await?;
+        Ok(uploaded)
+    }
+
+    async fn download(&self, client: Option<&Self::Client>, file_name: &str) -> Result<Vec<u8>> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        let response = self
+            .send(
+                self.request(client, Method::GET, &self.resolve_url(file_name)),
+                &[StatusCode::OK],
+            )
+            .await?;
+        Ok(response.bytes().await.map_err(webdav_error)?.to_vec())
+    }
+
+    async fn delete(&self, client: Option<&Self::Client>, file_name: &str) -> Result<()> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        // Already deleted by an earlier attempt
+        self.send(
+            self.request(client, Method::DELETE, &self.resolve_url(file_name)),
+            &[
+                StatusCode::OK,
+                StatusCode::NO_CONTENT,
+                StatusCode::NOT_FOUND,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn contains(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        hash: &str,
+    ) -> Result<bool> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        let url = self.resolve_url(&chunk_path(job_id, hash));
+        let response = self
+            .send(
+                self.request(client, Method::HEAD, &url),
+                &[StatusCode::OK, StatusCode::NOT_FOUND],
+            )
+            .await?;
+        Ok(response.status() == StatusCode::OK)
+    }
+
+    async fn list_all(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+    ) -> Result<Vec<Self::Item>> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        // Chunks live at <job_id>/chunks/<shard>/<hash>.chunk
+        let mut chunks = Vec::<String>::new();
+        for (shard, is_collection) in self.list(client, &format!("{job_id}/chunks")).await? {
+            if !is_collection {
+                continue;
+            }
+            let shard_dir = format!("{job_id}/chunks/{shard}");
+            for (name, is_collection) in self.list(client, &shard_dir).await? {
+                if !is_collection && name.ends_with(".chunk") {
+                    chunks.push(format!("{shard_dir}/{name}"));
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    async fn upload_manifest<'b>(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+        manifest_bytes: &'b [u8],
+    ) -> Result<usize> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        self.put(
+            client,
+            &format!("{job_id}/manifests/{run_id}.manifest"),
+            Bytes::copy_from_slice(manifest_bytes),
+        )
+        .await?;
+        Ok(manifest_bytes.len())
+    }
+
+    async fn list_manifests(&self, client: Option<&Self::Client>) -> Result<Vec<String>> {
+        let Some(client) = client else {
+            bail!("webdav client not provided")
+        };
+        // Manifests live at <url>/<job_id>/manifests/<run_id>.manifest
+        let mut manifests = Vec::<String>::new();
+        for (job_id, is_collection) in self.list(client, "").await? {
+            if !is_collection || Uuid::parse_str(&job_id).is_err() {
+                continue;
+            }
+            for (name, is_collection) in self.list(client, &format!("{job_id}/manifests")).await? {
+                if !is_collection && name.ends_with(".manifest") {
+                    manifests.push(format!("{job_id}/manifests/{name}"));
+                }
+            }
+        }
+        Ok(manifests)
+    }
+
+    async fn delete_manifest(
+        &self,
+        client: Option<&Self::Client>,
+        job_id: Uuid,
+        run_id: u64,
+    ) -> Result<()> {
+        // The run's manifest may never have been uploaded
+        self.delete(client, &format!("{job_id}/manifests/{run_id}.manifest"))
+            .await
+    }
+}
+
+/// Returns the href of every response in a PROPFIND
+/// multistatus and whether it's a collection
+fn parse_propfind(xml: &str) -> Result<Vec<(String, bool)>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut entries = Vec::<(String, bool)>::new();
+    let mut href: Option<String> = None;
+    let mut in_href = false;
+    let mut is_collection = false;
+    loop {
+        // Servers use different namespace prefixes, so
+        // elements are matched by their local name
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"response" => {
+                href = None;
+                is_collection = false;
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"href" => in_href = true,
+            Event::Text(text) if in_href => href = Some(text.unescape()?.into_owned()),
+            Event::End(e) if e.local_name().as_ref() == b"href" => in_href = false,
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                is_collection = true
+            }
+            Event::End(e) if e.local_name().as_ref() == b"response" => {
+                if let Some(href) = href.take() {
+                    entries.push((href, is_collection));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// Whether a response status may succeed if retried:
+/// timeouts, throttling and 5xx responses
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+/// Marks timed out and dropped requests as transient
+fn webdav_error(e: reqwest::Error) -> anyhow::Error {
+    if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() {
+        return transient(e);
+    }
+    match e.status() {
+        Some(status) if is_transient_status(status) => transient(e),
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webdav_urls() {
+        let webdav = KipWebDav::new(
+            "https://cloud.example.com/remote.php/dav/files/alice/kip/",
+            KipWebDavAuth::Bearer,
+        );
+        assert_eq!(
+            webdav.resolve_url("a/chunks/abc123.chunk"),
+            "https://cloud.example.com/remote.php/dav/files/alice/kip/a/chunks/abc123.chunk"
+        );
+        assert_eq!(
+            webdav.resolve_url(""),
+            "https://cloud.example.com/remote.php/dav/files/alice/kip/"
+        );
+        assert_eq!(
+            webdav.uploads_url().unwrap(),
+            "https://cloud.example.com/remote.php/dav/uploads/alice"
+        );
+        // Other servers don't take chunked uploads
+        let webdav = KipWebDav::new("http://localhost:8080/kip", KipWebDavAuth::Bearer);
+        assert!(webdav.uploads_url().is_none());
+    }
+
+    #[test]
+    fn test_parse_propfind() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/kip/a/chunks/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/kip/a/chunks/abc123.chunk</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(
+            parse_propfind(xml).unwrap(),
+            vec![
+                (
+                    String::from("/remote.php/dav/files/alice/kip/a/chunks/"),
+                    true
+                ),
+                (
+                    String::from("/remote.php/dav/files/alice/kip/a/chunks/abc123.chunk"),
+                    false
+                ),
+            ]
+        );
+        // Without a namespace prefix
+        let xml = r#"<multistatus xmlns="DAV:"><response><href>/kip/a/</href>
+<propstat><prop><resourcetype><collection></collection></resourcetype></prop></propstat>
+</response></multistatus>"#;
+        assert_eq!(
+            parse_propfind(xml).unwrap(),
+            vec![(String::from("/kip/a/"), true)]
+        );
+    }
+
+    #[test]
+    fn test_is_transient_status() {
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_transient_status(StatusCode::UNAUTHORIZED));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+    }
+
+    // Runs against a WebDAV server, such as:
+    // docker run -p 8080:80 -e AUTH_TYPE=Basic -e USERNAME=kip -e PASSWORD=kip bytemark/webdav
+    // KIP_TEST_WEBDAV_URL=http://localhost:8080 KIP_TEST_WEBDAV_USER=kip
+    // KIP_WEBDAV_SECRET=kip cargo test test_webdav_server -- --ignored
+    #[tokio::test]
+    #[ignore = "needs a WebDAV server"]
+    async fn test_webdav_server() {
+        let url = std::env::var("KIP_TEST_WEBDAV_URL").expect("KIP_TEST_WEBDAV_URL must be set");
+        let username = std::env::var("KIP_TEST_WEBDAV_USER").unwrap();
+        let job_id = Uuid::new_v4();
+        let webdav = KipWebDav::new(
+            format!(
+                "{}/kip-test-{}",
+                url.trim_end_matches('/'),
+                &job_id.to_string()[..8]
+            ),
+            KipWebDavAuth::Basic { username },
+        );
+        let client = KipWebDavClient::from_env().unwrap();
+        // The root collection must exist
+        webdav
+            .send(
+                webdav.request(&client, Method::from_bytes(b"MKCOL").unwrap(), &webdav.url),
+                &[StatusCode::CREATED],
+            )
+            .await
+            .unwrap();
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let chunk = FileChunk::new("test/random.txt", "abc123", 0, 3, 3);
+        let uploaded = webdav
+            .upload(
+                Some(&client),
+                KipUploadOpts::new(job_id, tx),
+                &chunk,
+                Bytes::from_static(b"kip"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(uploaded, 3);
+        assert!(webdav
+            .contains(Some(&client), job_id, "abc123")
+            .await
+            .unwrap());
+        assert!(!webdav
+            .contains(Some(&client), job_id, "def456")
+            .await
+            .unwrap());
+        let remote_path = chunk_path(job_id, "abc123");
+        assert_eq!(
+            webdav.list_chunks(&client, job_id).await.unwrap(),
+            vec![remote_path.clone()]
+        );
+        assert_eq!(
+            webdav.download(Some(&client), &remote_path).await.unwrap(),
+            b"kip"
+        );
+        webdav
+            .upload_manifest(Some(&client), job_id, 1, b"manifest")
+            .await
+            .unwrap();
+        assert_eq!(
+            webdav.list_manifests(Some(&client)).await.unwrap(),
+            vec![format!("{job_id}/manifests/1.manifest")]
+        );
+
+        webdav.delete(Some(&client), &remote_path).await.unwrap();
+        // Deleting twice isn't an error
+        webdav.delete(Some(&client), &remote_path).await.unwrap();
+        webdav
+            .delete_manifest(Some(&client), job_id, 1)
+            .await
+            .unwrap();
+        assert!(webdav
+            .list_chunks(&client, job_id)
+            .await
+            .unwrap()
+            .is_empty());
+        webdav
+            .send(webdav.request(&client, Method::DELETE, &webdav.url), &[])
+            .await
+            .unwrap();
+    }
+}
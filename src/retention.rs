@@ -0,0 +1,247 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use crate::run::Run;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+/// Rules deciding which of a job's runs are kept when
+/// running 'kip forget'. A run is kept if any rule keeps
+/// it. Time periods are in UTC.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KipRetention {
+    /// Keep the latest n runs
+    pub keep_last: Option<usize>,
+    /// Keep the latest run of each of the last n hours
+    pub keep_hourly: Option<usize>,
+    /// Keep the latest run of each of the last n days
+    pub keep_daily: Option<usize>,
+    /// Keep the latest run of each of the last n weeks
+    pub keep_weekly: Option<usize>,
+    /// Keep the latest run of each of the last n months
+    pub keep_monthly: Option<usize>,
+    /// Keep the latest run of each of the last n years
+    pub keep_yearly: Option<usize>,
+    /// Keep every run started within this duration of
+    /// now. Ex: 30days, 2weeks
+    pub keep_within: Option<String>,
+}
+
+impl KipRetention {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the IDs of the runs kept by this policy. Runs
+    /// marked retain_forever are always kept.
+    pub fn runs_to_keep(
+        &self,
+        runs: &BTreeMap<usize, Run>,
+        now: DateTime<Utc>,
+    ) -> Result<BTreeSet<usize>> {
+        let mut keep = runs
+            .iter()
+            .filter(|(_, r)| r.retain_forever)
+            .map(|(id, _)| *id)
+            .collect::<BTreeSet<usize>>();
+
+        // Newest runs first
+        let mut newest = runs.iter().collect::<Vec<(&usize, &Run)>>();
+        newest.sort_by(|(a_id, a), (b_id, b)| (b.started, b_id).cmp(&(a.started, a_id)));
+
+        if let Some(n) = self.keep_last {
+            keep.extend(newest.iter().take(n).map(|(id, _)| **id));
+        }
+        let buckets: [(Option<usize>, fn(&DateTime<Utc>) -> String); 5] = [
+            (self.keep_hourly, |t| t.format("%Y-%m-%d %H").to_string()),
+            (self.keep_daily, |t| t.format("%Y-%m-%d").to_string()),
+            (self.keep_weekly, |t| t.format("%G-%V").to_string()),
+            (self.keep_monthly, |t| t.format("%Y-%m").to_string()),
+            (self.keep_yearly, |t| t.format("%Y").to_string()),
+        ];
+        for (n, bucket) in buckets {
+            let Some(n) = n else {
+                continue;
+            };
+            // Keep the newest run of each period until
+            // n periods have been kept
+            let mut periods = BTreeSet::<String>::new();
+            for (id, r) in newest.iter() {
+                if periods.len() == n {
+                    break;
+                }
+                if periods.insert(bucket(&r.started)) {
+                    keep.insert(**id);
+                }
+            }
+        }
+        if let Some(within) = &self.keep_within {
+            let within = humantime::parse_duration(within)
+                .with_context(|| format!("invalid keep-within duration '{within}'"))?;
+            let since = now - chrono::Duration::from_std(within)?;
+            keep.extend(
+                newest
+                    .iter()
+                    .filter(|(_, r)| r.started >= since)
+                    .map(|(id, _)| **id),
+            );
+        }
+        Ok(keep)
+    }
+
+    /// Returns the most days any rule keeps a run for, or
+    /// None if no rule keeps runs for a set time
+    pub fn max_age_days(&self) -> Result<Option<u32>> {
+        let mut days = [
+            self.keep_hourly.map(|n| (n as u64).div_ceil(24)),
+            self.keep_daily.map(|n| n as u64),
+            self.keep_weekly.map(|n| n as u64 * 7),
+            self.keep_monthly.map(|n| n as u64 * 31),
+            self.keep_yearly.map(|n| n as u64 * 366),
+        ]
+        .into_iter()
+        .flatten()
+        .max();
+        if let Some(within) = &self.keep_within {
+            let within = humantime::parse_duration(within)
+                .with_context(|| format!("invalid keep-within duration '{within}'"))?;
+            days = days.max(Some(within.as_secs().div_ceil(24 * 60 * 60)));
+        }
+        Ok(days.map(|d| u32::try_from(d).unwrap_or(u32::MAX)))
+    }
+}
+
+impl Display for KipRetention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        let mut rules = Vec::<String>::new();
+        let counts = [
+            ("last", self.keep_last),
+            ("hourly", self.keep_hourly),
+            ("daily", self.keep_daily),
+            ("weekly", self.keep_weekly),
+            ("monthly", self.keep_monthly),
+            ("yearly", self.keep_yearly),
+        ];
+        for (name, n) in counts {
+            if let Some(n) = n {
+                rules.push(format!("{name}: {n}"));
+            }
+        }
+        if let Some(within) = &self.keep_within {
+            rules.push(format!("within: {within}"));
+        }
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{KipCompressAlg, KipCompressLevel, KipCompressOpts};
+
+    fn test_runs(started: &[DateTime<Utc>]) -> BTreeMap<usize, Run> {
+        let compress = KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Best);
+        let mut runs = BTreeMap::new();
+        for (i, s) in started.iter().enumerate() {
+            let mut r = Run::new(i as u64 + 1, compress);
+            r.started = *s;
+            runs.insert(i + 1, r);
+        }
+        runs
+    }
+
+    #[test]
+    fn test_keep_last() {
+        let now = Utc.with_ymd_and_hms(2022, 6, 1, 0, 0, 0).unwrap();
+        let runs = test_runs(&[
+            Utc.with_ymd_and_hms(2022, 5, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 2, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 3, 0, 0, 0).unwrap(),
+        ]);
+        let policy = KipRetention {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let keep = policy.runs_to_keep(&runs, now).unwrap();
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_keep_daily_and_monthly() {
+        let now = Utc.with_ymd_and_hms(2022, 6, 1, 0, 0, 0).unwrap();
+        let runs = test_runs(&[
+            Utc.with_ymd_and_hms(2022, 4, 10, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 4, 20, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 30, 8, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 30, 9, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 31, 9, 0, 0).unwrap(),
+        ]);
+        // The newest run of each of the last two days
+        let daily = KipRetention {
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+        let keep = daily.runs_to_keep(&runs, now).unwrap();
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![4, 5]);
+        // The newest run of May and of April
+        let monthly = KipRetention {
+            keep_monthly: Some(2),
+            ..Default::default()
+        };
+        let keep = monthly.runs_to_keep(&runs, now).unwrap();
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![2, 5]);
+    }
+
+    #[test]
+    fn test_keep_within_and_forever() {
+        let now = Utc.with_ymd_and_hms(2022, 6, 1, 0, 0, 0).unwrap();
+        let mut runs = test_runs(&[
+            Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2022, 5, 30, 0, 0, 0).unwrap(),
+        ]);
+        runs.get_mut(&1).unwrap().retain_forever = true;
+        let policy = KipRetention {
+            keep_within: Some(String::from("7days")),
+            ..Default::default()
+        };
+        let keep = policy.runs_to_keep(&runs, now).unwrap();
+        assert_eq!(keep.into_iter().collect::<Vec<_>>(), vec![1, 3]);
+        let invalid = KipRetention {
+            keep_within: Some(String::from("forever")),
+            ..Default::default()
+        };
+        assert!(invalid.runs_to_keep(&runs, now).is_err());
+    }
+
+    #[test]
+    fn test_max_age_days() {
+        assert_eq!(KipRetention::default().max_age_days().unwrap(), None);
+        // Runs kept by count alone have no set age
+        let last = KipRetention {
+            keep_last: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(last.max_age_days().unwrap(), None);
+        let policy = KipRetention {
+            keep_hourly: Some(36),
+            keep_daily: Some(7),
+            keep_weekly: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(policy.max_age_days().unwrap(), Some(28));
+        let within = KipRetention {
+            keep_daily: Some(7),
+            keep_within: Some(String::from("60days 1h")),
+            ..Default::default()
+        };
+        assert_eq!(within.max_age_days().unwrap(), Some(61));
+    }
+}
@@ -0,0 +1,218 @@
+//
+// Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
+//
+
+use anyhow::Result;
+use rand::Rng;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tracing::warn;
+
+/// How many times a failed provider request is retried by default
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 5;
+// First and longest delays between retries
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Retries configured in kip.toml
+static RETRY_ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_RETRY_ATTEMPTS);
+
+/// Sets how many times failed provider requests are retried
+pub fn set_retry_attempts(attempts: u32) {
+    RETRY_ATTEMPTS.store(attempts, Ordering::Relaxed);
+}
+
+/// Marks a provider error as transient, such as a timeout,
+/// a 5xx response or throttling, which may succeed if retried
+#[derive(Debug)]
+pub struct KipTransientError(anyhow::Error);
+
+impl std::fmt::Display for KipTransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for KipTransientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Wraps an error so provider requests failing with it are retried
+pub fn transient<E: Into<anyhow::Error>>(e: E) -> anyhow::Error {
+    anyhow::Error::new(KipTransientError(e.into()))
+}
+
+/// Whether a failed request may succeed if retried. Errors are
+/// permanent unless a provider marked them transient or they
+/// come from a dropped or timed out connection.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if cause.is::<KipTransientError>() {
+            return true;
+        }
+        match cause.downcast_ref::<std::io::Error>() {
+            Some(io) => matches!(
+                io.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::WouldBlock
+            ),
+            None => false,
+        }
+    })
+}
+
+/// How failed provider requests are retried
+#[derive(Clone, Copy, Debug)]
+pub struct KipRetryOpts {
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl KipRetryOpts {
+    pub fn new(attempts: u32) -> Self {
+        Self {
+            attempts,
+            base_delay: RETRY_BASE_DELAY,
+            max_delay: RETRY_MAX_DELAY,
+        }
+    }
+
+    /// The retries configured in kip.toml
+    pub fn configured() -> Self {
+        Self::new(RETRY_ATTEMPTS.load(Ordering::Relaxed))
+    }
+
+    /// Delay before a retry, doubling from the base delay up to
+    /// the max. Jitter keeps concurrent uploads from retrying
+    /// in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(ceiling / 2..=ceiling)
+    }
+
+    /// Runs a request, retrying it while it fails with a transient
+    /// error. on_retry is called with the attempt number and error
+    /// before each retry.
+    pub async fn retry<T, F, Fut, R>(&self, op: &str, mut on_retry: R, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+        R: FnMut(u32, &anyhow::Error),
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Ok(t) => return Ok(t),
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    attempt += 1;
+                    let delay = self.backoff(attempt);
+                    warn!(
+                        "{op} failed, retrying in {delay:?} ({attempt}/{}): {e}",
+                        self.attempts
+                    );
+                    on_retry(attempt, &e);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_is_transient() {
+        let reset = std::io::Error::new(ErrorKind::ConnectionReset, "reset");
+        assert!(is_transient(&anyhow::Error::new(reset)));
+        let missing = std::io::Error::new(ErrorKind::NotFound, "missing");
+        assert!(!is_transient(&anyhow::Error::new(missing)));
+        assert!(!is_transient(&anyhow!("access denied")));
+        assert!(is_transient(&transient(anyhow!("503 slow down"))));
+        // Context added later doesn't hide the cause
+        let e = transient(anyhow!("429")).context("uploading chunk");
+        assert!(is_transient(&e));
+        assert_eq!(transient(anyhow!("429")).to_string(), "429");
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let opts = KipRetryOpts::new(10);
+        for attempt in 1..=10 {
+            let delay = opts.backoff(attempt);
+            let ceiling = (RETRY_BASE_DELAY * 2u32.pow(attempt - 1)).min(RETRY_MAX_DELAY);
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let mut opts = KipRetryOpts::new(3);
+        opts.base_delay = Duration::from_millis(1);
+        // Transient errors are retried until the request succeeds
+        let mut calls = 0;
+        let mut retries = vec![];
+        let result = opts
+            .retry(
+                "test",
+                |attempt, _| retries.push(attempt),
+                || {
+                    calls += 1;
+                    let fail = calls < 3;
+                    async move {
+                        match fail {
+                            true => Err(transient(anyhow!("timeout"))),
+                            false => Ok(calls),
+                        }
+                    }
+                },
+            )
+            .await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(retries, vec![1, 2]);
+        // Permanent errors aren't
+        let mut calls = 0;
+        let result: Result<()> = opts
+            .retry(
+                "test",
+                |_, _| {},
+                || {
+                    calls += 1;
+                    async { Err(anyhow!("forbidden")) }
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        // Nor retried past the attempt count
+        let mut calls = 0;
+        let result: Result<()> = opts
+            .retry(
+                "test",
+                |_, _| {},
+                || {
+                    calls += 1;
+                    async { Err(transient(anyhow!("timeout"))) }
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+}
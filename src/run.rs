@@ -2,32 +2,39 @@
 // Copyright (c) 2022 Ryan Ciehanski <ryan@ciehanski.com>
 //
 
-use crate::chunk::chunk_file;
+use crate::checkpoint::KipCheckpoint;
+use crate::chunk::chunker;
 use crate::chunk::{FileChunk, KipFileChunked};
 use crate::compress::{
     compress_brotli, compress_gzip, compress_lzma, compress_zstd, decompress_brotli,
     decompress_gzip, decompress_lzma, decompress_zstd, KipCompressAlg, KipCompressOpts,
 };
-use crate::crypto::{decrypt, encrypt_bytes, encrypt_in_place};
-use crate::job::{Job, KipFile, KipStatus};
+use crate::control::KipRunSignals;
+use crate::crypto::{decrypt, derive_chunk_key, encrypt_bytes, encrypt_in_place, hex_encode};
+use crate::index::KipChunkIndex;
+use crate::job::{Job, KipFile, KipFileState, KipStatus};
+use crate::limiter::KipBandwidth;
+use crate::meta::{KipFileKind, KipFileMeta};
+use crate::providers::local;
 use crate::providers::KipProviders;
 use crate::providers::{KipClient, KipUploadOpts};
 use anyhow::{bail, Result};
+use bytes::Bytes;
 use chrono::prelude::*;
 use colored::*;
-use crypto_hash::{hex_digest, Algorithm};
+use crypto_hash::{Algorithm, Hasher};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use humantime::format_duration;
 use linya::{Bar, Progress};
-use memmap2::{MmapMut, MmapOptions};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Cursor;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::fs::{create_dir_all, read, File, OpenOptions};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use std::time::Duration;
+use tokio::fs::{create_dir_all, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::{mpsc::unbounded_channel, mpsc::UnboundedSender, Mutex};
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
@@ -36,7 +43,14 @@ use uuid::Uuid;
 use walkdir::WalkDir;
 
 const CONCURRENT_FILE_UPLOADS: usize = 10;
+// 1 MB read buffer when hashing files
+const HASH_BUF_LEN: usize = 1024 * 1024;
+// A file being chunked holds the chunker's buffer, the chunk
+// and its compressed and encrypted copy in memory
+const CHUNK_SLOT_LEN: u64 = 3 * crate::chunk::MAX_SIZE as u64;
 const MAX_PROGRESS_LABEL_LEN: usize = 57;
+// How often a restore checks whether archived chunks are readable
+const KIP_RESTORE_POLL: Duration = Duration::from_secs(15 * 60);
 
 /// A "Run" is a backup job with all the metadata
 /// pertaining to the backed up files.
@@ -49,17 +63,88 @@ pub struct Run {
     pub finished: DateTime<Utc>,
     pub bytes_uploaded: u64,
     pub delta: Vec<KipFileChunked>,
+    /// Every file in the job as of this run, including unchanged
+    /// files which point at chunks stored by earlier runs.
+    #[serde(default)]
+    pub snapshot: Vec<KipFileChunked>,
     pub status: KipStatus,
     pub logs: Vec<String>,
     pub retain_forever: bool,
+    /// State of every file read during this run. Handed
+    /// back to the job once the run completes.
+    #[serde(skip)]
+    pub file_states: BTreeMap<PathBuf, KipFileState>,
+}
+
+/// Options for a single backup run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KipRunOpts {
+    pub follow_links: bool,
+    /// Hash every file even if its stat data is unchanged
+    pub force_rehash: bool,
+    /// Rough ceiling, in bytes, of the memory used to
+    /// chunk, compress and encrypt files
+    pub memory_limit: u64,
+}
+
+impl KipRunOpts {
+    pub fn new(follow_links: bool, force_rehash: bool, memory_limit: u64) -> Self {
+        Self {
+            follow_links,
+            force_rehash,
+            memory_limit,
+        }
+    }
+}
+
+/// Read-only state shared by every upload task spawned
+/// during a run.
+struct KipRunCtx {
+    secret: String,
+    chunk_key: Vec<u8>,
+    /// Stored chunks by hash, with the compression
+    /// they were stored with
+    known_chunks: HashMap<String, KipCompressOpts>,
+    prev_snapshot: HashMap<PathBuf, KipFileChunked>,
+    force_rehash: bool,
+    /// Bounds how many files are chunked at once so
+    /// the run stays under its memory ceiling
+    chunk_slots: Semaphore,
+    /// Abort and pause requests for the run
+    signals: KipRunSignals,
+    /// Journals the run's progress so it can be resumed
+    checkpoint: Arc<KipCheckpoint>,
+    /// The job's chunk index, updated as chunks are uploaded
+    index: std::sync::Mutex<KipChunkIndex>,
+    bandwidth: KipBandwidth,
+}
+
+impl std::fmt::Debug for KipRunCtx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "KipRunCtx {{ known_chunks: {}, prev_snapshot: {}, force_rehash: {} }}",
+            self.known_chunks.len(),
+            self.prev_snapshot.len(),
+            self.force_rehash
+        )
+    }
 }
 
 #[derive(Debug)]
 pub enum KipUploadMsg {
     BytesUploaded(u64),
     KipFileChunked(KipFileChunked),
+    Unchanged(KipFileChunked),
+    FileState(PathBuf, KipFileState),
     Log(String),
     Error(String),
+    /// A chunk upload failed and is being retried
+    Retry {
+        hash: String,
+        attempt: u32,
+        error: String,
+    },
     GdriveParentFolder(String),
     Skipped,
     Done,
@@ -86,14 +171,29 @@ impl Run {
             finished: time_init,
             bytes_uploaded: 0,
             delta: Vec::new(),
+            snapshot: Vec::new(),
             status: KipStatus::NEVER_RUN,
             logs: Vec::<String>::new(),
             retain_forever: false,
+            file_states: BTreeMap::new(),
         }
     }
 
-    #[instrument]
-    pub async fn start(&mut self, job: Arc<Job>, secret: String, follow_links: bool) -> Result<()> {
+    /// Uploads the job's changed files. Once the run is aborted,
+    /// no more files are queued, in-flight uploads are dropped and
+    /// the run finishes as aborted. While it's paused, no chunks
+    /// are uploaded after the in-flight ones finish. Progress is journaled to the
+    /// checkpoint, and chunks and files it already lists are not
+    /// uploaded again.
+    #[instrument(skip(signals, checkpoint))]
+    pub async fn start(
+        &mut self,
+        job: Arc<Job>,
+        secret: String,
+        opts: KipRunOpts,
+        signals: KipRunSignals,
+        checkpoint: Arc<KipCheckpoint>,
+    ) -> Result<()> {
         info!("START -- {}-{}", job.name, self.id);
 
         // Print job start
@@ -116,6 +216,56 @@ impl Run {
         let mut warn: u32 = 0;
         let (upload_tx, mut upload_rx) = unbounded_channel::<KipUploadMsg>();
 
+        // Derive the chunk naming key once per run and collect every
+        // chunk previous runs still reference so they aren't uploaded again,
+        // along with the compression each was stored with. Damaged files
+        // are uploaded again.
+        // Chunks are only skipped if the job's index also lists
+        // them as stored, so lost chunks are uploaded again
+        let client = Arc::new(job.provider.get_client().await?);
+        let index = KipChunkIndex::open_or_rebuild(&job.provider, &client, job.id).await?;
+        // No other run of this job is in progress, so any upload
+        // left unfinished in the provider was abandoned
+        if let Err(e) = job.provider.abort_incomplete_uploads(&client, job.id).await {
+            warn!("unable to clean up incomplete uploads: {e}");
+        }
+        let ctx = Arc::new(KipRunCtx {
+            chunk_key: derive_chunk_key(&secret, job.id)?,
+            known_chunks: job
+                .runs
+                .values()
+                .flat_map(|r| {
+                    r.delta
+                        .iter()
+                        .chain(r.snapshot.iter())
+                        .filter(|kfc| !kfc.damaged)
+                        .flat_map(|kfc| kfc.chunks.iter())
+                        .map(move |c| (c, c.compress.unwrap_or(r.compress)))
+                })
+                .filter(|(c, _)| index.contains(&c.remote_path))
+                .map(|(c, compress)| (c.hash.clone(), compress))
+                .chain(
+                    checkpoint
+                        .chunks
+                        .iter()
+                        .map(|hash| (hash.clone(), self.compress)),
+                )
+                .collect(),
+            prev_snapshot: job
+                .latest_snapshot()
+                .into_iter()
+                .filter(|kfc| !kfc.damaged)
+                .map(|kfc| (kfc.file.path.clone(), kfc))
+                .collect(),
+            force_rehash: opts.force_rehash,
+            chunk_slots: Semaphore::new(chunk_slots(opts.memory_limit)),
+            secret,
+            signals,
+            checkpoint,
+            index: std::sync::Mutex::new(index),
+            bandwidth: job.bandwidth(),
+        });
+
         // Create futures handle for each file iteration and join
         // at the end of this function to let for concurrent uploads
         let upload_queue = FuturesUnordered::new();
@@ -123,15 +273,29 @@ impl Run {
         // Rate limiting amount of concurrent uploads
         let semaphore = Arc::new(Semaphore::new(CONCURRENT_FILE_UPLOADS));
 
+        // Directories and symlinks found while walking the job's files
+        let mut meta_only = Vec::<KipFileChunked>::new();
+
         // Convert job KipFile's into async stream
         let mut kf_stream = tokio_stream::iter(job.files.clone());
 
         // Check if file is excluded
         debug!("checking file exlusions");
         while let Some(kf) = kf_stream.next().await {
+            // Stop queueing files while the run is paused
+            // and once it's aborted
+            ctx.signals.wait_while_paused().await;
+            if ctx.signals.is_aborted() {
+                break;
+            }
+
+            // A symlink listed in the job is recorded as a link
+            // unless links are followed, like the ones it contains
+            let is_link = kf.is_link(opts.follow_links);
+
             // Check if file or directory exists
             debug!("confirming path exists");
-            if !kf.path.exists() {
+            if !is_link && !kf.path.exists() {
                 warn += 1;
                 let log = format!(
                     "[{}] {}-{} ⇉ '{}' can not be found.",
@@ -201,8 +365,13 @@ impl Run {
                 }
             }
 
-            // Create job's provider client
-            let client = Arc::new(job.provider.get_client().await?);
+            if is_link {
+                debug!("recording metadata of {}", kf.path_str());
+                let mut kfc = KipFileChunked::new(&kf.path, String::new(), 0);
+                kfc.file.meta = KipFileMeta::new(&kf.path)?;
+                meta_only.push(kfc);
+                continue;
+            }
 
             // Check if f is file or directory
             debug!("confirming if file or directory");
@@ -218,7 +387,7 @@ impl Run {
                     Arc::clone(&client),
                     Arc::new(kf),
                     Arc::clone(&job),
-                    secret.clone(),
+                    Arc::clone(&ctx),
                     Arc::clone(&progress),
                     upload_tx.clone(),
                     limiter_permit,
@@ -233,17 +402,28 @@ impl Run {
                 // walk all the recursive directories as well. Upload
                 // all files found within the directory.
                 debug!("walking directory: {}", kf.path_str());
-                for entry in WalkDir::new(&kf.path).follow_links(follow_links) {
+                for entry in WalkDir::new(&kf.path).follow_links(opts.follow_links) {
+                    ctx.signals.wait_while_paused().await;
+                    if ctx.signals.is_aborted() {
+                        break;
+                    }
                     let entry = entry?;
-                    let entry_kf = KipFile::new(entry.path())?;
 
-                    // If a directory, skip since upload will create
-                    // the parent folder by default
-                    let fmd = entry.path().metadata()?;
-                    if fmd.is_dir() {
-                        debug!("is dir, continue walking");
+                    // Directories and symlinks have nothing to upload, so
+                    // only their metadata is recorded in the snapshot.
+                    // This keeps empty directories around on restore.
+                    let file_type = entry.file_type();
+                    if file_type.is_dir() || file_type.is_symlink() {
+                        debug!("recording metadata of {}", entry.path().display());
+                        let mut kfc = KipFileChunked::new(entry.path(), String::new(), 0);
+                        kfc.file.meta = KipFileMeta::new(entry.path())?;
+                        meta_only.push(kfc);
+                        continue;
+                    } else if !file_type.is_file() {
+                        debug!("skipping special file {}", entry.path().display());
                         continue;
                     }
+                    let entry_kf = KipFile::new(entry.path())?;
 
                     // Semaphore rate limiting
                     let limiter_permit = semaphore.clone().acquire_owned().await?;
@@ -255,7 +435,7 @@ impl Run {
                         Arc::clone(&client),
                         Arc::new(entry_kf),
                         Arc::clone(&job),
-                        secret.clone(),
+                        Arc::clone(&ctx),
                         Arc::clone(&progress),
                         upload_tx.clone(),
                         limiter_permit,
@@ -273,22 +453,45 @@ impl Run {
         debug!("joining all upload futures");
         let upload_queue_count = upload_queue.len();
         futures::future::join_all(upload_queue).await;
+        // Every upload task has finished, so dropping the last sender
+        // lets the receiver drain the remaining messages and stop
+        drop(upload_tx);
 
         let mut err: u32 = 0;
-        let mut finished_futures = 0;
         let mut skipped: usize = 0;
-        let mut no_changes = false;
+        let mut retries: u32 = 0;
         while let Some(msg) = upload_rx.recv().await {
             match msg {
                 KipUploadMsg::BytesUploaded(bu) => {
                     self.bytes_uploaded += bu;
                 }
                 KipUploadMsg::KipFileChunked(kfc) => {
+                    self.snapshot.push(kfc.clone());
                     self.delta.push(kfc);
                 }
+                KipUploadMsg::Unchanged(kfc) => {
+                    self.snapshot.push(kfc);
+                }
+                KipUploadMsg::FileState(path, state) => {
+                    self.file_states.insert(path, state);
+                }
                 KipUploadMsg::Log(l) => {
                     self.logs.push(l);
                 }
+                KipUploadMsg::Retry {
+                    hash,
+                    attempt,
+                    error,
+                } => {
+                    retries += 1;
+                    self.logs.push(format!(
+                        "[{}] {}-{} ⇉ '{}' upload failed, retrying (attempt {attempt}): {error}",
+                        Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                        job.name,
+                        self.id,
+                        hash.yellow(),
+                    ));
+                }
                 KipUploadMsg::Error(e) => {
                     err += 1;
                     eprintln!("{e}");
@@ -302,18 +505,57 @@ impl Run {
                 }
                 KipUploadMsg::Skipped => {
                     skipped += 1;
-                    if skipped == upload_queue_count {
-                        no_changes = true;
-                        break;
-                    }
                 }
-                KipUploadMsg::Done => {
-                    finished_futures += 1;
-                    if finished_futures == upload_queue_count {
-                        break;
-                    }
+                KipUploadMsg::Done => {}
+            }
+        }
+        let aborted = ctx.signals.is_aborted();
+        if aborted {
+            // The snapshot is incomplete, so only the files this
+            // run finished are kept and layered over the previous
+            // run's snapshot on restore
+            self.snapshot.clear();
+        } else {
+            self.snapshot.append(&mut meta_only);
+        }
+        // Files removed from the job, and changes to metadata
+        // alone, such as a chmod or a new empty directory or
+        // symlink, still need a run to restore them
+        let no_changes =
+            skipped == upload_queue_count && same_snapshot(&self.snapshot, &job.latest_snapshot());
+
+        // Chunks uploaded by earlier runs stay locked
+        // for as long as this run is kept
+        if !aborted {
+            let remote_paths = self
+                .snapshot
+                .iter()
+                .flat_map(|kfc| kfc.chunks.iter().map(|c| c.remote_path.clone()))
+                .collect::<BTreeSet<String>>()
+                .into_iter()
+                .collect::<Vec<String>>();
+            if let Err(e) = job.provider.extend_locks(&client, &remote_paths).await {
+                warn += 1;
+                let log = format!(
+                    "[{}] {}-{} ⇉ unable to extend the lock of reused chunks: {e}.",
+                    Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                    job.name,
+                    self.id,
+                );
+                self.logs.push(log.clone());
+                println!("{log}");
+                warn!(warn, "unable to extend chunk locks: {e}");
+            }
+        }
+
+        // Fold the chunks this run recorded into the index
+        match ctx.index.lock() {
+            Ok(mut index) => {
+                if let Err(e) = index.save() {
+                    warn!("unable to save chunk index: {e}");
                 }
             }
+            Err(e) => warn!("unable to save chunk index: {e}"),
         }
 
         // Finished! Set the run metadata before returning
@@ -321,7 +563,9 @@ impl Run {
         self.finished = Utc::now();
         let dur = self.finished.signed_duration_since(started).to_std()?;
         self.time_elapsed = format_duration(dur).to_string();
-        if !no_changes {
+        if aborted {
+            self.status = KipStatus::ABORTED;
+        } else if !no_changes {
             if err == 0 && warn == 0 {
                 self.status = KipStatus::OK;
             } else if warn > 0 && err == 0 {
@@ -335,10 +579,16 @@ impl Run {
 
         // Print the run's logs
         let fin_log = format!(
-            "[{}] {}-{} ⇉ upload completed.",
+            "[{}] {}-{} ⇉ upload {}{}.",
             Utc::now().format("%Y-%m-%d %H:%M:%S"),
             job.name,
             self.id,
+            if aborted { "aborted" } else { "completed" },
+            match retries {
+                0 => String::new(),
+                1 => " after 1 retried upload".to_string(),
+                n => format!(" after {n} retried uploads"),
+            },
         );
         self.logs.push(fin_log.clone());
         println!("{fin_log}");
@@ -352,7 +602,7 @@ impl Run {
         client: Arc<KipClient>,
         f: Arc<KipFile>,
         job: Arc<Job>,
-        secret: &str,
+        ctx: Arc<KipRunCtx>,
         progress: Arc<Mutex<Progress>>,
         tx: UnboundedSender<KipUploadMsg>,
     ) -> Result<()> {
@@ -363,14 +613,49 @@ impl Run {
             f.path.display()
         );
 
-        // Open the file
-        let file = open_file(&f.path, f.len.try_into()?).await?;
+        // If the file's size, timestamps and inode are the same as
+        // its last known state, trust its last hash instead of
+        // reading the whole file again. New files are hashed while
+        // they're chunked instead.
+        let md = f.path.metadata()?;
+        let file_len: usize = md.len().try_into()?;
+        let cached_state = job
+            .file_states
+            .get(&f.path)
+            .filter(|state| !ctx.force_rehash && state.matches(&md));
+        let file_hash = match cached_state {
+            Some(state) => {
+                debug!("stat data unchanged, skipping hash");
+                Some(state.hash.clone())
+            }
+            None if ctx.prev_snapshot.contains_key(&f.path) => Some(hash_file(&f.path).await?),
+            None => None,
+        };
 
-        // If hash is the same and no chunks are missing from S3
-        // skip uploading this file
+        // If hash is the same as the file's last known state, skip
+        // uploading this file and point this run's snapshot at the
+        // chunks already stored
         debug!("comparing chunk's hash");
-        let file_hash = hex_digest(Algorithm::SHA256, &file);
-        if f.hash == file_hash {
+        let unchanged = file_hash.as_ref().and_then(|file_hash| {
+            let known_hash = match job.file_states.get(&f.path) {
+                Some(state) => &state.hash == file_hash,
+                // Jobs from before file states were tracked
+                None => &f.hash == file_hash,
+            };
+            ctx.prev_snapshot
+                .get(&f.path)
+                .filter(|kfc| known_hash && &kfc.file.hash == file_hash)
+        });
+        if let Some(kfc) = unchanged {
+            tx.send(KipUploadMsg::FileState(
+                f.path.clone(),
+                KipFileState::new(&md, &kfc.file.hash)?,
+            ))?;
+            // The contents are unchanged but permissions
+            // or ownership may not be
+            let mut kfc = kfc.clone();
+            kfc.file.meta = KipFileMeta::from_metadata(&f.path, &md)?;
+            tx.send(KipUploadMsg::Unchanged(kfc))?;
             let log = format!(
                 "{}-{} ⇉ skipped '{}', no changes found.",
                 job.name,
@@ -380,7 +665,20 @@ impl Run {
             tx.send(KipUploadMsg::Log(log.clone()))?;
             tx.send(KipUploadMsg::Skipped)?;
             debug!("no changes found");
+        } else if let Some((kfc, state)) = ctx
+            .checkpoint
+            .files
+            .get(&f.path)
+            .filter(|(_, state)| state.matches(&md))
+        {
+            // Finished before the run was interrupted
+            tx.send(KipUploadMsg::FileState(f.path.clone(), state.clone()))?;
+            tx.send(KipUploadMsg::KipFileChunked(kfc.clone()))?;
+            debug!("file already uploaded before the run was resumed");
         } else {
+            // Wait for a free slot under the run's memory ceiling
+            let _chunk_slot = ctx.chunk_slots.acquire().await?;
+
             // Create progress bar
             let progress_cancel = Arc::clone(&progress);
             let bar_label = gen_progress_label(
@@ -394,40 +692,93 @@ impl Run {
                 &bar_label,
             );
 
-            // Encrypt the whole file
-            let encrypted_file = encrypt_and_compress(&file, secret, self.compress).await?;
-
             // Show progress bar
             progress
                 .lock()
                 .await
-                .set_total_and_draw(&bar, encrypted_file.len());
+                .set_total_and_draw(&bar, file_len.max(1));
 
-            // Check if all file chunks are already in provider
-            // to avoid overwite and needless upload
+            // Chunk the plaintext as it's read from disk so that
+            // unchanged data always produces the same chunks, then
+            // compress and encrypt each chunk on its own
             debug!("chunking file: {}", f.path.display());
-            let (mut kcf, chunks) =
-                chunk_file(&f.path, f.hash.to_owned(), f.len, &encrypted_file).await?;
-            // Set file hash before return
-            kcf.file.set_hash(file_hash);
+            let mut kcf = KipFileChunked::new(&f.path, String::new(), file_len);
+            kcf.file.meta = KipFileMeta::from_metadata(&f.path, &md)?;
+            let mut hasher = Hasher::new(Algorithm::SHA256);
+            let mut chunker = chunker(File::open(&f.path).await?);
+            let mut chunk_stream = Box::pin(chunker.as_stream());
 
             // Upload to the provider for this job
             // Either S3, Gdrive, or USB
-            for (chunk, chunk_bytes) in chunks {
-                debug!("starting S3 upload");
-                match job
-                    .provider
-                    .upload(
-                        &client,
-                        KipUploadOpts::new(job.id, tx.clone()),
-                        &chunk,
-                        chunk_bytes,
-                    )
-                    .await
-                {
+            let mut uploaded = HashSet::<String>::new();
+            while let Some(entry) = chunk_stream.next().await {
+                // A paused run lets in-flight chunks finish
+                // and waits to upload the next ones
+                ctx.signals.wait_while_paused().await;
+                // An aborted run leaves the file out of its delta
+                if ctx.signals.is_aborted() {
+                    progress_cancel.lock().await.cancel(bar);
+                    debug!("run aborted, stopping upload of {}", f.path.display());
+                    return Ok(());
+                }
+                let entry = entry?;
+                hasher.write_all(&entry.data)?;
+                let mut chunk = FileChunk::from_chunk_data(&f.path, &entry, &ctx.chunk_key)?;
+                // Skip chunks a previous run or an earlier chunk of
+                // this file already stored, keeping their compression
+                let stored = match ctx.known_chunks.get(&chunk.hash) {
+                    Some(compress) => Some(*compress),
+                    None if !uploaded.insert(chunk.hash.clone()) => Some(self.compress),
+                    None => None,
+                };
+                if let Some(compress) = stored {
+                    debug!("chunk {} already stored, skipping upload", chunk.hash);
+                    chunk.set_compress(compress);
+                    progress.lock().await.inc_and_draw(&bar, chunk.length);
+                    kcf.add_chunk(chunk);
+                    continue;
+                }
+                // Providers take ownership so large chunks can be
+                // uploaded in parts without copying them
+                let encrypted_chunk = Bytes::from(
+                    encrypt_and_compress(&entry.data, &ctx.secret, self.compress).await?,
+                );
+                chunk.set_compress(self.compress);
+                // Release the plaintext before uploading
+                drop(entry);
+                debug!("starting provider upload");
+                let upload = async {
+                    // Wait for the job's bandwidth limit
+                    ctx.bandwidth.acquire(encrypted_chunk.len()).await;
+                    job.provider
+                        .upload(
+                            &client,
+                            KipUploadOpts::new(job.id, tx.clone()).with_run(&job.name, self.id),
+                            &chunk,
+                            encrypted_chunk,
+                        )
+                        .await
+                };
+                // Drop the in-flight upload if the run is aborted
+                let result = tokio::select! {
+                    result = upload => result,
+                    _ = ctx.signals.aborted() => {
+                        progress_cancel.lock().await.cancel(bar);
+                        debug!("run aborted, dropped upload of {}", chunk.hash);
+                        return Ok(());
+                    }
+                };
+                match result {
                     Ok(bu) => {
-                        // Increment progress bar by chunk bytes len
-                        progress.lock().await.inc_and_draw(&bar, bu);
+                        // Record the chunk so a resumed run skips it
+                        ctx.checkpoint.record_chunk(&chunk.hash, bu.try_into()?)?;
+                        let remote_path = chunk_path(&job.provider, job.id, &chunk.hash);
+                        match ctx.index.lock() {
+                            Ok(mut index) => index.record(&remote_path)?,
+                            Err(e) => bail!("chunk index lock poisoned: {e}"),
+                        }
+                        // Increment progress bar by chunk's plaintext len
+                        progress.lock().await.inc_and_draw(&bar, chunk.length);
                         // Increment run's uploaded bytes
                         tx.send(KipUploadMsg::BytesUploaded(bu.try_into()?))?;
                         // Push logs
@@ -440,8 +791,7 @@ impl Run {
                             chunk.hash,
                             job.provider.name(),
                         )))?;
-                        // Set chunk's remote path
-                        set_chunk_path(&mut kcf, job.provider.clone(), job.id, &chunk.hash)
+                        kcf.add_chunk(chunk);
                     }
                     Err(e) => {
                         // Cancel progress bar
@@ -458,7 +808,17 @@ impl Run {
                     }
                 }
             }
+            // The file's hash covers exactly the bytes chunked,
+            // even if the file changed after it was stat'd
+            let file_hash = hex_encode(&hasher.finish());
+            kcf.file.set_hash(file_hash.clone());
+            // Set every chunk's remote path, including
+            // deduplicated ones
+            set_chunk_paths(&mut kcf, &job.provider, job.id);
             // Add completed file
+            let state = KipFileState::new(&md, file_hash)?;
+            ctx.checkpoint.record_file(&kcf, &state)?;
+            tx.send(KipUploadMsg::FileState(f.path.clone(), state))?;
             tx.send(KipUploadMsg::KipFileChunked(kcf))?;
         }
 
@@ -482,131 +842,169 @@ impl Run {
             self.id,
         );
 
-        // Confirm delta is not nil
-        if self.delta.is_empty() {
-            bail!("nothing to restore, no files were changed on this run.")
+        // Restore every file in the job as it was at this run
+        let files = job.snapshot_at(self.id.try_into()?);
+        if files.is_empty() {
+            bail!("nothing to restore, no files were backed up as of this run.")
         }
 
         // Create job's provider client
         let client = job.provider.get_client().await?;
+        let bandwidth = job.bandwidth();
+
+        // Chunks in archive storage classes must be restored
+        // before they can be downloaded
+        let mut archived = files
+            .iter()
+            .filter(|kfc| !kfc.damaged)
+            .flat_map(|kfc| kfc.chunks.iter().map(|c| c.remote_path.clone()))
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>();
+        // Files with chunks that can't be restored are damaged
+        let mut unavailable = HashSet::<String>::new();
+        loop {
+            let status = job.provider.restore_archived(&client, &archived).await?;
+            unavailable.extend(status.unavailable);
+            archived = status.pending;
+            if archived.is_empty() {
+                break;
+            }
+            println!(
+                "[{}] {}-{} ⇉ waiting for {} archived chunks to be restored. This can take several hours.",
+                Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                job.name,
+                self.id,
+                archived.len(),
+            );
+            tokio::time::sleep(KIP_RESTORE_POLL).await;
+        }
 
-        // For each object in the bucket, download it
+        // For each file in the run, download its chunks in order,
+        // decrypting and writing each one before fetching the next
         let mut counter: u64 = 0;
-        for kfc in self.delta.iter() {
+        let total = files
+            .iter()
+            .filter(|kfc| kfc.file.meta.kind == KipFileKind::File)
+            .count();
+        let mut dirs = Vec::<(PathBuf, &KipFileMeta)>::new();
+        'files: for kfc in files.iter() {
             let local_path = kfc.file.path.display().to_string();
+            match kfc.file.meta.kind {
+                KipFileKind::Dir => {
+                    let dir = restore_path(&kfc.file.path, output_folder)?;
+                    create_dir_all(&dir).await?;
+                    // Restoring files within a directory changes its
+                    // mtime, so its metadata is applied last
+                    dirs.push((dir, &kfc.file.meta));
+                    continue;
+                }
+                KipFileKind::Symlink => {
+                    debug!("creating symlink");
+                    let link = create_symlink(&kfc.file.path, &kfc.file.meta, output_folder)
+                        .await
+                        .and_then(|link| kfc.file.meta.restore(link));
+                    if let Err(e) = link {
+                        self.restore_meta_failed(job, &local_path, e);
+                    }
+                    continue;
+                }
+                KipFileKind::File => {}
+            }
 
-            if kfc.is_single_chunk() {
-                let chunk = kfc.chunks.iter().next().map(|(_, c)| c).unwrap();
+            // Some of the file's chunks are known to be lost
+            let damaged = kfc
+                .chunks
+                .iter()
+                .any(|c| unavailable.contains(&c.remote_path));
+            if kfc.damaged || damaged {
+                let log = format!(
+                    "[{}] {}-{} ⇉ '{}' is damaged and can't be restored. ({counter}/{total})",
+                    Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                    job.name,
+                    self.id,
+                    local_path.red(),
+                );
+                error!("{log}");
+                eprintln!("{log}");
+                continue;
+            }
+
+            // Creates or opens restored file
+            debug!("creating or opening file");
+            let restored_path = restore_path(&kfc.file.path, output_folder)?;
+            let mut cfile = create_file(&kfc.file.path, output_folder).await?;
+            let mut hasher = Hasher::new(Algorithm::SHA256);
+
+            let mut chunks = kfc.chunks.iter().collect::<Vec<&FileChunk>>();
+            chunks.sort_by_key(|c| c.offset);
+            for chunk in chunks {
                 // Download chunk
                 let chunk_bytes = match job.provider.download(&client, &chunk.remote_path).await {
                     Ok(cb) => cb,
                     Err(e) => {
                         let log = format!(
-                            "[{}] {}-{} ⇉ '{}' restore failed. ({counter}/{})",
+                            "[{}] {}-{} ⇉ '{}' chunk download failed. ({counter}/{})",
                             Utc::now().format("%Y-%m-%d %H:%M:%S"),
                             job.name,
                             self.id,
-                            local_path.red(),
-                            self.delta.len(),
+                            chunk.hash.red(),
+                            total,
                         );
                         error!("{log}: {e}");
                         eprintln!("{log}");
-                        continue;
+                        continue 'files;
                     }
                 };
+                // Downloads are held to the job's bandwidth limit
+                bandwidth.acquire(chunk_bytes.len()).await;
                 // Decrypt before decompression (if enabled)
-                let decrypted = decrypt_decompress(&chunk_bytes, secret, self.compress).await?;
-                // If a single-chunk file, simply decrypt and write
-                let mut cfile = create_file(&kfc.file.path, output_folder).await?;
-                cfile.write_all(&decrypted).await?;
-            } else {
-                // Create anon mmap to temporarily store chunks
-                // during file assembly before writing to disk
-                let mut multi_chunks = HashMap::<FileChunk, Vec<u8>>::new();
-                let mut chunks_len: usize = 0;
-
-                // Download all chunks
-                let mut chunks_stream = tokio_stream::iter(kfc.chunks.values());
-                while let Some(chunk) = chunks_stream.next().await {
-                    let chunk_bytes = match job.provider.download(&client, &chunk.remote_path).await
-                    {
-                        Ok(cb) => cb,
-                        Err(e) => {
-                            error!("error downloading chunk {}: {e}", &chunk.remote_path);
-                            vec![]
-                        }
-                    };
-                    // Ruh-roh, chunk bytes shouldn't be empty,
-                    // download failed
-                    if chunk_bytes.is_empty() {
+                let compress = chunk.compress.unwrap_or(self.compress);
+                let decrypted = match decrypt_decompress(&chunk_bytes, secret, compress).await {
+                    Ok(decrypted) => decrypted,
+                    Err(e) => {
                         let log = format!(
-                            "[{}] {}-{} ⇉ '{}' chunk download failed. ({counter}/{})",
-                            Utc::now().format("%y-%m-%d %h:%m:%s"),
+                            "[{}] {}-{} ⇉ '{}' chunk is corrupt and can't be decrypted. ({counter}/{})",
+                            Utc::now().format("%Y-%m-%d %H:%M:%S"),
                             job.name,
                             self.id,
                             chunk.hash.red(),
-                            self.delta.len(),
+                            total,
                         );
-                        error!("{log}");
+                        error!("{log}: {e}");
                         eprintln!("{log}");
-                        break;
+                        continue 'files;
                     }
-                    // Seeks to the offset where this chunked data
-                    // segment begins and write it to completion
-                    chunks_len += chunk_bytes.len();
-                    multi_chunks.insert(chunk.clone(), chunk_bytes);
-                    debug!("chunk written to offset {}", chunk.offset);
-                }
-
-                // Error downloading or assembling chunk bytes,
-                // vec is empty
-                if multi_chunks.is_empty() {
-                    let log = format!(
-                        "[{}] {}-{} ⇉ '{}' file assembly failed. ({counter}/{})",
-                        Utc::now().format("%y-%m-%d %h:%m:%s"),
-                        job.name,
-                        self.id,
-                        kfc.file.path.display().to_string().red(),
-                        self.delta.len(),
-                    );
-                    error!("{log}");
-                    eprintln!("{log}");
-                    continue;
-                }
+                };
+                hasher.write_all(&decrypted)?;
+                cfile.write_all(&decrypted).await?;
+                debug!("chunk written to offset {}", chunk.offset);
+            }
+            debug!("flushing to disk");
+            cfile.flush().await?;
+            drop(cfile);
 
-                // Decrypt before decompression (if enabled)
-                debug!("decrypting and decompressing restored file");
-                let mut mcm: MmapMut = MmapOptions::new().len(chunks_len).map_anon()?;
-                let mut cursor = Cursor::new(&mut mcm[..]);
-                for (chk, cb) in multi_chunks.iter() {
-                    cursor.seek(SeekFrom::Start(chk.offset.try_into()?)).await?;
-                    cursor.write_all(cb).await?;
-                }
-                let decrypted = decrypt_decompress(&mcm[..], secret, self.compress).await?;
-
-                // Hash the restored file and compare it to
-                // the original KipFile hash
-                debug!("comparing hash with the original file's hash");
-                if hex_digest(Algorithm::SHA256, &decrypted) != kfc.file.hash {
-                    let log = format!(
-                        "[{}] {}-{} ⇉ '{}' restore failed. ({counter}/{})",
-                        Utc::now().format("%Y-%m-%d %H:%M:%S"),
-                        job.name,
-                        self.id,
-                        local_path.red(),
-                        self.delta.len(),
-                    );
-                    error!("{log}: restored hash did not match original file hash");
-                    eprintln!("{log}");
-                    continue;
-                }
+            // Hash the restored file and compare it to
+            // the original KipFile hash
+            debug!("comparing hash with the original file's hash");
+            if hex_encode(&hasher.finish()) != kfc.file.hash {
+                let log = format!(
+                    "[{}] {}-{} ⇉ '{}' restore failed. ({counter}/{})",
+                    Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                    job.name,
+                    self.id,
+                    local_path.red(),
+                    total,
+                );
+                error!("{log}: restored hash did not match original file hash");
+                eprintln!("{log}");
+                continue;
+            }
 
-                // Creates or opens restored file
-                debug!("creating or opening file");
-                let mut cfile = create_file(&kfc.file.path, output_folder).await?;
-                cfile.write_all(&decrypted).await?;
-                debug!("flushing to disk");
-                cfile.flush().await?;
+            // The contents are intact, so a file whose metadata
+            // can't be applied is still counted as restored
+            if let Err(e) = kfc.file.meta.restore(&restored_path) {
+                self.restore_meta_failed(job, &local_path, e);
             }
 
             // Increment file resote counter
@@ -617,11 +1015,32 @@ impl Run {
                 job.name,
                 self.id,
                 local_path.green(),
-                self.delta.len(),
+                total,
             );
         }
+
+        // Deepest directories first so restoring one
+        // doesn't change its parent's mtime
+        dirs.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+        for (dir, meta) in dirs {
+            if let Err(e) = meta.restore(&dir) {
+                self.restore_meta_failed(job, &dir.display().to_string(), e);
+            }
+        }
         Ok(())
     }
+
+    fn restore_meta_failed(&self, job: &Job, local_path: &str, e: anyhow::Error) {
+        let log = format!(
+            "[{}] {}-{} ⇉ unable to restore metadata of '{}': {e}.",
+            Utc::now().format("%Y-%m-%d %H:%M:%S"),
+            job.name,
+            self.id,
+            local_path.yellow(),
+        );
+        warn!("{log}");
+        eprintln!("{log}");
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -630,7 +1049,7 @@ fn upload_future(
     client: Arc<KipClient>,
     kf: Arc<KipFile>,
     job: Arc<Job>,
-    secret: String,
+    ctx: Arc<KipRunCtx>,
     progress: Arc<Mutex<Progress>>,
     upload_tx: UnboundedSender<KipUploadMsg>,
     limiter_permit: OwnedSemaphorePermit,
@@ -638,7 +1057,7 @@ fn upload_future(
     let path = kf.path.display().to_string();
     tokio::task::spawn(async move {
         match run
-            .start_inner(client, kf, job, &secret, progress, upload_tx.clone())
+            .start_inner(client, kf, job, ctx, progress, upload_tx.clone())
             .await
         {
             Ok(_) => {
@@ -658,34 +1077,61 @@ fn upload_future(
     })
 }
 
-fn set_chunk_path(kcf: &mut KipFileChunked, provider: KipProviders, jid: Uuid, hash: &str) {
-    if let Some(c) = kcf.chunks.get_mut(hash) {
-        match provider {
-            KipProviders::S3(_) => {
-                c.set_remote_path(&format!("{jid}/chunks/{hash}.chunk"));
-            }
-            KipProviders::Usb(_) => {
-                c.set_remote_path(&format!("{jid}/chunks/{hash}.chunk",));
-            }
-            KipProviders::Gdrive(ref gd) => {
-                c.set_remote_path(&format!(
-                    "{}/chunks/{hash}.chunk",
-                    gd.parent_folder.clone().unwrap(),
-                ));
-            }
+fn set_chunk_paths(kcf: &mut KipFileChunked, provider: &KipProviders, jid: Uuid) {
+    for c in kcf.chunks.iter_mut() {
+        let remote_path = chunk_path(provider, jid, &c.hash);
+        c.set_remote_path(remote_path);
+    }
+}
+
+/// Returns where a provider stores a chunk
+fn chunk_path(provider: &KipProviders, jid: Uuid, hash: &str) -> String {
+    match provider {
+        KipProviders::S3(_) | KipProviders::Azure(_) => format!("{jid}/chunks/{hash}.chunk"),
+        KipProviders::Usb(_) => format!("{jid}/chunks/{hash}.chunk"),
+        KipProviders::Local(_)
+        | KipProviders::Sftp(_)
+        | KipProviders::Smb(_)
+        | KipProviders::WebDav(_) => local::chunk_path(jid, hash),
+        KipProviders::Gdrive(gd) => {
+            format!("{}/chunks/{hash}.chunk", gd.parent_folder.clone().unwrap(),)
         }
     }
 }
 
-/// Creates a restored file and its parent folders while
-/// properly handling file prefixes depending on the running OS.
-async fn create_file(path: &Path, output_folder: &str) -> Result<File> {
+/// Whether two snapshots hold the same files with the same
+/// contents and metadata. Access times are left out since
+/// reading a file changes its own.
+fn same_snapshot(a: &[KipFileChunked], b: &[KipFileChunked]) -> bool {
+    let files = |snapshot: &[KipFileChunked]| {
+        snapshot
+            .iter()
+            .map(|kfc| {
+                let meta = KipFileMeta {
+                    atime: None,
+                    ..kfc.file.meta.clone()
+                };
+                (kfc.file.path.clone(), (kfc.file.hash.clone(), meta))
+            })
+            .collect::<BTreeMap<PathBuf, (String, KipFileMeta)>>()
+    };
+    files(a) == files(b)
+}
+
+/// Returns where a file is restored to within the output folder
+/// while properly handling file prefixes depending on the running OS.
+fn restore_path(path: &Path, output_folder: &str) -> Result<PathBuf> {
     // Only strip prefix if path has a prefix
     let mut correct_chunk_path = path;
     if !cfg!(windows) && path.starts_with("/") {
         correct_chunk_path = path.strip_prefix("/")?;
     }
-    let folder_path = Path::new(&output_folder).join(correct_chunk_path);
+    Ok(Path::new(&output_folder).join(correct_chunk_path))
+}
+
+/// Creates a restored file and its parent folders.
+async fn create_file(path: &Path, output_folder: &str) -> Result<File> {
+    let folder_path = restore_path(path, output_folder)?;
     let folder_parent = folder_path.parent().unwrap_or(&folder_path);
     create_dir_all(folder_parent).await?;
     // Create the file
@@ -699,7 +1145,27 @@ async fn create_file(path: &Path, output_folder: &str) -> Result<File> {
     Ok(cfile)
 }
 
-async fn encrypt_and_compress(
+/// Recreates a restored symlink, replacing anything
+/// already at its path.
+async fn create_symlink(path: &Path, meta: &KipFileMeta, output_folder: &str) -> Result<PathBuf> {
+    let Some(target) = &meta.symlink_target else {
+        bail!("no symlink target recorded for {}", path.display())
+    };
+    let link = restore_path(path, output_folder)?;
+    if let Some(parent) = link.parent() {
+        create_dir_all(parent).await?;
+    }
+    if tokio::fs::symlink_metadata(&link).await.is_ok() {
+        tokio::fs::remove_file(&link).await?;
+    }
+    #[cfg(unix)]
+    tokio::fs::symlink(target, &link).await?;
+    #[cfg(windows)]
+    tokio::fs::symlink_file(target, &link).await?;
+    Ok(link)
+}
+
+pub(crate) async fn encrypt_and_compress(
     bytes: &[u8],
     secret: &str,
     compress: KipCompressOpts,
@@ -761,22 +1227,27 @@ pub async fn decrypt_decompress(
     Ok(decrypted)
 }
 
-pub async fn open_file(path: &Path, file_len: u64) -> Result<Vec<u8>> {
-    // Open the file
-    let file = if file_len > crate::MAX_OPEN_FILE_LEN {
-        debug!("opening {} with mmap", path.display());
-        // SAFETY: unsafe used here for mmap
-        let mmap = unsafe {
-            MmapOptions::new()
-                .populate()
-                .map(&File::open(path).await?)?
-        };
-        mmap.to_vec()
-    } else {
-        debug!("opening {} with tokio", path.display());
-        read(path).await?
-    };
-    Ok(file)
+/// Hashes a file's contents with SHA256, reading it in
+/// blocks instead of loading the whole file into memory.
+pub async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::new(Algorithm::SHA256);
+    let mut buf = vec![0u8; HASH_BUF_LEN];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..n])?;
+    }
+    Ok(hex_encode(&hasher.finish()))
+}
+
+/// Returns how many files can be chunked at once under the
+/// memory limit provided. At least one file is always allowed.
+fn chunk_slots(memory_limit: u64) -> usize {
+    let slots = (memory_limit / CHUNK_SLOT_LEN).max(1);
+    slots.min(CONCURRENT_FILE_UPLOADS as u64) as usize
 }
 
 fn gen_progress_label(job: &str, id: u64, file: &str) -> String {
@@ -792,180 +1263,169 @@ fn gen_progress_label(job: &str, id: u64, file: &str) -> String {
     }
 }
 
-//fn check_missing_chunks() {
-//    debug!("checking provider for missing chunks");
-//    let mut chunks_missing: u32 = 0;
-//    for chunk in kcf.chunks.iter() {
-//        match &job.provider {
-//            KipProviders::S3(s3) => {
-//                if !s3.contains(job.id, &chunk.hash).await? {
-//                    debug!(
-//                        "missing chunk {}; total missing: {chunks_missing}",
-//                        &chunk.hash
-//                    );
-//                    chunks_missing += 1;
-//                };
-//            }
-//            KipProviders::Usb(usb) => {
-//                if !usb.contains(job.id, &chunk.hash).await? {
-//                    debug!(
-//                        "missing chunk {}; total missing: {chunks_missing}",
-//                        &chunk.hash
-//                    );
-//                    chunks_missing += 1;
-//                };
-//            }
-//            KipProviders::Gdrive(gdrive) => {
-//                if !gdrive.contains(job.id, &chunk.hash).await? {
-//                    debug!(
-//                        "missing chunk {}; total missing: {chunks_missing}",
-//                        &chunk.hash
-//                    );
-//                    chunks_missing += 1;
-//                };
-//            }
-//        }
-//    }
-//}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    // use crate::compress::{KipCompressAlg, KipCompressLevel, KipCompressOpts};
     use std::fs::read;
     use std::path::PathBuf;
     use tempfile::tempdir;
 
-    // #[test]
-    // fn test_is_single_chunk() {
-    //     let mut r = Run::new(
-    //         9998,
-    //         KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Default),
-    //     );
-    //     let content_result = read(&PathBuf::from("test/random.txt"));
-    //     assert!(content_result.is_ok());
-    //     let contents = content_result.unwrap();
-    //     let chunk_hmap_result =
-    //         chunk_file(PathBuf::from("test/random.txt"), String::new(), &contents);
-    //     assert!(chunk_hmap_result.is_ok());
-    //     let (_, chunk_hmap) = chunk_hmap_result.unwrap();
-    //     let mut t = vec![];
-    //     let mut fc = FileChunk::new(&Path::new("test/random.txt"), "", 0, 0, 0);
-    //     for c in chunk_hmap {
-    //         t.push(c.0.clone());
-    //         fc = c.0;
-    //     }
-    //     for cc in t {
-    //         r.delta.push(cc);
-    //     }
-    //     assert!(r.is_single_chunk(&fc))
-    // }
-
-    // #[test]
-    // fn test_is_not_single_chunk() {
-    //     let mut r = Run::new(
-    //         9999,
-    //         KipCompressOpts::new(true, KipCompressAlg::Zstd, KipCompressLevel::Default),
-    //     );
-    //     let content_result = read(&PathBuf::from("test/kip"));
-    //     assert!(content_result.is_ok());
-    //     let contents = content_result.unwrap();
-    //     let chunk_hmap_result = chunk_file(PathBuf::from("test/kip"), String::new(), &contents);
-    //     assert!(chunk_hmap_result.is_ok());
-    //     let (_, chunk_hmap) = chunk_hmap_result.unwrap();
-    //     let mut t = vec![];
-    //     let mut fc = FileChunk::new(&Path::new("test/kip"), "", 0, 0, 0);
-    //     for c in chunk_hmap {
-    //         t.push(c.0.clone());
-    //         fc = c.0;
-    //     }
-    //     for cc in t {
-    //         r.delta.push(cc);
-    //     }
-    //     assert!(!r.is_single_chunk(&fc))
-    // }
-
-    // #[test]
-    // fn test_assemble_chunks() {
-    //     // Chunk test file
-    //     let read_result = read(Path::new("test/kip"));
-    //     assert!(read_result.is_ok());
-    //     let contents = read_result.unwrap();
-    //     let (_, chunks) = chunk_file(PathBuf::from("test/kip"), String::new(), &contents);
-    //     // Create temp dir for testing
-    //     let tmp_dir = tempdir();
-    //     assert!(tmp_dir.is_ok());
-    //     let tmp_dir = tmp_dir.unwrap();
-    //     let dir = tmp_dir.path().to_str().unwrap();
-    //     // Convert chunks from HashMap to Vec for reassembly
-    //     let mut multi_chunks = HashMap::new();
-    //     for (chunk, chunk_bytes) in chunks.iter() {
-    //         multi_chunks.insert(chunk, chunk_bytes.to_vec());
-    //     }
-    //     assert_eq!(multi_chunks.len(), 4);
-    //     // Time to assemble
-    //     let result = assemble_chunks(&multi_chunks, Path::new("kip"), dir);
-    //     assert!(result.is_ok());
-    //     // Compare restored file with original
-    //     let test_result = read(tmp_dir.path().join("kip"));
-    //     assert!(test_result.is_ok());
-    //     let test_contents = test_result.unwrap();
-    //     assert_eq!(contents, test_contents);
-    //     // Destroy temp dir
-    //     let dir_result = tmp_dir.close();
-    //     assert!(dir_result.is_ok())
-    // }
-
-    // #[test]
-    // fn test_assemble_chunks() {
-    //     // Chunk test file
-    //     let read_result = read(Path::new("test/kip"));
-    //     assert!(read_result.is_ok());
-    //     let contents = read_result.unwrap();
-    //     let hash = hex_digest(Algorithm::SHA256, &contents);
-    //     let len = contents.len();
-    //     let (kfc, chunks) = chunk_file(PathBuf::from("test/kip"), hash, len, &contents);
-    //     // Create temp dir for testing
-    //     let tmp_dir = tempdir();
-    //     assert!(tmp_dir.is_ok());
-    //     let tmp_dir = tmp_dir.unwrap();
-    //     let dir = tmp_dir.path().to_str().unwrap();
-    //     // Creates or opens restored file
-    //     let mut cfile = create_file(&kfc.file.path, dir).unwrap();
-    //     // Create anon mmap to temporarily store chunks
-    //     // during file assembly before writing to disk
-    //     let mut mmap = MmapOptions::new().len(kfc.file.len).map_anon().unwrap();
-    //     mmap.advise(memmap2::Advice::Random).unwrap();
-    //     for chunk in kfc.chunks.iter() {
-    //         // Seeks to the offset where this chunked data
-    //         // segment begins and write it to completion
-    //         let mut mmap_cursor = Cursor::new(&mut mmap[..]);
-    //         mmap_cursor
-    //             .seek(SeekFrom::Start(chunk.offset.try_into().unwrap()))
-    //             .unwrap();
-    //         // cfile.seek(SeekFrom::Start(chunk.offset.try_into()?));
-    //         mmap_cursor.write_all(&chunks[chunk]).unwrap();
-    //         // mmap.deref_mut().write_all(&chunk_bytes)?;
-    //         // cfile.write_all(&chunk_bytes)?;
-    //     }
-    //     mmap.advise(memmap2::Advice::Sequential).unwrap();
-    //     // Decrypt before decompression (if enabled)
-    //     let decrypted = decrypt_decompress(&mmap[..], secret, self.compress)
-    //         .await
-    //         .unwrap();
-    //     drop(mmap);
-    //     // Hash the restored file and compare it to
-    //     // the original KipFile hash
-    //     assert_eq!(hex_digest(Algorithm::SHA256, &decrypted), kfc.file.hash)
-    //     // Seek to beginning of mmap and write (flush) changes to disk
-    //     //debug!("resetting mmap cursor to start of file");
-    //     // Cursor::new(&mut mmap[..]).seek(SeekFrom::Start(0))?;
-    //     // cfile.rewind()?;
-    //     // mmap.deref_mut().write_all(&decrypted)?;
-    //     cfile.write_all(&decrypted).unwrap();
-    //     // mmap.flush_async()?;
-    //     cfile.flush().unwrap();
-    // }
+    #[tokio::test]
+    async fn test_hash_file() {
+        let hash = hash_file(Path::new("test/random.txt")).await.unwrap();
+        assert_eq!(
+            hash,
+            "44b4cdaf713dfaf961dedb34f07e15604f75eb049c83067ab35bf388b369dbf3"
+        )
+    }
+
+    #[tokio::test]
+    #[cfg_attr(target_os = "windows", ignore)]
+    async fn test_restore_corrupt_chunk() {
+        let tmp_dir = tempdir().unwrap();
+        let provider = KipProviders::Usb(crate::providers::usb::KipUsb::new(
+            "test_usb",
+            tmp_dir.path(),
+            0,
+            0,
+        ));
+        let compress = KipCompressOpts::new(
+            true,
+            KipCompressAlg::Zstd,
+            crate::compress::KipCompressLevel::Best,
+        );
+        let mut job = Job::new("testing1", provider, compress);
+        let jid = job.id;
+        std::fs::create_dir_all(tmp_dir.path().join(format!("{jid}/chunks"))).unwrap();
+        std::fs::create_dir_all(tmp_dir.path().join("src")).unwrap();
+        let mut run = Run::new(1, compress);
+        for (name, corrupt) in [("bad.txt", true), ("good.txt", false)] {
+            let path = tmp_dir.path().join("src").join(name);
+            std::fs::write(&path, name).unwrap();
+            let mut kfc = KipFileChunked::new(&path, hash_file(&path).await.unwrap(), name.len());
+            let mut chunk = FileChunk::new(&path, name, 0, name.len(), name.len());
+            chunk.set_remote_path(format!("{jid}/chunks/{name}.chunk"));
+            let chunk_bytes = match corrupt {
+                true => b"not a chunk".to_vec(),
+                false => encrypt_and_compress(name.as_bytes(), "hunter2", compress)
+                    .await
+                    .unwrap(),
+            };
+            std::fs::write(tmp_dir.path().join(&chunk.remote_path), chunk_bytes).unwrap();
+            kfc.add_chunk(chunk);
+            run.snapshot.push(kfc);
+        }
+        job.runs.insert(1, run.clone());
+
+        // The corrupt chunk fails its file, not the restore
+        let out = tmp_dir.path().join("out");
+        run.restore(&job, "hunter2", out.to_str().unwrap())
+            .await
+            .unwrap();
+        let restored = restore_path(&tmp_dir.path().join("src"), out.to_str().unwrap()).unwrap();
+        assert_eq!(read(restored.join("good.txt")).unwrap(), b"good.txt");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_backup_restore_symlink() {
+        let tmp_dir = tempdir().unwrap();
+        let provider = KipProviders::Usb(crate::providers::usb::KipUsb::new(
+            "test_usb",
+            tmp_dir.path(),
+            0,
+            0,
+        ));
+        let compress = KipCompressOpts::new(
+            true,
+            KipCompressAlg::Zstd,
+            crate::compress::KipCompressLevel::Best,
+        );
+        let mut job = Job::new("testing1", provider, compress);
+        let src = tmp_dir.path().join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("target.txt"), "kip").unwrap();
+        std::os::unix::fs::symlink("target.txt", src.join("link")).unwrap();
+        // The job lists the link itself, not what it points to
+        let entry = crate::job::resolve_entry(src.join("link"), false).unwrap();
+        assert_eq!(entry.file_name().unwrap(), "link");
+        job.files.push(KipFile::new(&entry).unwrap());
+
+        let control =
+            crate::control::KipRunControl::acquire_in(tmp_dir.path().join("control"), job.id)
+                .unwrap();
+        let checkpoint =
+            KipCheckpoint::create_in(tmp_dir.path().join("checkpoints"), job.id, 1, compress)
+                .unwrap();
+        let mut run = Run::new(1, compress);
+        run.start(
+            Arc::new(job.clone()),
+            String::from("hunter2"),
+            KipRunOpts::new(false, false, 0),
+            control.signals(),
+            Arc::new(checkpoint),
+        )
+        .await
+        .unwrap();
+        // A job of only a link still records a run
+        assert_eq!(run.status, KipStatus::OK);
+        assert_eq!(run.snapshot.len(), 1);
+        assert_eq!(run.snapshot[0].file.meta.kind, KipFileKind::Symlink);
+        assert!(run.snapshot[0].chunks.is_empty());
+
+        job.runs.insert(1, run.clone());
+        let out = tmp_dir.path().join("out");
+        run.restore(&job, "hunter2", out.to_str().unwrap())
+            .await
+            .unwrap();
+        let restored = restore_path(&entry, out.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_link(restored).unwrap(),
+            PathBuf::from("target.txt")
+        );
+    }
+
+    #[test]
+    fn test_same_snapshot() {
+        let kfc = |path: &str, hash: &str| KipFileChunked::new(path, hash, 1);
+        let prev = vec![kfc("/a.txt", "a1"), kfc("/b.txt", "b1")];
+        assert!(same_snapshot(
+            &[kfc("/b.txt", "b1"), kfc("/a.txt", "a1")],
+            &prev
+        ));
+        // A deleted file
+        assert!(!same_snapshot(&[kfc("/a.txt", "a1")], &prev));
+        // A changed file
+        assert!(!same_snapshot(
+            &[kfc("/a.txt", "a2"), kfc("/b.txt", "b1")],
+            &prev
+        ));
+
+        let meta = |path: &str, mode: u32| {
+            let mut kfc = kfc(path, "");
+            kfc.file.meta.mode = Some(mode);
+            kfc.file.meta.atime = Some(Utc::now());
+            kfc
+        };
+        let prev = vec![meta("/a.txt", 0o644)];
+        // Reading a file only changes its access time
+        assert!(same_snapshot(&[meta("/a.txt", 0o644)], &prev));
+        // A chmod
+        assert!(!same_snapshot(&[meta("/a.txt", 0o600)], &prev));
+        // A new empty directory
+        let mut dir = meta("/c", 0o755);
+        dir.file.meta.kind = KipFileKind::Dir;
+        assert!(!same_snapshot(&[meta("/a.txt", 0o644), dir], &prev));
+    }
+
+    #[test]
+    fn test_chunk_slots() {
+        // Always at least one file at a time
+        assert_eq!(chunk_slots(0), 1);
+        assert_eq!(chunk_slots(CHUNK_SLOT_LEN * 4), 4);
+        // Never more than the concurrent file uploads
+        assert_eq!(chunk_slots(u64::MAX), CONCURRENT_FILE_UPLOADS);
+    }
 
     #[tokio::test]
     async fn test_create_file() {
@@ -1043,4 +1503,28 @@ mod tests {
         let dir_result = tmp_dir.close();
         assert!(dir_result.is_ok())
     }
+
+    #[tokio::test]
+    #[cfg_attr(target_os = "windows", ignore)]
+    async fn test_create_symlink() {
+        let tmp_dir = tempdir().unwrap();
+        let dir = tmp_dir.path().display().to_string();
+        let meta = KipFileMeta {
+            kind: KipFileKind::Symlink,
+            symlink_target: Some(PathBuf::from("target.txt")),
+            ..Default::default()
+        };
+        // Replaces whatever is already at the link's path
+        let path = PathBuf::from("/links/link");
+        create_file(&path, &dir).await.unwrap();
+        let link = create_symlink(&path, &meta, &dir).await.unwrap();
+        assert_eq!(link, tmp_dir.path().join("links/link"));
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            PathBuf::from("target.txt")
+        );
+        // Nothing to link to
+        let result = create_symlink(&path, &KipFileMeta::default(), &dir).await;
+        assert!(result.is_err());
+    }
 }